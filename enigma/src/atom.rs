@@ -1,3 +1,4 @@
+use crate::exception::{Exception, Reason};
 use hashbrown::HashMap;
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
@@ -10,6 +11,18 @@ pub fn cmp(a1: Atom, a2: Atom) -> std::cmp::Ordering {
 /// Maximum character length of an atom.
 pub const MAX_ATOM_CHARS: usize = 255;
 
+/// Default maximum number of atoms (same as BEAM's `+t` default).
+pub const DEFAULT_ATOM_LIMIT: usize = 1_048_576;
+
+/// Smallest atom table size accepted by `+t`.
+pub const MIN_ATOM_LIMIT: usize = 8192;
+
+/// Largest atom table size accepted by `+t`.
+pub const MAX_ATOM_LIMIT: usize = i32::max_value() as usize;
+
+/// Size of a single arena chunk used to store atom names.
+const ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Atom(pub u32);
 
@@ -18,6 +31,49 @@ impl Atom {
     pub fn to_str(&self) -> Option<&'static str> {
         ATOMS.read().to_str(self.0)
     }
+
+    /// Returns the atom if it already exists, without creating it.
+    pub fn lookup(value: &str) -> Option<Atom> {
+        ATOMS.read().lookup(value).map(Atom)
+    }
+
+    /// Creates (or looks up) an atom, failing with `system_limit` if the name is too long or the
+    /// atom table is full. Use this for any atom created from user input.
+    pub fn try_from_str(value: &str) -> Result<Atom, Exception> {
+        if value.chars().count() > MAX_ATOM_CHARS {
+            return Err(Exception::new(Reason::EXC_SYSTEM_LIMIT));
+        }
+        if let Some(id) = ATOMS.read().lookup(value) {
+            return Ok(Atom(id));
+        }
+        ATOMS
+            .write()
+            .try_insert(value)
+            .map(Atom)
+            .ok_or_else(|| Exception::new(Reason::EXC_SYSTEM_LIMIT))
+    }
+}
+
+/// Number of atoms currently in the atom table.
+pub fn count() -> usize {
+    ATOMS.read().names.len()
+}
+
+/// Maximum number of atoms the atom table can hold.
+pub fn limit() -> usize {
+    ATOMS.read().limit
+}
+
+/// Sets the maximum number of atoms (`+t`). The limit can't be lowered below the current count.
+pub fn set_limit(limit: usize) {
+    let mut atoms = ATOMS.write();
+    let limit = std::cmp::max(limit, atoms.names.len());
+    atoms.limit = std::cmp::min(limit, u32::max_value() as usize);
+}
+
+/// Number of bytes used to store atom names.
+pub fn space() -> usize {
+    ATOMS.read().arena.size
 }
 
 impl std::fmt::Display for Atom {
@@ -28,25 +84,62 @@ impl std::fmt::Display for Atom {
 
 impl From<&str> for Atom {
     fn from(value: &str) -> Self {
-        assert!(value.len() <= MAX_ATOM_CHARS);
-        if let Some(id) = ATOMS.read().lookup(&value) {
-            return Atom(id);
+        match Atom::try_from_str(value) {
+            Ok(atom) => atom,
+            // BEAM also brings down the whole node in this case.
+            Err(_) => panic!(
+                "no more index entries in atom_tab (max={}) or atom too long",
+                limit()
+            ),
         }
-        let name = Box::leak(String::from(value).into_boxed_str());
-        let id = ATOMS.write().insert(name);
-        Atom(id)
     }
 }
 
 impl From<String> for Atom {
     fn from(value: String) -> Self {
-        assert!(value.len() <= MAX_ATOM_CHARS);
-        if let Some(id) = ATOMS.read().lookup(&value) {
-            return Atom(id);
+        Atom::from(value.as_str())
+    }
+}
+
+/// Append-only storage for atom names.
+///
+/// Names are copied into fixed-size chunks that are never reallocated, so the bytes stay at a
+/// stable address for as long as the table exists (which is forever, since it's static).
+struct Arena {
+    chunks: Vec<Vec<u8>>,
+    /// Total bytes stored.
+    size: usize,
+}
+
+impl Arena {
+    fn new() -> Self {
+        Arena {
+            chunks: Vec::new(),
+            size: 0,
+        }
+    }
+
+    fn alloc(&mut self, name: &str) -> &'static str {
+        let len = name.len();
+        let has_room = match self.chunks.last() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= len,
+            None => false,
+        };
+        if !has_room {
+            self.chunks
+                .push(Vec::with_capacity(std::cmp::max(ARENA_CHUNK_SIZE, len)));
+        }
+
+        let chunk = self.chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.extend_from_slice(name.as_bytes());
+        self.size += len;
+
+        // chunks never grow past their initial capacity, so this slice never moves.
+        unsafe {
+            let bytes = std::slice::from_raw_parts(chunk.as_ptr().add(start), len);
+            std::str::from_utf8_unchecked(bytes)
         }
-        let name = Box::leak(value.into_boxed_str());
-        let id = ATOMS.write().insert(name);
-        Atom(id)
     }
 }
 
@@ -56,23 +149,49 @@ pub(self) struct AtomTable {
 
     /// Reverse mapping atom index to string (sorted by index)
     names: Vec<&'static str>,
+
+    /// Backing storage for the names.
+    arena: Arena,
+
+    /// Maximum number of atoms `try_insert` accepts.
+    limit: usize,
 }
 
 impl AtomTable {
     pub fn new() -> Self {
+        AtomTable::with_limit(DEFAULT_ATOM_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
         AtomTable {
             ids: HashMap::new(),
             names: Vec::new(),
+            arena: Arena::new(),
+            limit,
+        }
+    }
+
+    /// Inserts an atom regardless of the limit. Only used to set up the predefined atoms.
+    pub fn insert(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.lookup(name) {
+            return id;
         }
+        let name = self.arena.alloc(name);
+        let index = self.names.len() as u32;
+        self.names.push(name);
+        self.ids.insert(name, index);
+        index
     }
 
-    pub fn insert(&mut self, name: &'static str) -> u32 {
-        let names = &mut self.names;
-        *self.ids.entry(name).or_insert_with(|| {
-            let index = names.len() as u32;
-            names.push(name);
-            index
-        })
+    /// Inserts an atom, returning None if the table is full.
+    pub fn try_insert(&mut self, name: &str) -> Option<u32> {
+        if let Some(id) = self.lookup(name) {
+            return Some(id);
+        }
+        if self.names.len() >= self.limit {
+            return None;
+        }
+        Some(self.insert(name))
     }
 
     pub fn lookup(&self, val: &str) -> Option<u32> {
@@ -418,6 +537,134 @@ pub(self) static ATOMS: Lazy<RwLock<AtomTable>> = Lazy::new(|| {
     atoms.insert("dotall");
     atoms.insert("re_pattern");
 
+    atoms.insert("monitors");
+    atoms.insert("minor_gcs");
+    atoms.insert("error_logger");
+    atoms.insert("old_heap_block_size");
+    atoms.insert("heap_block_size");
+    atoms.insert("mbuf_size");
+    atoms.insert("recent_size");
+    atoms.insert("old_heap_size");
+    atoms.insert("bin_vheap_size");
+    atoms.insert("bin_vheap_block_size");
+    atoms.insert("bin_old_vheap_size");
+    atoms.insert("bin_old_vheap_block_size");
+
+    atoms.insert("process_count");
+    atoms.insert("process_limit");
+    atoms.insert("atom_count");
+    atoms.insert("atom_limit");
+    atoms.insert("port_count");
+    atoms.insert("schedulers");
+    atoms.insert("schedulers_online");
+    atoms.insert("wordsize");
+    atoms.insert("build_type");
+    atoms.insert("opt");
+    atoms.insert("debug");
+    atoms.insert("logical_processors");
+    atoms.insert("logical_processors_available");
+    atoms.insert("logical_processors_online");
+    atoms.insert("thread_pool_size");
+    atoms.insert("allocated_areas");
+    atoms.insert("multi_scheduling");
+    atoms.insert("backtrace_depth");
+    atoms.insert("enabled");
+    atoms.insert("blocked");
+    atoms.insert("disabled");
+    atoms.insert("block");
+    atoms.insert("unblock");
+    atoms.insert("block_normal");
+    atoms.insert("unblock_normal");
+    atoms.insert("blocked_normal");
+    atoms.insert("ets_count");
+    atoms.insert("internal");
+    atoms.insert("processes");
+    atoms.insert("atom_table");
+    atoms.insert("atom_space");
+
+    atoms.insert("unless_suspending");
+    atoms.insert("asynchronous");
+
+    atoms.insert("fixed");
+    atoms.insert("safe_fixed");
+    atoms.insert("safe_fixed_monotonic_time");
+
+    atoms.insert("ETS-TRANSFER");
+
+    atoms.insert("owner");
+
+    atoms.insert("id");
+
+    atoms.insert("is_boolean");
+    atoms.insert("is_bitstring");
+    atoms.insert("tuple_size");
+    atoms.insert("byte_size");
+    atoms.insert("binary_part");
+    atoms.insert("ceil");
+    atoms.insert("floor");
+
+    atoms.insert("random");
+    atoms.insert("sequential");
+    atoms.insert("will_need");
+    atoms.insert("dont_need");
+    atoms.insert("no_reuse");
+    atoms.insert("enotsup");
+
+    atoms.insert("ram");
+
+    atoms.insert("continue");
+
+    atoms.insert("not_found");
+
+    atoms.insert("capture");
+    atoms.insert("first");
+    atoms.insert("all_but_first");
+    atoms.insert("all_names");
+    atoms.insert("list");
+    atoms.insert("offset");
+    atoms.insert("notempty");
+    atoms.insert("notempty_atstart");
+    atoms.insert("notbol");
+    atoms.insert("noteol");
+    atoms.insert("anchored");
+    atoms.insert("extended");
+    atoms.insert("dollar_endonly");
+    atoms.insert("no_auto_capture");
+    atoms.insert("dupnames");
+    atoms.insert("firstline");
+    atoms.insert("newline");
+    atoms.insert("cr");
+    atoms.insert("lf");
+    atoms.insert("crlf");
+    atoms.insert("anycrlf");
+    atoms.insert("any");
+    atoms.insert("return");
+    atoms.insert("iodata");
+    atoms.insert("parts");
+    atoms.insert("group");
+    atoms.insert("report_errors");
+    atoms.insert("namelist");
+    atoms.insert("bsr_anycrlf");
+    atoms.insert("bsr_unicode");
+    atoms.insert("no_start_optimize");
+    atoms.insert("ucp");
+    atoms.insert("never_utf");
+
+    atoms.insert("match_limit");
+    atoms.insert("match_limit_recursion");
+
+    atoms.insert("uppercase");
+    atoms.insert("lowercase");
+    atoms.insert("insert_replaced");
+
+    atoms.insert("finished");
+    atoms.insert("need_dictionary");
+    atoms.insert("not_initialized");
+    atoms.insert("not_on_controlling_process");
+    atoms.insert("data_error");
+    atoms.insert("stream_error");
+    atoms.insert("not_supported");
+
     RwLock::new(atoms)
 });
 
@@ -744,3 +991,167 @@ pub const UNGREEDY: Atom = Atom(269);
 pub const MULTILINE: Atom = Atom(270);
 pub const DOTALL: Atom = Atom(271);
 pub const RE_PATTERN: Atom = Atom(272);
+
+pub const MONITORS: Atom = Atom(273);
+pub const MINOR_GCS: Atom = Atom(274);
+pub const ERROR_LOGGER: Atom = Atom(275);
+pub const OLD_HEAP_BLOCK_SIZE: Atom = Atom(276);
+pub const HEAP_BLOCK_SIZE: Atom = Atom(277);
+pub const MBUF_SIZE: Atom = Atom(278);
+pub const RECENT_SIZE: Atom = Atom(279);
+pub const OLD_HEAP_SIZE: Atom = Atom(280);
+pub const BIN_VHEAP_SIZE: Atom = Atom(281);
+pub const BIN_VHEAP_BLOCK_SIZE: Atom = Atom(282);
+pub const BIN_OLD_VHEAP_SIZE: Atom = Atom(283);
+pub const BIN_OLD_VHEAP_BLOCK_SIZE: Atom = Atom(284);
+
+pub const PROCESS_COUNT: Atom = Atom(285);
+pub const PROCESS_LIMIT: Atom = Atom(286);
+pub const ATOM_COUNT: Atom = Atom(287);
+pub const ATOM_LIMIT: Atom = Atom(288);
+pub const PORT_COUNT: Atom = Atom(289);
+pub const SCHEDULERS: Atom = Atom(290);
+pub const SCHEDULERS_ONLINE: Atom = Atom(291);
+pub const WORDSIZE: Atom = Atom(292);
+pub const BUILD_TYPE: Atom = Atom(293);
+pub const OPT: Atom = Atom(294);
+pub const DEBUG: Atom = Atom(295);
+pub const LOGICAL_PROCESSORS: Atom = Atom(296);
+pub const LOGICAL_PROCESSORS_AVAILABLE: Atom = Atom(297);
+pub const LOGICAL_PROCESSORS_ONLINE: Atom = Atom(298);
+pub const THREAD_POOL_SIZE: Atom = Atom(299);
+pub const ALLOCATED_AREAS: Atom = Atom(300);
+pub const MULTI_SCHEDULING: Atom = Atom(301);
+pub const BACKTRACE_DEPTH: Atom = Atom(302);
+pub const ENABLED: Atom = Atom(303);
+pub const BLOCKED: Atom = Atom(304);
+pub const DISABLED: Atom = Atom(305);
+pub const BLOCK: Atom = Atom(306);
+pub const UNBLOCK: Atom = Atom(307);
+pub const BLOCK_NORMAL: Atom = Atom(308);
+pub const UNBLOCK_NORMAL: Atom = Atom(309);
+pub const BLOCKED_NORMAL: Atom = Atom(310);
+pub const ETS_COUNT: Atom = Atom(311);
+pub const INTERNAL: Atom = Atom(312);
+pub const PROCESSES: Atom = Atom(313);
+pub const ATOM_TABLE: Atom = Atom(314);
+pub const ATOM_SPACE: Atom = Atom(315);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_from_str() {
+        let atom = Atom::try_from_str("test_try_from_str").unwrap();
+        assert_eq!(atom.to_str(), Some("test_try_from_str"));
+        assert_eq!(Atom::try_from_str("test_try_from_str").unwrap(), atom);
+        assert_eq!(Atom::from("true"), TRUE);
+    }
+
+    #[test]
+    fn test_try_from_str_too_long() {
+        let name = "a".repeat(MAX_ATOM_CHARS + 1);
+        assert!(Atom::try_from_str(&name).is_err());
+        assert!(Atom::try_from_str(&name[1..]).is_ok());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(Atom::lookup("badarg"), Some(BADARG));
+        assert_eq!(Atom::lookup("test_lookup_does_not_exist"), None);
+    }
+
+    #[test]
+    fn test_table_full() {
+        let mut table = AtomTable::with_limit(2);
+        assert_eq!(table.try_insert("a"), Some(0));
+        assert_eq!(table.try_insert("b"), Some(1));
+        assert_eq!(table.try_insert("c"), None);
+        // existing atoms can still be looked up
+        assert_eq!(table.try_insert("a"), Some(0));
+    }
+}
+
+pub const UNLESS_SUSPENDING: Atom = Atom(316);
+pub const ASYNCHRONOUS: Atom = Atom(317);
+
+pub const FIXED: Atom = Atom(318);
+pub const SAFE_FIXED: Atom = Atom(319);
+pub const SAFE_FIXED_MONOTONIC_TIME: Atom = Atom(320);
+
+pub const ETS_TRANSFER: Atom = Atom(321);
+
+pub const OWNER: Atom = Atom(322);
+
+pub const ID: Atom = Atom(323);
+
+pub const IS_BOOLEAN: Atom = Atom(324);
+pub const IS_BITSTRING: Atom = Atom(325);
+pub const TUPLE_SIZE: Atom = Atom(326);
+pub const BYTE_SIZE: Atom = Atom(327);
+pub const BINARY_PART: Atom = Atom(328);
+pub const CEIL: Atom = Atom(329);
+pub const FLOOR: Atom = Atom(330);
+
+pub const RANDOM: Atom = Atom(331);
+pub const SEQUENTIAL: Atom = Atom(332);
+pub const WILL_NEED: Atom = Atom(333);
+pub const DONT_NEED: Atom = Atom(334);
+pub const NO_REUSE: Atom = Atom(335);
+pub const ENOTSUP: Atom = Atom(336);
+
+pub const RAM: Atom = Atom(337);
+
+pub const CONTINUE: Atom = Atom(338);
+
+pub const NOT_FOUND: Atom = Atom(339);
+
+pub const CAPTURE: Atom = Atom(340);
+pub const FIRST: Atom = Atom(341);
+pub const ALL_BUT_FIRST: Atom = Atom(342);
+pub const ALL_NAMES: Atom = Atom(343);
+pub const LIST: Atom = Atom(344);
+pub const OFFSET: Atom = Atom(345);
+pub const NOTEMPTY: Atom = Atom(346);
+pub const NOTEMPTY_ATSTART: Atom = Atom(347);
+pub const NOTBOL: Atom = Atom(348);
+pub const NOTEOL: Atom = Atom(349);
+pub const ANCHORED: Atom = Atom(350);
+pub const EXTENDED: Atom = Atom(351);
+pub const DOLLAR_ENDONLY: Atom = Atom(352);
+pub const NO_AUTO_CAPTURE: Atom = Atom(353);
+pub const DUPNAMES: Atom = Atom(354);
+pub const FIRSTLINE: Atom = Atom(355);
+pub const NEWLINE: Atom = Atom(356);
+pub const CR: Atom = Atom(357);
+pub const LF: Atom = Atom(358);
+pub const CRLF: Atom = Atom(359);
+pub const ANYCRLF: Atom = Atom(360);
+pub const ANY: Atom = Atom(361);
+pub const RETURN: Atom = Atom(362);
+pub const IODATA: Atom = Atom(363);
+pub const PARTS: Atom = Atom(364);
+pub const GROUP: Atom = Atom(365);
+pub const REPORT_ERRORS: Atom = Atom(366);
+pub const NAMELIST: Atom = Atom(367);
+pub const BSR_ANYCRLF: Atom = Atom(368);
+pub const BSR_UNICODE: Atom = Atom(369);
+pub const NO_START_OPTIMIZE: Atom = Atom(370);
+pub const UCP: Atom = Atom(371);
+pub const NEVER_UTF: Atom = Atom(372);
+
+pub const MATCH_LIMIT: Atom = Atom(373);
+pub const MATCH_LIMIT_RECURSION: Atom = Atom(374);
+
+pub const UPPERCASE: Atom = Atom(375);
+pub const LOWERCASE: Atom = Atom(376);
+pub const INSERT_REPLACED: Atom = Atom(377);
+
+pub const FINISHED: Atom = Atom(378);
+pub const NEED_DICTIONARY: Atom = Atom(379);
+pub const NOT_INITIALIZED: Atom = Atom(380);
+pub const NOT_ON_CONTROLLING_PROCESS: Atom = Atom(381);
+pub const DATA_ERROR: Atom = Atom(382);
+pub const STREAM_ERROR: Atom = Atom(383);
+pub const NOT_SUPPORTED: Atom = Atom(384);
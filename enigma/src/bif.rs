@@ -16,19 +16,21 @@ use once_cell::sync::Lazy;
 
 pub mod arith;
 pub mod binary;
+pub mod blocking;
 mod chrono;
 mod dtrace;
 pub mod erf;
 pub mod erlang;
-mod info;
+pub mod info;
 mod lists;
 mod load;
 mod maps;
 mod os;
 mod pdict;
 pub mod prim_buffer;
-mod prim_file;
+pub mod prim_file;
 mod timer;
+pub mod zlib;
 
 macro_rules! trap {
     ($context:expr, $ptr:expr, $($arg:expr),*) => {{
@@ -66,6 +68,9 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
     bif_map![
         "erlang" => {
             "md5", 1 => erlang::md5_1,
+            "md5_init", 0 => erlang::md5_init_0,
+            "md5_update", 2 => erlang::md5_update_2,
+            "md5_final", 1 => erlang::md5_final_1,
             "float", 1 => arith::float_1,
             "abs", 1 => arith::abs_1,
             "round", 1 => arith::round_1,
@@ -110,6 +115,7 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "is_bitstring", 1 => bif_erlang_is_bitstring_1,
             "is_function", 1 => bif_erlang_is_function_1,
             "is_function", 2 => bif_erlang_is_function_2,
+            "is_record", 3 => bif_erlang_is_record_3,
             "is_boolean", 1 => bif_erlang_is_boolean_1,
             "is_map", 1 => bif_erlang_is_map_1,
             "is_map_key", 2 => bif_erlang_is_map_key_2,
@@ -130,6 +136,10 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "throw", 1 => bif_erlang_throw_1,
             "exit", 1 => bif_erlang_exit_1,
             "exit", 2 => bif_erlang_exit_2,
+            "suspend_process", 1 => bif_erlang_suspend_process_1,
+            "suspend_process", 2 => bif_erlang_suspend_process_2,
+            "resume_process", 1 => bif_erlang_resume_process_1,
+            "hibernate", 3 => bif_erlang_hibernate_3,
             "halt", 2 => bif_erlang_halt_2,
             "whereis", 1 => bif_erlang_whereis_1,
             "nif_error", 1 => bif_erlang_nif_error_1,
@@ -144,6 +154,7 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "loaded", 0 => bif_erlang_loaded_0,
             "module_loaded", 1 => bif_erlang_module_loaded_1,
             "process_flag", 2 => bif_erlang_process_flag_2,
+            "process_info", 1 => info::process_info_1,
             "process_info", 2 => info::process_info_2,
             "group_leader", 0 => info::group_leader_0,
             "make_tuple", 2 => erlang::make_tuple_2,
@@ -157,13 +168,16 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "term_to_binary", 1 => erlang::term_to_binary_1,
             "term_to_binary", 2 => erlang::term_to_binary_2,
             "binary_to_atom", 2 => erlang::binary_to_atom_2,
+            "binary_to_existing_atom", 2 => erlang::binary_to_existing_atom_2,
             "list_to_atom", 1 => erlang::list_to_atom_1,
+            "list_to_existing_atom", 1 => erlang::list_to_existing_atom_1,
             "list_to_binary", 1 => erlang::list_to_binary_1,
             "iolist_to_binary", 1 => erlang::iolist_to_binary_1,
             "iolist_to_iovec", 1 => erlang::iolist_to_iovec_1,
             "atom_to_list", 1 => erlang::atom_to_list_1,
             "atom_to_binary", 2 => erlang::atom_to_binary_2,
             "pid_to_list", 1 => erlang::pid_to_list_1,
+            "list_to_pid", 1 => erlang::list_to_pid_1,
             "integer_to_list", 1 => erlang::integer_to_list_1,
             "integer_to_list", 2 => erlang::integer_to_list_2,
             "integer_to_binary", 1 => erlang::integer_to_binary_1,
@@ -210,7 +224,7 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "==", 2 => erlang::seqeq_2,
             "=/=", 2 => erlang::sneq_2,
             "/=", 2 => erlang::sneqeq_2,
-            // "/", 2 => erlang::div_2,
+            "/", 2 => arith::fdiv_2,
             "bor", 2 => erlang::bor_2,
             "band", 2 => erlang::band_2,
             "bxor", 2 => erlang::bxor_2,
@@ -309,13 +323,41 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "lookup_element", 3 => ets::bif::lookup_element_3,
             "delete", 1 => ets::bif::delete_1,
             "delete", 2 => ets::bif::delete_2,
+            "select", 1 => ets::bif::select_1,
             "select", 2 => ets::bif::select_2,
+            "select", 3 => ets::bif::select_3,
+            "select_reverse", 1 => ets::bif::select_1,
+            "select_reverse", 2 => ets::bif::select_reverse_2,
+            "select_reverse", 3 => ets::bif::select_reverse_3,
             "select_delete", 2 => ets::bif::select_delete_2,
+            "internal_select_delete", 2 => ets::bif::select_delete_2,
+            "select_count", 2 => ets::bif::select_count_2,
+            "select_replace", 2 => ets::bif::select_replace_2,
+            "match_delete", 2 => ets::bif::match_delete_2,
+            "delete_object", 2 => ets::bif::delete_object_2,
+            "delete_all_objects", 1 => ets::bif::delete_all_objects_1,
+            "internal_delete_all", 2 => ets::bif::internal_delete_all_2,
+            "take", 2 => ets::bif::take_2,
             "update_element", 3 => ets::bif::update_element_3,
+            "update_element", 4 => ets::bif::update_element_4,
+            "update_counter", 3 => ets::bif::update_counter_3,
+            "update_counter", 4 => ets::bif::update_counter_4,
+            "match", 1 => ets::bif::select_1,
             "match", 2 => ets::bif::match_2,
+            "match", 3 => ets::bif::match_3,
+            "match_object", 1 => ets::bif::select_1,
+            "match_object", 2 => ets::bif::match_object_2,
+            "match_object", 3 => ets::bif::match_object_3,
             "member", 2 => ets::bif::member_2,
             "first", 1 => ets::bif::first_1,
             "last", 1 => ets::bif::last_1,
+            "next", 2 => ets::bif::next_2,
+            "prev", 2 => ets::bif::prev_2,
+            "tab2list", 1 => ets::bif::tab2list_1,
+            "safe_fixtable", 2 => ets::bif::safe_fixtable_2,
+            "give_away", 3 => ets::bif::give_away_3,
+            "setopts", 2 => ets::bif::setopts_2,
+            "info", 1 => ets::bif::info_1,
             "info", 2 => ets::bif::info_2,
         },
         "os" => {
@@ -361,6 +403,21 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
             "longest_common_prefix", 1 => binary::longest_common_prefix_1,
             "first", 1 => binary::first_1,
             "last", 1 => binary::last_1,
+            "at", 2 => binary::at_2,
+            "bin_to_list", 1 => binary::bin_to_list_1,
+            "bin_to_list", 2 => binary::bin_to_list_2,
+            "bin_to_list", 3 => binary::bin_to_list_3,
+            "encode_unsigned", 1 => binary::encode_unsigned_1,
+            "encode_unsigned", 2 => binary::encode_unsigned_2,
+            "decode_unsigned", 1 => binary::decode_unsigned_1,
+            "decode_unsigned", 2 => binary::decode_unsigned_2,
+            "longest_common_suffix", 1 => binary::longest_common_suffix_1,
+            "referenced_byte_size", 1 => binary::referenced_byte_size_1,
+            "replace", 3 => binary::replace_3,
+            "replace", 4 => binary::replace_4,
+            "encode_hex", 1 => binary::encode_hex_1,
+            "encode_hex", 2 => binary::encode_hex_2,
+            "decode_hex", 1 => binary::decode_hex_1,
         },
         "unicode" => {
             "characters_to_binary", 2 => erlang::unicode_characters_to_binary_2,
@@ -387,9 +444,15 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
         },
         "re" => {
             "version", 0 => regex::bif::version_0,
+            "run", 2 => regex::bif::run_2,
             "run", 3 => regex::bif::run_3,
             "compile", 1 => regex::bif::compile_1,
             "compile", 2 => regex::bif::compile_2,
+            "inspect", 2 => regex::bif::inspect_2,
+            "replace", 3 => regex::bif::replace_3,
+            "replace", 4 => regex::bif::replace_4,
+            "split", 2 => regex::bif::split_2,
+            "split", 3 => regex::bif::split_3,
         },
         "persistent_term" => {
             // monitor nodes is unimplemented for now
@@ -400,10 +463,6 @@ pub static BIFS: Lazy<BifTable> = Lazy::new(|| {
         "file" => {
             "native_name_encoding", 0 => prim_file::native_name_encoding_0,
         },
-        "zlib" => {
-            // override like we did for beam_file
-            "compress", 1 => prim_file::zlib_compress_1,
-        },
     ]
 });
 
@@ -478,12 +537,41 @@ pub static NIFS: Lazy<NifTable> = Lazy::new(|| {
             "size", 1 => prim_buffer::bif::size_1,
             "peek_head", 1 => prim_buffer::bif::peek_head_1,
             "copying_read", 2 => prim_buffer::bif::copying_read_2,
+            "read_iovec", 2 => prim_buffer::bif::read_iovec_2,
             "write", 2 => prim_buffer::bif::write_2,
             "skip", 2 => prim_buffer::bif::skip_2,
+            "wipe", 1 => prim_buffer::bif::wipe_1,
             "find_byte_index", 2 => prim_buffer::bif::find_byte_index_2,
             "try_lock", 1 => prim_buffer::bif::try_lock_1,
             "unlock", 1 => prim_buffer::bif::unlock_1,
         },
+        "zlib" => {
+            "open_nif", 0 => zlib::bif::open_nif_0,
+            "close_nif", 1 => zlib::bif::close_nif_1,
+            "set_controller_nif", 2 => zlib::bif::set_controller_nif_2,
+            "enqueue_nif", 2 => zlib::bif::enqueue_nif_2,
+            "crc32_nif", 1 => zlib::bif::crc32_nif_1,
+
+            "deflateInit_nif", 6 => zlib::bif::deflate_init_nif_6,
+            "deflateSetDictionary_nif", 2 => zlib::bif::deflate_set_dictionary_nif_2,
+            "deflateReset_nif", 1 => zlib::bif::deflate_reset_nif_1,
+            "deflateParams_nif", 3 => zlib::bif::deflate_params_nif_3,
+            "deflate_nif", 4 => zlib::bif::deflate_nif_4,
+            "deflateEnd_nif", 1 => zlib::bif::deflate_end_nif_1,
+
+            "inflateInit_nif", 3 => zlib::bif::inflate_init_nif_3,
+            "inflateSetDictionary_nif", 2 => zlib::bif::inflate_set_dictionary_nif_2,
+            "inflateGetDictionary_nif", 1 => zlib::bif::inflate_get_dictionary_nif_1,
+            "inflateReset_nif", 1 => zlib::bif::inflate_reset_nif_1,
+            "inflate_nif", 4 => zlib::bif::inflate_nif_4,
+            "inflateEnd_nif", 1 => zlib::bif::inflate_end_nif_1,
+
+            "getBufSize_nif", 1 => zlib::bif::get_buf_size_nif_1,
+            "setBufSize_nif", 2 => zlib::bif::set_buf_size_nif_2,
+            "getStash_nif", 1 => zlib::bif::get_stash_nif_1,
+            "setStash_nif", 2 => zlib::bif::set_stash_nif_2,
+            "clearStash_nif", 1 => zlib::bif::clear_stash_nif_1,
+        },
     ]
 });
 
@@ -832,6 +920,17 @@ pub fn bif_erlang_is_tuple_1(_vm: &Machine, _process: &RcProcess, args: &[Term])
     Ok(Term::boolean(args[0].is_tuple()))
 }
 
+pub fn bif_erlang_is_record_3(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> Result {
+    let size = match args[2].to_int() {
+        Some(size) if size > 0 && args[1].is_atom() => size as usize,
+        _ => return Err(badarg!()),
+    };
+    Ok(Term::boolean(match Tuple::cast_from(&args[0]) {
+        Ok(tuple) => tuple.len() == size && tuple[0] == args[1],
+        Err(_) => false,
+    }))
+}
+
 pub fn bif_erlang_is_float_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> Result {
     Ok(Term::boolean(args[0].is_float()))
 }
@@ -887,7 +986,7 @@ pub fn bif_erlang_is_function_2(_vm: &Machine, _process: &RcProcess, args: &[Ter
     Ok(atom!(FALSE))
 }
 
-fn bif_erlang_is_boolean_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> Result {
+pub fn bif_erlang_is_boolean_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> Result {
     Ok(Term::boolean(args[0].is_boolean()))
 }
 
@@ -920,13 +1019,12 @@ pub fn bif_erlang_map_get_2(_vm: &Machine, _process: &RcProcess, args: &[Term])
     Err(Exception::with_value(Reason::EXC_BADMAP, *map))
 }
 
-fn bif_erlang_tuple_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
-    let (mfa, _) = process.context().ip.lookup_func_info().unwrap();
+pub fn bif_erlang_tuple_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
     let tuple = Tuple::cast_from(&args[0])?;
     Ok(Term::uint(&process.context_mut().heap, tuple.len))
 }
 
-fn bif_erlang_byte_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+pub fn bif_erlang_byte_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
     let heap = &process.context_mut().heap;
 
     // TODO: extracted from binary_size macro, share impl!
@@ -952,7 +1050,7 @@ fn bif_erlang_byte_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) ->
     Ok(Term::uint64(heap, size as u64))
 }
 
-fn bif_erlang_bit_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+pub fn bif_erlang_bit_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
     let heap = &process.context_mut().heap;
 
     // TODO: extracted from binary_size macro, share impl!
@@ -1025,16 +1123,14 @@ fn bif_erlang_exit_2(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result
 
     match args[0].into_variant() {
         Variant::Pid(pid) => {
-            process::send_signal(
-                vm,
-                pid,
-                // TODO: deep copy reason
-                process::Signal::Exit {
-                    from: process.pid,
-                    reason: Exception::with_value(Reason::EXC_EXIT, args[1]),
-                    kind: process::ExitKind::Exit,
-                },
-            );
+            let reason = Exception::with_value(Reason::EXC_EXIT, args[1]);
+            let signal = process::Signal::exit(process.pid, &reason, process::ExitKind::Exit);
+            if pid == process.pid {
+                // exiting ourselves has to take effect before exit/2 returns
+                process.handle_exit_signal(signal)?;
+            } else {
+                process::send_signal(vm, pid, signal);
+            }
             Ok(atom!(TRUE))
         }
         // TODO: port
@@ -1042,6 +1138,121 @@ fn bif_erlang_exit_2(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result
     }
 }
 
+fn bif_erlang_suspend_process_1(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+    bif_erlang_suspend_process_2(vm, process, &[args[0], Term::nil()])
+}
+
+/// Suspends the target until it is resumed as many times as it was suspended. The target stops
+/// at its next scheduling point, so the synchronous and asynchronous variants only differ in
+/// whether a reply message is sent.
+fn bif_erlang_suspend_process_2(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let pid = match args[0].into_variant() {
+        Variant::Pid(pid) if pid != process.pid => pid,
+        _ => return Err(badarg!()),
+    };
+
+    let mut unless_suspending = false;
+    let mut reply_tag = None;
+    if !args[1].is_nil() {
+        for opt in Cons::cast_from(&args[1])?.iter() {
+            match opt.into_variant() {
+                Variant::Atom(atom::UNLESS_SUSPENDING) => unless_suspending = true,
+                Variant::Atom(atom::ASYNCHRONOUS) => (),
+                _ => match Tuple::cast_from(opt) {
+                    Ok(tup) if tup.len() == 2 && tup[0] == atom!(ASYNCHRONOUS) => {
+                        reply_tag = Some(tup[1])
+                    }
+                    _ => return Err(badarg!()),
+                },
+            }
+        }
+    }
+
+    let target = match vm.process_table.lock().get(pid) {
+        Some(target) => target,
+        None => return Err(badarg!()),
+    };
+
+    let suspending = &mut process.local_data_mut().suspending;
+    let suspended = if unless_suspending && suspending.contains_key(&pid) {
+        false
+    } else {
+        *suspending.entry(pid).or_insert(0) += 1;
+        target.suspend();
+        true
+    };
+
+    if let Some(tag) = reply_tag {
+        let heap = &process.context_mut().heap;
+        process.send_message(process.pid, tup2!(heap, tag, atom!(SUSPENDED)));
+    }
+
+    Ok(Term::boolean(suspended))
+}
+
+fn bif_erlang_resume_process_1(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let pid = match args[0].into_variant() {
+        Variant::Pid(pid) => pid,
+        _ => return Err(badarg!()),
+    };
+
+    // only processes we've suspended can be resumed
+    let suspending = &mut process.local_data_mut().suspending;
+    match suspending.get_mut(&pid) {
+        Some(count) if *count > 1 => *count -= 1,
+        Some(_) => {
+            suspending.remove(&pid);
+        }
+        None => return Err(badarg!()),
+    }
+
+    match vm.process_table.lock().get(pid) {
+        Some(target) => {
+            target.resume();
+            Ok(atom!(TRUE))
+        }
+        None => Err(badarg!()),
+    }
+}
+
+/// Puts the process into a wait state where its memory is minimized. Once a message arrives, the
+/// process continues at `Module:Function(Args)` with an empty stack, so it exits when that call
+/// returns.
+fn bif_erlang_hibernate_3(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
+    let (module, func) = match (args[0].into_variant(), args[1].into_variant()) {
+        (Variant::Atom(module), Variant::Atom(func)) => (module, func),
+        _ => return Err(badarg!()),
+    };
+
+    use crate::exports_table::Export;
+
+    let arity = if args[2].is_nil() {
+        0
+    } else {
+        Cons::cast_from(&args[2])?.iter().count()
+    };
+
+    let mfa = module::MFA(module, func, arity as u32);
+    let ptr = match { vm.exports.read().lookup(&mfa) } {
+        Some(Export::Fun(ptr)) => ptr,
+        // TODO: hibernating into a BIF
+        _ => return Err(Exception::new(Reason::EXC_UNDEF)),
+    };
+
+    let context = process.context_mut();
+    if arity > 0 {
+        for (i, arg) in Cons::cast_from(&args[2])?.iter().enumerate() {
+            context.x[i] = *arg;
+        }
+    }
+    // the arguments are the only live registers that get moved to the new heap
+    context.live = arity;
+    context.ip = ptr;
+
+    process.hibernate();
+    Err(Exception::new(Reason::TRAP))
+}
+
 fn bif_erlang_error_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> Result {
     // println!("raising val {}", args[0]);
     Err(Exception::with_value(Reason::EXC_ERROR, args[0]))
@@ -1352,8 +1563,14 @@ fn dflag_unicode_io(_vm: &Machine, _process: &RcProcess, _args: &[Term]) -> Resu
 }
 
 fn open_port_2(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
-    let pid = port::spawn(vm, process.pid, args[0], args[1])?;
-    Ok(Term::port(pid))
+    match port::spawn(vm, process.pid, args[0], args[1])? {
+        Ok(pid) => Ok(Term::port(pid)),
+        Err(reason) => Ok(tup2!(
+            &process.context_mut().heap,
+            atom!(ERROR),
+            Term::atom(reason)
+        )),
+    }
 }
 
 fn port_control_3(vm: &Machine, process: &RcProcess, args: &[Term]) -> Result {
@@ -1733,4 +1950,19 @@ mod tests {
 
         assert_eq!(res, Ok(Term::int(2)));
     }
+
+    #[test]
+    fn test_open_port_2_unsupported() {
+        let vm = Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let fd = tup3!(heap, atom!(FD), Term::int(0), Term::int(1));
+        let args = vec![fd, Term::nil()];
+        let res = open_port_2(&vm, &process, &args);
+
+        assert_eq!(res, Ok(tup2!(heap, atom!(ERROR), atom!(ENOTSUP))));
+        assert!(open_port_2(&vm, &process, &[Term::int(1), Term::nil()]).is_err());
+    }
 }
@@ -1,4 +1,5 @@
 use crate::bif;
+use crate::exception::{Exception, Reason};
 use crate::numeric::division::{FlooredDiv, OverflowingFlooredDiv};
 use crate::numeric::modulo::{Modulo, OverflowingModulo};
 use crate::process::RcProcess;
@@ -6,7 +7,7 @@ use crate::value::{self, Term};
 use crate::vm;
 use num_bigint::BigInt;
 // use num_bigint::ToBigInt;
-use num_traits::Signed;
+use num_traits::{Signed, ToPrimitive};
 use std::ops::{Add, Mul, Sub};
 
 pub fn float_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
@@ -73,6 +74,22 @@ pub fn mult_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Res
     Ok(integer_overflow_op!(heap, args, mul, overflowing_mul))
 }
 
+pub fn fdiv_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    let to_float = |term: Term| match term.into_number() {
+        Ok(value::Num::Integer(i)) => Ok(f64::from(i)),
+        Ok(value::Num::Float(f)) => Ok(f),
+        Ok(value::Num::Bignum(i)) => i
+            .to_f64()
+            .ok_or_else(|| Exception::new(Reason::EXC_BADARITH)),
+        Err(_) => Err(Exception::new(Reason::EXC_BADARITH)),
+    };
+    let divisor = to_float(args[1])?;
+    if divisor == 0.0 {
+        return Err(Exception::new(Reason::EXC_BADARITH));
+    }
+    Ok(Term::from(to_float(args[0])? / divisor))
+}
+
 pub fn intdiv_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let heap = &process.context_mut().heap;
     Ok(integer_overflow_op!(
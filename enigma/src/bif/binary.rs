@@ -3,8 +3,12 @@ use crate::bif;
 use crate::bitstring::{self, Binary, RcBinary, SubBinary};
 use crate::exception::Exception;
 use crate::process::RcProcess;
-use crate::value::{self, CastFrom, CastInto, Cons, Term, Tuple, Variant};
+use crate::value::{self, CastFrom, CastInto, Cons, Num, Term, Tuple, Variant};
 use crate::vm;
+use num_bigint::{BigInt, Sign};
+use num_traits::ToPrimitive;
+use regex::bytes::{Regex, RegexBuilder};
+use std::borrow::Cow;
 
 pub fn split_binary_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // bin, pos
@@ -67,7 +71,33 @@ pub fn split_binary_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
     ))
 }
 
-fn part(source: Term, mut pos: usize, len: isize) -> Result<SubBinary, Exception> {
+/// The bytes of a binary. Bitstrings with trailing bits aren't binaries.
+fn binary_bytes(term: &Term) -> Result<&[u8], Exception> {
+    if !term.is_binary() {
+        return Err(badarg!());
+    }
+    term.to_bytes().ok_or_else(|| badarg!())
+}
+
+/// Returns the start and end of the part at `pos` of `len` bytes in a binary of `size` bytes.
+/// A negative `len` counts back from `pos`.
+fn range(size: usize, pos: usize, len: isize) -> Result<(usize, usize), Exception> {
+    let (start, end) = if len < 0 {
+        let len = (-len) as usize;
+        if len > pos {
+            return Err(badarg!());
+        }
+        (pos - len, pos)
+    } else {
+        (pos, pos + len as usize)
+    };
+    if size < end {
+        return Err(badarg!());
+    }
+    Ok((start, end))
+}
+
+fn part(source: Term, pos: usize, len: isize) -> Result<SubBinary, Exception> {
     let (bin, offs, bitoffs, size, bitsize) = match source.get_boxed_header() {
         Ok(value::BOXED_BINARY) => {
             let value = &source.get_boxed_value::<RcBinary>().unwrap();
@@ -86,28 +116,11 @@ fn part(source: Term, mut pos: usize, len: isize) -> Result<SubBinary, Exception
         _ => return Err(badarg!()),
     };
 
-    let len = if len < 0 {
-        let len = (-len) as usize;
-        if len > pos {
-            return Err(badarg!());
-        }
-        pos -= len;
-        len
-    } else {
-        len as usize
-    };
-
-    /* overflow */
-    // if ((pos + len) < pos || (len > 0 && (pos + len) == pos) {
-    // goto badarg;
-    // }
-    if size < pos || size < (pos + len) {
-        return Err(badarg!());
-    }
+    let (start, end) = range(size, pos, len)?;
 
     // TODO: make a constructor that doesn't need bits.
-    let offset = (offs * 8) + bitoffs as usize + (pos * 8);
-    let size = len * 8;
+    let offset = (offs * 8) + bitoffs as usize + (start * 8);
+    let size = (end - start) * 8;
 
     // TODO: tests
     Ok(SubBinary::new(bin.clone(), size, offset, false))
@@ -158,38 +171,52 @@ pub fn part_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Res
     Ok(Term::subbinary(heap, subbin))
 }
 
-// TODO: share some of the impl with split
-pub fn compile_pattern_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use regex::bytes::Regex;
-    let heap = &process.context_mut().heap;
-
-    // pattern = binary | [binary] | compiled
-    let regex = if let Some(bytes) = args[0].to_bytes() {
-        let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-        let regex = Regex::new(&pattern).unwrap();
-        regex
-    } else if args[0].is_list() {
-        let mut iter = args[0];
-        let mut acc = Vec::new();
-        while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-            // TODO: error handling
-            let bytes = head.to_bytes().unwrap();
-            let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-            acc.push(pattern);
-            iter = *tail;
-        }
-
-        if !iter.is_nil() {
-            return Err(badarg!());
-        }
-
-        let pattern = acc.join("|");
-        let regex = Regex::new(&pattern).unwrap();
-        regex
+/// Compiles a binary or a list of binaries to a regex matching any of them. Of the patterns
+/// matching at the same position the longest one is used, so they are tried longest first.
+fn compile_pattern(pattern: &Term) -> Result<Regex, Exception> {
+    let mut patterns = if pattern.is_nil() {
+        Vec::new()
+    } else if pattern.is_list() {
+        Cons::cast_from(pattern)?
+            .iter()
+            .map(binary_bytes)
+            .collect::<Result<Vec<_>, Exception>>()?
     } else {
-        return Err(badarg!());
+        vec![binary_bytes(pattern)?]
     };
+    // an empty alternation or an empty binary would match everywhere
+    if patterns.is_empty() || patterns.iter().any(|pattern| pattern.is_empty()) {
+        return Err(badarg!());
+    }
+    patterns.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    let alternatives: Vec<String> = patterns
+        .into_iter()
+        .map(|pattern| {
+            pattern
+                .iter()
+                .map(|byte| format!("\\x{:02X}", byte))
+                .collect()
+        })
+        .collect();
+    RegexBuilder::new(&alternatives.join("|"))
+        .unicode(false)
+        .build()
+        .map_err(|_| badarg!())
+}
+
+/// Returns the regex for a pattern argument: a binary, a list of binaries or a pattern compiled
+/// by `compile_pattern/1`.
+fn get_pattern(pattern: &Term) -> Result<Cow<Regex>, Exception> {
+    match Regex::cast_from(pattern) {
+        Ok(regex) => Ok(Cow::Borrowed(regex)),
+        Err(_) => compile_pattern(pattern).map(Cow::Owned),
+    }
+}
 
+pub fn compile_pattern_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let regex = compile_pattern(&args[0])?;
     Ok(Term::regex(heap, regex))
 }
 
@@ -197,10 +224,7 @@ pub fn split_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Res
     split_3(vm, process, &[args[0], args[1], Term::nil()])
 }
 
-// TODO: split on "" is invalid
 pub fn split_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use regex::bytes::Regex;
-    use std::borrow::Cow;
     let heap = &process.context_mut().heap;
     // <subject> <pattern> <options>
     // split or replace via regex crate and regex::escape the contents. It'll pick the most
@@ -230,33 +254,7 @@ pub fn split_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Re
     let subject = &bin.data[offs..offs + size];
 
     // pattern = binary | [binary] | compiled
-    let regex = if let Ok(regex) = Regex::cast_from(&args[1]) {
-        Cow::Borrowed(regex)
-    } else if let Some(bytes) = args[1].to_bytes() {
-        let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else if args[1].is_list() {
-        let mut iter = args[1];
-        let mut acc = Vec::new();
-        while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-            // TODO: error handling
-            let bytes = head.to_bytes().unwrap();
-            let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-            acc.push(pattern);
-            iter = *tail;
-        }
-
-        if !iter.is_nil() {
-            return Err(badarg!());
-        }
-
-        let pattern = acc.join("|");
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else {
-        return Err(badarg!());
-    };
+    let regex = get_pattern(&args[1])?;
 
     let mut global = false;
 
@@ -365,8 +363,6 @@ pub fn match_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Res
 
 // mostly identical to matches/3
 pub fn match_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use regex::bytes::Regex;
-    use std::borrow::Cow;
     let heap = &process.context_mut().heap;
     // <subject> <pattern> <options>
 
@@ -377,33 +373,7 @@ pub fn match_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Re
     };
 
     // pattern = binary | [binary] | compiled
-    let regex = if let Ok(regex) = Regex::cast_from(&args[1]) {
-        Cow::Borrowed(regex)
-    } else if let Some(bytes) = args[1].to_bytes() {
-        let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else if args[1].is_list() {
-        let mut iter = args[1];
-        let mut acc = Vec::new();
-        while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-            // TODO: error handling
-            let bytes = head.to_bytes().unwrap();
-            let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-            acc.push(pattern);
-            iter = *tail;
-        }
-
-        if !iter.is_nil() {
-            return Err(badarg!());
-        }
-
-        let pattern = acc.join("|");
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else {
-        return Err(badarg!());
-    };
+    let regex = get_pattern(&args[1])?;
 
     // parse options
     if let Ok(cons) = Cons::cast_from(&args[2]) {
@@ -452,8 +422,6 @@ pub fn matches_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::R
 
 // very similar to split: extract helpers
 pub fn matches_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use regex::bytes::Regex;
-    use std::borrow::Cow;
     let heap = &process.context_mut().heap;
     // <subject> <pattern> <options>
 
@@ -464,33 +432,7 @@ pub fn matches_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::
     };
 
     // pattern = binary | [binary] | compiled
-    let regex = if let Ok(regex) = Regex::cast_from(&args[1]) {
-        Cow::Borrowed(regex)
-    } else if let Some(bytes) = args[1].to_bytes() {
-        let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else if args[1].is_list() {
-        let mut iter = args[1];
-        let mut acc = Vec::new();
-        while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-            // TODO: error handling
-            let bytes = head.to_bytes().unwrap();
-            let pattern = regex::escape(std::str::from_utf8(bytes).unwrap());
-            acc.push(pattern);
-            iter = *tail;
-        }
-
-        if !iter.is_nil() {
-            return Err(badarg!());
-        }
-
-        let pattern = acc.join("|");
-        let regex = Regex::new(&pattern).unwrap();
-        Cow::Owned(regex)
-    } else {
-        return Err(badarg!());
-    };
+    let regex = get_pattern(&args[1])?;
 
     // parse options
     if let Ok(cons) = Cons::cast_from(&args[2]) {
@@ -540,15 +482,14 @@ use std::cmp;
 
 /// Longest Common Prefix
 ///
-/// Given a vector of string slices, calculate the string
-/// slice that is the longest common prefix of the strings.
+/// Given a slice of byte strings, calculate the length of
+/// the longest common prefix of the strings.
 ///
 /// ```
-/// let words = vec!["zebrawood", "zebrafish", "zebra mussel"];
-/// let prefix = longest_common_prefix(words);
-/// assert_eq!(prefix, "zebra");
+/// let words: &[&[u8]] = &[b"zebrawood", b"zebrafish", b"zebra mussel"];
+/// assert_eq!(longest_common_prefix(words), 5);
 /// ```
-pub fn longest_common_prefix(strings: &[Vec<u8>]) -> usize {
+pub fn longest_common_prefix(strings: &[&[u8]]) -> usize {
     if strings.is_empty() {
         return 0;
     }
@@ -557,32 +498,55 @@ pub fn longest_common_prefix(strings: &[Vec<u8>]) -> usize {
     for str in &strings[1..] {
         len = cmp::min(
             len,
-            str.iter().zip(str0).take_while(|&(a, b)| a == b).count(),
+            str.iter().zip(*str0).take_while(|&(a, b)| a == b).count(),
         );
     }
     len
 }
 
+/// Length of the longest common suffix of the strings.
+pub fn longest_common_suffix(strings: &[&[u8]]) -> usize {
+    if strings.is_empty() {
+        return 0;
+    }
+    let str0 = &strings[0];
+    let mut len = str0.len();
+    for str in &strings[1..] {
+        len = cmp::min(
+            len,
+            str.iter()
+                .rev()
+                .zip(str0.iter().rev())
+                .take_while(|&(a, b)| a == b)
+                .count(),
+        );
+    }
+    len
+}
+
+/// The contents of a non-empty list of binaries.
+fn binaries(list: &Term) -> Result<Vec<&[u8]>, Exception> {
+    Cons::cast_from(list)?.iter().map(binary_bytes).collect()
+}
+
 pub fn longest_common_prefix_1(
     _vm: &vm::Machine,
     process: &RcProcess,
     args: &[Term],
 ) -> bif::Result {
     let heap = &process.context_mut().heap;
-    let mut iter = args[0];
-    let mut acc = Vec::new();
-    while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-        // TODO: error handling
-        let bytes = head.to_bytes().unwrap();
-        acc.push(bytes.to_vec()); // TODO: this is not great since we're looping and can't use a ref
-        iter = *tail;
-    }
-
-    if !iter.is_nil() {
-        return Err(badarg!());
-    }
+    let strings = binaries(&args[0])?;
+    Ok(Term::uint64(heap, longest_common_prefix(&strings) as u64))
+}
 
-    Ok(Term::uint64(heap, longest_common_prefix(&acc) as u64))
+pub fn longest_common_suffix_1(
+    _vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let strings = binaries(&args[0])?;
+    Ok(Term::uint64(heap, longest_common_suffix(&strings) as u64))
 }
 
 fn copy(bytes: &[u8], n: usize) -> Binary {
@@ -647,3 +611,424 @@ pub fn last_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Res
         None => Err(badarg!()),
     }
 }
+
+pub fn at_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let bytes = binary_bytes(&args[0])?;
+
+    let pos = match args[1].into_variant() {
+        Variant::Integer(i) if i >= 0 => i as usize,
+        _ => return Err(badarg!()),
+    };
+
+    match bytes.get(pos) {
+        Some(b) => Ok(Term::uint(heap, u32::from(*b))),
+        None => Err(badarg!()),
+    }
+}
+
+fn bin_to_list(process: &RcProcess, source: &Term, pos: usize, len: isize) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let bytes = binary_bytes(source)?;
+    let (start, end) = range(bytes.len(), pos, len)?;
+    Ok(bitstring::bytes_to_list(
+        heap,
+        Term::nil(),
+        &bytes[start..end],
+        end - start,
+        0,
+    ))
+}
+
+pub fn bin_to_list_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let len = binary_bytes(&args[0])?.len();
+    bin_to_list(process, &args[0], 0, len as isize)
+}
+
+pub fn bin_to_list_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // PosLen = {Start :: integer() >= 0, Length :: integer()}
+    let tup = Tuple::cast_from(&args[1])?;
+    if tup.len != 2 {
+        return Err(badarg!());
+    }
+    bin_to_list_3(vm, process, &[args[0], tup[0], tup[1]])
+}
+
+pub fn bin_to_list_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let pos = match args[1].into_variant() {
+        Variant::Integer(i) if i >= 0 => i as usize,
+        _ => return Err(badarg!()),
+    };
+
+    let len = match args[2].into_variant() {
+        Variant::Integer(i) => i as isize,
+        _ => return Err(badarg!()),
+    };
+
+    bin_to_list(process, &args[0], pos, len)
+}
+
+/// Whether an endianness argument is `little` rather than `big`.
+fn is_little(endianness: Term) -> Result<bool, Exception> {
+    match endianness.into_variant() {
+        Variant::Atom(atom::BIG) => Ok(false),
+        Variant::Atom(atom::LITTLE) => Ok(true),
+        _ => Err(badarg!()),
+    }
+}
+
+pub fn encode_unsigned_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    encode_unsigned_2(vm, process, &[args[0], atom!(BIG)])
+}
+
+pub fn encode_unsigned_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let little = is_little(args[1])?;
+
+    let mut bytes = match args[0].into_number() {
+        Ok(Num::Integer(i)) if i >= 0 => {
+            let bytes = (i as u32).to_be_bytes();
+            // zero is encoded as a single byte
+            let zeros = bytes.iter().take_while(|b| **b == 0).count().min(3);
+            bytes[zeros..].to_vec()
+        }
+        Ok(Num::Bignum(ref value)) if value.sign() != Sign::Minus => value.to_bytes_be().1,
+        _ => return Err(badarg!()),
+    };
+    if little {
+        bytes.reverse();
+    }
+    Ok(Term::binary(heap, Binary::from(bytes)))
+}
+
+pub fn decode_unsigned_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    decode_unsigned_2(vm, process, &[args[0], atom!(BIG)])
+}
+
+pub fn decode_unsigned_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let bytes = binary_bytes(&args[0])?;
+    let little = is_little(args[1])?;
+
+    if bytes.len() <= 8 {
+        let fold = |acc: u64, byte: &u8| (acc << 8) | u64::from(*byte);
+        let value = if little {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        };
+        return Ok(Term::uint64(heap, value));
+    }
+
+    let value = if little {
+        BigInt::from_bytes_le(Sign::Plus, bytes)
+    } else {
+        BigInt::from_bytes_be(Sign::Plus, bytes)
+    };
+    // leading zeros can make it fit a small integer
+    match value.to_i32() {
+        Some(i) => Ok(Term::int(i)),
+        None => Ok(Term::bigint(heap, value)),
+    }
+}
+
+pub fn referenced_byte_size_1(
+    _vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    if !args[0].is_binary() {
+        return Err(badarg!());
+    }
+
+    let size = match args[0].get_boxed_header() {
+        Ok(value::BOXED_BINARY) => args[0].get_boxed_value::<RcBinary>().unwrap().data.len(),
+        Ok(value::BOXED_SUBBINARY) => {
+            let value = args[0].get_boxed_value::<SubBinary>().unwrap();
+            value.original.data.len()
+        }
+        _ => return Err(badarg!()),
+    };
+    Ok(Term::uint64(heap, size as u64))
+}
+
+pub fn replace_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    replace_4(vm, process, &[args[0], args[1], args[2], Term::nil()])
+}
+
+pub fn replace_4(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    // <subject> <pattern> <replacement> <options>
+    let subject = binary_bytes(&args[0])?;
+    let regex = get_pattern(&args[1])?;
+    let replacement = binary_bytes(&args[2])?;
+
+    let mut global = false;
+    let mut scope = (0, subject.len());
+    // positions in the replacement to insert the matched part at
+    let mut insert = Vec::new();
+
+    let position = |term: &Term| match term.into_variant() {
+        Variant::Integer(i) if i >= 0 && (i as usize) <= replacement.len() => Ok(i as usize),
+        _ => Err(badarg!()),
+    };
+
+    if !args[3].is_nil() {
+        for option in Cons::cast_from(&args[3])?.iter() {
+            if let Variant::Atom(atom::GLOBAL) = option.into_variant() {
+                global = true;
+                continue;
+            }
+
+            let tup = Tuple::cast_from(option)?;
+            if tup.len != 2 {
+                return Err(badarg!());
+            }
+            match tup[0].into_variant() {
+                Variant::Atom(atom::SCOPE) => {
+                    let part = Tuple::cast_from(&tup[1])?;
+                    if part.len != 2 {
+                        return Err(badarg!());
+                    }
+                    let (pos, len) = match (part[0].into_variant(), part[1].into_variant()) {
+                        (Variant::Integer(pos), Variant::Integer(len)) if pos >= 0 => {
+                            (pos as usize, len as isize)
+                        }
+                        _ => return Err(badarg!()),
+                    };
+                    scope = range(subject.len(), pos, len)?;
+                }
+                Variant::Atom(atom::INSERT_REPLACED) => {
+                    insert = match tup[1].into_variant() {
+                        Variant::Integer(..) => vec![position(&tup[1])?],
+                        Variant::Nil(..) => Vec::new(),
+                        _ => Cons::cast_from(&tup[1])?
+                            .iter()
+                            .map(position)
+                            .collect::<Result<_, Exception>>()?,
+                    };
+                    insert.sort();
+                }
+                _ => return Err(badarg!()),
+            }
+        }
+    }
+
+    let (start, end) = scope;
+    let limit = if global { usize::max_value() } else { 1 };
+    let mut result = Vec::with_capacity(subject.len());
+    let mut last = 0;
+    for found in regex.find_iter(&subject[start..end]).take(limit) {
+        let (from, to) = (start + found.start(), start + found.end());
+        result.extend_from_slice(&subject[last..from]);
+        let mut copied = 0;
+        for pos in &insert {
+            result.extend_from_slice(&replacement[copied..*pos]);
+            result.extend_from_slice(&subject[from..to]);
+            copied = *pos;
+        }
+        result.extend_from_slice(&replacement[copied..]);
+        last = to;
+    }
+    result.extend_from_slice(&subject[last..]);
+
+    Ok(Term::binary(heap, Binary::from(result)))
+}
+
+pub fn encode_hex_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    encode_hex_2(vm, process, &[args[0], atom!(UPPERCASE)])
+}
+
+pub fn encode_hex_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let bytes = binary_bytes(&args[0])?;
+    let digits = match args[1].into_variant() {
+        Variant::Atom(atom::UPPERCASE) => b"0123456789ABCDEF",
+        Variant::Atom(atom::LOWERCASE) => b"0123456789abcdef",
+        _ => return Err(badarg!()),
+    };
+
+    let mut hex = Vec::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(digits[usize::from(byte >> 4)]);
+        hex.push(digits[usize::from(byte & 0xF)]);
+    }
+    Ok(Term::binary(heap, Binary::from(hex)))
+}
+
+pub fn decode_hex_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let hex = binary_bytes(&args[0])?;
+    if hex.len() % 2 != 0 {
+        return Err(badarg!());
+    }
+
+    let digit = |byte: u8| {
+        (byte as char)
+            .to_digit(16)
+            .map(|digit| digit as u8)
+            .ok_or_else(|| badarg!())
+    };
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| -> Result<u8, Exception> { Ok((digit(pair[0])? << 4) | digit(pair[1])?) })
+        .collect::<Result<Vec<u8>, Exception>>()?;
+    Ok(Term::binary(heap, Binary::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use crate::process;
+
+    fn bin(process: &RcProcess, bytes: &[u8]) -> Term {
+        let heap = &process.context_mut().heap;
+        Term::binary(heap, Binary::from(bytes.to_vec()))
+    }
+
+    #[test]
+    fn test_at_and_bin_to_list() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let subject = bin(&process, b"abc");
+
+        let res = at_2(&vm, &process, &[subject, Term::int(1)]);
+        assert_eq!(res.unwrap(), Term::int(i32::from(b'b')));
+        assert!(at_2(&vm, &process, &[subject, Term::int(3)]).is_err());
+
+        let res = bin_to_list_1(&vm, &process, &[subject]).unwrap();
+        let list: Vec<Term> = Cons::cast_from(&res).unwrap().iter().copied().collect();
+        assert_eq!(list, vec![Term::int(97), Term::int(98), Term::int(99)]);
+
+        // a negative length counts backwards from pos
+        let res = bin_to_list_3(&vm, &process, &[subject, Term::int(3), Term::int(-2)]).unwrap();
+        let list: Vec<Term> = Cons::cast_from(&res).unwrap().iter().copied().collect();
+        assert_eq!(list, vec![Term::int(98), Term::int(99)]);
+
+        let res = bin_to_list_3(&vm, &process, &[subject, Term::int(2), Term::int(2)]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_unsigned() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+
+        let res = encode_unsigned_1(&vm, &process, &[Term::int(0)]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&[0u8][..]));
+
+        let res = encode_unsigned_2(&vm, &process, &[Term::int(0x0102), atom!(LITTLE)]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&[2u8, 1][..]));
+        assert!(encode_unsigned_1(&vm, &process, &[Term::int(-1)]).is_err());
+
+        let res = decode_unsigned_2(&vm, &process, &[bin(&process, &[2, 1]), atom!(LITTLE)]);
+        assert_eq!(res.unwrap(), Term::int(0x0102));
+
+        // round trip through a bignum
+        let bytes = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF];
+        let value = decode_unsigned_1(&vm, &process, &[bin(&process, &bytes)]).unwrap();
+        assert_eq!(value.get_boxed_header(), Ok(value::BOXED_BIGINT));
+        let res = encode_unsigned_1(&vm, &process, &[value]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&bytes[..]));
+
+        // leading zeros still produce a small integer
+        let bytes = [0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+        let res = decode_unsigned_1(&vm, &process, &[bin(&process, &bytes)]);
+        assert_eq!(res.unwrap(), Term::int(7));
+    }
+
+    #[test]
+    fn test_longest_common_suffix() {
+        assert_eq!(longest_common_suffix(&[&b"erlang"[..], b"fang"]), 3);
+        assert_eq!(longest_common_suffix(&[&b"erlang"[..], b"perl"]), 0);
+        assert_eq!(longest_common_prefix(&[&b"erlang"[..], b"ergonomy"]), 2);
+    }
+
+    #[test]
+    fn test_referenced_byte_size() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let subject = bin(&process, b"hello world");
+        let pos_len = tup2!(&process.context_mut().heap, Term::int(0), Term::int(5));
+        let sub = part_2(&vm, &process, &[subject, pos_len]).unwrap();
+
+        let res = referenced_byte_size_1(&vm, &process, &[sub]);
+        assert_eq!(res.unwrap(), Term::int(11));
+    }
+
+    #[test]
+    fn test_replace() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+        let subject = bin(&process, b"abcde");
+        let pattern = bin(&process, b"b");
+        let replacement = bin(&process, b"[]");
+
+        let options = cons!(
+            heap,
+            tup2!(heap, atom!(INSERT_REPLACED), Term::int(1)),
+            Term::nil()
+        );
+        let res = replace_4(&vm, &process, &[subject, pattern, replacement, options]);
+        assert_eq!(res.unwrap().to_bytes(), Some(&b"a[b]cde"[..]));
+
+        let subject = bin(&process, b"abcb");
+        let res = replace_3(&vm, &process, &[subject, pattern, replacement]);
+        assert_eq!(res.unwrap().to_bytes(), Some(&b"a[]cb"[..]));
+
+        let options = cons!(heap, atom!(GLOBAL), Term::nil());
+        let res = replace_4(&vm, &process, &[subject, pattern, replacement, options]);
+        assert_eq!(res.unwrap().to_bytes(), Some(&b"a[]c[]"[..]));
+
+        let scope = tup2!(heap, Term::int(2), Term::int(2));
+        let options = cons!(heap, tup2!(heap, atom!(SCOPE), scope), Term::nil());
+        let res = replace_4(&vm, &process, &[subject, pattern, replacement, options]);
+        assert_eq!(res.unwrap().to_bytes(), Some(&b"abc[]"[..]));
+    }
+
+    #[test]
+    fn test_compile_pattern() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let patterns = cons!(heap, bin(&process, b"b"), Term::nil());
+        let pattern = compile_pattern_1(&vm, &process, &[patterns]).unwrap();
+        let (subject, empty) = (bin(&process, b"abc"), bin(&process, b""));
+        let res = replace_3(&vm, &process, &[subject, pattern, empty]);
+        assert_eq!(res.unwrap().to_bytes(), Some(&b"ac"[..]));
+
+        // nothing to search for
+        assert!(compile_pattern_1(&vm, &process, &[Term::nil()]).is_err());
+        assert!(compile_pattern_1(&vm, &process, &[empty]).is_err());
+        let patterns = cons!(heap, empty, Term::nil());
+        assert!(compile_pattern_1(&vm, &process, &[patterns]).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let subject = bin(&process, &[0x01, 0xAB]);
+
+        let res = encode_hex_1(&vm, &process, &[subject]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&b"01AB"[..]));
+        let lower = encode_hex_2(&vm, &process, &[subject, atom!(LOWERCASE)]).unwrap();
+        assert_eq!(lower.to_bytes(), Some(&b"01ab"[..]));
+
+        let res = decode_hex_1(&vm, &process, &[lower]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&[0x01u8, 0xAB][..]));
+
+        assert!(decode_hex_1(&vm, &process, &[bin(&process, b"abc")]).is_err());
+        assert!(decode_hex_1(&vm, &process, &[bin(&process, b"zz")]).is_err());
+    }
+}
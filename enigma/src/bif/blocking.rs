@@ -0,0 +1,319 @@
+//! Offloads blocking work (mostly file I/O) from the process pool.
+//!
+//! A BIF calls `run` with the blocking part of its work, which is sent to a thread pool
+//! dedicated to blocking calls, and returns a trap. The interpreter records where the result
+//! goes and hands the process back to the scheduler loop, which parks it until the work is done
+//! and then `resume`s it with the converted result. The scheduler thread is free to run other
+//! processes in the meantime, and the parked process still handles signals, so it can be killed
+//! or suspended.
+//!
+//! BIFs that need an answer from another process, like `process_info/2`, `wait` for it the same
+//! way. Long running BIFs do their work in slices: when they run out of reductions they save
+//! their state in the process and `trap`. The scheduler loop handles signals and suspension like
+//! at any other scheduling point, then continues the BIF with a fresh slice.
+use crate::bif;
+use crate::exception::{Exception, Reason};
+use crate::instruction;
+use crate::process::{self, RcProcess};
+use crate::vm;
+use futures::channel::oneshot;
+use futures::future::{self, Either, Future};
+use std::pin::Pin;
+
+/// Converts the output of the blocking work into the BIF's return value, or continues a BIF
+/// that trapped. It runs on the process' own thread, so it can allocate on the heap.
+pub type Resume = Box<dyn FnOnce(&RcProcess) -> bif::Result + Send>;
+
+/// Work a process is suspended on, stored in its local data until the scheduler picks it up.
+pub type Job = Pin<Box<dyn Future<Output = Resume> + Send>>;
+
+/// Where the result of a BIF that trapped on offloaded work goes, recorded by the instruction
+/// that called it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Return {
+    /// Into x0, like `call_bif`.
+    X0,
+    /// Into x0, then back to the caller, like `call_bif_last` and `call_bif_only`.
+    X0AndReturn,
+    /// Into the register, like `bif1`, `bif2` and the `gc_bif`s. Errors jump to the fail label
+    /// if there is one.
+    Register(instruction::Register, instruction::Label),
+}
+
+/// Runs `op` on the blocking pool and suspends the calling process. Once `op` is done, `then` is
+/// called with its output to produce the result of the BIF. The pool grows as needed, so slow
+/// calls don't hold up the runtime's workers.
+pub fn run<T, F, C>(_vm: &vm::Machine, process: &RcProcess, op: F, then: C) -> bif::Result
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+    C: FnOnce(&RcProcess, T) -> bif::Result + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    // the result comes back through our own channel, which also tells us if op panicked.
+    let _ = tokio_executor::blocking::run(move || {
+        let _ = sender.send(op());
+    });
+
+    wait(process, receiver, |process, value| match value {
+        Some(value) => then(process, value),
+        // op panicked
+        None => Err(Exception::new(Reason::EXC_INTERNAL_ERROR)),
+    })
+}
+
+/// Suspends the calling process until a value arrives on `receiver`, then calls `then` with it
+/// to produce the result of the BIF, or with `None` if the sender was dropped.
+pub fn wait<T, C>(process: &RcProcess, receiver: oneshot::Receiver<T>, then: C) -> bif::Result
+where
+    T: Send + 'static,
+    C: FnOnce(&RcProcess, Option<T>) -> bif::Result + Send + 'static,
+{
+    process.local_data_mut().blocking = Some(Box::pin(async move {
+        let value = receiver.await.ok();
+        let resume: Resume = Box::new(move |process: &RcProcess| then(process, value));
+        resume
+    }));
+    Err(Exception::new(Reason::TRAP))
+}
+
+/// Parks the process until `job` is done, handling signals in the meantime, then resumes the
+/// BIF and stores its result like the instruction that called it would have. An Err is either
+/// an exception raised by the BIF, a new trap, or an exit signal that arrived while waiting.
+pub async fn resume(process: &RcProcess, mut job: Job) -> Result<process::State, Exception> {
+    let resume = loop {
+        process.process_incoming()?;
+        let signal = match process.context_mut().recv_channel.take() {
+            Some(signal) => signal,
+            None => {
+                // the channel was used up, make process_incoming set up a new one
+                process.context_mut().timeout.take();
+                continue;
+            }
+        };
+        match future::select(job, signal).await {
+            Either::Left((resume, signal)) => {
+                process.context_mut().recv_channel = Some(signal);
+                break resume;
+            }
+            Either::Right((_, pending)) => job = pending,
+        }
+    };
+    // a suspended process doesn't run, not even the rest of the BIF
+    process.wait_while_suspended().await?;
+
+    finish(process, resume(process))
+}
+
+/// Continues a BIF that trapped, once the signals that arrived in the meantime are handled and
+/// the process isn't suspended. Returns like `resume`.
+pub async fn resume_trap(process: &RcProcess, resume: Resume) -> Result<process::State, Exception> {
+    process.process_incoming()?;
+    process.wait_while_suspended().await?;
+
+    process.context_mut().reds = process::CONTEXT_REDS;
+    finish(process, resume(process))
+}
+
+/// Stores the result of a resumed BIF like the instruction that called it would have.
+fn finish(process: &RcProcess, result: bif::Result) -> Result<process::State, Exception> {
+    let context = process.context_mut();
+    match (result, process.local_data().blocking_return) {
+        (Err(exc), _) if exc.reason == Reason::TRAP => Err(exc),
+        (Ok(val), Return::X0) => {
+            context.x[0] = val;
+            Ok(process::State::Yield)
+        }
+        (Ok(val), Return::X0AndReturn) => {
+            context.x[0] = val;
+            match context.cp.take() {
+                Some(cp) => {
+                    context.ip = cp;
+                    Ok(process::State::Yield)
+                }
+                None => Ok(process::State::Done),
+            }
+        }
+        (Ok(val), Return::Register(reg, _)) => {
+            context.set_register(reg, val);
+            Ok(process::State::Yield)
+        }
+        (Err(_), Return::Register(_, fail)) if fail != 0 => {
+            context.ip.ptr = fail;
+            Ok(process::State::Yield)
+        }
+        (Err(exc), _) => Err(exc),
+    }
+}
+
+/// Saves `then` in the process and traps. The process goes back to the scheduler loop, which
+/// calls `then` with a fresh slice of reductions to continue the BIF, unless the process was
+/// killed in the meantime.
+pub fn trap<C>(process: &RcProcess, then: C) -> bif::Result
+where
+    C: FnOnce(&RcProcess) -> bif::Result + Send + 'static,
+{
+    process.local_data_mut().trap = Some(Box::new(then));
+    Err(Exception::new(Reason::TRAP))
+}
+
+/// Calls `bif` outside of the interpreter, blocking the current thread on offloaded work and
+/// continuing traps right away.
+#[cfg(test)]
+pub fn call(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    bif: bif::Fn,
+    args: &[crate::value::Term],
+) -> bif::Result {
+    let mut res = bif(vm, process, args);
+    loop {
+        match res {
+            Err(ref exc) if exc.reason == Reason::TRAP => (),
+            res => return res,
+        }
+        let trap = process.local_data_mut().trap.take();
+        res = match trap {
+            Some(then) => {
+                process.context_mut().reds = process::CONTEXT_REDS;
+                then(process)
+            }
+            None => {
+                let job = process.local_data_mut().blocking.take().unwrap();
+                vm.runtime.block_on(job)(process)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use crate::process;
+    use crate::value::Term;
+    use std::task::Poll;
+
+    fn current_thread(vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+        let caller = std::thread::current().id();
+        run(
+            vm,
+            process,
+            move || std::thread::current().id() != caller,
+            |_, offloaded| Ok(Term::boolean(offloaded)),
+        )
+    }
+
+    #[test]
+    fn test_run() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+
+        let res = current_thread(&vm, &process, &[]);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        assert!(process.local_data().blocking.is_some());
+        process.local_data_mut().blocking = None;
+
+        assert_eq!(call(&vm, &process, current_thread, &[]), Ok(atom!(TRUE)));
+        assert!(process.local_data().blocking.is_none());
+    }
+
+    fn count_slices(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+        fn slice(process: &RcProcess, n: i32) -> bif::Result {
+            process.context_mut().reds = 0;
+            if n == 3 {
+                return Ok(Term::int(n));
+            }
+            trap(process, move |process| slice(process, n + 1))
+        }
+        slice(process, 1)
+    }
+
+    #[test]
+    fn test_resume_handles_signals() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+
+        futures::executor::block_on(async {
+            // work that never finishes, like a read from a hung NFS mount
+            let job: Job = Box::pin(future::pending());
+            let mut wait = Box::pin(resume(&process, job));
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+
+            let reason = Exception::with_value(Reason::EXC_EXIT, atom!(KILL));
+            process.send_signal(process::Signal::exit(
+                process.pid + 1,
+                &reason,
+                process::ExitKind::Exit,
+            ));
+            match futures::poll!(wait.as_mut()) {
+                Poll::Ready(Err(exc)) => assert_eq!(exc.value, atom!(KILLED)),
+                _ => panic!("the exit signal wasn't handled"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_resume_returns() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let done = |result: bif::Result| -> Job {
+            let resume: Resume = Box::new(move |_: &RcProcess| result);
+            Box::pin(future::ready(resume))
+        };
+        let reg = instruction::Register::X(instruction::RegisterX(1));
+
+        process.local_data_mut().blocking_return = Return::Register(reg, 0);
+        let res = futures::executor::block_on(resume(&process, done(Ok(Term::int(1)))));
+        assert!(res.is_ok());
+        assert_eq!(process.context().x[1], Term::int(1));
+
+        // errors jump to the fail label
+        process.local_data_mut().blocking_return = Return::Register(reg, 42);
+        let res = futures::executor::block_on(resume(&process, done(Err(badarg!()))));
+        assert!(res.is_ok());
+        assert_eq!(process.context().ip.ptr, 42);
+
+        // returning without a continuation pointer ends the process
+        process.local_data_mut().blocking_return = Return::X0AndReturn;
+        match futures::executor::block_on(resume(&process, done(Ok(Term::int(2))))) {
+            Ok(process::State::Done) => (),
+            _ => panic!("the process didn't return"),
+        }
+        assert_eq!(process.context().x[0], Term::int(2));
+    }
+
+    #[test]
+    fn test_trap() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+
+        let res = count_slices(&vm, &process, &[]);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        let then = process.local_data_mut().trap.take().unwrap();
+
+        // the next slice traps again
+        process.local_data_mut().blocking_return = Return::X0;
+        let res = futures::executor::block_on(resume_trap(&process, then));
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+
+        // unless the process is killed between slices
+        let then = process.local_data_mut().trap.take().unwrap();
+        let reason = Exception::with_value(Reason::EXC_EXIT, atom!(KILL));
+        process.send_signal(process::Signal::exit(
+            process.pid + 1,
+            &reason,
+            process::ExitKind::Exit,
+        ));
+        let res = futures::executor::block_on(resume_trap(&process, then));
+        assert_eq!(res.unwrap_err().value, atom!(KILLED));
+        assert!(process.local_data().trap.is_none());
+
+        assert_eq!(call(&vm, &process, count_slices, &[]), Ok(Term::int(3)));
+        assert!(process.local_data().trap.is_none());
+    }
+}
@@ -2,6 +2,7 @@ use crate::atom;
 use crate::bif;
 use crate::bitstring;
 use crate::exception::{Exception, Reason};
+use crate::process::table::{make_pid, pid_number, pid_serial};
 use crate::process::RcProcess;
 use crate::value::{self, CastFrom, CastInto, Cons, Term, Tuple, Variant};
 use crate::vm::Machine;
@@ -15,6 +16,174 @@ pub fn md5_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     Ok(Term::binary(heap, bitstring::Binary::from(digest.to_vec())))
 }
 
+/// Per-round shift amounts of MD5.
+const MD5_S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+/// Per-round constants of MD5, the integer part of `abs(sin(i + 1)) * 2^32`.
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// Size of the serialized context without the pending bytes: four state words and the length.
+const MD5_HEADER_SIZE: usize = 24;
+
+/// Incremental MD5 state, kept in an opaque binary between `md5_update/2` calls. The binary
+/// holds the state words and the message length in bytes, little endian, followed by the bytes
+/// that don't fill a block yet.
+struct Md5Context {
+    state: [u32; 4],
+    length: u64,
+    pending: Vec<u8>,
+}
+
+impl Md5Context {
+    fn new() -> Self {
+        Self {
+            state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+            length: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < MD5_HEADER_SIZE {
+            return None;
+        }
+        let (header, pending) = bytes.split_at(MD5_HEADER_SIZE);
+        let mut state = [0; 4];
+        for (word, bytes) in state.iter_mut().zip(header.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut length = [0; 8];
+        length.copy_from_slice(&header[16..]);
+        let length = u64::from_le_bytes(length);
+
+        if pending.len() as u64 != length % 64 {
+            return None;
+        }
+        Some(Self {
+            state,
+            length,
+            pending: pending.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MD5_HEADER_SIZE + self.pending.len());
+        for word in &self.state {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes.extend_from_slice(&self.pending);
+        bytes
+    }
+
+    fn consume(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        if !self.pending.is_empty() {
+            let n = std::cmp::min(64 - self.pending.len(), data.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            md5_block(&mut self.state, &self.pending);
+            self.pending.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            md5_block(&mut self.state, block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn compute(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        // pad with a one bit and zeroes up to 8 bytes short of a block, then append the length
+        let rem = (self.length % 64) as usize;
+        let mut padding = vec![0; if rem < 56 { 56 - rem } else { 120 - rem }];
+        padding[0] = 0x80;
+        self.consume(&padding);
+        self.consume(&bits.to_le_bytes());
+
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+/// Runs the MD5 compression function over a 64 byte block.
+fn md5_block(state: &mut [u32; 4], block: &[u8]) {
+    let mut m = [0u32; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+    for i in 0..64 {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(MD5_S[i]));
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+pub fn md5_init_0(_vm: &Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let context = Md5Context::new().to_bytes();
+    Ok(Term::binary(heap, bitstring::Binary::from(context)))
+}
+
+pub fn md5_update_2(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let mut context = args[0]
+        .to_bytes()
+        .and_then(Md5Context::from_bytes)
+        .ok_or_else(|| badarg!())?;
+    context.consume(&list_to_iodata(args[1])?);
+
+    let heap = &process.context_mut().heap;
+    Ok(Term::binary(
+        heap,
+        bitstring::Binary::from(context.to_bytes()),
+    ))
+}
+
+pub fn md5_final_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let context = args[0]
+        .to_bytes()
+        .and_then(Md5Context::from_bytes)
+        .ok_or_else(|| badarg!())?;
+
+    let heap = &process.context_mut().heap;
+    let digest = context.compute();
+    Ok(Term::binary(heap, bitstring::Binary::from(digest.to_vec())))
+}
+
 pub fn make_tuple_2(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let num = match args[0].into_number() {
         Ok(value::Num::Integer(i)) if !i < 0 => i,
@@ -92,7 +261,7 @@ pub fn setelement_3(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::R
 // TODO swap with GetTupleElement ins?
 pub fn element_2(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     let number = match args[0].into_number() {
-        Ok(value::Num::Integer(i)) if !i < 1 => (i - 1) as usize,
+        Ok(value::Num::Integer(i)) if i >= 1 => (i - 1) as usize,
         _ => return Err(badarg!()),
     };
     let t = Tuple::cast_from(&args[1])?;
@@ -162,14 +331,17 @@ pub fn list_to_atom_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif
     // ASSERT(is_atom(res));
     // erts_free(ERTS_ALC_T_TMP, (void *) buf);
     // BIF_RET(res);
+    if args[0].is_nil() {
+        return Ok(Term::atom(atom::Atom::try_from_str("")?));
+    }
     let list = Cons::cast_from(&args[0])?;
     let string = value::cons::unicode_list_to_buf(list, atom::MAX_ATOM_CHARS)?;
-    let atom = atom::Atom::from(string);
+    let atom = atom::Atom::try_from_str(&string)?;
     Ok(Term::atom(atom))
 }
 
 /// conditionally convert a list of ascii integers to an atom
-pub fn list_to_existing_atom_1(_vm: &Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
+pub fn list_to_existing_atom_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
     // byte *buf = (byte *) erts_alloc(ERTS_ALC_T_TMP, MAX_ATOM_SZ_LIMIT);
     // Sint written;
     // int i = erts_unicode_list_to_buf(BIF_ARG_1, buf, MAX_ATOM_CHARACTERS,
@@ -188,7 +360,16 @@ pub fn list_to_existing_atom_1(_vm: &Machine, _process: &RcProcess, _args: &[Ter
     // goto error;
     // }
     // }
-    unimplemented!()
+    let string = if args[0].is_nil() {
+        String::new()
+    } else {
+        let list = Cons::cast_from(&args[0])?;
+        value::cons::unicode_list_to_buf(list, atom::MAX_ATOM_CHARS)?
+    };
+    match atom::Atom::lookup(&string) {
+        Some(atom) => Ok(Term::atom(atom)),
+        None => Err(badarg!()),
+    }
 }
 
 // TODO: use Cow
@@ -224,10 +405,9 @@ pub fn list_to_iodata(list: Term) -> Result<Vec<u8>, Exception> {
                                 // append int to bytes
                                 bytes.push(i as u8);
                             }
-                            Variant::Pointer(..) => match elem.to_bytes() {
-                                Some(data) => bytes.extend_from_slice(data),
-                                None => return Err(badarg!()),
-                            },
+                            Variant::Pointer(..) if elem.is_binary() => {
+                                bytes.extend_from_slice(elem.to_bytes().unwrap())
+                            }
                             Variant::Cons(p) => {
                                 ptr = p;
                                 stack.push(cons.tail);
@@ -241,15 +421,11 @@ pub fn list_to_iodata(list: Term) -> Result<Vec<u8>, Exception> {
 
                     elem = cons.tail;
 
+                    // an improper tail can only be a binary
                     match elem.into_variant() {
-                        Variant::Integer(i @ 0..=255) => {
-                            // append int to bytes
-                            bytes.push(i as u8);
+                        Variant::Pointer(..) if elem.is_binary() => {
+                            bytes.extend_from_slice(elem.to_bytes().unwrap())
                         }
-                        Variant::Pointer(..) => match elem.to_bytes() {
-                            Some(data) => bytes.extend_from_slice(data),
-                            None => return Err(badarg!()),
-                        },
                         Variant::Nil(..) => {}
                         Variant::Cons(p) => {
                             ptr = p;
@@ -260,10 +436,9 @@ pub fn list_to_iodata(list: Term) -> Result<Vec<u8>, Exception> {
                     break;
                 }
             }
-            Variant::Pointer(..) => match elem.to_bytes() {
-                Some(data) => bytes.extend_from_slice(data),
-                None => return Err(badarg!()),
-            },
+            Variant::Pointer(..) if elem.is_binary() => {
+                bytes.extend_from_slice(elem.to_bytes().unwrap())
+            }
             Variant::Nil(..) => {}
             _ => return Err(badarg!()),
         }
@@ -273,6 +448,10 @@ pub fn list_to_iodata(list: Term) -> Result<Vec<u8>, Exception> {
 }
 
 pub fn list_to_binary_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // unlike iolist_to_binary, a binary on its own is not accepted
+    if !args[0].is_list() {
+        return Err(badarg!());
+    }
     let bytes = list_to_iodata(args[0])?;
 
     let heap = &process.context_mut().heap;
@@ -540,35 +719,30 @@ pub fn iolist_size_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::
 pub fn binary_to_term_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // TODO: needs to yield mid parsing...
     if let Some(string) = args[0].to_bytes() {
-        match Ok(crate::etf::decode(string, &process.context_mut().heap)) {
-            Ok(term) => return Ok(term),
-            Err::<_, std::io::Error>(error) => panic!("binary_to_term error: {:?}", error),
-        };
+        return crate::etf::try_decode(string, &process.context_mut().heap);
     }
     Err(badarg!())
 }
 
 pub fn term_to_binary_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // TODO: needs to yield mid parsing...
-    match crate::etf::encode(args[0]) {
-        Ok(term) => Ok(Term::binary(
-            &process.context_mut().heap,
-            bitstring::Binary::from(term),
-        )),
-        Err::<_, std::io::Error>(error) => panic!("binary_to_term error: {:?}", error),
-    }
+    // terms that can't be encoded
+    let bytes = crate::etf::encode(args[0]).map_err(|_| badarg!())?;
+    Ok(Term::binary(
+        &process.context_mut().heap,
+        bitstring::Binary::from(bytes),
+    ))
 }
 
 pub fn term_to_binary_2(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // TODO: needs to yield mid parsing...
     // TODO: args[1] for compression settings
-    match crate::etf::encode(args[0]) {
-        Ok(term) => Ok(Term::binary(
-            &process.context_mut().heap,
-            bitstring::Binary::from(term),
-        )),
-        Err::<_, std::io::Error>(error) => panic!("binary_to_term error: {:?}", error),
-    }
+    // terms that can't be encoded
+    let bytes = crate::etf::encode(args[0]).map_err(|_| badarg!())?;
+    Ok(Term::binary(
+        &process.context_mut().heap,
+        bitstring::Binary::from(bytes),
+    ))
 }
 
 pub fn binary_to_atom_2(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
@@ -582,7 +756,7 @@ pub fn binary_to_atom_2(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> b
     if let Some(bytes) = args[0].to_bytes() {
         return match std::str::from_utf8(bytes) {
             Ok(string) => {
-                let atom = atom::Atom::from(string);
+                let atom = atom::Atom::try_from_str(string)?;
                 Ok(Term::atom(atom))
             }
             Err(_) => Err(badarg!()),
@@ -591,6 +765,24 @@ pub fn binary_to_atom_2(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> b
     Err(badarg!())
 }
 
+pub fn binary_to_existing_atom_2(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    match args[1].into_variant() {
+        Variant::Atom(atom::LATIN1) => (),
+        Variant::Atom(atom::UNICODE) => (),
+        Variant::Atom(atom::UTF8) => (),
+        _ => return Err(badarg!()),
+    };
+
+    if let Some(bytes) = args[0].to_bytes() {
+        if let Ok(string) = std::str::from_utf8(bytes) {
+            if let Some(atom) = atom::Atom::lookup(string) {
+                return Ok(Term::atom(atom));
+            }
+        }
+    }
+    Err(badarg!())
+}
+
 pub fn atom_to_list_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     match args[0].into_variant() {
         Variant::Atom(i) => {
@@ -628,7 +820,7 @@ pub fn atom_to_binary_2(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bi
 pub fn pid_to_list_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     match args[0].into_variant() {
         Variant::Pid(i) => {
-            let string = format!("<0.0.{}>", i); // TODO: proper format
+            let string = format!("<0.{}.{}>", pid_number(i), pid_serial(i));
             let heap = &process.context_mut().heap;
 
             Ok(bitstring!(heap, string))
@@ -637,6 +829,29 @@ pub fn pid_to_list_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::
     }
 }
 
+pub fn list_to_pid_1(_vm: &Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
+    let cons = Cons::cast_from(&args[0])?;
+    let string = value::cons::unicode_list_to_buf(cons, 64)?;
+
+    // <Node.Number.Serial>, only local pids are supported.
+    if !string.starts_with('<') || !string.ends_with('>') {
+        return Err(badarg!());
+    }
+    let parts = string[1..string.len() - 1]
+        .split('.')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| badarg!())?;
+
+    match parts.as_slice() {
+        [0, number, serial] => match make_pid(*number, *serial) {
+            Some(pid) => Ok(Term::pid(pid)),
+            None => Err(badarg!()),
+        },
+        _ => Err(badarg!()),
+    }
+}
+
 pub fn integer_to_list_1(_vm: &Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     match args[0].into_number() {
         Ok(value::Num::Integer(i)) => {
@@ -1630,4 +1845,71 @@ mod tests {
         let res = list_to_iodata(list);
         assert_eq!(Ok(vec![1, 2, 3, 0xAB, 0xCD, 0xEF]), res)
     }
+
+    #[test]
+    fn test_md5_incremental() {
+        let vm = Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let whole = Term::binary(heap, bitstring::Binary::from(data.clone()));
+        let expected = md5_1(&vm, &process, &[whole]).unwrap();
+
+        let mut context = md5_init_0(&vm, &process, &[]).unwrap();
+        for chunk in data.chunks(37) {
+            let chunk = Term::binary(heap, bitstring::Binary::from(chunk.to_vec()));
+            context = md5_update_2(&vm, &process, &[context, chunk]).unwrap();
+        }
+        assert_eq!(md5_final_1(&vm, &process, &[context]), Ok(expected));
+
+        let context = md5_init_0(&vm, &process, &[]).unwrap();
+        let digest = md5_final_1(&vm, &process, &[context]).unwrap();
+        assert_eq!(digest.to_bytes().unwrap(), &md5::compute(b"")[..],);
+        assert!(md5_final_1(&vm, &process, &[Term::int(1)]).is_err());
+        // the pending bytes have to match the length
+        let context = Term::binary(heap, bitstring::Binary::from(vec![0; 25]));
+        assert!(md5_final_1(&vm, &process, &[context]).is_err());
+    }
+
+    #[test]
+    fn test_term_to_binary_roundtrip() {
+        let vm = Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let binary = Term::binary(heap, bitstring::Binary::from(vec![1, 2, 3]));
+        let mut map = value::HAMT::new();
+        map.insert(str_to_atom!("key"), binary);
+        let term = tup2!(heap, Term::map(heap, map), binary);
+
+        let encoded = term_to_binary_1(&vm, &process, &[term]).unwrap();
+        assert_eq!(binary_to_term_1(&vm, &process, &[encoded]), Ok(term));
+
+        // references can't be encoded yet
+        let reference = Term::reference(heap, 1);
+        assert!(term_to_binary_1(&vm, &process, &[reference]).is_err());
+    }
+
+    #[test]
+    fn test_list_to_binary_errors() {
+        let vm = Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let binary = Term::binary(heap, bitstring::Binary::from(vec![1, 2]));
+        let list = cons!(heap, Term::int(0), binary);
+        let res = list_to_binary_1(&vm, &process, &[list]).unwrap();
+        assert_eq!(res.to_bytes(), Some(&[0u8, 1, 2][..]));
+
+        // a bare binary, an integer tail and out of range bytes are rejected
+        assert!(list_to_binary_1(&vm, &process, &[binary]).is_err());
+        let list = cons!(heap, Term::int(0), Term::int(1));
+        assert!(list_to_binary_1(&vm, &process, &[list]).is_err());
+        let list = cons!(heap, Term::int(256), Term::nil());
+        assert!(list_to_binary_1(&vm, &process, &[list]).is_err());
+    }
 }
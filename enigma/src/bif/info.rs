@@ -1,18 +1,244 @@
 use crate::atom::{self, Atom};
 use crate::bif;
-use crate::process::RcProcess;
+use crate::bif::{blocking, prim_buffer, prim_file, zlib};
+use crate::bitstring::{Binary, RcBinary, SubBinary};
+use crate::exception::Exception;
+use crate::immix::Heap;
+use crate::instruction::Ptr;
+use crate::process::{ExecutionContext, Process, RcProcess, Signal};
+use crate::servo_arc::Arc;
 use crate::value::{self, CastFrom, Cons, Term, Variant};
 use crate::vm;
-use crate::Itertools;
+use futures::channel::oneshot;
+use std::sync::atomic::Ordering;
 
+/// Items returned by `process_info/1`, in the order BEAM reports them.
+const PROCESS_INFO_1_ITEMS: &[Atom] = &[
+    atom::REGISTERED_NAME,
+    atom::CURRENT_FUNCTION,
+    atom::INITIAL_CALL,
+    atom::STATUS,
+    atom::MESSAGE_QUEUE_LEN,
+    atom::LINKS,
+    atom::DICTIONARY,
+    atom::TRAP_EXIT,
+    atom::ERROR_HANDLER,
+    atom::PRIORITY,
+    atom::GROUP_LEADER,
+    atom::TOTAL_HEAP_SIZE,
+    atom::HEAP_SIZE,
+    atom::STACK_SIZE,
+    atom::REDUCTIONS,
+    atom::GARBAGE_COLLECTION,
+    atom::SUSPENDING,
+];
+
+// We don't have a generational GC (yet), so report the BEAM defaults.
+const MIN_HEAP_SIZE: u32 = 233;
+const MIN_BIN_VHEAP_SIZE: u32 = 46422;
+const FULLSWEEP_AFTER: u32 = 65535;
+
+const WORD_SIZE: usize = std::mem::size_of::<Term>();
+
+fn max_heap_size(heap: &Heap) -> Term {
+    map!(
+        heap,
+        atom!(SIZE) => Term::int(0),
+        atom!(KILL) => atom!(TRUE),
+        atom!(ERROR_LOGGER) => atom!(TRUE),
+    )
+}
+
+/// Heap size in words, including the stack.
+fn heap_size(process: &Process) -> usize {
+    let context = process.context();
+    context.heap.size() / WORD_SIZE + stack_size(process)
+}
+
+/// Allocated heap blocks in words, including the stack.
+fn total_heap_size(process: &Process) -> usize {
+    let context = process.context();
+    context.heap.capacity() / WORD_SIZE + context.stack.capacity()
+}
+
+fn stack_size(process: &Process) -> usize {
+    let context = process.context();
+    context.stack.len() + context.callstack.len()
+}
+
+/// Total process size in bytes, including the heap, stack and message queue.
+fn process_memory(process: &Process) -> usize {
+    let local_data = process.local_data();
+    std::mem::size_of::<Process>()
+        + std::mem::size_of::<ExecutionContext>()
+        + total_heap_size(process) * WORD_SIZE
+        + local_data.mailbox.len() * WORD_SIZE
+        + local_data.dictionary.len() * 2 * WORD_SIZE
+}
+
+/// Calls `visit` on every boxed term reachable from the process roots: the live registers,
+/// the stack, the message queue and the dictionary. Shared subterms are only visited once.
+fn visit_reachable<F: FnMut(Term)>(process: &Process, mut visit: F) {
+    let context = process.context();
+    let local_data = process.local_data();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut stack: Vec<Term> = context.x[..context.live].to_vec();
+    stack.extend_from_slice(&context.stack);
+    stack.extend(local_data.mailbox.iter().copied());
+    for (key, val) in local_data.dictionary.iter() {
+        stack.push(*key);
+        stack.push(*val);
+    }
+
+    while let Some(term) = stack.pop() {
+        match term.into_variant() {
+            Variant::Cons(ptr) => {
+                if !seen.insert(ptr as usize) {
+                    continue;
+                }
+                let cons = unsafe { &*ptr };
+                stack.push(cons.head);
+                stack.push(cons.tail);
+            }
+            Variant::Pointer(ptr) => {
+                if !seen.insert(ptr as usize) {
+                    continue;
+                }
+                visit(term);
+                match unsafe { *ptr } {
+                    value::BOXED_TUPLE => {
+                        let tuple = value::Tuple::cast_from(&term).unwrap();
+                        stack.extend(tuple.iter().copied());
+                    }
+                    value::BOXED_MAP => {
+                        let map = value::Map::cast_from(&term).unwrap();
+                        for (key, val) in map.0.iter() {
+                            stack.push(*key);
+                            stack.push(*val);
+                        }
+                    }
+                    value::BOXED_CLOSURE => {
+                        let closure = value::Closure::cast_from(&term).unwrap();
+                        if let Some(binding) = &closure.binding {
+                            stack.extend_from_slice(binding);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// `{Id, Size, RefCount}` of every off-heap binary the process references.
+fn binaries(process: &Process, heap: &Heap) -> Term {
+    let mut binaries = Vec::new();
+    visit_reachable(process, |term| {
+        let binary = match term.get_boxed_header() {
+            Ok(value::BOXED_BINARY) => term.get_boxed_value::<RcBinary>().unwrap(),
+            Ok(value::BOXED_SUBBINARY) => &term.get_boxed_value::<SubBinary>().unwrap().original,
+            _ => return,
+        };
+        binaries.push(binary.clone());
+    });
+    binaries.sort_by_key(|binary| &**binary as *const Binary as usize);
+    binaries.dedup_by(|a, b| Arc::ptr_eq(a, b));
+
+    binaries.iter().rev().fold(Term::nil(), |acc, binary| {
+        let id = Term::uint64(heap, &**binary as *const Binary as u64);
+        let size = Term::uint64(heap, binary.data.len() as u64);
+        // don't count the clone we're holding
+        let refc = Term::uint64(heap, Arc::strong_count(binary) as u64 - 1);
+        cons!(heap, tup3!(heap, id, size, refc), acc)
+    })
+}
+
+/// `{Id, Size, RefCount}` of the native resources (files, buffers, compiled patterns, zlib
+/// streams) the process references.
+fn magic_refs(process: &Process, heap: &Heap) -> Term {
+    use std::mem::size_of;
+
+    let mut refs = Vec::new();
+    visit_reachable(process, |term| {
+        let (size, refc) = match term.get_boxed_header() {
+            Ok(value::BOXED_FILE) => {
+                let file = term.get_boxed_value::<prim_file::RawFile>().unwrap();
+                (size_of::<prim_file::Handle>(), file.ref_count())
+            }
+            Ok(value::BOXED_BUFFER) => (size_of::<prim_buffer::Buffer>(), 1),
+            Ok(value::BOXED_REGEX) => (size_of::<regex::bytes::Regex>(), 1),
+            Ok(value::BOXED_RE_PATTERN) => (size_of::<crate::regex::Pattern>(), 1),
+            Ok(value::BOXED_ZLIB) => {
+                let stream = term.get_boxed_value::<zlib::Zstream>().unwrap();
+                (size_of::<zlib::Zstream>(), stream.ref_count())
+            }
+            _ => return,
+        };
+        let id = match term.into_variant() {
+            Variant::Pointer(ptr) => ptr as u64,
+            _ => unreachable!(),
+        };
+        refs.push((id, size, refc));
+    });
+
+    refs.iter()
+        .rev()
+        .fold(Term::nil(), |acc, (id, size, refc)| {
+            let item = tup3!(
+                heap,
+                Term::uint64(heap, *id),
+                Term::uint64(heap, *size as u64),
+                Term::uint64(heap, *refc as u64)
+            );
+            cons!(heap, item, acc)
+        })
+}
+
+/// Format the call stack the same way `erlang:process_display/2` does.
+fn backtrace(process: &Process) -> String {
+    use std::fmt::Write;
+
+    let context = process.context();
+    let mut out = String::new();
+
+    let describe = |ptr: &Ptr| match ptr.lookup_func_info() {
+        Some((mfa, _)) => format!("{}", mfa),
+        None => String::from("unknown function"),
+    };
+
+    writeln!(
+        out,
+        "Program counter: {:#x} ({})",
+        context.ip.ptr,
+        describe(&context.ip)
+    )
+    .unwrap();
+    match &context.cp {
+        Some(cp) => writeln!(out, "CP: {:#x} ({})", cp.ptr, describe(cp)).unwrap(),
+        None => writeln!(out, "CP: 0x0 (invalid)").unwrap(),
+    };
+    writeln!(out).unwrap();
+
+    for (_, cp) in context.callstack.iter().rev() {
+        if let Some(cp) = cp {
+            writeln!(out, "Return addr {:#x} ({})", cp.ptr, describe(cp)).unwrap();
+        }
+    }
+    out
+}
+
+/// Looks up `item` on `process`, building the result on `heap`. Terms owned by the process are
+/// copied, so the result doesn't point into the process' own heap.
 pub fn process_info_aux(
-    _vm: &vm::Machine,
-    process: &RcProcess,
+    process: &Process,
+    status: Term,
+    heap: &Heap,
     item: Term,
     always_wrap: bool,
 ) -> bif::Result {
-    use crate::process::Flag;
-    let heap = &process.context_mut().heap;
+    use crate::process::{Flag, StateFlag};
 
     // TODO: bump process regs
     // (*reds)++;
@@ -42,6 +268,7 @@ pub fn process_info_aux(
     };
 
     let local_data = process.local_data();
+    let context = process.context();
 
     let res = match item {
         atom::REGISTERED_NAME => {
@@ -55,14 +282,19 @@ pub fn process_info_aux(
                 }
             }
         }
-        atom::CURRENT_FUNCTION => {
-            //process.context_mut().ip.lookup_func_info()
-            unimplemented!()
-        }
-        atom::CURRENT_LOCATION => {
-            let fi = process.context_mut().ip.lookup_func_info().unwrap();
-            crate::exception::erts_build_mfa_item(&fi, heap, Term::nil())
-        }
+        atom::CURRENT_FUNCTION => match context.ip.lookup_func_info() {
+            Some((mfa, _)) => tup3!(
+                heap,
+                Term::atom(mfa.0),
+                Term::atom(mfa.1),
+                Term::uint(heap, mfa.2)
+            ),
+            None => atom!(UNDEFINED),
+        },
+        atom::CURRENT_LOCATION => match context.ip.lookup_func_info() {
+            Some(fi) => crate::exception::erts_build_mfa_item(&fi, heap, Term::nil()),
+            None => atom!(UNDEFINED),
+        },
         atom::CURRENT_STACKTRACE => {
             let mut trace = Vec::with_capacity(crate::exception::DEFAULT_BACKTRACE_SIZE as usize);
             crate::exception::erts_save_stacktrace(
@@ -70,14 +302,18 @@ pub fn process_info_aux(
                 &mut trace,
                 crate::exception::DEFAULT_BACKTRACE_SIZE,
             );
-            trace.into_iter().rev().fold(Term::nil(), |acc, ptr| {
-                let func_info = ptr.lookup_func_info().unwrap();
-                cons!(
-                    heap,
-                    crate::exception::erts_build_mfa_item(&func_info, heap, Term::nil()),
-                    acc
-                )
-            })
+            // frames outside of any known function (natives, traps) are left out
+            trace
+                .into_iter()
+                .rev()
+                .filter_map(|ptr| ptr.lookup_func_info())
+                .fold(Term::nil(), |acc, func_info| {
+                    cons!(
+                        heap,
+                        crate::exception::erts_build_mfa_item(&func_info, heap, Term::nil()),
+                        acc
+                    )
+                })
         }
         atom::INITIAL_CALL => {
             let call = local_data.initial_call;
@@ -88,98 +324,256 @@ pub fn process_info_aux(
                 Term::uint(heap, call.2)
             )
         }
-        atom::STATUS => {
-            // TODO: quick cheat
-            atom!(RUNNING)
-        }
-        atom::MESSAGES => {
-            // TODO: quick cheat
-            Term::nil()
-        }
+        atom::STATUS => status,
+        atom::MESSAGES => local_data
+            .mailbox
+            .iter()
+            .rev()
+            .fold(Term::nil(), |acc, msg| {
+                cons!(heap, msg.deep_clone(heap), acc)
+            }),
         atom::MESSAGE_QUEUE_LEN => Term::uint(heap, local_data.mailbox.len() as u32),
-        atom::MESSAGE_QUEUE_DATA => unimplemented!(),
+        atom::MESSAGE_QUEUE_DATA => atom!(ON_HEAP),
         atom::LINKS => local_data
             .links
             .iter()
             .fold(Term::nil(), |acc, pid| cons!(heap, Term::pid(*pid), acc)),
-        atom::MONITORED_BY => unimplemented!(),
-        atom::DICTIONARY => {
-            let pdict = &process.local_data_mut().dictionary;
-            let heap = &process.context_mut().heap;
-
-            pdict.iter().fold(Term::nil(), |res, (key, val)| {
-                let tuple = tup2!(heap, *key, *val);
+        atom::MONITORS => local_data.monitors.values().fold(Term::nil(), |acc, pid| {
+            cons!(heap, tup2!(heap, atom!(PROCESS), Term::pid(*pid)), acc)
+        }),
+        atom::MONITORED_BY => local_data
+            .lt_monitors
+            .iter()
+            .fold(Term::nil(), |acc, (pid, _)| {
+                cons!(heap, Term::pid(*pid), acc)
+            }),
+        atom::DICTIONARY => local_data
+            .dictionary
+            .iter()
+            .fold(Term::nil(), |res, (key, val)| {
+                let tuple = tup2!(heap, key.deep_clone(heap), val.deep_clone(heap));
                 cons!(heap, tuple, res)
-            })
-        }
+            }),
         atom::TRAP_EXIT => Term::boolean(local_data.flags.contains(Flag::TRAP_EXIT)),
-        atom::ERROR_HANDLER => unimplemented!(),
-        atom::HEAP_SIZE => {
-            // TODO: temporary
-            Term::int(512)
-        }
-        atom::STACK_SIZE => {
-            // TODO: temporary
-            Term::int(512)
-        }
-        atom::MEMORY => {
-            // TODO: temporary
-            Term::int(1024)
+        atom::ERROR_HANDLER => Term::atom(local_data.error_handler),
+        atom::HEAP_SIZE => Term::uint64(heap, heap_size(process) as u64),
+        atom::STACK_SIZE => Term::uint64(heap, stack_size(process) as u64),
+        atom::MEMORY => Term::uint64(heap, process_memory(process) as u64),
+        atom::GARBAGE_COLLECTION => {
+            let items = [
+                (atom::MAX_HEAP_SIZE, max_heap_size(heap)),
+                (
+                    atom::MIN_BIN_VHEAP_SIZE,
+                    Term::uint(heap, MIN_BIN_VHEAP_SIZE),
+                ),
+                (atom::MIN_HEAP_SIZE, Term::uint(heap, MIN_HEAP_SIZE)),
+                (atom::FULLSWEEP_AFTER, Term::uint(heap, FULLSWEEP_AFTER)),
+                (atom::MINOR_GCS, Term::int(0)),
+            ];
+            items.iter().rev().fold(Term::nil(), |acc, (key, val)| {
+                cons!(heap, tup2!(heap, Term::atom(*key), *val), acc)
+            })
         }
-        atom::GARBAGE_COLLECTION => unimplemented!(),
-        atom::GARBAGE_COLLECTION_INFO => unimplemented!(),
-        atom::GROUP_LEADER => unimplemented!(),
-        atom::REDUCTIONS => Term::uint(heap, process.context().reds as u32),
-        atom::PRIORITY => {
-            // TODO: temporary
-            atom!(NORMAL)
+        atom::GARBAGE_COLLECTION_INFO => {
+            let heap_block_size = total_heap_size(process);
+            let items = [
+                (atom::OLD_HEAP_BLOCK_SIZE, 0),
+                (atom::HEAP_BLOCK_SIZE, heap_block_size),
+                (atom::MBUF_SIZE, 0),
+                (atom::RECENT_SIZE, 0),
+                (atom::STACK_SIZE, stack_size(process)),
+                (atom::OLD_HEAP_SIZE, 0),
+                (atom::HEAP_SIZE, heap_size(process)),
+                (atom::BIN_VHEAP_SIZE, 0),
+                (atom::BIN_VHEAP_BLOCK_SIZE, MIN_BIN_VHEAP_SIZE as usize),
+                (atom::BIN_OLD_VHEAP_SIZE, 0),
+                (atom::BIN_OLD_VHEAP_BLOCK_SIZE, MIN_BIN_VHEAP_SIZE as usize),
+            ];
+            items.iter().rev().fold(Term::nil(), |acc, (key, val)| {
+                let val = Term::uint64(heap, *val as u64);
+                cons!(heap, tup2!(heap, Term::atom(*key), val), acc)
+            })
         }
-        atom::TRACE => unimplemented!(),
-        atom::BINARY => unimplemented!(),
-        atom::SEQUENTIAL_TRACE_TOKEN => unimplemented!(),
-        atom::CATCH_LEVEL => unimplemented!(),
-        atom::BACKTRACE => unimplemented!(),
-        atom::LAST_CALLS => unimplemented!(),
-        atom::TOTAL_HEAP_SIZE => unimplemented!(),
-        atom::SUSPENDING => unimplemented!(),
-        atom::MIN_HEAP_SIZE => unimplemented!(),
-        atom::MIN_BIN_VHEAP_SIZE => unimplemented!(),
-        atom::MAX_HEAP_SIZE => unimplemented!(),
-        atom::MAGIC_REF => unimplemented!(),
-        atom::FULLSWEEP_AFTER => unimplemented!(),
+        atom::GROUP_LEADER => Term::pid(local_data.group_leader),
+        atom::REDUCTIONS => Term::uint(heap, context.reds as u32),
+        atom::PRIORITY => match local_data.state & StateFlag::PRQ_MASK {
+            StateFlag::PRQ_MAX => atom!(MAX),
+            StateFlag::PRQ_HIGH => atom!(HIGH),
+            StateFlag::PRQ_LOW => atom!(LOW),
+            _ => atom!(NORMAL),
+        },
+        // no tracing support, so no trace flags are ever set
+        atom::TRACE => Term::int(0),
+        atom::BINARY => binaries(process, heap),
+        atom::SEQUENTIAL_TRACE_TOKEN => Term::nil(),
+        atom::CATCH_LEVEL => Term::uint64(heap, context.catches as u64),
+        atom::BACKTRACE => Term::binary(heap, Binary::from(backtrace(process).into_bytes())),
+        atom::LAST_CALLS => atom!(FALSE),
+        atom::TOTAL_HEAP_SIZE => Term::uint64(heap, total_heap_size(process) as u64),
+        // suspensions take effect immediately, so none are outstanding
+        atom::SUSPENDING => local_data
+            .suspending
+            .iter()
+            .fold(Term::nil(), |acc, (pid, count)| {
+                let count = Term::uint(heap, *count as u32);
+                cons!(heap, tup3!(heap, Term::pid(*pid), count, Term::int(0)), acc)
+            }),
+        atom::MIN_HEAP_SIZE => Term::uint(heap, MIN_HEAP_SIZE),
+        atom::MIN_BIN_VHEAP_SIZE => Term::uint(heap, MIN_BIN_VHEAP_SIZE),
+        atom::MAX_HEAP_SIZE => max_heap_size(heap),
+        atom::MAGIC_REF => magic_refs(process, heap),
+        atom::FULLSWEEP_AFTER => Term::uint(heap, FULLSWEEP_AFTER),
         _ => return Err(badarg!()),
     };
 
     Ok(tup2!(heap, Term::atom(item), res))
 }
 
-pub fn process_info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    // args are pid, `[item, .. ]` or just `item`.
-    // response is `[tup,..]` or just `tup`
-    if !args[0].is_pid() {
-        return Err(badarg!());
+fn lookup_process(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    pid: Term,
+) -> Result<Option<RcProcess>, Exception> {
+    let pid = match pid.into_variant() {
+        Variant::Pid(pid) => pid,
+        _ => return Err(badarg!()),
+    };
+
+    if pid == process.pid {
+        return Ok(Some(process.clone()));
     }
 
-    let pid = args[0].to_pid().unwrap();
+    Ok(vm.process_table.lock().get(pid))
+}
 
-    // TODO optimize for if process.pid == pid
-    let proc = {
-        let table = vm.process_table.lock();
-        table.get(pid)
+/// What a `process_info/1,2` call asks for.
+#[derive(Debug)]
+pub enum Items {
+    /// Everything `process_info/1` reports.
+    All,
+    /// A list of items, each reported as `{Item, Value}`.
+    List(Vec<Term>),
+    /// A single item.
+    Item(Term),
+}
+
+/// A `process_info/1,2` call from another process. It's answered by the process itself while it
+/// handles its signals, so its state is never read while it runs. A process that exits before
+/// getting to the request drops it, which the caller reports as `undefined`.
+#[derive(Debug)]
+pub struct Request {
+    pub items: Items,
+    /// The status as the caller saw it, since the process is always running when it answers.
+    pub status: Term,
+    /// The result, built on a heap fragment for the caller to absorb.
+    pub reply: oneshot::Sender<(Heap, bif::Result)>,
+}
+
+fn info(process: &Process, status: Term, heap: &Heap, items: &Items) -> bif::Result {
+    match items {
+        Items::All => {
+            let items = PROCESS_INFO_1_ITEMS
+                .iter()
+                .map(|item| process_info_aux(process, status, heap, Term::atom(*item), false))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // registered_name is left out entirely if the process has no name.
+            Ok(items
+                .into_iter()
+                .rev()
+                .filter(|item| !item.is_nil())
+                .fold(Term::nil(), |acc, val| cons!(heap, val, acc)))
+        }
+        Items::List(items) => {
+            let items = items
+                .iter()
+                .map(|item| process_info_aux(process, status, heap, *item, true))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(items
+                .into_iter()
+                .rev()
+                .fold(Term::nil(), |acc, val| cons!(heap, val, acc)))
+        }
+        Items::Item(item) => process_info_aux(process, status, heap, *item, false),
+    }
+}
+
+/// Answers a `process_info/1,2` request from another process.
+pub fn reply(process: &Process, request: Request) {
+    let heap = Heap::fragment();
+    let result = info(process, request.status, &heap, &request.items);
+    if let Err((heap, _)) = request.reply.send((heap, result)) {
+        // the caller exited in the meantime, so nothing points into the reply
+        unsafe { heap.free() }
+    }
+}
+
+/// Answers right away for the calling process itself. Any other process is asked through a
+/// signal, and the caller waits for the reply.
+fn process_info(process: &RcProcess, target: Option<RcProcess>, items: Items) -> bif::Result {
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(atom!(UNDEFINED)),
     };
 
-    if let Some(proc) = proc {
-        match Cons::cast_from(&args[1]) {
-            Ok(cons) => {
-                let heap = &process.context_mut().heap;
-                cons.iter()
-                    .map(|val| process_info_aux(vm, &proc, *val, true))
-                    .fold_results(Term::nil(), |acc, val| cons!(heap, val, acc))
+    if target.pid == process.pid {
+        let heap = &process.context_mut().heap;
+        return info(process, atom!(RUNNING), heap, &items);
+    }
+
+    let status = if target.is_suspended() {
+        atom!(SUSPENDED)
+    } else if target.waiting_for_message.load(Ordering::Relaxed) {
+        atom!(WAITING)
+    } else {
+        atom!(RUNNABLE)
+    };
+    let (reply, receiver) = oneshot::channel();
+    target.send_signal(Signal::ProcessInfo {
+        from: process.pid,
+        request: Request {
+            items,
+            status,
+            reply,
+        },
+    });
+    blocking::wait(process, receiver, |process, reply| match reply {
+        Some((heap, result)) => {
+            process.context_mut().heap.absorb(heap);
+            result
+        }
+        None => Ok(atom!(UNDEFINED)),
+    })
+}
+
+pub fn process_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let proc = lookup_process(vm, process, args[0])?;
+    process_info(process, proc, Items::All)
+}
+
+pub fn process_info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    // args are pid, `[item, .. ]` or just `item`.
+    // response is `[tup,..]` or just `tup`
+    let proc = lookup_process(vm, process, args[0])?;
+
+    if args[1].is_nil() {
+        return Ok(proc.map_or(atom!(UNDEFINED), |_| Term::nil()));
+    }
+
+    match Cons::cast_from(&args[1]) {
+        Ok(cons) => {
+            // validate the whole list before looking at the process
+            if cons.iter().any(|val| !val.is_atom()) {
+                return Err(badarg!());
             }
-            _ => process_info_aux(vm, &proc, args[1], false),
+            process_info(process, proc, Items::List(cons.iter().copied().collect()))
+        }
+        _ => {
+            if !args[1].is_atom() {
+                return Err(badarg!());
+            }
+            process_info(process, proc, Items::Item(args[1]))
         }
-    } else {
-        return Ok(atom!(UNDEFINED));
     }
 }
 
@@ -238,8 +632,51 @@ const ENDIAN: Atom = atom::LITTLE;
 #[cfg(target_endian = "big")]
 const ENDIAN: Atom = atom::BIG;
 
+fn system_info_memory(vm: &vm::Machine) -> Vec<(Atom, usize)> {
+    let processes = vm
+        .process_table
+        .lock()
+        .iter()
+        .map(|process| process_memory(&process))
+        .sum();
+    let atom_count = atom::count();
+
+    vec![
+        (atom::PROCESSES, processes),
+        (atom::ATOM_SPACE, atom::space()),
+        (
+            atom::ATOM_TABLE,
+            atom_count * std::mem::size_of::<(&str, u32)>(),
+        ),
+    ]
+}
+
+/// Textual dump of the runtime state, akin to `erl_crash.dump` sections.
+fn system_info_text(vm: &vm::Machine) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    writeln!(out, "=memory").unwrap();
+    let memory = system_info_memory(vm);
+    let total: usize = memory.iter().map(|(_, size)| size).sum();
+    writeln!(out, "total: {}", total).unwrap();
+    for (name, size) in memory {
+        writeln!(out, "{}: {}", name.to_str().unwrap(), size).unwrap();
+    }
+    writeln!(out, "=hash_table:atom_tab").unwrap();
+    writeln!(out, "size: {}", atom::limit()).unwrap();
+    writeln!(out, "objs: {}", atom::count()).unwrap();
+    writeln!(out, "=index_table:process_table").unwrap();
+    writeln!(out, "size: {}", vm.process_table.lock().limit()).unwrap();
+    writeln!(out, "entries: {}", vm.process_table.lock().len()).unwrap();
+    writeln!(out, "=index_table:port_table").unwrap();
+    writeln!(out, "entries: {}", vm.port_table.read().len()).unwrap();
+    writeln!(out, "=ets").unwrap();
+    writeln!(out, "tables: {}", vm.ets_tables.len()).unwrap();
+    out
+}
+
 pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use std::sync::atomic::Ordering;
     let heap = &process.context_mut().heap;
 
     match args[0].into_variant() {
@@ -261,6 +698,58 @@ pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bi
         Variant::Atom(atom::ENDIAN) => {
             Ok(Term::atom(ENDIAN))
         }
+        Variant::Atom(atom::PROCESS_COUNT) => {
+            Ok(Term::uint64(heap, vm.process_table.lock().len() as u64))
+        }
+        Variant::Atom(atom::PROCESS_LIMIT) => {
+            Ok(Term::uint64(heap, vm.process_table.lock().limit() as u64))
+        }
+        Variant::Atom(atom::ATOM_COUNT) => Ok(Term::uint64(heap, atom::count() as u64)),
+        Variant::Atom(atom::ATOM_LIMIT) => Ok(Term::uint64(heap, atom::limit() as u64)),
+        Variant::Atom(atom::PORT_COUNT) => {
+            Ok(Term::uint64(heap, vm.port_table.read().len() as u64))
+        }
+        Variant::Atom(atom::ETS_COUNT) => {
+            Ok(Term::uint64(heap, vm.ets_tables.len() as u64))
+        }
+        Variant::Atom(atom::SCHEDULERS) => Ok(Term::uint64(heap, vm.schedulers as u64)),
+        // The process pool can't be resized at runtime, so every scheduler is always online.
+        Variant::Atom(atom::SCHEDULERS_ONLINE) => Ok(Term::uint64(heap, vm.schedulers as u64)),
+        Variant::Atom(atom::MULTI_SCHEDULING) => Ok(multi_scheduling(vm)),
+        Variant::Atom(atom::LOGICAL_PROCESSORS)
+        | Variant::Atom(atom::LOGICAL_PROCESSORS_AVAILABLE)
+        | Variant::Atom(atom::LOGICAL_PROCESSORS_ONLINE) => {
+            Ok(Term::uint64(heap, vm::logical_processors() as u64))
+        }
+        // I/O work runs on the utility runtime, which is sized like the process pool.
+        Variant::Atom(atom::THREAD_POOL_SIZE) => Ok(Term::uint64(heap, vm.schedulers as u64)),
+        Variant::Atom(atom::WORDSIZE) => Ok(Term::uint(heap, WORD_SIZE as u32)),
+        Variant::Atom(atom::BUILD_TYPE) => {
+            if cfg!(debug_assertions) {
+                Ok(atom!(DEBUG))
+            } else {
+                Ok(atom!(OPT))
+            }
+        }
+        Variant::Atom(atom::BACKTRACE_DEPTH) => {
+            Ok(Term::uint64(heap, vm.backtrace_depth.load(Ordering::Relaxed) as u64))
+        }
+        Variant::Atom(atom::FULLSWEEP_AFTER) => Ok(tup2!(
+            heap,
+            atom!(FULLSWEEP_AFTER),
+            Term::uint(heap, FULLSWEEP_AFTER)
+        )),
+        Variant::Atom(atom::ALLOCATED_AREAS) => Ok(system_info_memory(vm)
+            .into_iter()
+            .rev()
+            .fold(Term::nil(), |acc, (name, size)| {
+                let size = Term::uint64(heap, size as u64);
+                cons!(heap, tup2!(heap, Term::atom(name), size), acc)
+            })),
+        Variant::Atom(atom::INFO) => Ok(Term::binary(
+            heap,
+            Binary::from(system_info_text(vm).into_bytes()),
+        )),
         // Variant::Atom(atom::START_TIME) => {
         //     Ok(Term::int(vm.start_time))
         // }
@@ -268,19 +757,42 @@ pub fn system_info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bi
         Variant::Pointer(..) => {
             if let Ok(tup) = value::Tuple::cast_from(&args[0]) {
                match tup[0].into_variant() {
-                   Variant::Atom(atom::PURIFY) => return Err(badarg!()),
-                   _ => unimplemented!("system_info for {}", args[0])
+                   Variant::Atom(atom::PURIFY) => Err(badarg!()),
+                   // {wordsize, internal | external}
+                   Variant::Atom(atom::WORDSIZE) if tup.len() == 2 => match tup[1].into_variant() {
+                       Variant::Atom(atom::INTERNAL) | Variant::Atom(atom::EXTERNAL) => {
+                           Ok(Term::uint(heap, WORD_SIZE as u32))
+                       }
+                       _ => Err(badarg!()),
+                   },
+                   _ => Err(badarg!()),
                }
             } else {
-                unimplemented!("system_info for {}", args[0])
+                Err(badarg!())
             }
         }
-        _ => unimplemented!("system_info for {}", args[0]),
+        _ => Err(badarg!()),
+    }
+}
+
+fn to_usize(term: Term) -> Result<usize, Exception> {
+    match term.into_variant() {
+        Variant::Integer(i) if i >= 0 => Ok(i as usize),
+        _ => Err(badarg!()),
     }
 }
 
-pub fn system_flag_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    use std::sync::atomic::Ordering;
+fn multi_scheduling(vm: &vm::Machine) -> Term {
+    if vm.schedulers == 1 {
+        atom!(DISABLED)
+    } else {
+        atom!(ENABLED)
+    }
+}
+
+pub fn system_flag_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+
     match args[0].into_variant() {
         Variant::Atom(atom::SYSTEM_LOGGER) => {
             let pid = match args[1].into_variant() {
@@ -291,7 +803,34 @@ pub fn system_flag_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> b
             let old_pid = vm.system_logger.swap(pid as usize, Ordering::Relaxed);
             Ok(Term::pid(old_pid as u32)) // TODO: unsafe
         }
-        _ => unimplemented!(),
+        // The process pool can't be resized or blocked at runtime: unblocking is a no-op and
+        // blocking isn't supported.
+        Variant::Atom(atom::MULTI_SCHEDULING) => match args[1].into_variant() {
+            Variant::Atom(atom::UNBLOCK) | Variant::Atom(atom::UNBLOCK_NORMAL) => {
+                Ok(multi_scheduling(vm))
+            }
+            Variant::Atom(atom::BLOCK) | Variant::Atom(atom::BLOCK_NORMAL)
+                if vm.schedulers == 1 =>
+            {
+                Ok(atom!(DISABLED))
+            }
+            _ => Err(badarg!()),
+        },
+        Variant::Atom(atom::SCHEDULERS_ONLINE) => {
+            if to_usize(args[1])? != vm.schedulers {
+                return Err(badarg!());
+            }
+            Ok(Term::uint64(heap, vm.schedulers as u64))
+        }
+        Variant::Atom(atom::BACKTRACE_DEPTH) => {
+            let depth = std::cmp::min(
+                to_usize(args[1])?,
+                crate::exception::MAX_BACKTRACE_SIZE as usize,
+            );
+            let old = vm.backtrace_depth.swap(depth, Ordering::Relaxed);
+            Ok(Term::uint64(heap, old as u64))
+        }
+        _ => Err(badarg!()),
     }
 }
 
@@ -324,3 +863,159 @@ pub fn group_leader_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) ->
         Err(badarg!())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use crate::process;
+    use crate::value::Tuple;
+
+    /// Runs `process_info(self(), Item)` and unwraps the `{Item, Value}` tuple.
+    fn info(vm: &vm::Machine, process: &RcProcess, item: Term) -> Term {
+        let res = process_info_2(vm, process, &[Term::pid(process.pid), item]).unwrap();
+        let tuple = Tuple::cast_from(&res).unwrap();
+        assert_eq!(tuple[0], item);
+        tuple[1]
+    }
+
+    fn tuples(list: Term) -> Vec<Vec<Term>> {
+        Cons::cast_from(&list)
+            .unwrap()
+            .iter()
+            .map(|item| Tuple::cast_from(item).unwrap().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_binary() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        assert!(info(&vm, &process, atom!(BINARY)).is_nil());
+
+        let heap = &process.context_mut().heap;
+        let bin = Term::binary(heap, Binary::from(vec![0; 100]));
+        let sub = Term::subbinary(
+            heap,
+            SubBinary::new(
+                bin.get_boxed_value::<RcBinary>().unwrap().clone(),
+                80,
+                0,
+                false,
+            ),
+        );
+        let dictionary = &mut process.local_data_mut().dictionary;
+        dictionary.insert(Term::int(1), bin);
+        // a sub binary of the same binary shouldn't be reported twice
+        dictionary.insert(Term::int(2), tup2!(heap, sub, bin));
+
+        let binaries = tuples(info(&vm, &process, atom!(BINARY)));
+        assert_eq!(binaries.len(), 1);
+        assert_eq!(binaries[0][1], Term::uint64(heap, 100));
+        // the term on the heap and the sub binary both hold a reference
+        assert_eq!(binaries[0][2], Term::uint64(heap, 2));
+    }
+
+    #[test]
+    fn test_magic_ref() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        assert!(info(&vm, &process, atom!(MAGIC_REF)).is_nil());
+
+        let z = zlib::bif::open_nif_0(&vm, &process, &[]).unwrap();
+        process.context_mut().stack.push(z);
+
+        let refs = tuples(info(&vm, &process, atom!(MAGIC_REF)));
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0][2], Term::int(1));
+    }
+
+    #[test]
+    fn test_location_outside_code() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        assert_eq!(
+            info(&vm, &process, atom!(CURRENT_FUNCTION)),
+            atom!(UNDEFINED)
+        );
+        assert_eq!(
+            info(&vm, &process, atom!(CURRENT_LOCATION)),
+            atom!(UNDEFINED)
+        );
+        assert!(info(&vm, &process, atom!(CURRENT_STACKTRACE)).is_nil());
+    }
+
+    #[test]
+    fn test_item_list() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+        let items = cons!(
+            heap,
+            atom!(MESSAGE_QUEUE_LEN),
+            cons!(heap, atom!(REGISTERED_NAME), Term::nil())
+        );
+        let res = tuples(process_info_2(&vm, &process, &[Term::pid(process.pid), items]).unwrap());
+        assert_eq!(res[0], vec![atom!(MESSAGE_QUEUE_LEN), Term::int(0)]);
+        // registered_name is always wrapped when asked for in a list
+        assert_eq!(res[1], vec![atom!(REGISTERED_NAME), Term::nil()]);
+
+        let bad = cons!(heap, Term::int(1), Term::nil());
+        assert!(process_info_2(&vm, &process, &[Term::pid(process.pid), bad]).is_err());
+    }
+
+    #[test]
+    fn test_other_process() {
+        use crate::exception::Reason;
+
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let target = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &target.context_mut().heap;
+        target
+            .local_data_mut()
+            .dictionary
+            .insert(atom!(OK), tup2!(heap, Term::int(1), Term::int(2)));
+
+        let heap = &process.context_mut().heap;
+        let items = cons!(
+            heap,
+            atom!(STATUS),
+            cons!(heap, atom!(DICTIONARY), Term::nil())
+        );
+        let args = [Term::pid(target.pid), items];
+        let res = process_info_2(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+
+        // the target answers once it handles its signals
+        target.process_incoming().unwrap();
+        let job = process.local_data_mut().blocking.take().unwrap();
+        let res = tuples(futures::executor::block_on(job)(&process).unwrap());
+        assert_eq!(res[0], vec![atom!(STATUS), atom!(RUNNABLE)]);
+        let dictionary = tuples(res[1][1]);
+        assert_eq!(dictionary[0][0], atom!(OK));
+        // the value was copied, not shared with the target's heap
+        let (original, copy) = (target.local_data().dictionary[&atom!(OK)], dictionary[0][1]);
+        assert_eq!(copy, original);
+        let address = |term: Term| match term.into_variant() {
+            Variant::Pointer(ptr) => ptr as usize,
+            _ => unreachable!(),
+        };
+        assert_ne!(address(copy), address(original));
+
+        // a process that exits before getting to the request drops it
+        let res = process_info_2(&vm, &process, &[Term::pid(target.pid), atom!(STATUS)]);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        drop(target.local_data_mut().signal_queue.receive());
+        let job = process.local_data_mut().blocking.take().unwrap();
+        assert_eq!(
+            futures::executor::block_on(job)(&process),
+            Ok(atom!(UNDEFINED))
+        );
+    }
+}
@@ -1,35 +1,80 @@
 use crate::atom;
-use crate::bitstring::{Binary, RcBinary};
+use crate::bitstring::{Binary, RcBinary, SubBinary};
 use crate::exception::{Exception, Reason};
-use crate::immix::Heap;
 use crate::process::RcProcess;
 use crate::value::{self, CastFrom, CastFromMut, Cons, Term, Variant};
 use crate::vm;
-use std::fs;
 use std::sync::atomic::AtomicU32;
 
-// it uses a queue (vecdeq? or just a slice of iovecs) of iovecs.
-// vectored reads / writes on copying_read
-// -- it uses an accumulator (ioqueue) just for the small writes it seems
-// ACCUMULATOR_SIZE = 2 << 10 (2048 bytes)
-// if accumulated_bytes + iovec >= ACCUMULATOR_SIZE
-//   and iovec < ACCUMULATOR_SIZE / 2 --> then enqueue?
-//
-//   --------
-//
-//  buffer into accumulator up to a certain size, at which point accumulator is appended to queue
-//  and replaced by a new empty one
-//
-//  VecDeque<Binary> + cursor for ioqueue --> TODO: should hold iovecs too
-//  Vec<u8> for accumulator
-//
-//  TODO: a substring is technically a BytesMut into a string?
-
-type IOQueue = BufDeque<Cursor<RcBinary>>;
+// The queue holds iovecs: slices of the binaries that were written, so reads can hand out
+// sub-binaries without copying. Small writes are combined in an accumulator instead, which is
+// enqueued as a binary of its own once it fills up or the buffer is read from.
+
+/// Writes at least this big are always enqueued as is.
+const ACCUMULATOR_SIZE: usize = 2 << 10;
+
+type IOQueue = BufDeque<Cursor<Slice>>;
+
+/// A byte range of a binary.
+#[derive(Clone, Debug)]
+pub struct Slice {
+    binary: RcBinary,
+    offset: usize,
+    len: usize,
+}
+
+impl Slice {
+    /// Returns the slice of a binary term, bitstrings can't be queued.
+    fn from_term(term: Term) -> Option<Self> {
+        match term.get_boxed_header() {
+            Ok(value::BOXED_BINARY) => {
+                let binary = term.get_boxed_value::<RcBinary>().unwrap();
+                Some(Slice {
+                    binary: binary.clone(),
+                    offset: 0,
+                    len: binary.data.len(),
+                })
+            }
+            Ok(value::BOXED_SUBBINARY) => {
+                let sub = term.get_boxed_value::<SubBinary>().unwrap();
+                if sub.bit_offset != 0 || sub.bitsize != 0 {
+                    return None;
+                }
+                Some(Slice {
+                    binary: sub.original.clone(),
+                    offset: sub.offset,
+                    len: sub.size,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Slice {
+    fn as_ref(&self) -> &[u8] {
+        &self.binary.data[self.offset..self.offset + self.len]
+    }
+}
+
+impl Cursor<Slice> {
+    /// A sub-binary of the next `len` unread bytes.
+    fn sub_binary(&self, len: usize) -> SubBinary {
+        debug_assert!(len <= self.remaining());
+        SubBinary {
+            original: self.bytes.binary.clone(),
+            size: len,
+            offset: self.bytes.offset + self.pos,
+            bit_offset: 0,
+            bitsize: 0,
+            is_writable: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Buffer {
-    // accumulator: Vec<u8>,
+    accumulator: Vec<u8>,
     ioqueue: IOQueue,
 
     external_lock: AtomicU32,
@@ -38,36 +83,106 @@ pub struct Buffer {
 impl Buffer {
     pub fn new() -> Self {
         Self {
+            accumulator: Vec::new(),
             ioqueue: IOQueue::new(),
             external_lock: AtomicU32::new(0),
         }
     }
 
     pub fn size(&self) -> usize {
-        self.ioqueue.remaining()
+        self.ioqueue.remaining() + self.accumulator.len()
     }
 
-    pub fn peek_head(&self) -> Result<Term, Exception> {
-        unimplemented!()
-    }
-
-    pub fn copying_read(&self) -> usize {
-        unimplemented!()
+    /// Moves the accumulated small writes to the end of the queue.
+    fn enqueue_accumulator(&mut self) {
+        if self.accumulator.is_empty() {
+            return;
+        }
+        let data = std::mem::replace(&mut self.accumulator, Vec::new());
+        let len = data.len();
+        self.ioqueue.buffer(Cursor::new(Slice {
+            binary: RcBinary::new(Binary::from(data)),
+            offset: 0,
+            len,
+        }));
+    }
+
+    /// Returns the first queued binary, without removing it.
+    pub fn peek_head(&mut self) -> Option<SubBinary> {
+        self.enqueue_accumulator();
+        self.ioqueue
+            .bufs
+            .front()
+            .map(|head| head.sub_binary(head.remaining()))
+    }
+
+    /// Removes `size` bytes from the front of the buffer and returns a copy of them.
+    pub fn copying_read(&mut self, size: usize) -> Vec<u8> {
+        debug_assert!(size <= self.size());
+        self.enqueue_accumulator();
+        let mut data = vec![0; size];
+        self.ioqueue.copy_to_slice(&mut data);
+        data
+    }
+
+    /// Removes `size` bytes from the front of the buffer, returning them as slices of the queued
+    /// binaries.
+    pub fn read_iovec(&mut self, mut size: usize) -> Vec<SubBinary> {
+        debug_assert!(size <= self.size());
+        self.enqueue_accumulator();
+        let mut iovec = Vec::new();
+        while size > 0 {
+            let head = &self.ioqueue.bufs[0];
+            let len = std::cmp::min(size, head.remaining());
+            iovec.push(head.sub_binary(len));
+            self.ioqueue.advance(len);
+            size -= len;
+        }
+        iovec
     }
 
-    pub fn write(&mut self, iovec: RcBinary) {
-        // TODO: combine_small_writes
-        // TODO: enqueue accumulator if needed
+    pub fn write(&mut self, iovec: Slice) {
+        if iovec.len == 0 {
+            return;
+        }
+        // combine small writes
+        if iovec.len < ACCUMULATOR_SIZE / 2 {
+            if self.accumulator.len() + iovec.len >= ACCUMULATOR_SIZE {
+                self.enqueue_accumulator();
+            }
+            self.accumulator.extend_from_slice(iovec.as_ref());
+            return;
+        }
+        self.enqueue_accumulator();
         self.ioqueue.buffer(Cursor::new(iovec))
-        // TODO: if tail isn't nil, write it too
     }
 
     pub fn skip(&mut self, block_size: usize) {
+        debug_assert!(block_size <= self.size());
+        self.enqueue_accumulator();
         self.ioqueue.advance(block_size)
     }
 
-    pub fn find_byte_index(&self, block_size: usize) -> Option<usize> {
-        unimplemented!()
+    /// Empties the buffer.
+    pub fn wipe(&mut self) {
+        self.accumulator.clear();
+        self.ioqueue.bufs.clear();
+    }
+
+    /// Returns the offset of the first occurrence of `byte`.
+    pub fn find_byte_index(&self, byte: u8) -> Option<usize> {
+        let queued = self.ioqueue.remaining();
+        let mut offset = 0;
+        for buf in &self.ioqueue.bufs {
+            if let Some(i) = buf.bytes().iter().position(|b| *b == byte) {
+                return Some(offset + i);
+            }
+            offset += buf.remaining();
+        }
+        self.accumulator
+            .iter()
+            .position(|b| *b == byte)
+            .map(|i| queued + i)
     }
 
     pub fn try_lock(&self) -> bool {
@@ -119,7 +234,14 @@ pub mod bif {
     use super::*;
     use crate::bif::Result;
 
-    pub fn new_0(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    fn to_size(term: Term) -> std::result::Result<usize, Exception> {
+        match term.into_variant() {
+            Variant::Integer(i) if i >= 0 => Ok(i as usize),
+            _ => Err(badarg!()),
+        }
+    }
+
+    pub fn new_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
         let heap = &process.context_mut().heap;
         let buf = Buffer::new();
         Ok(Term::buffer(heap, buf))
@@ -132,40 +254,64 @@ pub mod bif {
     }
 
     pub fn peek_head_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-        let buf = Buffer::cast_from(&args[0])?;
-        unimplemented!()
+        let heap = &process.context_mut().heap;
+        let buf = Buffer::cast_from_mut(&args[0])?;
+        match buf.peek_head() {
+            Some(head) => Ok(Term::subbinary(heap, head)),
+            None => Err(badarg!()),
+        }
     }
 
     pub fn copying_read_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-        let buf = Buffer::cast_from(&args[0])?;
-        unimplemented!()
+        let heap = &process.context_mut().heap;
+        let buf = Buffer::cast_from_mut(&args[0])?;
+        let size = to_size(args[1])?;
+        if buf.size() < size {
+            return Err(badarg!());
+        }
+        Ok(Term::binary(heap, Binary::from(buf.copying_read(size))))
     }
 
-    pub fn write_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-        let buf = Buffer::cast_from(&args[0])?;
-        // parse into iovec
-        // let iovec = Vec::new();
-        // let iter = args[1];
-        // while let Ok(Cons { head, tail }) = Cons::cast_from(&iter) {
-        //     if !head.is_binary() {
-        //         panic!();
-        //     }
-        //     buf.push(*head);
-        // }
-        // if tail.is_binary {}
-        println!("write/2 {}\r", args[1]);
-        // buf.write(iovec);
-        // unimplemented!()
+    pub fn read_iovec_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+        let buf = Buffer::cast_from_mut(&args[0])?;
+        let size = to_size(args[1])?;
+        if buf.size() < size {
+            return Err(badarg!());
+        }
+        let iovec = buf.read_iovec(size);
+        Ok(Cons::from_iter(
+            iovec.into_iter().map(|sub| Term::subbinary(heap, sub)),
+            heap,
+        ))
+    }
+
+    /// Enqueues a list of binaries.
+    pub fn write_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> Result {
+        let buf = Buffer::cast_from_mut(&args[0])?;
+
+        // validate everything first so a bad iovec doesn't leave a partial write behind
+        let mut iovec = Vec::new();
+        let mut iter = args[1];
+        while let Ok(cons) = Cons::cast_from(&iter) {
+            iovec.push(Slice::from_term(cons.head).ok_or_else(|| badarg!())?);
+            iter = cons.tail;
+        }
+        if !iter.is_nil() {
+            // a binary tail is written last
+            iovec.push(Slice::from_term(iter).ok_or_else(|| badarg!())?);
+        }
+
+        for slice in iovec {
+            buf.write(slice);
+        }
         Ok(atom!(OK))
     }
 
     // a skip 0 makes no sense
     pub fn skip_2(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> Result {
         let buf = Buffer::cast_from_mut(&args[0])?;
-        let cnt = match args[1].into_variant() {
-            Variant::Integer(i) if i >= 0 => i as usize,
-            _ => return Err(badarg!()),
-        };
+        let cnt = to_size(args[1])?;
         if buf.size() < cnt {
             return Err(badarg!());
         }
@@ -173,12 +319,27 @@ pub mod bif {
         Ok(atom!(OK))
     }
 
+    pub fn wipe_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> Result {
+        let buf = Buffer::cast_from_mut(&args[0])?;
+        buf.wipe();
+        Ok(atom!(OK))
+    }
+
+    /// Returns `{ok, Index}` of the first occurrence of a byte, or `not_found`.
     pub fn find_byte_index_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
         let buf = Buffer::cast_from(&args[0])?;
-        unimplemented!()
+        let byte = match args[1].into_variant() {
+            Variant::Integer(i @ 0..=255) => i as u8,
+            _ => return Err(badarg!()),
+        };
+        match buf.find_byte_index(byte) {
+            Some(i) => Ok(tup2!(heap, atom!(OK), Term::uint64(heap, i as u64))),
+            None => Ok(atom!(NOT_FOUND)),
+        }
     }
 
-    pub fn try_lock_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    pub fn try_lock_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> Result {
         let buf = Buffer::cast_from(&args[0])?;
         if !buf.try_lock() {
             return Ok(atom!(BUSY));
@@ -186,7 +347,7 @@ pub mod bif {
         Ok(atom!(ACQUIRED))
     }
 
-    pub fn unlock_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    pub fn unlock_1(_vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> Result {
         let buf = Buffer::cast_from(&args[0])?;
         if !buf.unlock() {
             return Err(Exception::with_value(
@@ -317,3 +478,52 @@ impl<T: Buf> Buf for BufDeque<T> {
         vecs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(data: &[u8]) -> Slice {
+        Slice {
+            binary: RcBinary::new(Binary::from(data)),
+            offset: 0,
+            len: data.len(),
+        }
+    }
+
+    fn bytes(sub: &SubBinary) -> &[u8] {
+        &sub.original.data[sub.offset..sub.offset + sub.size]
+    }
+
+    #[test]
+    fn test_buffer() {
+        let mut buf = Buffer::new();
+        let large = slice(&[b'x'; ACCUMULATOR_SIZE]);
+        buf.write(slice(b"hello "));
+        buf.write(slice(b"world\n"));
+        buf.write(large.clone());
+        buf.write(slice(b"tail"));
+        assert_eq!(buf.size(), 12 + ACCUMULATOR_SIZE + 4);
+        assert_eq!(buf.find_byte_index(b'\n'), Some(11));
+        assert_eq!(buf.find_byte_index(b'l'), Some(2));
+        assert_eq!(buf.find_byte_index(b't'), Some(12 + ACCUMULATOR_SIZE));
+        assert_eq!(buf.find_byte_index(b'?'), None);
+
+        // small writes were combined
+        assert_eq!(bytes(&buf.peek_head().unwrap()), b"hello world\n");
+        assert_eq!(buf.copying_read(3), b"hel");
+        buf.skip(3);
+
+        let iovec = buf.read_iovec(8);
+        assert_eq!(iovec.len(), 2);
+        assert_eq!(bytes(&iovec[0]), b"world\n");
+        assert_eq!(bytes(&iovec[1]), b"xx");
+        // large writes aren't copied
+        assert!(RcBinary::ptr_eq(&iovec[1].original, &large.binary));
+
+        assert_eq!(buf.size(), ACCUMULATOR_SIZE - 2 + 4);
+        buf.wipe();
+        assert_eq!(buf.size(), 0);
+        assert!(buf.peek_head().is_none());
+    }
+}
@@ -1,16 +1,40 @@
 use crate::atom;
-use crate::bif;
+use crate::bif::{self, blocking};
 use crate::bitstring::Binary;
-use crate::exception::{Exception, Reason};
+use crate::exception::Exception;
 use crate::immix::Heap;
+use crate::posix;
 use crate::process::RcProcess;
-use crate::value::{self, CastFrom, CastFromMut, Cons, Term, Variant};
+use crate::value::{self, CastFrom, Cons, Term, Variant};
 use crate::vm;
-use std::fs::{self, File};
+use num_traits::ToPrimitive;
+use parking_lot::Mutex;
+use std::ffi::CString;
+use std::fs;
 use std::io::prelude::*;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::sync::Arc;
 
-impl CastFrom<Term> for std::fs::File {
+mod handle;
+pub use handle::Handle;
+
+/// A file handle term. Copies of the term share the handle, and can be sent to other processes,
+/// so every call locks it.
+#[derive(Clone)]
+pub struct RawFile(Arc<Mutex<Handle>>);
+
+impl RawFile {
+    pub fn new(handle: Handle) -> Self {
+        RawFile(Arc::new(Mutex::new(handle)))
+    }
+
+    /// The number of terms sharing the handle.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+}
+
+impl CastFrom<Term> for RawFile {
     type Error = value::WrongBoxError;
 
     #[inline]
@@ -26,41 +50,89 @@ impl CastFrom<Term> for std::fs::File {
     }
 }
 
-impl CastFromMut<Term> for std::fs::File {
-    type Error = value::WrongBoxError;
+/// Offsets, lengths and times don't have to fit a small integer.
+fn to_i64(term: Term) -> Option<i64> {
+    match term.into_number() {
+        Ok(value::Num::Integer(i)) => Some(i64::from(i)),
+        Ok(value::Num::Bignum(value)) => value.to_i64(),
+        _ => None,
+    }
+}
 
-    #[inline]
-    fn cast_from_mut(value: &Term) -> Result<&mut Self, value::WrongBoxError> {
-        if let value::Variant::Pointer(ptr) = value.into_variant() {
-            unsafe {
-                if *ptr == value::BOXED_FILE {
-                    return Ok(&mut (*(ptr as *const value::Boxed<Self>
-                        as *mut value::Boxed<Self>))
-                        .value);
-                }
-            }
-        }
-        Err(value::WrongBoxError)
+fn to_offset(term: Term) -> Result<u64, Exception> {
+    match to_i64(term) {
+        Some(i) if i >= 0 => Ok(i as u64),
+        _ => Err(badarg!()),
     }
 }
 
-fn error_to_tuple(heap: &Heap, error: std::io::Error) -> Term {
-    use std::io::ErrorKind;
-    let kind = match error.kind() {
-        ErrorKind::NotFound => atom!(ENOENT),
-        ErrorKind::Other => {
-            let errno = error.raw_os_error().unwrap();
-            match errno {
-                20 => atom!(ENOTDIR),
-                _ => unimplemented!("error_to_tuple for {:?}", error),
-            }
+fn to_cstring(path: Term) -> Result<CString, Exception> {
+    let path = path.to_bytes().ok_or_else(|| badarg!())?;
+    CString::new(path).map_err(|_| badarg!())
+}
+
+/// Converts the return value of a libc call, errno has to be read on the same thread.
+fn check_libc(ret: libc::c_int) -> std::io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+fn ok_or_error(heap: &Heap, res: std::io::Result<()>) -> bif::Result {
+    match res {
+        Ok(()) => Ok(atom!(OK)),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
+    }
+}
+
+/// Runs a filesystem call on the runtime, returning `ok` or an error tuple.
+fn run_io<F>(vm: &vm::Machine, process: &RcProcess, op: F) -> bif::Result
+where
+    F: FnOnce() -> std::io::Result<()> + Send + 'static,
+{
+    blocking::run(vm, process, op, |process, res| {
+        ok_or_error(&process.context_mut().heap, res)
+    })
+}
+
+/// Runs `op` on the file handle in `term`, then converts its output with `then`. Ram files
+/// never block, so they're used in place, everything else goes through the blocking pool. The
+/// handle is only locked on the scheduler thread if it's free: another process may hold it for
+/// as long as a slow read takes.
+fn with_handle<T, F, C>(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    term: Term,
+    op: F,
+    then: C,
+) -> bif::Result
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Handle) -> T + Send + 'static,
+    C: FnOnce(&Heap, T) -> bif::Result + Send + 'static,
+{
+    let file = RawFile::cast_from(&term)?.clone();
+    if let Some(mut handle) = file.0.try_lock() {
+        if let Handle::Ram { .. } = *handle {
+            let value = op(&mut handle);
+            return then(&process.context_mut().heap, value);
         }
-        _ => unimplemented!("error_to_tuple for {:?}", error),
-    };
-    tup2!(heap, atom!(ERROR), kind)
+    }
+
+    blocking::run(
+        vm,
+        process,
+        move || op(&mut file.0.lock()),
+        |process, value| then(&process.context_mut().heap, value),
+    )
 }
-pub fn get_device_cwd_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    unimplemented!()
+
+/// Drive letters only exist on Windows.
+pub fn get_device_cwd_nif_1(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    Ok(tup2!(heap, atom!(ERROR), atom!(ENOTSUP)))
 }
 
 pub fn get_cwd_nif_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
@@ -73,9 +145,8 @@ pub fn get_cwd_nif_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) ->
 
             Ok(tup2!(heap, atom!(OK), Term::binary(heap, bin)))
         }
-        _ => Err(Exception::new(Reason::EXC_INTERNAL_ERROR)),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
     }
-    // TODO: make a function that converts io::Error to a tuple
 }
 
 pub fn set_cwd_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
@@ -84,35 +155,76 @@ pub fn set_cwd_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> b
 
     match std::env::set_current_dir(path) {
         Ok(()) => Ok(atom!(OK)),
-        Err(err) => Ok(error_to_tuple(heap, err)),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
     }
 }
 
 /// Reads an entire file into \c result, stopping after \c size bytes or EOF. It will read until
 /// EOF if size is 0.
-pub fn read_file_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+pub fn read_file_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // arg[0] = filename
-    let heap = &process.context_mut().heap;
-    let path = args[0].to_str().unwrap();
-
-    let bytes = match std::fs::read(path) {
-        Ok(bytes) => bytes,
-        Err(err) => return Ok(error_to_tuple(heap, err)),
-    };
-
-    Ok(tup2!(
-        heap,
-        atom!(OK),
-        Term::binary(heap, Binary::from(bytes))
-    ))
+    let path = args[0].to_str().unwrap().to_owned();
+
+    blocking::run(
+        vm,
+        process,
+        move || fs::read(path),
+        |process, res| {
+            let heap = &process.context_mut().heap;
+            match res {
+                Ok(bytes) => Ok(tup2!(
+                    heap,
+                    atom!(OK),
+                    Term::binary(heap, Binary::from(bytes))
+                )),
+                Err(err) => Ok(posix::error_tuple(heap, err)),
+            }
+        },
+    )
 }
 
+/// Reads an indirect payload: a 32-bit big endian size and offset at `offset`, followed by the
+/// payload they point to. Returns `{ok, {Size, Offset, Data}}`.
 pub fn ipread_s32bu_p32bu_nif_3(
-    _vm: &vm::Machine,
+    vm: &vm::Machine,
     process: &RcProcess,
     args: &[Term],
 ) -> bif::Result {
-    unimplemented!()
+    let offset = to_offset(args[1])?;
+    let max_size = to_offset(args[2])?;
+
+    // Ok(None) on EOF
+    let read = move |file: &mut Handle| -> std::io::Result<Option<(u32, u32, Vec<u8>)>> {
+        let mut header = [0; 8];
+        if file.read_at(&mut header, offset)? != 8 {
+            return Ok(None);
+        }
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let pointer = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if u64::from(size) > max_size {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let buffer = file.read_vec_at(u64::from(size), u64::from(pointer))?;
+        if buffer.len() != size as usize {
+            return Ok(None);
+        }
+        Ok(Some((size, pointer, buffer)))
+    };
+
+    with_handle(vm, process, args[0], read, |heap, res| match res {
+        Ok(Some((size, pointer, buffer))) => {
+            let res = tup3!(
+                heap,
+                Term::uint(heap, size),
+                Term::uint(heap, pointer),
+                Term::binary(heap, Binary::from(buffer))
+            );
+            Ok(tup2!(heap, atom!(OK), res))
+        }
+        Ok(None) => Ok(atom!(EOF)),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
+    })
 }
 
 // TODO: maybe we should pass around as OsString which is null terminated dunno
@@ -278,9 +390,7 @@ fn meta_to_tuple(heap: &Heap, meta: std::fs::Metadata) -> Term {
     )
 }
 
-pub fn read_info_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let heap = &process.context_mut().heap;
-
+pub fn read_info_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     assert!(args.len() == 2);
 
     let follow_links = match args[1].to_int() {
@@ -288,264 +398,455 @@ pub fn read_info_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
         None => return Err(badarg!()),
     };
 
-    let path = args[0].to_str().unwrap();
-
-    let meta = if follow_links {
-        std::fs::metadata(path)
-    } else {
-        std::fs::symlink_metadata(path)
-    };
+    let path = args[0].to_str().unwrap().to_owned();
 
-    // TODO map/and then?
-    let info = match meta {
-        Ok(meta) => meta,
-        Err(err) => return Ok(error_to_tuple(heap, err)),
+    let read_info = move || {
+        if follow_links {
+            fs::metadata(path)
+        } else {
+            fs::symlink_metadata(path)
+        }
     };
 
-    Ok(meta_to_tuple(heap, info))
+    blocking::run(vm, process, read_info, |process, meta| {
+        let heap = &process.context_mut().heap;
+        match meta {
+            Ok(meta) => Ok(meta_to_tuple(heap, meta)),
+            Err(err) => Ok(posix::error_tuple(heap, err)),
+        }
+    })
 }
 
-pub fn list_dir_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+pub fn list_dir_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::ffi::OsStrExt;
     // arg[0] = filename
-    let heap = &process.context_mut().heap;
-
-    let path = args[0].to_str().unwrap();
+    let path = args[0].to_str().unwrap().to_owned();
 
-    let res = match std::fs::read_dir(path) {
-        Ok(entries) => Cons::from_iter(
-            entries
-                .map(|entry| {
-                    Term::binary(
-                        heap,
-                        Binary::from(entry.unwrap().file_name().to_str().unwrap().as_bytes()),
-                    )
-                    // bitstring!(heap, entry.unwrap().file_name().to_str().unwrap()
-                })
-                .collect::<Vec<Term>>()
-                .into_iter(),
-            heap,
-        ),
-        Err(err) => return Ok(error_to_tuple(heap, err)),
+    let list_dir = move || {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()
     };
 
-    Ok(tup2!(heap, atom!(OK), res))
+    blocking::run(vm, process, list_dir, |process, names| {
+        let heap = &process.context_mut().heap;
+        match names {
+            Ok(names) => {
+                let res = Cons::from_iter(
+                    names
+                        .iter()
+                        .map(|name| Term::binary(heap, Binary::from(name.as_bytes()))),
+                    heap,
+                );
+                Ok(tup2!(heap, atom!(OK), res))
+            }
+            Err(err) => Ok(posix::error_tuple(heap, err)),
+        }
+    })
 }
 
-pub fn open_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+/// Opens a file. With `ram`, the first argument is the initial contents instead of a path.
+pub fn open_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
     let heap = &process.context_mut().heap;
 
-    // println!("opening.. {} opts: {}\r", args[0], args[1]);
-
-    let mut opts = OpenOptions::new();
+    let (mut read, mut write, mut append, mut exclusive) = (false, false, false, false);
+    let (mut sync, mut skip_type_check, mut ram, mut compressed) = (false, false, false, false);
     for value in Cons::cast_from(&args[1])?.iter() {
         match value.into_variant() {
-            Variant::Atom(atom::READ) => opts.read(true),
-            Variant::Atom(atom::WRITE) => opts.write(true).create(true),
-            Variant::Atom(atom::EXCLUSIVE) => unimplemented!(),
-            Variant::Atom(atom::APPEND) => opts.append(true),
-            Variant::Atom(atom::SYNC) => unimplemented!(),
-            Variant::Atom(atom::SKIP_TYPE_CHECK) => unimplemented!(),
-            // Modes like 'raw', 'ram', 'delayed_writes' etc are handled further up the chain.
-            _ => &mut opts,
+            Variant::Atom(atom::READ) => read = true,
+            Variant::Atom(atom::WRITE) => write = true,
+            Variant::Atom(atom::EXCLUSIVE) => exclusive = true,
+            Variant::Atom(atom::APPEND) => append = true,
+            Variant::Atom(atom::SYNC) => sync = true,
+            Variant::Atom(atom::SKIP_TYPE_CHECK) => skip_type_check = true,
+            Variant::Atom(atom::RAM) => ram = true,
+            Variant::Atom(atom::COMPRESSED) => compressed = true,
+            // Modes like 'raw', 'binary', 'delayed_write' etc are handled further up the chain.
+            _ => (),
         };
     }
 
-    // FIXME:
-    // if (modes & (EFILE_MODE_APPEND | EFILE_MODE_EXCLUSIVE)) {
-    //     /* 'append' and 'exclusive' are documented as "open for writing." */
-    //     modes |= EFILE_MODE_WRITE;
-    // } else if !(modes & EFILE_MODE_READ_WRITE) {
-    //     /* Defaulting to read if !(W|R) is undocumented, but specifically
-    //      * tested against in file_SUITE. */
-    //     modes |= EFILE_MODE_READ;
-    // }
-    let path = args[0].to_str().unwrap();
+    if append || exclusive {
+        // 'append' and 'exclusive' are documented as "open for writing."
+        write = true;
+    } else if !read && !write {
+        // Defaulting to read if !(W|R) is undocumented, but specifically tested against in
+        // file_SUITE.
+        read = true;
+    }
+    if compressed && read && write {
+        // gzip streams only go one way
+        return Ok(posix::error_tuple(
+            heap,
+            std::io::Error::from_raw_os_error(libc::EINVAL),
+        ));
+    }
 
-    let file = match opts.open(path) {
-        Ok(file) => file,
-        Err(err) => {
-            // println!("pid={} attempted open on {}, failed.", process.pid, args[0]);
-            return Ok(error_to_tuple(heap, err));
+    if ram {
+        let mut data = crate::bif::erlang::list_to_iodata(args[0])?;
+        if compressed {
+            if write {
+                return Ok(posix::error_tuple(
+                    heap,
+                    std::io::Error::from_raw_os_error(libc::EINVAL),
+                ));
+            }
+            data = match ram_uncompress(data) {
+                Ok(data) => data,
+                Err(err) => return Ok(posix::error_tuple(heap, err)),
+            };
         }
-    };
-    Ok(tup2!(heap, atom!(OK), Term::file(heap, file)))
-}
-
-pub fn close_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let file = File::cast_from_mut(&args[0])?;
-    unsafe { std::ptr::drop_in_place(file) }
-    // FIXME: ^
-    Ok(atom!(OK))
-}
+        let file = RawFile::new(Handle::ram(data, read, write, append));
+        return Ok(tup2!(heap, atom!(OK), Term::file(heap, file)));
+    }
 
-pub fn read_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let heap = &process.context_mut().heap;
-    let file = File::cast_from_mut(&args[0])?;
+    let mut opts = OpenOptions::new();
+    opts.read(read)
+        .write(write)
+        .append(append)
+        .create(write)
+        // write-only files are truncated, like O_WRONLY | O_CREAT | O_TRUNC
+        .truncate(write && !read && !append)
+        .create_new(exclusive);
+    if sync {
+        opts.custom_flags(libc::O_SYNC);
+    }
 
-    let size = match args[1].into_variant() {
-        Variant::Integer(i) => i as usize,
-        // TODO: bigint
-        _ => unimplemented!(),
-        // _ => return Err(badarg!()),
+    let path = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+    let open = move || {
+        let file = opts.open(path)?;
+        if !skip_type_check && file.metadata()?.is_dir() {
+            return Err(std::io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        match (compressed, write) {
+            (false, _) => Ok(Handle::File(file)),
+            (true, false) => Handle::gzip_reader(file),
+            (true, true) => Handle::gzip_writer(file),
+        }
     };
-
-    let mut buffer = vec![0; size];
-
-    // eprintln!("read_nif_2: fd: {:?} size: {}", file, size);
-
-    match file.read(&mut buffer) {
-        Ok(0) => Ok(atom!(EOF)),
-        Ok(n) => {
-            buffer.truncate(n);
-            Ok(tup2!(
+    blocking::run(vm, process, open, |process, res| {
+        let heap = &process.context_mut().heap;
+        match res {
+            Ok(handle) => Ok(tup2!(
                 heap,
                 atom!(OK),
-                Term::binary(heap, Binary::from(buffer))
-            ))
+                Term::file(heap, RawFile::new(handle))
+            )),
+            Err(err) => Ok(posix::error_tuple(heap, err)),
         }
-        Err(err) => Ok(error_to_tuple(heap, err)),
+    })
+}
+
+/// Decompresses the contents of a compressed ram file. Data without the gzip magic is used as
+/// is, like compressed regular files.
+fn ram_uncompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    use libflate::gzip;
+    if data.len() < 2 || data[..2] != [0x1f, 0x8b] {
+        return Ok(data);
     }
+    let mut res = Vec::new();
+    gzip::Decoder::new(std::io::Cursor::new(data))?.read_to_end(&mut res)?;
+    Ok(res)
 }
 
-pub fn write_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let heap = &process.context_mut().heap;
-    let file = File::cast_from_mut(&args[0])?;
+pub fn close_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    with_handle(vm, process, args[0], |file| file.close(), ok_or_error)
+}
+
+pub fn read_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let size = to_offset(args[1])?;
 
+    let read = move |file: &mut Handle| file.read_vec(size);
+
+    with_handle(vm, process, args[0], read, |heap, res| match res {
+        Ok(ref buffer) if buffer.is_empty() => Ok(atom!(EOF)),
+        Ok(buffer) => Ok(tup2!(
+            heap,
+            atom!(OK),
+            Term::binary(heap, Binary::from(buffer))
+        )),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
+    })
+}
+
+pub fn write_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     let bytes = crate::bif::erlang::list_to_iodata(args[1])?;
-    match file.write_all(&bytes) {
-        Ok(()) => Ok(atom!(OK)),
-        Err(err) => Ok(error_to_tuple(heap, err)),
-    }
+    with_handle(
+        vm,
+        process,
+        args[0],
+        move |file| file.write_all(&bytes),
+        ok_or_error,
+    )
 }
 
-pub fn pread_nif_3(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn pread_nif_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let offset = to_offset(args[1])?;
+    let size = to_offset(args[2])?;
+
+    let read = move |file: &mut Handle| file.read_vec_at(size, offset);
+
+    with_handle(vm, process, args[0], read, move |heap, res| match res {
+        Ok(ref buffer) if buffer.is_empty() && size > 0 => Ok(atom!(EOF)),
+        Ok(buffer) => Ok(tup2!(
+            heap,
+            atom!(OK),
+            Term::binary(heap, Binary::from(buffer))
+        )),
+        Err(err) => Ok(posix::error_tuple(heap, err)),
+    })
 }
 
-pub fn pwrite_nif_3(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn pwrite_nif_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let offset = to_offset(args[1])?;
+
+    let bytes = crate::bif::erlang::list_to_iodata(args[2])?;
+    with_handle(
+        vm,
+        process,
+        args[0],
+        move |file| file.write_all_at(&bytes, offset),
+        ok_or_error,
+    )
 }
 
-pub fn seek_nif_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+pub fn seek_nif_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     use std::io::SeekFrom;
-    let heap = &process.context_mut().heap;
     // file, :bof->set/:cur->cur/:eof->end, 0
-    let file = File::cast_from_mut(&args[0])?;
-    let pos = match args[2].into_variant() {
-        Variant::Integer(i) if i >= 0 => i as usize,
-        _ => return Err(badarg!()),
-    };
+    RawFile::cast_from(&args[0])?;
+    let pos = to_i64(args[2]).ok_or_else(|| badarg!())?;
     let seek = match args[1].into_variant() {
-        Variant::Atom(atom::BOF) => SeekFrom::Start(pos as u64),
-        Variant::Atom(atom::CUR) => SeekFrom::Current(pos as i64),
-        Variant::Atom(atom::EOF) => SeekFrom::End(pos as i64),
+        Variant::Atom(atom::BOF) if pos >= 0 => SeekFrom::Start(pos as u64),
+        // positioning before the start of the file
+        Variant::Atom(atom::BOF) => {
+            return Ok(posix::error_tuple(
+                &process.context_mut().heap,
+                std::io::Error::from_raw_os_error(libc::EINVAL),
+            ))
+        }
+        Variant::Atom(atom::CUR) => SeekFrom::Current(pos),
+        Variant::Atom(atom::EOF) => SeekFrom::End(pos),
         _ => return Err(badarg!()),
     };
-    match file.seek(seek) {
-        Ok(new_pos) => Ok(tup2!(heap, atom!(OK), Term::uint64(heap, new_pos))),
-        Err(err) => Ok(error_to_tuple(heap, err)),
-    }
+    // compressed files read up to the new position
+    with_handle(
+        vm,
+        process,
+        args[0],
+        move |file| file.seek(seek),
+        |heap, res| match res {
+            Ok(new_pos) => Ok(tup2!(heap, atom!(OK), Term::uint64(heap, new_pos))),
+            Err(err) => Ok(posix::error_tuple(heap, err)),
+        },
+    )
 }
 
-pub fn sync_nif_2(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// file:sync/1 and file:datasync/1, the second argument is 1 for datasync.
+pub fn sync_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let data_only = match args[1].to_int() {
+        Some(data_only) => data_only != 0,
+        None => return Err(badarg!()),
+    };
+    with_handle(
+        vm,
+        process,
+        args[0],
+        move |file| file.sync(data_only),
+        ok_or_error,
+    )
 }
 
-pub fn truncate_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Truncates the file at the current position.
+pub fn truncate_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    with_handle(vm, process, args[0], |file| file.truncate(), ok_or_error)
 }
 
-pub fn allocate_nif_3(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn allocate_nif_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::io::AsRawFd;
+    let offset = to_offset(args[1])?;
+    let length = to_offset(args[2])?;
+
+    let allocate = move |file: &mut Handle| -> std::io::Result<()> {
+        let fd = file.file()?.as_raw_fd();
+        // posix_fallocate returns the error instead of setting errno
+        match unsafe { libc::posix_fallocate(fd, offset as libc::off_t, length as libc::off_t) } {
+            0 => Ok(()),
+            errno => Err(std::io::Error::from_raw_os_error(errno)),
+        }
+    };
+    with_handle(vm, process, args[0], allocate, ok_or_error)
 }
 
-pub fn advise_nif_4(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn advise_nif_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::io::AsRawFd;
+    let offset = to_offset(args[1])?;
+    let length = to_offset(args[2])?;
+
+    let advice = match args[3].into_variant() {
+        Variant::Atom(atom::NORMAL) => libc::POSIX_FADV_NORMAL,
+        Variant::Atom(atom::RANDOM) => libc::POSIX_FADV_RANDOM,
+        Variant::Atom(atom::SEQUENTIAL) => libc::POSIX_FADV_SEQUENTIAL,
+        Variant::Atom(atom::WILL_NEED) => libc::POSIX_FADV_WILLNEED,
+        Variant::Atom(atom::DONT_NEED) => libc::POSIX_FADV_DONTNEED,
+        Variant::Atom(atom::NO_REUSE) => libc::POSIX_FADV_NOREUSE,
+        _ => return Err(badarg!()),
+    };
+
+    let advise = move |file: &mut Handle| -> std::io::Result<()> {
+        let fd = file.file()?.as_raw_fd();
+        // like posix_fallocate, the error is returned directly
+        let ret = unsafe {
+            libc::posix_fadvise(fd, offset as libc::off_t, length as libc::off_t, advice)
+        };
+        match ret {
+            0 => Ok(()),
+            errno => Err(std::io::Error::from_raw_os_error(errno)),
+        }
+    };
+    with_handle(vm, process, args[0], advise, ok_or_error)
 }
 
+// TODO: file:sendfile/5 hands the file and the socket to prim_inet, which needs TCP sockets.
+// Ports don't have them yet, so there's no sendfile transfer until they do.
+
 // filesystem ops
 
-pub fn make_hard_link_nif_2(
-    _vm: &vm::Machine,
-    _process: &RcProcess,
-    _args: &[Term],
-) -> bif::Result {
-    unimplemented!()
-}
+pub fn make_hard_link_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let existing = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+    let new = args[1].to_str().ok_or_else(|| badarg!())?.to_owned();
 
-pub fn make_soft_link_nif_2(
-    _vm: &vm::Machine,
-    _process: &RcProcess,
-    _args: &[Term],
-) -> bif::Result {
-    unimplemented!()
+    run_io(vm, process, move || fs::hard_link(existing, new))
 }
 
-pub fn rename_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let heap = &process.context_mut().heap;
-    let from = args[0].to_str().unwrap();
-    let to = args[1].to_str().unwrap();
+pub fn make_soft_link_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let existing = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+    let new = args[1].to_str().ok_or_else(|| badarg!())?.to_owned();
 
-    println!("renaming {} to {}", from, to);
+    run_io(vm, process, move || {
+        std::os::unix::fs::symlink(existing, new)
+    })
+}
 
-    match fs::rename(from, to) {
-        Ok(()) => Ok(atom!(OK)),
-        Err(err) => Ok(error_to_tuple(heap, err)),
-    }
+pub fn rename_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let from = args[0].to_str().unwrap().to_owned();
+    let to = args[1].to_str().unwrap().to_owned();
+
+    run_io(vm, process, move || fs::rename(from, to))
 }
 
-pub fn set_permissions_nif_2(
-    _vm: &vm::Machine,
-    _process: &RcProcess,
-    _args: &[Term],
-) -> bif::Result {
-    unimplemented!()
+pub fn set_permissions_nif_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::fs::PermissionsExt;
+    let path = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+    let mode = args[1].to_uint().ok_or_else(|| badarg!())?;
+
+    run_io(vm, process, move || {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    })
 }
 
-pub fn set_owner_nif_3(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Changes the owner and group of a file, -1 leaves either unchanged.
+pub fn set_owner_nif_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let path = to_cstring(args[0])?;
+    let uid = args[1].to_int().ok_or_else(|| badarg!())?;
+    let gid = args[2].to_int().ok_or_else(|| badarg!())?;
+
+    run_io(vm, process, move || {
+        check_libc(unsafe { libc::chown(path.as_ptr(), uid as libc::uid_t, gid as libc::gid_t) })
+    })
 }
 
-pub fn set_time_nif_4(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Sets the access and modification times in POSIX seconds. The change time can't be set
+/// on unix, the kernel updates it.
+pub fn set_time_nif_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let path = to_cstring(args[0])?;
+    let atime = to_i64(args[1]).ok_or_else(|| badarg!())?;
+    let mtime = to_i64(args[2]).ok_or_else(|| badarg!())?;
+
+    let times = [
+        libc::timeval {
+            tv_sec: atime as libc::time_t,
+            tv_usec: 0,
+        },
+        libc::timeval {
+            tv_sec: mtime as libc::time_t,
+            tv_usec: 0,
+        },
+    ];
+    run_io(vm, process, move || {
+        check_libc(unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) })
+    })
 }
 
-pub fn read_link_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn read_link_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::ffi::OsStrExt;
+    let path = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+
+    blocking::run(
+        vm,
+        process,
+        move || fs::read_link(path),
+        |process, res| {
+            let heap = &process.context_mut().heap;
+            match res {
+                Ok(target) => {
+                    let bin = Binary::from(target.as_os_str().as_bytes());
+                    Ok(tup2!(heap, atom!(OK), Term::binary(heap, bin)))
+                }
+                Err(err) => Ok(posix::error_tuple(heap, err)),
+            }
+        },
+    )
 }
 
-pub fn make_dir_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn make_dir_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let path = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+
+    run_io(vm, process, move || fs::create_dir(path))
 }
 
-pub fn del_file_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+pub fn del_file_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
     // arg[0] = filename
-    let heap = &process.context_mut().heap;
-    let path = args[0].to_str().unwrap();
+    let path = args[0].to_str().unwrap().to_owned();
 
-    match fs::remove_file(path) {
-        Ok(()) => Ok(atom!(OK)),
-        Err(err) => Ok(error_to_tuple(heap, err)),
-    }
+    run_io(vm, process, move || fs::remove_file(path))
 }
 
-pub fn del_dir_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    unimplemented!()
+pub fn del_dir_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let path = args[0].to_str().ok_or_else(|| badarg!())?.to_owned();
+
+    run_io(vm, process, move || fs::remove_dir(path))
 }
 
 // internal nifs
 
-pub fn get_handle_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Returns the native file descriptor as a binary, used by the `file` module to pass handles
+/// to drivers.
+pub fn get_handle_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    use std::os::unix::io::AsRawFd;
+    with_handle(
+        vm,
+        process,
+        args[0],
+        |handle| handle.file().map(|file| file.as_raw_fd()),
+        |heap, fd| match fd {
+            Ok(fd) => Ok(Term::binary(heap, Binary::from(&fd.to_ne_bytes()[..]))),
+            Err(err) => Ok(posix::error_tuple(heap, err)),
+        },
+    )
 }
 
-pub fn delayed_close_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Called when a file is closed while another process is still using it. There are no
+/// pending operations to wait for, so this is a regular close.
+pub fn delayed_close_nif_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    close_nif_1(vm, process, args)
 }
 
-pub fn altname_nif_1(_vm: &vm::Machine, _process: &RcProcess, _args: &[Term]) -> bif::Result {
-    unimplemented!()
+/// Short (8.3) names only exist on Windows.
+pub fn altname_nif_1(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    Ok(tup2!(heap, atom!(ERROR), atom!(ENOTSUP)))
 }
 
 // gzip
@@ -583,20 +884,183 @@ pub fn uncompress_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bi
     Ok(Term::binary(heap, Binary::from(data)))
 }
 
-// Override zlib:compress/1, zlib:uncompress/1
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bif::blocking::call;
+    use crate::module;
+    use crate::process;
+
+    fn path(heap: &Heap, path: &std::path::Path) -> Term {
+        Term::binary(heap, Binary::from(path.to_str().unwrap().as_bytes()))
+    }
 
-pub fn zlib_compress_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    use libflate::zlib;
-    let heap = &process.context_mut().heap;
-    let string = crate::bif::erlang::list_to_iodata(args[0]).unwrap(); // TODO: error handling
-    let mut cursor = std::io::Cursor::new(string);
+    #[test]
+    fn test_positional_io() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let dir = std::env::temp_dir().join(format!("enigma_prim_file_{}", std::process::id()));
+        let dir_name = path(heap, &dir);
+        assert_eq!(
+            call(&vm, &process, make_dir_nif_1, &[dir_name]),
+            Ok(atom!(OK))
+        );
+
+        let file_name = path(heap, &dir.join("data"));
+        let modes = Cons::from_iter(vec![atom!(READ), atom!(WRITE)].into_iter(), heap);
+        let res = call(&vm, &process, open_nif_2, &[file_name, modes]).unwrap();
+        let fd = value::Tuple::cast_from(&res).unwrap()[1];
+
+        let data = Term::binary(heap, Binary::from(&b"hello world"[..]));
+        assert_eq!(
+            call(&vm, &process, pwrite_nif_3, &[fd, Term::int(4), data]),
+            Ok(atom!(OK))
+        );
+        assert_eq!(
+            call(&vm, &process, sync_nif_2, &[fd, Term::int(1)]),
+            Ok(atom!(OK))
+        );
+
+        let res = call(
+            &vm,
+            &process,
+            pread_nif_3,
+            &[fd, Term::int(10), Term::int(5)],
+        )
+        .unwrap();
+        let res = value::Tuple::cast_from(&res).unwrap();
+        assert_eq!(res[1].to_bytes(), Some(&b"world"[..]));
+        assert_eq!(
+            call(
+                &vm,
+                &process,
+                pread_nif_3,
+                &[fd, Term::int(100), Term::int(5)]
+            ),
+            Ok(atom!(EOF))
+        );
+
+        // truncates at the current position
+        call(&vm, &process, seek_nif_3, &[fd, atom!(BOF), Term::int(9)]).unwrap();
+        assert_eq!(call(&vm, &process, truncate_nif_1, &[fd]), Ok(atom!(OK)));
+        assert_eq!(fs::metadata(dir.join("data")).unwrap().len(), 9);
+
+        let link_name = path(heap, &dir.join("link"));
+        assert_eq!(
+            call(&vm, &process, make_soft_link_nif_2, &[file_name, link_name]),
+            Ok(atom!(OK))
+        );
+        let res = call(&vm, &process, read_link_nif_1, &[link_name]).unwrap();
+        let res = value::Tuple::cast_from(&res).unwrap();
+        assert_eq!(res[1].to_bytes(), file_name.to_bytes());
+
+        assert_eq!(call(&vm, &process, close_nif_1, &[fd]), Ok(atom!(OK)));
+        call(&vm, &process, del_file_nif_1, &[link_name]).unwrap();
+        call(&vm, &process, del_file_nif_1, &[file_name]).unwrap();
+        assert_eq!(
+            call(&vm, &process, del_dir_nif_1, &[dir_name]),
+            Ok(atom!(OK))
+        );
+    }
 
-    let mut encoder = zlib::Encoder::new(Vec::new()).unwrap();
-    std::io::copy(&mut cursor, &mut encoder).unwrap();
-    let encoded_data = encoder.finish().into_result().unwrap();
+    #[test]
+    fn test_shared_handle() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let other = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let file = std::env::temp_dir().join(format!("enigma_shared_{}", std::process::id()));
+        let modes = Cons::from_iter(vec![atom!(READ), atom!(WRITE)].into_iter(), heap);
+        let res = call(&vm, &process, open_nif_2, &[path(heap, &file), modes]).unwrap();
+        let fd = value::Tuple::cast_from(&res).unwrap()[1];
+
+        // a copy on another process' heap uses the same handle
+        let copy = fd.deep_clone(&other.context_mut().heap);
+        assert_eq!(RawFile::cast_from(&fd).unwrap().ref_count(), 2);
+        let data = Term::binary(heap, Binary::from(&b"shared"[..]));
+        assert_eq!(call(&vm, &other, write_nif_2, &[copy, data]), Ok(atom!(OK)));
+        let res = call(
+            &vm,
+            &process,
+            pread_nif_3,
+            &[fd, Term::int(0), Term::int(6)],
+        )
+        .unwrap();
+        let res = value::Tuple::cast_from(&res).unwrap();
+        assert_eq!(res[1].to_bytes(), Some(&b"shared"[..]));
 
-    Ok(Term::binary(heap, Binary::from(encoded_data)))
-}
+        assert_eq!(call(&vm, &process, close_nif_1, &[fd]), Ok(atom!(OK)));
+        // closed through the other copy
+        assert_ne!(call(&vm, &other, close_nif_1, &[copy]), Ok(atom!(OK)));
+        fs::remove_file(&file).unwrap();
+    }
 
-#[cfg(test)]
-mod tests {}
+    /// Every NIF in the table needs a stub in prim_file.beam, loading panics otherwise.
+    #[test]
+    fn test_load_nifs() {
+        let vm = vm::Machine::new();
+        let index = vm::PRE_LOADED_NAMES
+            .iter()
+            .position(|name| *name == "prim_file")
+            .unwrap();
+        let module = module::load_bytes(&vm, vm::PRE_LOADED[index]).unwrap();
+        let nifs = bif::NIFS.get(&atom::Atom::from("prim_file")).unwrap();
+        unsafe { &mut *(module as *mut module::Module) }.load_nifs(&vm, nifs);
+    }
+
+    #[test]
+    fn test_ram_and_compressed() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+        let modes = |modes: Vec<Term>| Cons::from_iter(modes.into_iter(), heap);
+        let read_all = |fd: Term| {
+            let res = call(&vm, &process, read_nif_2, &[fd, Term::int(1024)]).unwrap();
+            value::Tuple::cast_from(&res).unwrap()[1]
+                .to_bytes()
+                .unwrap()
+                .to_vec()
+        };
+
+        let data = Term::binary(heap, Binary::from(&b"in memory"[..]));
+        let opts = modes(vec![atom!(RAM), atom!(READ)]);
+        let res = call(&vm, &process, open_nif_2, &[data, opts]).unwrap();
+        let fd = value::Tuple::cast_from(&res).unwrap()[1];
+        // read only
+        let res = call(&vm, &process, write_nif_2, &[fd, data]).unwrap();
+        assert_eq!(value::Tuple::cast_from(&res).unwrap()[0], atom!(ERROR));
+        call(&vm, &process, seek_nif_3, &[fd, atom!(EOF), Term::int(-6)]).unwrap();
+        assert_eq!(read_all(fd), b"memory");
+
+        let path = std::env::temp_dir().join(format!("enigma_gzip_{}", std::process::id()));
+        let file_name = Term::binary(heap, Binary::from(path.to_str().unwrap().as_bytes()));
+        let opts = modes(vec![atom!(WRITE), atom!(COMPRESSED)]);
+        let res = call(&vm, &process, open_nif_2, &[file_name, opts]).unwrap();
+        let fd = value::Tuple::cast_from(&res).unwrap()[1];
+        call(&vm, &process, write_nif_2, &[fd, data]).unwrap();
+        assert_eq!(call(&vm, &process, close_nif_1, &[fd]), Ok(atom!(OK)));
+        assert_eq!(&fs::read(&path).unwrap()[..2], &[0x1f, 0x8b]);
+
+        let opts = modes(vec![atom!(READ), atom!(COMPRESSED)]);
+        let res = call(&vm, &process, open_nif_2, &[file_name, opts]).unwrap();
+        let fd = value::Tuple::cast_from(&res).unwrap()[1];
+        call(&vm, &process, seek_nif_3, &[fd, atom!(BOF), Term::int(3)]).unwrap();
+        assert_eq!(read_all(fd), b"memory");
+        call(&vm, &process, close_nif_1, &[fd]).unwrap();
+
+        // exclusive fails on existing files
+        let opts = modes(vec![atom!(EXCLUSIVE)]);
+        let res = call(&vm, &process, open_nif_2, &[file_name, opts]).unwrap();
+        assert_eq!(
+            value::Tuple::cast_from(&res).unwrap()[1],
+            Term::atom(atom::Atom::from("eexist"))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! File handles returned by `open_nif`.
+//!
+//! Besides regular files, `file:open/2` can open in-memory `ram` files and gzip `compressed`
+//! files. All of them support sequential reads and writes and `file:position/2`; positional
+//! I/O works on regular and ram files, and the fd based calls (sync, allocate, advise) only
+//! on regular files.
+use libflate::gzip;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+pub enum Handle {
+    /// A regular file.
+    File(File),
+    /// A `ram` file, the buffer grows as it's written past the end. With `append`, every write
+    /// goes to the end, wherever the position is.
+    Ram {
+        buf: Cursor<Vec<u8>>,
+        read: bool,
+        write: bool,
+        append: bool,
+    },
+    /// A `compressed` file opened for reading. Files that aren't gzip are read as is.
+    GzipReader {
+        reader: Box<dyn Read + Send>,
+        pos: u64,
+    },
+    /// A `compressed` file opened for writing, the gzip trailer is written on close.
+    GzipWriter {
+        encoder: gzip::Encoder<File>,
+        pos: u64,
+    },
+    Closed,
+}
+
+/// Reads from files without a known end (pipes, devices) return at most this many bytes.
+const READ_CHUNK: u64 = 1 << 20;
+
+fn error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+/// Grows a ram file so that `len` bytes can be written at `offset`, returning the end of the
+/// write. Offsets come from the caller, so a failed allocation is `enomem` instead of an abort.
+fn ram_grow(data: &mut Vec<u8>, offset: u64, len: usize) -> io::Result<usize> {
+    let end = offset
+        .checked_add(len as u64)
+        .and_then(|end| usize::try_from(end).ok())
+        .ok_or_else(|| error(libc::ENOMEM))?;
+    if end > data.len() {
+        data.try_reserve(end - data.len())
+            .map_err(|_| error(libc::ENOMEM))?;
+        data.resize(end, 0);
+    }
+    Ok(end)
+}
+
+impl Handle {
+    pub fn ram(data: Vec<u8>, read: bool, write: bool, append: bool) -> Self {
+        let mut buf = Cursor::new(data);
+        if append {
+            buf.set_position(buf.get_ref().len() as u64);
+        }
+        Handle::Ram {
+            buf,
+            read,
+            write,
+            append,
+        }
+    }
+
+    /// Opens a compressed file for reading, falling back to plain reads without the gzip magic.
+    pub fn gzip_reader(mut file: File) -> io::Result<Self> {
+        let mut magic = [0; 2];
+        let n = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let reader: Box<dyn Read + Send> = if n == 2 && magic == [0x1f, 0x8b] {
+            Box::new(gzip::Decoder::new(io::BufReader::new(file))?)
+        } else {
+            Box::new(file)
+        };
+        Ok(Handle::GzipReader { reader, pos: 0 })
+    }
+
+    pub fn gzip_writer(file: File) -> io::Result<Self> {
+        Ok(Handle::GzipWriter {
+            encoder: gzip::Encoder::new(file)?,
+            pos: 0,
+        })
+    }
+
+    /// The underlying OS file, for calls that need a file descriptor.
+    pub fn file(&self) -> io::Result<&File> {
+        match self {
+            Handle::File(file) => Ok(file),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Err(error(libc::ENOTSUP)),
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Handle::File(file) => file.read(buf),
+            Handle::Ram {
+                buf: data,
+                read: true,
+                ..
+            } => data.read(buf),
+            Handle::GzipReader { reader, pos } => {
+                let n = reader.read(buf)?;
+                *pos += n as u64;
+                Ok(n)
+            }
+            Handle::Ram { .. } | Handle::GzipWriter { .. } => Err(error(libc::EBADF)),
+            Handle::Closed => Err(error(libc::EINVAL)),
+        }
+    }
+
+    /// Reads up to `size` bytes into a new buffer. The size comes from the caller, so the buffer
+    /// is only as large as what's left of the file, where that's known.
+    pub fn read_vec(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if let Handle::GzipReader { reader, pos } = self {
+            let mut buf = Vec::new();
+            reader.by_ref().take(size).read_to_end(&mut buf)?;
+            *pos += buf.len() as u64;
+            return Ok(buf);
+        }
+        let mut buf = vec![0; self.readable(size, None)?];
+        let n = self.read(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Like `read_vec`, at `offset` instead of the current position.
+    pub fn read_vec_at(&mut self, size: u64, offset: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; self.readable(size, Some(offset))?];
+        let n = self.read_at(&mut buf, offset)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// How many of `size` bytes a read at `offset` (or the current position) can return.
+    fn readable(&mut self, size: u64, offset: Option<u64>) -> io::Result<usize> {
+        let left = match self {
+            Handle::File(file) => {
+                let meta = file.metadata()?;
+                if meta.is_file() {
+                    let pos = match offset {
+                        Some(offset) => offset,
+                        None => file.seek(SeekFrom::Current(0))?,
+                    };
+                    meta.len().saturating_sub(pos)
+                } else {
+                    READ_CHUNK
+                }
+            }
+            Handle::Ram { buf, .. } => {
+                let pos = offset.unwrap_or_else(|| buf.position());
+                (buf.get_ref().len() as u64).saturating_sub(pos)
+            }
+            // the read itself fails
+            _ => 0,
+        };
+        Ok(std::cmp::min(size, left) as usize)
+    }
+
+    pub fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Handle::File(file) => file.write_all(bytes),
+            Handle::Ram {
+                buf,
+                write: true,
+                append,
+                ..
+            } => {
+                if *append {
+                    buf.seek(SeekFrom::End(0))?;
+                }
+                let pos = buf.position();
+                ram_grow(buf.get_mut(), pos, bytes.len())?;
+                buf.write_all(bytes)
+            }
+            Handle::GzipWriter { encoder, pos } => {
+                encoder.write_all(bytes)?;
+                *pos += bytes.len() as u64;
+                Ok(())
+            }
+            Handle::Ram { .. } | Handle::GzipReader { .. } => Err(error(libc::EBADF)),
+            Handle::Closed => Err(error(libc::EINVAL)),
+        }
+    }
+
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        match self {
+            Handle::File(file) => file.read_at(buf, offset),
+            Handle::Ram {
+                buf: data,
+                read: true,
+                ..
+            } => {
+                let data = data.get_ref();
+                if offset >= data.len() as u64 {
+                    return Ok(0);
+                }
+                let data = &data[offset as usize..];
+                let n = std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            Handle::Ram { .. } => Err(error(libc::EBADF)),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Err(error(libc::ENOTSUP)),
+        }
+    }
+
+    pub fn write_all_at(&mut self, bytes: &[u8], offset: u64) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        match self {
+            Handle::File(file) => file.write_all_at(bytes, offset),
+            Handle::Ram {
+                buf, write: true, ..
+            } => {
+                let data = buf.get_mut();
+                let end = ram_grow(data, offset, bytes.len())?;
+                data[offset as usize..end].copy_from_slice(bytes);
+                Ok(())
+            }
+            Handle::Ram { .. } => Err(error(libc::EBADF)),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Err(error(libc::ENOTSUP)),
+        }
+    }
+
+    /// Compressed files can only move forward when reading, and not at all when writing.
+    pub fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        match self {
+            Handle::File(file) => file.seek(seek),
+            Handle::Ram { buf, .. } => buf.seek(seek),
+            Handle::GzipReader { reader, pos } => {
+                let target = match seek {
+                    SeekFrom::Start(n) => n as i64,
+                    SeekFrom::Current(n) => *pos as i64 + n,
+                    SeekFrom::End(_) => return Err(error(libc::EINVAL)),
+                };
+                if target < *pos as i64 {
+                    return Err(error(libc::EINVAL));
+                }
+                let skip = target as u64 - *pos;
+                *pos += io::copy(&mut reader.by_ref().take(skip), &mut io::sink())?;
+                Ok(*pos)
+            }
+            Handle::GzipWriter { pos, .. } => match seek {
+                SeekFrom::Start(n) if n == *pos => Ok(*pos),
+                SeekFrom::Current(0) => Ok(*pos),
+                _ => Err(error(libc::EINVAL)),
+            },
+            Handle::Closed => Err(error(libc::EINVAL)),
+        }
+    }
+
+    pub fn sync(&mut self, data_only: bool) -> io::Result<()> {
+        match self {
+            Handle::File(file) if data_only => file.sync_data(),
+            Handle::File(file) => file.sync_all(),
+            // nothing to persist
+            Handle::Ram { .. } => Ok(()),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Err(error(libc::ENOTSUP)),
+        }
+    }
+
+    /// Truncates the file at the current position.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        match self {
+            Handle::File(file) => {
+                let pos = file.seek(SeekFrom::Current(0))?;
+                file.set_len(pos)
+            }
+            Handle::Ram {
+                buf, write: true, ..
+            } => {
+                let pos = buf.position() as usize;
+                buf.get_mut().truncate(pos);
+                Ok(())
+            }
+            Handle::Ram { .. } => Err(error(libc::EBADF)),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Err(error(libc::ENOTSUP)),
+        }
+    }
+
+    /// Closes the handle, flushing compressed output. Closing twice is an error.
+    pub fn close(&mut self) -> io::Result<()> {
+        match std::mem::replace(self, Handle::Closed) {
+            Handle::GzipWriter { encoder, .. } => encoder.finish().into_result().map(|_| ()),
+            Handle::Closed => Err(error(libc::EINVAL)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram() {
+        let mut handle = Handle::ram(b"hello".to_vec(), true, true, false);
+        handle.seek(SeekFrom::End(0)).unwrap();
+        handle.write_all(b" world").unwrap();
+        handle.write_all_at(b"!", 12).unwrap();
+
+        let mut buf = [0; 16];
+        let n = handle.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"hello world\0!");
+
+        handle.seek(SeekFrom::Start(5)).unwrap();
+        handle.truncate().unwrap();
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        let n = handle.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        let mut handle = Handle::ram(Vec::new(), true, false, false);
+        assert!(handle.write_all(b"x").is_err());
+        handle.close().unwrap();
+        assert!(handle.close().is_err());
+    }
+
+    #[test]
+    fn test_read_size() {
+        let mut handle = Handle::ram(b"hello".to_vec(), true, true, false);
+        assert_eq!(handle.read_vec(std::u64::MAX).unwrap(), b"hello");
+        assert_eq!(handle.read_vec(std::u64::MAX).unwrap(), b"");
+        assert_eq!(handle.read_vec_at(std::u64::MAX, 1).unwrap(), b"ello");
+        assert_eq!(handle.read_vec_at(std::u64::MAX, 1 << 40).unwrap(), b"");
+
+        let err = handle.write_all_at(b"!", std::u64::MAX).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOMEM));
+        let err = handle.write_all_at(b"!", 1 << 62).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOMEM));
+        handle.seek(SeekFrom::Start(std::u64::MAX - 1)).unwrap();
+        let err = handle.write_all(b"!!").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOMEM));
+
+        let path = std::env::temp_dir().join(format!("enigma-read-size-{}", std::process::id()));
+        std::fs::write(&path, b"hello").unwrap();
+        let mut handle = Handle::File(File::open(&path).unwrap());
+        assert_eq!(handle.read_vec_at(std::u64::MAX, 3).unwrap(), b"lo");
+        assert_eq!(handle.read_vec(std::u64::MAX).unwrap(), b"hello");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ram_append() {
+        let mut handle = Handle::ram(b"hello".to_vec(), true, true, true);
+        assert_eq!(handle.seek(SeekFrom::Current(0)).unwrap(), 5);
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        handle.write_all(b" world").unwrap();
+        handle.seek(SeekFrom::Start(1)).unwrap();
+        handle.write_all(b"!").unwrap();
+        assert_eq!(handle.read_vec_at(16, 0).unwrap(), b"hello world!");
+    }
+}
@@ -13,18 +13,25 @@ pub fn send_after_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif
         _ => return Err(badarg!()),
     };
 
-    if !args[1].is_pid() {
-        return Err(badarg!());
-    }
+    let dest = match args[1].into_variant() {
+        Variant::Pid(pid) => pid,
+        _ => return Err(badarg!()),
+    };
 
-    let dest = args[1];
-    let msg = args[2];
-    let from = process.pid;
+    // copied right away, the sender may have hibernated and freed its heap by the time it fires
+    let signal = process::Signal::message(process.pid, args[2]);
 
     let when = Instant::now() + Duration::from_millis(u64::from(delay));
     let fut = async move {
         tokio::timer::delay(when).await;
-        vm::Machine::with_current(|vm| process::send_message(vm, from, dest, msg));
+        vm::Machine::with_current(|vm| {
+            if let Some(receiver) = vm.process_table.lock().get(dest) {
+                receiver.send_signal(signal);
+            } else if let process::Signal::Message { heap, .. } = signal {
+                // nothing else points into the copy
+                unsafe { heap.free() }
+            }
+        });
     };
     vm.runtime.executor().spawn(fut);
 
@@ -0,0 +1,1368 @@
+use crate::atom::{self, Atom};
+use crate::bif::erlang::list_to_iodata;
+use crate::bitstring::Binary;
+use crate::exception::{Exception, Reason};
+use crate::immix::Heap;
+use crate::process::{RcProcess, PID};
+use crate::value::{self, CastFrom, Cons, Term, Variant};
+use crate::vm;
+use adler32::RollingAdler32;
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::{deflate, inflate, DataFormat, MZError, MZFlush, MZStatus};
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Backend for the NIFs of the preloaded zlib module. zlib.erl enqueues input, then calls
+// deflate_nif/inflate_nif repeatedly with bounded chunk sizes until they report `finished`, so
+// a single call never does much work.
+//
+// miniz only ever produces or consumes raw deflate data, the zlib and gzip headers and
+// trailers are written and checked here. That also lets deflateParams swap the compressor
+// without starting a new stream.
+
+/// Size of the output chunks handed out by inflateChunk, until setBufSize is called.
+const DEFAULT_BUFSIZE: usize = 4000;
+
+/// Compression method, only deflate is defined.
+const Z_DEFLATED: i32 = 8;
+
+/// OS code written to gzip headers (unix, like zlib).
+const OS_CODE: u8 = 3;
+
+// What happens to input that follows the end of a stream, passed to inflateInit.
+const EOS_BEHAVIOR_ERROR: i32 = 0;
+const EOS_BEHAVIOR_RESET: i32 = 1;
+const EOS_BEHAVIOR_CUT: i32 = 2;
+
+/// The framing around the deflate data, selected by windowBits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Wrapper {
+    Raw,
+    Zlib,
+    Gzip,
+    /// zlib or gzip, detected from the header (inflate only).
+    Auto,
+}
+
+impl Wrapper {
+    fn from_window_bits(bits: i32) -> Option<Self> {
+        match bits {
+            -15..=-8 => Some(Wrapper::Raw),
+            8..=15 => Some(Wrapper::Zlib),
+            24..=31 => Some(Wrapper::Gzip),
+            40..=47 => Some(Wrapper::Auto),
+            _ => None,
+        }
+    }
+
+    fn trailer_len(self) -> usize {
+        match self {
+            Wrapper::Zlib => 4,
+            Wrapper::Gzip => 8,
+            _ => 0,
+        }
+    }
+}
+
+/// Checksums of the uncompressed data.
+struct Checksum {
+    adler: RollingAdler32,
+    crc: crc32fast::Hasher,
+    /// The uncompressed size, modulo 2^32 like in gzip trailers.
+    size: u32,
+}
+
+impl Checksum {
+    fn new() -> Self {
+        Checksum {
+            adler: RollingAdler32::new(),
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.adler.update_buffer(bytes);
+        self.crc.update(bytes);
+        self.size = self.size.wrapping_add(bytes.len() as u32);
+    }
+
+    fn crc(&self) -> u32 {
+        self.crc.clone().finalize()
+    }
+
+    fn trailer(&self, wrapper: Wrapper) -> Vec<u8> {
+        match wrapper {
+            Wrapper::Zlib => self.adler.hash().to_be_bytes().to_vec(),
+            Wrapper::Gzip => {
+                let mut trailer = self.crc().to_le_bytes().to_vec();
+                trailer.extend_from_slice(&self.size.to_le_bytes());
+                trailer
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Queued input, consumed from the front.
+#[derive(Default)]
+struct Input {
+    chunks: VecDeque<Vec<u8>>,
+    /// Offset into the front chunk.
+    pos: usize,
+    size: usize,
+}
+
+impl Input {
+    fn push(&mut self, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.chunks.push_back(bytes.to_vec());
+            self.size += bytes.len();
+        }
+    }
+
+    /// The next contiguous run of input, at most `max` bytes long.
+    fn peek(&self, max: usize) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => &chunk[self.pos..chunk.len().min(self.pos + max)],
+            None => &[],
+        }
+    }
+
+    fn skip(&mut self, mut n: usize) {
+        debug_assert!(n <= self.size);
+        self.size -= n;
+        while n > 0 {
+            let remaining = self.chunks[0].len() - self.pos;
+            if n < remaining {
+                self.pos += n;
+                return;
+            }
+            n -= remaining;
+            self.chunks.pop_front();
+            self.pos = 0;
+        }
+    }
+
+    /// Moves bytes over to `buf` until `complete` says it holds a whole header or trailer.
+    fn take_until<F>(&mut self, buf: &mut Vec<u8>, complete: F) -> Result<bool, Exception>
+    where
+        F: Fn(&[u8]) -> Result<bool, Exception>,
+    {
+        while !complete(buf)? {
+            match self.peek(1).first() {
+                Some(byte) => buf.push(*byte),
+                None => return Ok(false),
+            }
+            self.skip(1);
+        }
+        Ok(true)
+    }
+
+    fn clear(&mut self) {
+        *self = Input::default();
+    }
+
+    fn len(&self) -> usize {
+        self.size
+    }
+}
+
+struct Deflate {
+    compressor: CompressorOxide,
+    wrapper: Wrapper,
+    level: i32,
+    strategy: i32,
+    /// Framing bytes that didn't fit the last output chunk.
+    pending: Vec<u8>,
+    /// The compressor wrote the final block.
+    finished: bool,
+    /// Data was compressed, it's too late for a dictionary.
+    started: bool,
+    /// The Adler-32 of the dictionary, announced in the zlib header.
+    dictionary: Option<u32>,
+}
+
+impl Deflate {
+    fn new(wrapper: Wrapper, level: i32, strategy: i32) -> Self {
+        let mut deflate = Deflate {
+            compressor: compressor(level, strategy),
+            wrapper,
+            level,
+            strategy,
+            pending: Vec::new(),
+            finished: false,
+            started: false,
+            dictionary: None,
+        };
+        deflate.pending = deflate.header();
+        deflate
+    }
+
+    fn header(&self) -> Vec<u8> {
+        // the level is only advertised in the header, -1 is the default of 6
+        let level = if self.level < 0 { 6 } else { self.level };
+        match self.wrapper {
+            Wrapper::Zlib => {
+                // always claim a 32K window, it's what miniz uses
+                let cmf = 0x78u8;
+                let level_flags = match level {
+                    _ if self.strategy >= 2 || level < 2 => 0,
+                    2..=5 => 1,
+                    6 => 2,
+                    _ => 3,
+                };
+                let flg = match self.dictionary {
+                    Some(_) => level_flags << 6 | 0x20,
+                    None => level_flags << 6,
+                };
+                let check = (31 - (u16::from(cmf) << 8 | u16::from(flg)) % 31) % 31;
+                let mut header = vec![cmf, flg | check as u8];
+                if let Some(id) = self.dictionary {
+                    header.extend_from_slice(&id.to_be_bytes());
+                }
+                header
+            }
+            Wrapper::Gzip => {
+                let xfl = match level {
+                    9 => 2,
+                    _ if self.strategy >= 2 || level < 2 => 4,
+                    _ => 0,
+                };
+                vec![0x1f, 0x8b, Z_DEFLATED as u8, 0, 0, 0, 0, 0, xfl, OS_CODE]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Deflate::new(self.wrapper, self.level, self.strategy);
+    }
+
+    /// Primes the compressor with `dictionary` and returns its Adler-32. miniz can't load its
+    /// window directly, so the dictionary is compressed with a sync flush and the output is
+    /// thrown away: the data that follows starts on a byte boundary and can refer back into it.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> Result<u32, Exception> {
+        // zlib lets raw streams take a dictionary later on too, but there the flush would end
+        // up in the middle of the output
+        if self.started || self.wrapper == Wrapper::Gzip {
+            return Err(error(atom::STREAM_ERROR));
+        }
+        let mut input = dictionary;
+        let mut output = vec![0; dictionary.len() + 1024];
+        loop {
+            let res =
+                deflate::stream::deflate(&mut self.compressor, input, &mut output, MZFlush::Sync);
+            input = &input[res.bytes_consumed..];
+            match res.status {
+                Ok(..) | Err(MZError::Buf) => (),
+                Err(..) => return Err(error(atom::STREAM_ERROR)),
+            }
+            if input.is_empty() && res.bytes_written < output.len() {
+                break;
+            }
+        }
+
+        let id = RollingAdler32::from_buffer(dictionary).hash();
+        if self.wrapper == Wrapper::Zlib {
+            // tells the inflating side which dictionary to ask for
+            self.dictionary = Some(id);
+            self.pending = self.header();
+        }
+        Ok(id)
+    }
+
+    /// Compresses up to `in_chunk` bytes of input into at most `out_chunk` bytes. Returns
+    /// whether there's more to do.
+    fn run(
+        &mut self,
+        input: &mut Input,
+        checksum: &mut Checksum,
+        in_chunk: usize,
+        out_chunk: usize,
+        flush: MZFlush,
+    ) -> Result<(bool, Vec<u8>), Exception> {
+        let mut output = vec![0; out_chunk];
+        let mut written = self.drain_pending(&mut output);
+        self.started = true;
+
+        if self.finished {
+            if input.len() > 0 {
+                return Err(error(atom::STREAM_ERROR));
+            }
+        } else if written < out_chunk {
+            let chunk = input.peek(in_chunk);
+            // only flush once the rest of the queue has been handed over
+            let flush = if chunk.len() == input.len() {
+                flush
+            } else {
+                MZFlush::None
+            };
+            let res = deflate::stream::deflate(
+                &mut self.compressor,
+                chunk,
+                &mut output[written..],
+                flush,
+            );
+            checksum.update(&chunk[..res.bytes_consumed]);
+            input.skip(res.bytes_consumed);
+            written += res.bytes_written;
+
+            match res.status {
+                Ok(MZStatus::StreamEnd) => {
+                    self.finished = true;
+                    self.pending = checksum.trailer(self.wrapper);
+                    written += self.drain_pending(&mut output[written..]);
+                }
+                Ok(..) | Err(MZError::Buf) => (),
+                Err(..) => return Err(error(atom::STREAM_ERROR)),
+            }
+        }
+
+        let more = written == out_chunk || input.len() > 0 || !self.pending.is_empty();
+        output.truncate(written);
+        Ok((more, output))
+    }
+
+    fn drain_pending(&mut self, output: &mut [u8]) -> usize {
+        let n = self.pending.len().min(output.len());
+        output[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+fn compressor(level: i32, strategy: i32) -> CompressorOxide {
+    // negative window bits make miniz skip the zlib framing
+    CompressorOxide::new(create_comp_flags_from_zip_params(level, -15, strategy))
+}
+
+/// Returns whether `buf` holds a whole zlib header.
+fn zlib_header(buf: &[u8]) -> Result<bool, Exception> {
+    if buf.len() < 2 {
+        return Ok(false);
+    }
+    let (cmf, flg) = (buf[0], buf[1]);
+    if i32::from(cmf & 0x0F) != Z_DEFLATED
+        || cmf >> 4 > 7
+        || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0
+    {
+        return Err(error(atom::DATA_ERROR));
+    }
+    // a preset dictionary id follows
+    let len = if flg & 0x20 != 0 { 6 } else { 2 };
+    Ok(buf.len() >= len)
+}
+
+/// Returns whether `buf` holds a whole gzip header.
+fn gzip_header(buf: &[u8]) -> Result<bool, Exception> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if buf.len() < 10 {
+        return Ok(false);
+    }
+    if buf[..3] != [0x1f, 0x8b, Z_DEFLATED as u8] || buf[3] & 0xE0 != 0 {
+        return Err(error(atom::DATA_ERROR));
+    }
+    let flags = buf[3];
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        if buf.len() < len + 2 {
+            return Ok(false);
+        }
+        len += 2 + usize::from(u16::from_le_bytes([buf[len], buf[len + 1]]));
+    }
+    // zero terminated strings
+    for flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            match buf
+                .get(len..)
+                .and_then(|rest| rest.iter().position(|c| *c == 0))
+            {
+                Some(i) => len += i + 1,
+                None => return Ok(false),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+    Ok(buf.len() >= len)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Header,
+    /// The zlib header asked for a dictionary, waiting for inflateSetDictionary.
+    Dictionary,
+    Data,
+    Trailer,
+    /// Past the end of the stream.
+    End,
+}
+
+enum Inflated {
+    More(bool),
+    NeedDictionary(u32),
+}
+
+struct Inflate {
+    state: Box<InflateState>,
+    /// The configured framing, possibly Auto.
+    wrapper: Wrapper,
+    /// The framing of the current stream.
+    format: Wrapper,
+    eos_behavior: i32,
+    stage: Stage,
+    /// The header or trailer collected so far.
+    framing: Vec<u8>,
+    /// Data was inflated, it's too late for a dictionary.
+    started: bool,
+}
+
+impl Inflate {
+    fn new(wrapper: Wrapper, eos_behavior: i32) -> Self {
+        Inflate {
+            state: InflateState::new_boxed(DataFormat::Raw),
+            wrapper,
+            format: wrapper,
+            eos_behavior,
+            stage: if wrapper == Wrapper::Raw {
+                Stage::Data
+            } else {
+                Stage::Header
+            },
+            framing: Vec::new(),
+            started: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Inflate::new(self.wrapper, self.eos_behavior);
+    }
+
+    /// The Adler-32 of the dictionary the zlib header asked for.
+    fn dictionary_id(&self) -> u32 {
+        let mut id = [0; 4];
+        id.copy_from_slice(&self.framing[2..6]);
+        u32::from_be_bytes(id)
+    }
+
+    /// Loads `dictionary` into the window, once the zlib header asked for it or before a raw
+    /// stream starts. miniz can't load its window directly, so the dictionary is inflated as a
+    /// stored block and the output is thrown away.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> Result<(), Exception> {
+        match self.stage {
+            Stage::Dictionary => {
+                if RollingAdler32::from_buffer(dictionary).hash() != self.dictionary_id() {
+                    return Err(error(atom::DATA_ERROR));
+                }
+            }
+            // like on the deflating side, only before the first block
+            Stage::Data if self.format == Wrapper::Raw && !self.started => (),
+            _ => return Err(error(atom::STREAM_ERROR)),
+        }
+        self.framing.clear();
+        self.stage = Stage::Data;
+        if dictionary.is_empty() {
+            return Ok(());
+        }
+
+        // only the last 32K can be referred to
+        let dictionary = &dictionary[dictionary.len().saturating_sub(32 * 1024)..];
+        let len = dictionary.len() as u16;
+        // a stored block that isn't the final one
+        let mut block = vec![0];
+        block.extend_from_slice(&len.to_le_bytes());
+        block.extend_from_slice(&(!len).to_le_bytes());
+        block.extend_from_slice(dictionary);
+
+        let mut input = &block[..];
+        let mut output = vec![0; dictionary.len()];
+        let mut written = 0;
+        while !input.is_empty() || written < output.len() {
+            let res = inflate::stream::inflate(
+                &mut self.state,
+                input,
+                &mut output[written..],
+                MZFlush::None,
+            );
+            input = &input[res.bytes_consumed..];
+            written += res.bytes_written;
+            match res.status {
+                Ok(MZStatus::Ok) | Err(MZError::Buf)
+                    if res.bytes_consumed > 0 || res.bytes_written > 0 => {}
+                _ => return Err(error(atom::DATA_ERROR)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompresses up to `in_chunk` bytes of input into at most `out_chunk` bytes.
+    fn run(
+        &mut self,
+        input: &mut Input,
+        checksum: &mut Checksum,
+        in_chunk: usize,
+        out_chunk: usize,
+    ) -> Result<(Inflated, Vec<u8>), Exception> {
+        let mut output = vec![0; out_chunk];
+        let mut written = 0;
+        let mut budget = in_chunk;
+
+        loop {
+            match self.stage {
+                Stage::Header => {
+                    if self.format == Wrapper::Auto {
+                        if !input.take_until(&mut self.framing, |buf| Ok(buf.len() >= 2))? {
+                            break;
+                        }
+                        self.format = if self.framing[..2] == [0x1f, 0x8b] {
+                            Wrapper::Gzip
+                        } else {
+                            Wrapper::Zlib
+                        };
+                    }
+                    let complete = match self.format {
+                        Wrapper::Gzip => input.take_until(&mut self.framing, gzip_header)?,
+                        _ => input.take_until(&mut self.framing, zlib_header)?,
+                    };
+                    if !complete {
+                        break;
+                    }
+                    if self.format == Wrapper::Zlib && self.framing[1] & 0x20 != 0 {
+                        self.stage = Stage::Dictionary;
+                        continue;
+                    }
+                    self.framing.clear();
+                    self.stage = Stage::Data;
+                }
+                Stage::Dictionary => {
+                    // doesn't advance until the dictionary is set
+                    output.truncate(written);
+                    return Ok((Inflated::NeedDictionary(self.dictionary_id()), output));
+                }
+                Stage::Data => {
+                    let chunk = input.peek(budget);
+                    let res = inflate::stream::inflate(
+                        &mut self.state,
+                        chunk,
+                        &mut output[written..],
+                        MZFlush::None,
+                    );
+                    checksum.update(&output[written..written + res.bytes_written]);
+                    input.skip(res.bytes_consumed);
+                    budget -= res.bytes_consumed;
+                    written += res.bytes_written;
+                    self.started |= res.bytes_consumed > 0;
+
+                    match res.status {
+                        Ok(MZStatus::StreamEnd) => self.stage = Stage::Trailer,
+                        Ok(MZStatus::Ok) | Err(MZError::Buf) => {
+                            if res.bytes_consumed == 0 && res.bytes_written == 0 {
+                                break;
+                            }
+                        }
+                        _ => return Err(error(atom::DATA_ERROR)),
+                    }
+                    if written == out_chunk {
+                        break;
+                    }
+                }
+                Stage::Trailer => {
+                    let len = self.format.trailer_len();
+                    if !input.take_until(&mut self.framing, |buf| Ok(buf.len() >= len))? {
+                        break;
+                    }
+                    if self.framing != checksum.trailer(self.format) {
+                        return Err(error(atom::DATA_ERROR));
+                    }
+                    self.framing.clear();
+                    self.stage = Stage::End;
+                }
+                Stage::End => {
+                    if input.len() == 0 {
+                        break;
+                    }
+                    match self.eos_behavior {
+                        EOS_BEHAVIOR_RESET => {
+                            self.reset();
+                            *checksum = Checksum::new();
+                        }
+                        EOS_BEHAVIOR_CUT => input.clear(),
+                        _ => return Err(error(atom::DATA_ERROR)),
+                    }
+                }
+            }
+        }
+
+        let more = written == out_chunk || (budget == 0 && input.len() > 0);
+        output.truncate(written);
+        Ok((Inflated::More(more), output))
+    }
+}
+
+enum Codec {
+    None,
+    Deflate(Box<Deflate>),
+    Inflate(Box<Inflate>),
+    Closed,
+}
+
+pub struct Stream {
+    /// The only process allowed to use the stream.
+    controller: PID,
+    codec: Codec,
+    input: Input,
+    checksum: Checksum,
+    bufsize: usize,
+    /// Saved by zlib.erl between calls, kept on `stash_heap`.
+    stash: Option<Term>,
+    /// Holds the stash, cleared whenever it's replaced.
+    stash_heap: Heap,
+}
+
+impl Stream {
+    fn clear_stash(&mut self) {
+        self.stash = None;
+        // get_stash hands out copies, nothing else points into the heap
+        unsafe { self.stash_heap.clear() }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe { std::ptr::read(&self.stash_heap).free() }
+    }
+}
+
+/// A zstream(), copies of the term share the stream.
+#[derive(Clone)]
+pub struct Zstream(Arc<Mutex<Stream>>);
+
+impl Zstream {
+    /// The number of terms sharing the stream.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    pub fn new(controller: PID) -> Self {
+        Zstream(Arc::new(Mutex::new(Stream {
+            controller,
+            codec: Codec::None,
+            input: Input::default(),
+            checksum: Checksum::new(),
+            bufsize: DEFAULT_BUFSIZE,
+            stash: None,
+            stash_heap: Heap::fragment(),
+        })))
+    }
+}
+
+impl CastFrom<Term> for Zstream {
+    type Error = value::WrongBoxError;
+
+    #[inline]
+    fn cast_from(value: &Term) -> Result<&Self, value::WrongBoxError> {
+        if let Variant::Pointer(ptr) = value.into_variant() {
+            unsafe {
+                if *ptr == value::BOXED_ZLIB {
+                    return Ok(&(*(ptr as *const value::Boxed<Self>)).value);
+                }
+            }
+        }
+        Err(value::WrongBoxError)
+    }
+}
+
+fn error(reason: Atom) -> Exception {
+    Exception::with_value(Reason::EXC_ERROR, Term::atom(reason))
+}
+
+pub mod bif {
+    use super::*;
+    use crate::bif::Result;
+
+    fn to_int(term: Term) -> std::result::Result<i32, Exception> {
+        match term.into_variant() {
+            Variant::Integer(i) => Ok(i),
+            _ => Err(badarg!()),
+        }
+    }
+
+    fn to_size(term: Term) -> std::result::Result<usize, Exception> {
+        match term.into_variant() {
+            Variant::Integer(i) if i > 0 => Ok(i as usize),
+            _ => Err(badarg!()),
+        }
+    }
+
+    fn to_flush(term: Term) -> std::result::Result<MZFlush, Exception> {
+        match term.into_variant() {
+            Variant::Integer(0) => Ok(MZFlush::None),
+            Variant::Integer(2) => Ok(MZFlush::Sync),
+            Variant::Integer(3) => Ok(MZFlush::Full),
+            Variant::Integer(4) => Ok(MZFlush::Finish),
+            _ => Err(badarg!()),
+        }
+    }
+
+    /// Locks the stream, if the caller is its controlling process.
+    fn stream<'a>(
+        process: &RcProcess,
+        term: &'a Term,
+    ) -> std::result::Result<MutexGuard<'a, Stream>, Exception> {
+        let stream = Zstream::cast_from(term)?.0.lock();
+        if stream.controller != process.pid {
+            return Err(error(atom::NOT_ON_CONTROLLING_PROCESS));
+        }
+        if let Codec::Closed = stream.codec {
+            return Err(error(atom::NOT_INITIALIZED));
+        }
+        Ok(stream)
+    }
+
+    /// `{continue | finished, Output}`
+    fn progress(process: &RcProcess, more: bool, output: Vec<u8>) -> Term {
+        let heap = &process.context_mut().heap;
+        let status = if more {
+            atom!(CONTINUE)
+        } else {
+            atom!(FINISHED)
+        };
+        tup2!(heap, status, iolist(heap, output))
+    }
+
+    fn iolist(heap: &Heap, output: Vec<u8>) -> Term {
+        if output.is_empty() {
+            return Term::nil();
+        }
+        cons!(heap, Term::binary(heap, Binary::from(output)), Term::nil())
+    }
+
+    pub fn open_nif_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+        Ok(Term::zlib(heap, Zstream::new(process.pid)))
+    }
+
+    pub fn close_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        stream.codec = Codec::Closed;
+        stream.input.clear();
+        stream.clear_stash();
+        Ok(atom!(OK))
+    }
+
+    pub fn set_controller_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        match args[1].into_variant() {
+            Variant::Pid(pid) => stream.controller = pid,
+            _ => return Err(badarg!()),
+        }
+        Ok(atom!(OK))
+    }
+
+    pub fn deflate_init_nif_6(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let level = to_int(args[1])?;
+        let method = to_int(args[2])?;
+        let bits = to_int(args[3])?;
+        let mem_level = to_int(args[4])?;
+        let strategy = to_int(args[5])?;
+
+        let wrapper = match Wrapper::from_window_bits(bits) {
+            Some(Wrapper::Auto) | None => return Err(error(atom::STREAM_ERROR)),
+            Some(wrapper) => wrapper,
+        };
+        if !(-1..=9).contains(&level)
+            || method != Z_DEFLATED
+            || !(1..=9).contains(&mem_level)
+            || !(0..=4).contains(&strategy)
+        {
+            return Err(error(atom::STREAM_ERROR));
+        }
+        match stream.codec {
+            Codec::None => (),
+            _ => return Err(error(atom::STREAM_ERROR)),
+        }
+
+        stream.codec = Codec::Deflate(Box::new(Deflate::new(wrapper, level, strategy)));
+        stream.checksum = Checksum::new();
+        Ok(atom!(OK))
+    }
+
+    /// Returns the Adler-32 of the dictionary.
+    pub fn deflate_set_dictionary_nif_2(
+        _vm: &vm::Machine,
+        process: &RcProcess,
+        args: &[Term],
+    ) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let dictionary = list_to_iodata(args[1])?;
+        let id = match &mut stream.codec {
+            Codec::Deflate(deflate) => deflate.set_dictionary(&dictionary)?,
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        };
+        let heap = &process.context_mut().heap;
+        Ok(Term::uint(heap, id))
+    }
+
+    pub fn deflate_reset_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut guard = stream(process, &args[0])?;
+        let stream = &mut *guard;
+        match &mut stream.codec {
+            Codec::Deflate(deflate) => deflate.reset(),
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        }
+        stream.input.clear();
+        stream.checksum = Checksum::new();
+        Ok(atom!(OK))
+    }
+
+    /// zlib.erl sync flushes first, so the new compressor can carry on at a block boundary.
+    pub fn deflate_params_nif_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let level = to_int(args[1])?;
+        let strategy = to_int(args[2])?;
+        if !(-1..=9).contains(&level) || !(0..=4).contains(&strategy) {
+            return Err(error(atom::STREAM_ERROR));
+        }
+
+        match &mut stream.codec {
+            Codec::Deflate(deflate) => {
+                deflate.compressor = compressor(level, strategy);
+                deflate.level = level;
+                deflate.strategy = strategy;
+            }
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        }
+        Ok(atom!(OK))
+    }
+
+    pub fn deflate_nif_4(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut guard = stream(process, &args[0])?;
+        let stream = &mut *guard;
+        let in_chunk = to_size(args[1])?;
+        let out_chunk = to_size(args[2])?;
+        let flush = to_flush(args[3])?;
+
+        let (more, output) = match &mut stream.codec {
+            Codec::Deflate(deflate) => deflate.run(
+                &mut stream.input,
+                &mut stream.checksum,
+                in_chunk,
+                out_chunk,
+                flush,
+            )?,
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        };
+        Ok(progress(process, more, output))
+    }
+
+    /// Fails with data_error if the stream wasn't finished.
+    pub fn deflate_end_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let finished = match &stream.codec {
+            Codec::Deflate(deflate) => deflate.finished && deflate.pending.is_empty(),
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        };
+        let finished = finished && stream.input.len() == 0;
+        stream.codec = Codec::None;
+        stream.input.clear();
+
+        if !finished {
+            return Err(error(atom::DATA_ERROR));
+        }
+        Ok(atom!(OK))
+    }
+
+    pub fn inflate_init_nif_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let bits = to_int(args[1])?;
+        let eos_behavior = match to_int(args[2])? {
+            i @ EOS_BEHAVIOR_ERROR..=EOS_BEHAVIOR_CUT => i,
+            _ => return Err(badarg!()),
+        };
+
+        let wrapper = match Wrapper::from_window_bits(bits) {
+            Some(wrapper) => wrapper,
+            None => return Err(error(atom::STREAM_ERROR)),
+        };
+        match stream.codec {
+            Codec::None => (),
+            _ => return Err(error(atom::STREAM_ERROR)),
+        }
+
+        stream.codec = Codec::Inflate(Box::new(Inflate::new(wrapper, eos_behavior)));
+        stream.checksum = Checksum::new();
+        Ok(atom!(OK))
+    }
+
+    pub fn inflate_set_dictionary_nif_2(
+        _vm: &vm::Machine,
+        process: &RcProcess,
+        args: &[Term],
+    ) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let dictionary = list_to_iodata(args[1])?;
+        match &mut stream.codec {
+            Codec::Inflate(inflate) => inflate.set_dictionary(&dictionary)?,
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        }
+        Ok(atom!(OK))
+    }
+
+    /// zlib.erl turns this into an enotsup error.
+    pub fn inflate_get_dictionary_nif_1(
+        _vm: &vm::Machine,
+        process: &RcProcess,
+        args: &[Term],
+    ) -> Result {
+        let _stream = stream(process, &args[0])?;
+        Ok(atom!(NOT_SUPPORTED))
+    }
+
+    pub fn inflate_reset_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut guard = stream(process, &args[0])?;
+        let stream = &mut *guard;
+        match &mut stream.codec {
+            Codec::Inflate(inflate) => inflate.reset(),
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        }
+        stream.input.clear();
+        stream.checksum = Checksum::new();
+        Ok(atom!(OK))
+    }
+
+    /// Returns `{continue | finished, Output}` or `{need_dictionary, Adler, Output}`.
+    pub fn inflate_nif_4(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut guard = stream(process, &args[0])?;
+        let stream = &mut *guard;
+        let in_chunk = to_size(args[1])?;
+        let out_chunk = to_size(args[2])?;
+        // inflate makes progress regardless of flushing
+        to_flush(args[3])?;
+
+        let (status, output) = match &mut stream.codec {
+            Codec::Inflate(inflate) => {
+                inflate.run(&mut stream.input, &mut stream.checksum, in_chunk, out_chunk)?
+            }
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        };
+        match status {
+            Inflated::More(more) => Ok(progress(process, more, output)),
+            Inflated::NeedDictionary(adler) => {
+                let heap = &process.context_mut().heap;
+                Ok(tup3!(
+                    heap,
+                    atom!(NEED_DICTIONARY),
+                    Term::uint(heap, adler),
+                    iolist(heap, output)
+                ))
+            }
+        }
+    }
+
+    /// Fails with data_error if the end of the stream wasn't reached.
+    pub fn inflate_end_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        let finished = match &stream.codec {
+            Codec::Inflate(inflate) => inflate.stage == Stage::End,
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        };
+        stream.codec = Codec::None;
+        stream.input.clear();
+
+        if !finished {
+            return Err(error(atom::DATA_ERROR));
+        }
+        Ok(atom!(OK))
+    }
+
+    /// The CRC-32 of the uncompressed data so far.
+    pub fn crc32_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let crc = stream(process, &args[0])?.checksum.crc();
+        let heap = &process.context_mut().heap;
+        Ok(Term::uint(heap, crc))
+    }
+
+    pub fn enqueue_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        match stream.codec {
+            Codec::Deflate(..) | Codec::Inflate(..) => (),
+            _ => return Err(error(atom::NOT_INITIALIZED)),
+        }
+
+        // validate everything first so a bad iovec doesn't leave a partial write behind
+        let mut iovec = Vec::new();
+        if !args[1].is_nil() {
+            for term in Cons::cast_from(&args[1])?.iter() {
+                if !term.is_binary() {
+                    return Err(badarg!());
+                }
+                iovec.push(term.to_bytes().unwrap());
+            }
+        }
+        for bytes in iovec {
+            stream.input.push(bytes);
+        }
+        Ok(atom!(OK))
+    }
+
+    pub fn get_buf_size_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let bufsize = stream(process, &args[0])?.bufsize;
+        let heap = &process.context_mut().heap;
+        Ok(Term::uint64(heap, bufsize as u64))
+    }
+
+    pub fn set_buf_size_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut stream = stream(process, &args[0])?;
+        stream.bufsize = to_size(args[1])?;
+        Ok(atom!(OK))
+    }
+
+    /// Returns `{ok, Term}` or `error`.
+    pub fn get_stash_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let stream = stream(process, &args[0])?;
+        let heap = &process.context_mut().heap;
+        match &stream.stash {
+            Some(term) => Ok(tup2!(heap, atom!(OK), term.deep_clone(heap))),
+            None => Ok(atom!(ERROR)),
+        }
+    }
+
+    pub fn set_stash_nif_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let mut guard = stream(process, &args[0])?;
+        let stream = &mut *guard;
+        stream.clear_stash();
+        stream.stash = Some(args[1].deep_clone(&stream.stash_heap));
+        Ok(atom!(OK))
+    }
+
+    pub fn clear_stash_nif_1(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        stream(process, &args[0])?.clear_stash();
+        Ok(atom!(OK))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bif::*;
+    use super::*;
+    use crate::module;
+    use crate::process;
+    use std::io::Read;
+
+    const NONE: i32 = 0;
+    const SYNC: i32 = 2;
+    const FINISH: i32 = 4;
+
+    fn enqueue(vm: &vm::Machine, process: &RcProcess, z: Term, data: &[u8]) {
+        let heap = &process.context_mut().heap;
+        let iovec = cons!(heap, Term::binary(heap, Binary::from(data)), Term::nil());
+        assert_eq!(enqueue_nif_2(vm, process, &[z, iovec]), Ok(atom!(OK)));
+    }
+
+    /// Runs a NIF with small chunks until it's finished, like zlib.erl does.
+    fn drain<F>(mut step: F) -> Result<Vec<u8>, Exception>
+    where
+        F: FnMut() -> crate::bif::Result,
+    {
+        let mut output = Vec::new();
+        loop {
+            let res = step()?;
+            let tuple = value::Tuple::cast_from(&res).unwrap();
+            if let Ok(list) = Cons::cast_from(&tuple[1]) {
+                for bin in list.iter() {
+                    output.extend_from_slice(bin.to_bytes().unwrap());
+                }
+            }
+            if tuple[0] == atom!(FINISHED) {
+                return Ok(output);
+            }
+            assert_eq!(tuple[0], atom!(CONTINUE));
+        }
+    }
+
+    fn deflate(vm: &vm::Machine, process: &RcProcess, z: Term, data: &[u8], flush: i32) -> Vec<u8> {
+        enqueue(vm, process, z, data);
+        let args = [z, Term::int(64), Term::int(16), Term::int(flush)];
+        drain(|| deflate_nif_4(vm, process, &args)).unwrap()
+    }
+
+    fn inflate(
+        vm: &vm::Machine,
+        process: &RcProcess,
+        z: Term,
+        data: &[u8],
+    ) -> Result<Vec<u8>, Exception> {
+        enqueue(vm, process, z, data);
+        let args = [z, Term::int(64), Term::int(16), Term::int(NONE)];
+        drain(|| inflate_nif_4(vm, process, &args))
+    }
+
+    fn compress(bits: i32, data: &[u8]) -> Vec<u8> {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [
+            z,
+            Term::int(-1),
+            Term::int(8),
+            Term::int(bits),
+            Term::int(8),
+            Term::int(0),
+        ];
+        assert_eq!(deflate_init_nif_6(&vm, &process, &args), Ok(atom!(OK)));
+        let compressed = deflate(&vm, &process, z, data, FINISH);
+        assert_eq!(deflate_end_nif_1(&vm, &process, &[z]), Ok(atom!(OK)));
+        compressed
+    }
+
+    fn uncompress(bits: i32, eos: i32, data: &[u8]) -> Result<Vec<u8>, Exception> {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [z, Term::int(bits), Term::int(eos)];
+        assert_eq!(inflate_init_nif_3(&vm, &process, &args), Ok(atom!(OK)));
+        let output = inflate(&vm, &process, z, data)?;
+        inflate_end_nif_1(&vm, &process, &[z])?;
+        Ok(output)
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..2000u32)
+            .flat_map(|i| format!("line {} of {}\n", i % 17, i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = sample();
+
+        let raw = compress(-15, &data);
+        let mut out = Vec::new();
+        libflate::deflate::Decoder::new(&raw[..])
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(uncompress(-15, EOS_BEHAVIOR_CUT, &raw), Ok(data.clone()));
+
+        let zlib = compress(15, &data);
+        assert_eq!(zlib[..2], [0x78, 0x9c]);
+        let mut out = Vec::new();
+        libflate::zlib::Decoder::new(&zlib[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(uncompress(15, EOS_BEHAVIOR_CUT, &zlib), Ok(data.clone()));
+
+        let gzip = compress(31, &data);
+        let mut out = Vec::new();
+        libflate::gzip::Decoder::new(&gzip[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        // detected from the header
+        assert_eq!(uncompress(47, EOS_BEHAVIOR_CUT, &gzip), Ok(data.clone()));
+        assert_eq!(uncompress(47, EOS_BEHAVIOR_CUT, &zlib), Ok(data.clone()));
+
+        // gzip written by someone else, with a file name in the header
+        let header = libflate::gzip::HeaderBuilder::new()
+            .filename(std::ffi::CString::new("data.txt").unwrap())
+            .finish();
+        let options = libflate::gzip::EncodeOptions::new().header(header);
+        let mut encoder = libflate::gzip::Encoder::with_options(Vec::new(), options).unwrap();
+        std::io::Write::write_all(&mut encoder, &data).unwrap();
+        let gzip = encoder.finish().into_result().unwrap();
+        assert_eq!(uncompress(31, EOS_BEHAVIOR_CUT, &gzip), Ok(data));
+    }
+
+    #[test]
+    fn test_sync_flush() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [
+            z,
+            Term::int(-1),
+            Term::int(8),
+            Term::int(31),
+            Term::int(8),
+            Term::int(0),
+        ];
+        deflate_init_nif_6(&vm, &process, &args).unwrap();
+        let first = deflate(&vm, &process, z, b"hello ", SYNC);
+        assert_eq!(first[first.len() - 4..], [0, 0, 0xff, 0xff]);
+
+        // everything written so far can be decompressed
+        let d = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [d, Term::int(31), Term::int(EOS_BEHAVIOR_ERROR)];
+        inflate_init_nif_3(&vm, &process, &args).unwrap();
+        assert_eq!(inflate(&vm, &process, d, &first), Ok(b"hello ".to_vec()));
+
+        // and switching levels carries on with the same stream
+        let args = [z, Term::int(9), Term::int(0)];
+        assert_eq!(deflate_params_nif_3(&vm, &process, &args), Ok(atom!(OK)));
+        let rest = deflate(&vm, &process, z, b"world", FINISH);
+        assert_eq!(inflate(&vm, &process, d, &rest), Ok(b"world".to_vec()));
+        assert_eq!(inflate_end_nif_1(&vm, &process, &[d]), Ok(atom!(OK)));
+
+        let crc = crc32_nif_1(&vm, &process, &[z]).unwrap();
+        let expected = crc32fast::hash(b"hello world");
+        assert_eq!(crc, Term::uint(&process.context_mut().heap, expected));
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+        let dictionary = Term::binary(heap, Binary::from(&b"line 1 of 2\n"[..]));
+        let id = Term::uint(heap, 421_528_395);
+        let data = b"line 1 of 2\nline 1 of 2\n";
+        let need_dictionary = tup3!(heap, atom!(NEED_DICTIONARY), id, Term::nil());
+
+        // written by zlib with the same dictionary
+        let zlib = [
+            120, 249, 25, 32, 3, 75, 203, 193, 193, 6, 0, 89, 184, 6, 149,
+        ];
+        let d = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [d, Term::int(15), Term::int(EOS_BEHAVIOR_ERROR)];
+        inflate_init_nif_3(&vm, &process, &args).unwrap();
+        enqueue(&vm, &process, d, &zlib);
+        let args = [d, Term::int(64), Term::int(16), Term::int(NONE)];
+        assert_eq!(inflate_nif_4(&vm, &process, &args), Ok(need_dictionary));
+        let wrong = Term::binary(heap, Binary::from(&b"line 2"[..]));
+        let res = inflate_set_dictionary_nif_2(&vm, &process, &[d, wrong]);
+        assert_eq!(res, Err(error(atom::DATA_ERROR)));
+        let res = inflate_set_dictionary_nif_2(&vm, &process, &[d, dictionary]);
+        assert_eq!(res, Ok(atom!(OK)));
+        assert_eq!(inflate(&vm, &process, d, &[]), Ok(data.to_vec()));
+        assert_eq!(inflate_end_nif_1(&vm, &process, &[d]), Ok(atom!(OK)));
+
+        // our own stream announces it in the header too
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [
+            z,
+            Term::int(-1),
+            Term::int(8),
+            Term::int(15),
+            Term::int(8),
+            Term::int(0),
+        ];
+        deflate_init_nif_6(&vm, &process, &args).unwrap();
+        let res = deflate_set_dictionary_nif_2(&vm, &process, &[z, dictionary]);
+        assert_eq!(res, Ok(id));
+        let compressed = deflate(&vm, &process, z, data, FINISH);
+        assert_eq!(compressed[1] & 0x20, 0x20);
+        let res = deflate_set_dictionary_nif_2(&vm, &process, &[z, dictionary]);
+        assert_eq!(res, Err(error(atom::STREAM_ERROR)));
+
+        let args = [d, Term::int(15), Term::int(EOS_BEHAVIOR_ERROR)];
+        inflate_init_nif_3(&vm, &process, &args).unwrap();
+        enqueue(&vm, &process, d, &compressed);
+        let args = [d, Term::int(64), Term::int(16), Term::int(NONE)];
+        assert_eq!(inflate_nif_4(&vm, &process, &args), Ok(need_dictionary));
+        inflate_set_dictionary_nif_2(&vm, &process, &[d, dictionary]).unwrap();
+        assert_eq!(inflate(&vm, &process, d, &[]), Ok(data.to_vec()));
+        assert_eq!(inflate_end_nif_1(&vm, &process, &[d]), Ok(atom!(OK)));
+
+        // raw streams take it before the first block, and can't do without it
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let args = [
+            z,
+            Term::int(-1),
+            Term::int(8),
+            Term::int(-15),
+            Term::int(8),
+            Term::int(0),
+        ];
+        deflate_init_nif_6(&vm, &process, &args).unwrap();
+        deflate_set_dictionary_nif_2(&vm, &process, &[z, dictionary]).unwrap();
+        let raw = deflate(&vm, &process, z, data, FINISH);
+        assert_ne!(uncompress(-15, EOS_BEHAVIOR_CUT, &raw), Ok(data.to_vec()));
+
+        let args = [d, Term::int(-15), Term::int(EOS_BEHAVIOR_ERROR)];
+        inflate_init_nif_3(&vm, &process, &args).unwrap();
+        inflate_set_dictionary_nif_2(&vm, &process, &[d, dictionary]).unwrap();
+        assert_eq!(inflate(&vm, &process, d, &raw), Ok(data.to_vec()));
+        let res = inflate_set_dictionary_nif_2(&vm, &process, &[d, dictionary]);
+        assert_eq!(res, Err(error(atom::STREAM_ERROR)));
+    }
+
+    #[test]
+    fn test_end_of_stream() {
+        let mut members = compress(31, b"first ");
+        members.extend(compress(31, b"second"));
+
+        assert_eq!(
+            uncompress(31, EOS_BEHAVIOR_RESET, &members),
+            Ok(b"first second".to_vec())
+        );
+        assert_eq!(
+            uncompress(31, EOS_BEHAVIOR_CUT, &members),
+            Ok(b"first ".to_vec())
+        );
+        let data_error = Err(error(atom::DATA_ERROR));
+        assert_eq!(uncompress(31, EOS_BEHAVIOR_ERROR, &members), data_error);
+
+        // truncated or corrupt streams
+        let zlib = compress(15, b"some data");
+        assert_eq!(
+            uncompress(15, EOS_BEHAVIOR_CUT, &zlib[..zlib.len() - 1]),
+            data_error
+        );
+        let mut corrupt = zlib.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(uncompress(15, EOS_BEHAVIOR_CUT, &corrupt), data_error);
+        assert_eq!(uncompress(31, EOS_BEHAVIOR_CUT, &zlib), data_error);
+    }
+
+    #[test]
+    fn test_stream_state() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let z = open_nif_0(&vm, &process, &[]).unwrap();
+        let not_initialized = Err(error(atom::NOT_INITIALIZED));
+        let args = [z, Term::int(64), Term::int(16), Term::int(NONE)];
+        assert_eq!(deflate_nif_4(&vm, &process, &args), not_initialized);
+
+        // the stash survives in its own heap
+        assert_eq!(get_stash_nif_1(&vm, &process, &[z]), Ok(atom!(ERROR)));
+        let heap = &process.context_mut().heap;
+        let stash = tup2!(heap, atom!(OK), Term::binary(heap, Binary::from(&b"x"[..])));
+        set_stash_nif_2(&vm, &process, &[z, stash]).unwrap();
+        let res = get_stash_nif_1(&vm, &process, &[z]).unwrap();
+        assert_eq!(res, tup2!(heap, atom!(OK), stash));
+        // replacing it reuses the heap
+        set_stash_nif_2(&vm, &process, &[z, atom!(OK)]).unwrap();
+        let res = get_stash_nif_1(&vm, &process, &[z]).unwrap();
+        assert_eq!(res, tup2!(heap, atom!(OK), atom!(OK)));
+        clear_stash_nif_1(&vm, &process, &[z]).unwrap();
+        assert_eq!(get_stash_nif_1(&vm, &process, &[z]), Ok(atom!(ERROR)));
+
+        // only the controlling process may use the stream
+        let pid = Term::pid(process.pid + 1);
+        assert_eq!(
+            set_controller_nif_2(&vm, &process, &[z, pid]),
+            Ok(atom!(OK))
+        );
+        assert_eq!(
+            close_nif_1(&vm, &process, &[z]),
+            Err(error(atom::NOT_ON_CONTROLLING_PROCESS))
+        );
+    }
+}
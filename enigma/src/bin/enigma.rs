@@ -1,4 +1,5 @@
-use libenigma::vm;
+use libenigma::process::table::{MAX_PROCESS_LIMIT, MIN_PROCESS_LIMIT};
+use libenigma::{atom, vm};
 
 use std::env;
 use std::process;
@@ -13,7 +14,7 @@ fn run() -> i32 {
     // )])
     // .unwrap();
 
-    let _args: Vec<String> = env::args().collect();
+    let cli_args: Vec<String> = env::args().collect();
 
     // std::panic::set_hook(Box::new(|panic_info| {
     //     let backtrace = backtrace::Backtrace::new();
@@ -23,6 +24,40 @@ fn run() -> i32 {
 
     let vm = vm::Machine::new();
 
+    // emulator flags
+    let mut flags = cli_args.iter().skip(1);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            // maximum number of atoms
+            "+t" => {
+                let arg = flags.next().map_or("", String::as_str);
+                match arg.parse() {
+                    Ok(limit) if limit >= atom::MIN_ATOM_LIMIT && limit <= atom::MAX_ATOM_LIMIT => {
+                        atom::set_limit(limit)
+                    }
+                    _ => {
+                        eprintln!("bad atom table size {}", arg);
+                        return usage();
+                    }
+                }
+            }
+            // maximum number of processes
+            "+P" => {
+                let arg = flags.next().map_or("", String::as_str);
+                match arg.parse() {
+                    Ok(limit) if limit >= MIN_PROCESS_LIMIT && limit <= MAX_PROCESS_LIMIT => {
+                        vm.process_table.lock().set_limit(limit)
+                    }
+                    _ => {
+                        eprintln!("bad number of processes {}", arg);
+                        return usage();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
     // erlexec defaults:
     let args: Vec<String> = vec![
         // "/Users/speed/src/rust/enigma/target/debug/enigma",
@@ -84,6 +119,23 @@ fn run() -> i32 {
     0
 }
 
+/// Prints the emulator flags, like erl does when it's given a bad one. Returns the exit code.
+fn usage() -> i32 {
+    eprintln!("Usage: enigma [flags] [ -- [init_args] ]");
+    eprintln!("The flags are:");
+    eprintln!();
+    eprintln!(
+        "+t size       set the maximum number of atoms the emulator can handle\n              valid range is [{}-{}]",
+        atom::MIN_ATOM_LIMIT,
+        atom::MAX_ATOM_LIMIT
+    );
+    eprintln!(
+        "+P number     set maximum number of processes on this node\n              valid range is [{}-{}]",
+        MIN_PROCESS_LIMIT, MAX_PROCESS_LIMIT
+    );
+    1
+}
+
 fn main() {
     process::exit(run());
 }
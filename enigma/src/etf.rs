@@ -1,11 +1,15 @@
 use crate::atom::{self, Atom};
 use crate::bitstring;
+use crate::exception::{Exception, Reason};
 use crate::immix::Heap;
 use crate::module;
+use crate::process::table::{make_pid_truncated, pid_number, pid_serial, PID};
 use crate::value::{self, Term, Variant, HAMT};
 use nom::*;
 use num_bigint::{BigInt, Sign};
 use num_traits::ToPrimitive;
+// nom exports its own `Err`, keep the prelude one
+use std::result::Result::Err;
 
 /// External Term Format parser
 
@@ -43,36 +47,51 @@ enum Tag {
     SmallAtomU8 = 119,
 }
 
+/// Error code for a parse that failed because the atom table is full.
+const ATOM_LIMIT: u32 = 1;
+
+/// Decodes a trusted term, like a literal from a module that's being loaded.
 pub fn decode<'a>(bytes: &'a [u8], heap: &Heap) -> Term {
+    try_decode(bytes, heap).expect("invalid external term")
+}
+
+/// Decodes a term received from user code (`binary_to_term/1`), failing with `badarg` if the
+/// encoding is invalid or `system_limit` if it would create more atoms than the table can hold.
+pub fn try_decode<'a>(bytes: &'a [u8], heap: &Heap) -> Result<Term, Exception> {
     // starts with  be_u8 that's 131
-    let (rest, ver) = be_u8(bytes).unwrap();
-    assert_eq!(ver, 131, "Expected ETF version number to be 131!");
+    let rest = match be_u8(bytes) {
+        Ok((rest, 131)) => rest,
+        _ => return Err(badarg!()),
+    };
 
     // check if followed by 80+size, then decode
     // The compressed term format is as follows:
     // 1	1	4	N
     // 131	80	UncompressedSize	Zlib-compressedData
 
-    if rest[0] == 80 {
+    let res = if rest.first() == Some(&80) {
         use libflate::zlib;
         use std::io::Read;
-        let (rest, _) = be_u8(rest).unwrap();
-        let (rest, size) = be_u32(rest).unwrap();
+        let (rest, size) = be_u32(&rest[1..]).map_err(|_| badarg!())?;
 
-        let mut data = Vec::with_capacity(size as usize);
+        // the size is only a hint, don't let it allocate more than zlib can expand the data to
+        let mut data = Vec::with_capacity(std::cmp::min(size as usize, rest.len() * 1032));
 
-        // let iocursor = std::io::Cursor::new(rest);
         zlib::Decoder::new(rest)
-            .unwrap()
-            .read_to_end(&mut data)
-            .unwrap();
+            .and_then(|mut decoder| decoder.read_to_end(&mut data))
+            .map_err(|_| badarg!())?;
 
-        let (_, term) = decode_value(&data, heap).unwrap();
-        term
+        decode_value(&data, heap).map(|(_, term)| term)
     } else {
-        let (_, term) = decode_value(rest, heap).unwrap();
-        term
-    }
+        decode_value(rest, heap).map(|(_, term)| term)
+    };
+
+    res.map_err(|err| match err {
+        nom::Err::Failure(Context::Code(_, ErrorKind::Custom(ATOM_LIMIT))) => {
+            Exception::new(Reason::EXC_SYSTEM_LIMIT)
+        }
+        _ => badarg!(),
+    })
 }
 
 fn decode_value<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Term> {
@@ -99,20 +118,38 @@ fn decode_value<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Term> {
         // Float: outdated? in favour of NewFloat
         // Reference
         // Port
-        // Pid
+        Tag::Pid => {
+            let (rest, _node) = decode_value(rest, heap)?;
+            let (rest, id) = be_u32(rest)?;
+            let (rest, serial) = be_u32(rest)?;
+            let (rest, _creation) = be_u8(rest)?;
+            Ok((rest, Term::pid(make_pid_truncated(id, serial))))
+        }
+        Tag::NewPid => {
+            // TODO: we only have a single node, so the node and creation are ignored
+            let (rest, _node) = decode_value(rest, heap)?;
+            let (rest, id) = be_u32(rest)?;
+            let (rest, serial) = be_u32(rest)?;
+            let (rest, _creation) = be_u32(rest)?;
+            Ok((rest, Term::pid(make_pid_truncated(id, serial))))
+        }
         Tag::String => decode_string(rest, heap),
         Tag::Binary => decode_binary(rest, heap),
         Tag::BitBinary => decode_bitstring(rest, heap),
         // NewFun
         Tag::Export => decode_export(rest, heap),
         // NewReference
-        // SmallAtom (deprecated?)
         Tag::Map => decode_map(rest, heap),
         // Tag::Fun => panic!("etf: Fun tag is deprecated!"),
-        // AtomU8
-        // SmallAtomU8
         Tag::List => decode_list(rest, heap),
-        Tag::Atom => decode_atom(rest),
+        Tag::Atom | Tag::AtomU8 => {
+            let (rest, len) = be_u16(rest)?;
+            decode_atom(rest, len)
+        }
+        Tag::SmallAtom | Tag::SmallAtomU8 => {
+            let (rest, len) = be_u8(rest)?;
+            decode_atom(rest, u16::from(len))
+        }
         Tag::Nil => Ok((rest, Term::nil())),
         Tag::SmallTuple => {
             let (rest, size) = be_u8(rest)?;
@@ -135,27 +172,36 @@ fn decode_value<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Term> {
     }
 }
 
-fn decode_atom(rest: &[u8]) -> IResult<&[u8], Term> {
-    let (rest, len) = be_u16(rest)?;
+fn decode_atom(rest: &[u8], len: u16) -> IResult<&[u8], Term> {
     let (rest, string) = take_str!(rest, len)?;
 
-    // TODO: create atom &string
-    Ok((rest, Term::atom(Atom::from(string))))
+    match Atom::try_from_str(string) {
+        Ok(atom) => Ok((rest, Term::atom(atom))),
+        Err(_) => Err(nom::Err::Failure(Context::Code(
+            rest,
+            ErrorKind::Custom(ATOM_LIMIT),
+        ))),
+    }
 }
 
 fn decode_tuple<'a>(rest: &'a [u8], len: u32, heap: &Heap) -> IResult<&'a [u8], Term> {
     // alloc space for elements
     let tuple = value::tuple(heap, len);
 
-    // TODO: nested tuples are dropped and then segfault, prevent that!
-    let rest = (0..len).fold(rest, |rest, i| {
-        let (rest, el) = decode_value(rest, heap).unwrap();
-        // use ptr write to avoid dropping uninitialized values!
+    // use ptr write to avoid dropping uninitialized values! Every element gets initialized
+    // upfront, so the tuple is still valid if decoding bails out halfway through.
+    for i in 0..len as usize {
         unsafe {
-            std::ptr::write(&mut tuple[i as usize], el);
+            std::ptr::write(&mut tuple[i], Term::nil());
         }
-        rest
-    });
+    }
+
+    let mut rest = rest;
+    for i in 0..len as usize {
+        let (new_rest, el) = decode_value(rest, heap)?;
+        tuple[i] = el;
+        rest = new_rest;
+    }
 
     Ok((rest, tuple.into()))
 }
@@ -171,21 +217,22 @@ fn decode_list<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Term> {
             tail: Term::nil(),
         });
 
-        let (tail, rest) =
-            (0..len - 1).fold((start as *mut value::Cons, rest), |(cons, rest), _i| {
-                let value::Cons { ref mut tail, .. } = *cons;
-                let (rest, val) = decode_value(rest, heap).unwrap();
-                let new_cons = heap.alloc(value::Cons {
-                    head: val,
-                    tail: Term::nil(),
-                });
-                let ptr = new_cons as *mut value::Cons;
-                std::mem::replace(&mut *tail, Term::from(new_cons));
-                (ptr, rest)
+        let mut tail = start as *mut value::Cons;
+        let mut rest = rest;
+        for _ in 1..len {
+            let (new_rest, val) = decode_value(rest, heap)?;
+            let new_cons = heap.alloc(value::Cons {
+                head: val,
+                tail: Term::nil(),
             });
+            let ptr = new_cons as *mut value::Cons;
+            (*tail).tail = Term::from(new_cons);
+            tail = ptr;
+            rest = new_rest;
+        }
 
         // set the tail
-        let (rest, val) = decode_value(rest, heap).unwrap();
+        let (rest, val) = decode_value(rest, heap)?;
         (*tail).tail = val;
 
         Ok((rest, Term::from(start)))
@@ -267,7 +314,8 @@ fn decode_bitstring<'a>(rest: &'a [u8], heap: &Heap) -> IResult<&'a [u8], Term>
 
     let (rest, bytes) = take!(rest, len)?;
     let bin = crate::servo_arc::Arc::new(bitstring::Binary::from(bytes));
-    let num_bits = len as usize * 8 + bits as usize;
+    // bits is the number of bits used in the last byte
+    let num_bits = (len as usize).saturating_sub(1) * 8 + bits as usize;
     Ok((
         rest,
         Term::subbinary(heap, bitstring::SubBinary::new(bin, num_bits, 0, false)),
@@ -326,7 +374,7 @@ pub fn encode(term: Term) -> std::io::Result<Vec<u8>> {
 }
 
 fn encode_term(res: &mut Vec<u8>, term: Term) -> std::io::Result<()> {
-    use value::{CastFrom, Cons, Tuple};
+    use value::{CastFrom, Cons, Map, Tuple};
 
     match term.into_variant() {
         Variant::Integer(i) => {
@@ -346,6 +394,7 @@ fn encode_term(res: &mut Vec<u8>, term: Term) -> std::io::Result<()> {
             encode_atom(res, atom)?;
         }
         Variant::Float(value::Float(f)) => encode_float(res, f)?,
+        Variant::Pid(pid) => encode_pid(res, pid)?,
         Variant::Cons(..) => encode_list(res, Cons::cast_from(&term).unwrap())?,
         // encode list
         // encode improper list
@@ -354,17 +403,29 @@ fn encode_term(res: &mut Vec<u8>, term: Term) -> std::io::Result<()> {
             value::BOXED_BINARY => {
                 encode_binary(res, bitstring::RcBinary::cast_from(&term).unwrap())?
             }
+            value::BOXED_SUBBINARY => {
+                encode_subbinary(res, bitstring::SubBinary::cast_from(&term).unwrap())?
+            }
+            value::BOXED_MAP => encode_map(res, Map::cast_from(&term).unwrap())?,
             value::BOXED_BIGINT => {
                 let value = &term.get_boxed_value::<BigInt>().unwrap();
                 encode_bigint(res, value)?
             }
-            i => unimplemented!("etf::encode for boxed {}", i),
+            // TODO: references, funs and ports
+            i => return Err(unsupported(&format!("boxed {}", i))),
         },
-        _ => unimplemented!("etf::encode for: {}", term),
+        _ => return Err(unsupported(&term.to_string())),
     }
     Ok(())
 }
 
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("etf::encode for: {}", what),
+    )
+}
+
 fn encode_nil(res: &mut Vec<u8>) -> std::io::Result<()> {
     res.write_u8(Tag::Nil as u8)
 }
@@ -394,6 +455,16 @@ fn encode_atom(res: &mut Vec<u8>, atom: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+fn encode_pid(res: &mut Vec<u8>, pid: PID) -> std::io::Result<()> {
+    res.write_u8(Tag::NewPid as u8)?;
+    encode_atom(res, atom::NO_NODE_NO_HOST.to_str().unwrap())?;
+    res.write_u32::<BigEndian>(pid_number(pid))?;
+    res.write_u32::<BigEndian>(pid_serial(pid))?;
+    // creation
+    res.write_u32::<BigEndian>(0)?;
+    Ok(())
+}
+
 fn encode_float(res: &mut Vec<u8>, float: f64) -> std::io::Result<()> {
     res.write_u8(Tag::NewFloat as u8)?;
     res.write_f64::<BigEndian>(float)?;
@@ -401,7 +472,46 @@ fn encode_float(res: &mut Vec<u8>, float: f64) -> std::io::Result<()> {
 }
 
 fn encode_binary(res: &mut Vec<u8>, binary: &bitstring::RcBinary) -> std::io::Result<()> {
-    unimplemented!()
+    res.write_u8(Tag::Binary as u8)?;
+    res.write_u32::<BigEndian>(binary.data.len() as u32)?;
+    res.write_all(&binary.data)?;
+    Ok(())
+}
+
+fn encode_subbinary(res: &mut Vec<u8>, binary: &bitstring::SubBinary) -> std::io::Result<()> {
+    let num_bits = binary.size * 8 + binary.bitsize;
+    let mut bytes = vec![0; (num_bits + 7) / 8];
+    unsafe {
+        bitstring::copy_bits(
+            binary.original.data.as_ptr(),
+            binary.offset * 8 + binary.bit_offset as usize,
+            1,
+            bytes.as_mut_ptr(),
+            0,
+            1,
+            num_bits,
+        );
+    }
+    if binary.bitsize == 0 {
+        res.write_u8(Tag::Binary as u8)?;
+        res.write_u32::<BigEndian>(bytes.len() as u32)?;
+    } else {
+        res.write_u8(Tag::BitBinary as u8)?;
+        res.write_u32::<BigEndian>(bytes.len() as u32)?;
+        res.write_u8(binary.bitsize as u8)?;
+    }
+    res.write_all(&bytes)?;
+    Ok(())
+}
+
+fn encode_map(res: &mut Vec<u8>, map: &value::Map) -> std::io::Result<()> {
+    res.write_u8(Tag::Map as u8)?;
+    res.write_u32::<BigEndian>(map.0.len() as u32)?;
+    for (key, val) in map.0.iter() {
+        encode_term(res, *key)?;
+        encode_term(res, *val)?;
+    }
+    Ok(())
 }
 
 fn encode_list(res: &mut Vec<u8>, list: &value::Cons) -> std::io::Result<()> {
@@ -448,3 +558,49 @@ fn encode_bigint(res: &mut Vec<u8>, bigint: &BigInt) -> std::io::Result<()> {
     res.write_all(&bytes)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom_ext(name: &str) -> Vec<u8> {
+        let mut bytes = vec![100];
+        bytes.write_u16::<BigEndian>(name.len() as u16).unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_decode_atom_system_limit() {
+        let heap = &Heap::new();
+        // atoms that can't be created (the table is full, or the name too long) raise system_limit
+        let name = "a".repeat(atom::MAX_ATOM_CHARS + 1);
+
+        let mut bytes = vec![131];
+        bytes.extend(atom_ext(&name));
+        let err = try_decode(&bytes, heap).unwrap_err();
+        assert_eq!(err.reason, Reason::EXC_SYSTEM_LIMIT);
+
+        // nested in a tuple
+        let mut bytes = vec![131, 104, 2];
+        bytes.extend(atom_ext("true"));
+        bytes.extend(atom_ext(&name));
+        let err = try_decode(&bytes, heap).unwrap_err();
+        assert_eq!(err.reason, Reason::EXC_SYSTEM_LIMIT);
+
+        let mut bytes = vec![131];
+        bytes.extend(atom_ext(&name[1..]));
+        let term = try_decode(&bytes, heap).unwrap();
+        assert_eq!(term.to_atom().unwrap().to_str(), Some(&name[1..]));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let heap = &Heap::new();
+        assert!(try_decode(&[], heap).is_err());
+        assert!(try_decode(&[130, 97, 1], heap).is_err());
+        // truncated list
+        assert!(try_decode(&[131, 108, 0, 0, 0, 2, 97, 1], heap).is_err());
+        assert_eq!(try_decode(&[131, 97, 1], heap), Ok(Term::int(1)));
+    }
+}
@@ -1,13 +1,19 @@
 use crate::atom::Atom;
-use crate::value::Term;
+use crate::bitstring;
+use crate::etf;
+use crate::immix::Heap;
+use crate::value::{self, BigInt, CastFrom, CastIntoMut, Cons, Term, Tuple};
 use crate::vm;
 //use crate::servo_arc::Arc;
 
 use crate::process::{self, RcProcess};
-use hashbrown::HashMap;
-use parking_lot::Mutex;
+use hashbrown::{HashMap, HashSet};
+use parking_lot::{Mutex, RwLock};
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc; // servo_arc doesn't work with trait objects
+use std::time::{Duration, SystemTime};
 
 #[macro_export]
 macro_rules! table_kind {
@@ -25,13 +31,19 @@ macro_rules! table_protection {
 
 pub mod bag;
 pub mod bif;
+pub mod ca_ordered_set;
 pub mod hash_table;
+mod lock;
+mod order;
 pub mod ordered_set;
 pub mod pam;
+mod range;
+mod segment;
 
 pub mod error;
 // use std::error::Error;
 pub use error::Result;
+use error::{new_error, ErrorKind};
 
 /// Represents an interface to a single table.
 pub trait Table: Send + Sync {
@@ -57,14 +69,35 @@ pub trait Table: Send + Sync {
 
     fn member(&self, key: Term) -> bool;
 
-    fn update_element(&self, process: &RcProcess, key: Term, list: Term) -> Result<Term>;
+    /// Updates elements of the object, `list` is `[{Pos, Value}]`. If the key doesn't exist,
+    /// `default` is inserted first, otherwise `false` is returned.
+    fn update_element(
+        &self,
+        process: &RcProcess,
+        key: Term,
+        list: Term,
+        default: Option<Term>,
+    ) -> Result<Term>;
+
+    /// Atomically applies counter operations to the object, inserting `default` first if the
+    /// key doesn't exist. Returns the new counter values.
+    fn update_counter(
+        &self,
+        process: &RcProcess,
+        key: Term,
+        ops: &[CounterOp],
+        default: Option<Term>,
+    ) -> Result<Vec<BigInt>>;
 
     // erase  (remove_entry in rust)
     fn remove(&self, key: Term) -> Result<Term>;
 
-    fn remove_object(&mut self, object: Term) -> Result<Term>;
+    /// Removes the exact object (all copies of it in a duplicate_bag).
+    fn remove_object(&self, object: Term) -> Result<Term>;
 
-    fn slot(&self, slot: Term) -> Result<Term>;
+    /// Returns the objects in a slot, for `ets:slot/2`. Ordered tables have one object per slot,
+    /// in key order. Returns `'$end_of_table'` once the slot is past the last one.
+    fn slot(&self, process: &RcProcess, slot: Term) -> Result<Term>;
 
     // int (*db_select_chunk)(process: &RcProcess, table: &Self, Eterm tid, Eterm pattern, Sint chunk_size, int reverse, Eterm* ret);
 
@@ -76,9 +109,30 @@ pub trait Table: Send + Sync {
         pattern: &pam::Pattern,
         flags: pam::r#match::Flag,
         reverse: bool,
-    ) -> Result<Term>;
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let (matches, _) =
+            self.select_chunk(vm, process, pattern, flags, None, std::usize::MAX, reverse)?;
+        Ok(matches
+            .into_iter()
+            .rev()
+            .fold(Term::nil(), |acc, val| cons!(heap, val, acc)))
+    }
 
-    // fn select_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term>;
+    /// Like select, but stops once `limit` matches were found. `from` is the key the previous
+    /// chunk stopped at. Returns the matches, and the key to continue from if the table wasn't
+    /// exhausted.
+    #[allow(clippy::too_many_arguments)]
+    fn select_chunk(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+        from: Option<Term>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<(Vec<Term>, Option<Term>)>;
 
     fn select_delete(
         &self,
@@ -90,18 +144,46 @@ pub trait Table: Send + Sync {
 
     // fn select_delete_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term>;
 
-    fn select_count(&self, process: &RcProcess, tid: Term, pattern: Term) -> Result<Term>;
+    /// Counts the objects for which the match spec returns `true`.
+    fn select_count(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let am_true = atom!(TRUE);
+        let (matches, _) =
+            self.select_chunk(vm, process, pattern, flags, None, std::usize::MAX, false)?;
+        let count = matches.into_iter().filter(|res| *res == am_true).count();
+        Ok(Term::uint(&process.context_mut().heap, count as u32))
+    }
 
     // fn select_count_continue(&self, process: &RcProcess, continuation: Term) -> Result<Term>;
 
-    fn select_replace(&mut self, process: &RcProcess, tid: Term, pattern: Term) -> Result<Term>;
+    /// Replaces each matching object with the match spec result. The result has to keep the
+    /// key intact, otherwise BadItem is returned and the table is left untouched.
+    fn select_replace(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term>;
 
     // fn select_replace_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term>;
 
-    fn take(&mut self, process: &RcProcess, key: Term) -> Result<Term>;
+    /// Removes all objects with the key, returning them as a list.
+    fn take(&self, process: &RcProcess, key: Term) -> Result<Term>;
+
+    /// Number of objects in the table.
+    fn len(&self) -> usize;
+
+    /// Memory used by the table, in words.
+    fn memory(&self) -> usize;
 
     /// takes reds, then returns new reds (equal to delete_all)
-    fn clear(&mut self, process: &RcProcess, reds: usize) -> Result<usize>;
+    fn clear(&self, process: &RcProcess, reds: usize) -> Result<usize>;
 
     // don't think we'll need these
     // int (*db_free_empty_table)(DbTable* db);
@@ -121,6 +203,192 @@ pub trait Table: Send + Sync {
     // fn finalize_dbterm(&self, cret: usize, handle: DbUpdateHandle);
 }
 
+/// Runs the match program over `(key, object)` pairs until `limit` matches are found. All
+/// objects of the last key are matched before stopping, so a chunk of a bag table can contain
+/// more than `limit` matches. Returns the matches, and the last examined key if we stopped early.
+pub(crate) fn select_objects(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    pattern: &pam::Pattern,
+    flags: pam::r#match::Flag,
+    objects: impl Iterator<Item = (Term, Term)>,
+    limit: usize,
+) -> (Vec<Term>, Option<Term>) {
+    let mut matches = Vec::new();
+    let mut last = None;
+
+    for (key, object) in objects {
+        if matches.len() >= limit && last != Some(key) {
+            let heap = &process.context_mut().heap;
+            return (matches, last.map(|key: Term| key.deep_clone(heap)));
+        }
+        if let Some(res) = pam::r#match::run(vm, process, pattern, object, flags) {
+            matches.push(res);
+        }
+        last = Some(key);
+    }
+    (matches, None)
+}
+
+/// Returns the replacement for `object` if the match spec produced one. Replacements have to be
+/// tuples of the same arity range, with the key unchanged.
+pub(crate) fn replacement(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    pattern: &pam::Pattern,
+    flags: pam::r#match::Flag,
+    keypos: usize,
+    key: Term,
+    object: Term,
+) -> Result<Option<Term>> {
+    match pam::r#match::run(vm, process, pattern, object, flags) {
+        Some(res) => match Tuple::cast_from(&res) {
+            Ok(tup) if tup.len() > keypos && tup[keypos] == key => Ok(Some(res)),
+            _ => Err(new_error(ErrorKind::BadItem)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Copies `default` onto the table heap with its key replaced by `key`, for the default
+/// argument of update_counter/4 and update_element/4.
+pub(crate) fn default_object(heap: &Heap, keypos: usize, key: Term, default: Term) -> Result<Term> {
+    match Tuple::cast_from(&default) {
+        Ok(tup) if tup.len() > keypos => (),
+        _ => return Err(new_error(ErrorKind::BadItem)),
+    }
+    let object = default.deep_clone(heap);
+    let tup: &mut Tuple = object.cast_into_mut()?;
+    tup[keypos] = key.deep_clone(heap);
+    Ok(object)
+}
+
+/// Applies `[{Pos, Value}]` to the object. All positions are validated first, so a bad one
+/// leaves the object untouched.
+pub(crate) fn update_elements(heap: &Heap, keypos: usize, object: Term, list: Term) -> Result<()> {
+    let item: &mut Tuple = object.cast_into_mut()?;
+
+    // First verify that list is ok to avoid nasty rollback scenarios
+    let list = Cons::cast_from(&list)?;
+    let res: std::result::Result<Vec<_>, _> = list
+        .iter()
+        .map(|val| {
+            // value contains tuple
+            // tuple is arity 2 {pos, val}
+            // and has pos as integer, >= 1 and isn't == to keypos and is in the db term tuple
+            // arity range
+            if let Ok(tup) = Tuple::cast_from(&val) {
+                if tup.len() == 2 && tup[0].is_smallint() {
+                    let pos = (tup[0].to_int().unwrap() - 1) as usize; // 1 indexed
+                    if pos != keypos && pos < item.len() {
+                        return Ok((pos, tup[1]));
+                    }
+                }
+            }
+            Err(new_error(ErrorKind::BadItem))
+        })
+        .collect();
+
+    // The point of no return, no failures from here on.
+    res?.iter()
+        .for_each(|(pos, val)| item[*pos] = val.deep_clone(heap));
+    Ok(())
+}
+
+/// A parsed update_counter operation: `{Pos, Incr}` or `{Pos, Incr, Threshold, SetValue}`.
+pub struct CounterOp {
+    /// 0-indexed position of the counter.
+    pub pos: usize,
+    pub incr: BigInt,
+    pub threshold: Option<(BigInt, BigInt)>,
+}
+
+fn to_bigint(term: Term) -> Option<BigInt> {
+    match term.into_number() {
+        Ok(value::Num::Integer(i)) => Some(BigInt::from(i)),
+        Ok(value::Num::Bignum(i)) => Some(i),
+        _ => None,
+    }
+}
+
+impl CounterOp {
+    /// Parses an operation. A plain integer increments the element after the key.
+    pub fn from_term(term: Term, keypos: usize) -> Option<Self> {
+        if let Some(incr) = to_bigint(term) {
+            return Some(CounterOp {
+                pos: keypos + 1,
+                incr,
+                threshold: None,
+            });
+        }
+        let tup = Tuple::cast_from(&term).ok()?;
+        let pos = match tup[0].to_int() {
+            Some(i) if i > 0 => (i - 1) as usize,
+            _ => return None,
+        };
+        match tup.len() {
+            2 => Some(CounterOp {
+                pos,
+                incr: to_bigint(tup[1])?,
+                threshold: None,
+            }),
+            4 => Some(CounterOp {
+                pos,
+                incr: to_bigint(tup[1])?,
+                threshold: Some((to_bigint(tup[2])?, to_bigint(tup[3])?)),
+            }),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, value: BigInt) -> BigInt {
+        let value = value + &self.incr;
+        match &self.threshold {
+            Some((threshold, set_value)) => {
+                let zero = BigInt::from(0);
+                if (self.incr >= zero && value > *threshold)
+                    || (self.incr < zero && value < *threshold)
+                {
+                    set_value.clone()
+                } else {
+                    value
+                }
+            }
+            None => value,
+        }
+    }
+}
+
+/// Applies counter operations to an object on the table heap. Operations are validated first, so
+/// a bad position or a non-integer counter leaves the object untouched.
+pub(crate) fn update_counters(
+    heap: &Heap,
+    keypos: usize,
+    object: Term,
+    ops: &[CounterOp],
+) -> Result<Vec<BigInt>> {
+    let item: &mut Tuple = object.cast_into_mut()?;
+
+    for op in ops {
+        if op.pos == keypos || op.pos >= item.len() || to_bigint(item[op.pos]).is_none() {
+            return Err(new_error(ErrorKind::BadItem));
+        }
+    }
+
+    // The point of no return, no failures from here on.
+    Ok(ops
+        .iter()
+        .map(|op| {
+            let value = op.apply(to_bigint(item[op.pos]).unwrap());
+            item[op.pos] = match num_traits::ToPrimitive::to_i32(&value) {
+                Some(i) => Term::int(i),
+                None => Term::bigint(heap, value.clone()),
+            };
+            value
+        })
+        .collect())
+}
+
 bitflags! {
     pub struct Status: u32 {
         const INITIAL = 0;
@@ -159,72 +427,506 @@ pub struct Metadata {
     tid: process::Ref,
     /// Table name, if registered as a named table.
     name: Option<Atom>,
+    /// Flags the table was created with. The protection can change later, see `protection`.
     status: Status,
+    /// Access rights, one of the protection bits of `Status`. Can be changed with ets:setopts/2.
+    protection: AtomicU32,
     /// Table type
     kind: Status,
     /// Tuple element index that's used as the key.
     keypos: usize,
     /// Owning process
-    owner: process::PID,
-    /// Is the table compressed?
+    owner: AtomicU32,
+    /// Process inheriting the table when the owner exits.
+    heir: Mutex<Option<Heir>>,
+    /// Is the table compressed? Objects are then stored ETF encoded.
     compress: bool,
+    /// Bytes of encoded objects, which live outside the table heap.
+    compressed_bytes: AtomicUsize,
+    /// Processes that fixed the table with `ets:safe_fixtable/2`.
+    fixations: Mutex<Fixations>,
 }
 
-// TODO: we want to avoid mutex for concurrent writes tho, maybe dyn Table + Sync
-pub type RcTableRegistry = Mutex<TableRegistry>;
+/// Kind of access a table operation needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// ets:info, always allowed.
+    Info,
+}
+
+impl Metadata {
+    pub fn owner(&self) -> process::PID {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    pub fn set_owner(&self, pid: process::PID) {
+        self.owner.store(pid, Ordering::Relaxed)
+    }
+
+    /// Checks the table protection: private tables are only accessible by the owner, protected
+    /// tables can be read by anyone but only written by the owner.
+    pub fn allows(&self, pid: process::PID, access: Access) -> bool {
+        if access == Access::Info || pid == self.owner() {
+            return true;
+        }
+        match self.protection() {
+            Status::DB_PUBLIC => true,
+            Status::DB_PROTECTED => access == Access::Read,
+            _ => false,
+        }
+    }
+
+    pub fn protection(&self) -> Status {
+        Status::from_bits_truncate(self.protection.load(Ordering::Relaxed))
+    }
+
+    pub fn set_protection(&self, protection: Status) {
+        self.protection.store(protection.bits(), Ordering::Relaxed)
+    }
+
+    /// The identifier used in messages: the name for named tables, the tid otherwise.
+    pub fn id(&self, heap: &Heap) -> Term {
+        match self.name {
+            Some(name) if self.status.contains(Status::DB_NAMED_TABLE) => Term::atom(name),
+            _ => Term::reference(heap, self.tid),
+        }
+    }
+
+    /// Sets (or clears) the heir, copying the heir data onto its own heap. The heap is reused
+    /// when the heir is replaced and freed when it's cleared.
+    pub fn set_heir(&self, heir: Option<(process::PID, Term)>) {
+        let mut current = self.heir.lock();
+        *current = match (heir, current.take()) {
+            (Some((pid, data)), Some(mut heir)) => {
+                unsafe { heir.heap.clear() };
+                heir.pid = pid;
+                heir.data = data.deep_clone(&heir.heap);
+                Some(heir)
+            }
+            (Some((pid, data)), None) => {
+                let heap = Heap::fragment();
+                let data = data.deep_clone(&heap);
+                Some(Heir { pid, data, heap })
+            }
+            (None, Some(heir)) => {
+                unsafe { heir.heap.free() };
+                None
+            }
+            (None, None) => None,
+        };
+    }
+
+    /// The heir pid. The data is only handed out as a copy, see `heir_data`.
+    pub fn heir(&self) -> Option<process::PID> {
+        self.heir.lock().as_ref().map(|heir| heir.pid)
+    }
+
+    /// Returns the heir along with a copy of the heir data on a fragment, so that it can be sent
+    /// off while the table's heir is replaced.
+    pub fn heir_data(&self) -> Option<(process::PID, Term, Heap)> {
+        self.heir.lock().as_ref().map(|heir| {
+            let heap = Heap::fragment();
+            (heir.pid, heir.data.deep_clone(&heap), heap)
+        })
+    }
+
+    /// Copies an object onto the table heap. Objects of compressed tables are ETF encoded, with
+    /// only the key kept as a term. Returns the key and the stored object.
+    pub(crate) fn store(&self, heap: &Heap, object: Term) -> (Term, Term) {
+        if self.compress {
+            let key = Tuple::cast_from(&object).unwrap()[self.keypos].deep_clone(heap);
+            (key, self.pack(heap, object))
+        } else {
+            let object = object.deep_clone(heap);
+            (Tuple::cast_from(&object).unwrap()[self.keypos], object)
+        }
+    }
+
+    /// Builds a new object with `f` and stores it, returning the key and the stored object.
+    /// Objects of compressed tables are built on a scratch heap, since only the encoding is kept.
+    pub(crate) fn build<R>(
+        &self,
+        heap: &Heap,
+        f: impl FnOnce(&Heap) -> Result<(Term, R)>,
+    ) -> Result<(Term, Term, R)> {
+        if !self.compress {
+            let (object, res) = f(heap)?;
+            return Ok((Tuple::cast_from(&object)?[self.keypos], object, res));
+        }
+        with_scratch(|scratch| {
+            let (object, res) = f(scratch)?;
+            let (key, stored) = self.store(heap, object);
+            Ok((key, stored, res))
+        })
+    }
+
+    /// Updates a stored object in place with `f`, returning the object to store instead.
+    /// Compressed objects are decoded onto a scratch heap and encoded again, so that updates don't
+    /// grow the table heap.
+    pub(crate) fn update<R>(
+        &self,
+        heap: &Heap,
+        stored: Term,
+        f: impl FnOnce(&Heap, Term) -> Result<R>,
+    ) -> Result<(Term, R)> {
+        if !self.is_packed(stored) {
+            let res = f(heap, stored)?;
+            return Ok((stored, res));
+        }
+        with_scratch(|scratch| {
+            let object = self.load(scratch, stored);
+            let res = f(scratch, object)?;
+            self.release(stored);
+            Ok((self.pack(heap, object), res))
+        })
+    }
+
+    /// Encodes an object of a compressed table. Objects that can't be encoded (references, funs
+    /// and ports) are copied onto `heap` instead, like in uncompressed tables.
+    fn pack(&self, heap: &Heap, object: Term) -> Term {
+        match etf::encode(object) {
+            Ok(bytes) => {
+                self.compressed_bytes
+                    .fetch_add(bytes.len(), Ordering::Relaxed);
+                Term::binary(heap, bitstring::Binary::from(bytes))
+            }
+            Err(_) => object.deep_clone(heap),
+        }
+    }
+
+    /// Whether a stored object is ETF encoded. Only objects of compressed tables are, and only if
+    /// they could be encoded.
+    fn is_packed(&self, stored: Term) -> bool {
+        self.compress && stored.get_boxed_header() == Ok(value::BOXED_BINARY)
+    }
+
+    /// Must be called when a stored object is removed or replaced, so that its encoding is no
+    /// longer counted in the table memory.
+    pub(crate) fn release(&self, stored: Term) {
+        if self.is_packed(stored) {
+            let binary = bitstring::RcBinary::cast_from(&stored).unwrap();
+            self.compressed_bytes
+                .fetch_sub(binary.data.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns a stored object as a term. Compressed objects are decoded onto `heap`, other
+    /// objects are returned as is, without copying.
+    pub(crate) fn load(&self, heap: &Heap, stored: Term) -> Term {
+        if self.is_packed(stored) {
+            let binary = bitstring::RcBinary::cast_from(&stored).unwrap();
+            etf::decode(&binary.data, heap)
+        } else {
+            stored
+        }
+    }
+
+    /// Copies a stored object onto `heap`, usually a process heap.
+    pub(crate) fn copy(&self, heap: &Heap, stored: Term) -> Term {
+        if self.is_packed(stored) {
+            self.load(heap, stored)
+        } else {
+            stored.deep_clone(heap)
+        }
+    }
+
+    /// Compares a stored object with a term.
+    pub(crate) fn same(&self, stored: Term, object: Term) -> bool {
+        if self.is_packed(stored) {
+            let binary = bitstring::RcBinary::cast_from(&stored).unwrap();
+            etf::encode(object).map_or(false, |bytes| bytes == binary.data)
+        } else {
+            stored == object
+        }
+    }
+
+    /// Memory used by a table in words, given the bytes used on its heaps.
+    pub fn memory(&self, heap_bytes: usize) -> usize {
+        let bytes = heap_bytes + self.compressed_bytes.load(Ordering::Relaxed);
+        bytes / std::mem::size_of::<usize>()
+    }
+}
+
+impl std::fmt::Debug for dyn Table {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Table({:?})", self.meta().tid)
+    }
+}
+
+thread_local! {
+    /// Objects of compressed tables are decoded onto this heap to be updated.
+    static SCRATCH: RefCell<Heap> = RefCell::new(Heap::new());
+}
+
+/// Runs `f` with the scratch heap, which is cleared afterwards. The result must not point into it.
+fn with_scratch<R>(f: impl FnOnce(&Heap) -> R) -> R {
+    SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+        let res = f(&scratch);
+        // Binaries decoded onto the scratch heap stay allocated, since the heap doesn't run
+        // destructors.
+        unsafe { scratch.clear() };
+        res
+    })
+}
+
+/// Heir of a table, set with the `{heir, Pid, HeirData}` option.
+pub struct Heir {
+    pid: process::PID,
+    /// Sent along in the `'ETS-TRANSFER'` message, lives on `heap`.
+    data: Term,
+    heap: Heap,
+}
+
+/// Called when a process exits: tables it owns are handed to their heir if it's alive,
+/// otherwise they're deleted.
+pub fn owner_exited(vm: &vm::Machine, pid: process::PID) {
+    let registry = &vm.ets_tables;
+    registry.release_fixations(pid);
+
+    for table in registry.take_owned(pid) {
+        let heir = table.meta().heir_data().and_then(|(heir, data, heap)| {
+            let process = vm.process_table.lock().get(heir).filter(|_| heir != pid);
+            match process {
+                Some(process) => Some((process, data, heap)),
+                None => {
+                    unsafe { heap.free() };
+                    None
+                }
+            }
+        });
+
+        match heir {
+            Some((heir, data, heap)) => {
+                registry.set_owner(&table, heir.pid);
+                heir.send_signal(process::Signal::EtsTransfer {
+                    from: pid,
+                    table: table.clone(),
+                    data,
+                    heap,
+                });
+            }
+            None => {
+                registry.remove(&table);
+            }
+        }
+    }
+}
+
+/// Safe fixation state of a table. Traversals are always safe in our implementation, but we
+/// keep track of the fixing processes so that `ets:info/2` can report them.
+#[derive(Debug, Default)]
+pub struct Fixations {
+    /// When the table was first fixed, as VM monotonic time and system time.
+    since: Option<(Duration, SystemTime)>,
+    /// Fixing processes and their fixation count.
+    procs: HashMap<process::PID, usize>,
+}
+
+impl Fixations {
+    pub fn fix(&mut self, pid: process::PID, now: Duration) {
+        if self.procs.is_empty() {
+            self.since = Some((now, SystemTime::now()));
+        }
+        *self.procs.entry(pid).or_insert(0) += 1;
+    }
+
+    pub fn unfix(&mut self, pid: process::PID) {
+        if let Some(count) = self.procs.get_mut(&pid) {
+            *count -= 1;
+            if *count == 0 {
+                self.procs.remove(&pid);
+            }
+        }
+        if self.procs.is_empty() {
+            self.since = None;
+        }
+    }
+
+    /// Drops all fixations held by a process, used when it exits.
+    pub fn release(&mut self, pid: process::PID) {
+        self.procs.remove(&pid);
+        if self.procs.is_empty() {
+            self.since = None;
+        }
+    }
+
+    pub fn contains(&self, pid: process::PID) -> bool {
+        self.procs.contains_key(&pid)
+    }
+
+    pub fn since(&self) -> Option<(Duration, SystemTime)> {
+        self.since
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&process::PID, &usize)> {
+        self.procs.iter()
+    }
+}
+
+/// The table registry synchronizes internally, lookups never take a global lock.
+pub type RcTableRegistry = TableRegistry;
 
 pub type RcTable = Arc<dyn Table>;
 
+/// Number of shards the tid index is split into.
+const REGISTRY_SHARDS: usize = 16;
+
 pub struct TableRegistry {
-    tables: HashMap<process::Ref, RcTable>,
-    named_tables: HashMap<usize, RcTable>,
+    /// Tables indexed by tid, sharded to spread out lock contention.
+    tables: Vec<RwLock<HashMap<process::Ref, RcTable>>>,
+    named_tables: RwLock<HashMap<usize, RcTable>>,
+    /// Tables owned by each process, so that exits don't have to scan every table.
+    owned: Mutex<HashMap<process::PID, HashSet<process::Ref>>>,
+    /// Tables fixed by each process with `ets:safe_fixtable/2`.
+    fixed: Mutex<HashMap<process::PID, HashSet<process::Ref>>>,
+}
+
+/// Drops a table from a per-process index.
+fn unindex(
+    index: &mut HashMap<process::PID, HashSet<process::Ref>>,
+    pid: process::PID,
+    tid: process::Ref,
+) {
+    if let Some(tids) = index.get_mut(&pid) {
+        tids.remove(&tid);
+        if tids.is_empty() {
+            index.remove(&pid);
+        }
+    }
 }
 
 impl TableRegistry {
     pub fn with_rc() -> RcTableRegistry {
-        Mutex::new(Self {
-            tables: HashMap::new(),
-            named_tables: HashMap::new(),
-        })
+        Self {
+            tables: (0..REGISTRY_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            named_tables: RwLock::new(HashMap::new()),
+            owned: Mutex::new(HashMap::new()),
+            fixed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, reference: process::Ref) -> &RwLock<HashMap<process::Ref, RcTable>> {
+        &self.tables[reference % REGISTRY_SHARDS]
     }
 
     pub fn get(&self, reference: process::Ref) -> Option<RcTable> {
-        self.tables.get(&reference).cloned()
+        self.shard(reference).read().get(&reference).cloned()
     }
 
     pub fn get_named(&self, name: Atom) -> Option<RcTable> {
-        self.named_tables.get(&(name.0 as usize)).cloned()
+        self.named_tables.read().get(&(name.0 as usize)).cloned()
     }
 
-    pub fn insert(&mut self, reference: process::Ref, table: RcTable) {
-        self.tables.insert(reference, table);
+    pub fn insert(&self, reference: process::Ref, table: RcTable) {
+        self.owned
+            .lock()
+            .entry(table.meta().owner())
+            .or_default()
+            .insert(reference);
+        self.shard(reference).write().insert(reference, table);
     }
 
-    pub fn insert_named(&mut self, name: Atom, table: RcTable) -> bool {
+    pub fn insert_named(&self, name: Atom, table: RcTable) -> bool {
         let name = name.0 as usize;
-        if !self.named_tables.contains_key(&name) {
-            self.named_tables.insert(name, table);
+        let mut named_tables = self.named_tables.write();
+        if !named_tables.contains_key(&name) {
+            named_tables.insert(name, table);
             return true;
         }
         false
     }
 
-    pub fn remove(&mut self, table: &RcTable) -> bool {
+    pub fn remove(&self, table: &RcTable) -> bool {
         let meta = table.meta();
 
         // remove table from index
-        self.tables.remove(&meta.tid);
+        self.shard(meta.tid).write().remove(&meta.tid);
+        unindex(&mut self.owned.lock(), meta.owner(), meta.tid);
+        {
+            let mut fixed = self.fixed.lock();
+            for (pid, _) in meta.fixations.lock().iter() {
+                unindex(&mut fixed, *pid, meta.tid);
+            }
+        }
+        meta.set_heir(None);
 
         // if named, remove from named index
         if let Some(name) = meta.name {
-            self.named_tables.remove(&(name.0 as usize));
+            let name = name.0 as usize;
+            let mut named_tables = self.named_tables.write();
+            if named_tables.get(&name).map(|t| t.meta().tid) == Some(meta.tid) {
+                named_tables.remove(&name);
+            }
         }
 
         true
     }
 
+    /// Number of live tables.
+    pub fn len(&self) -> usize {
+        self.tables.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// Calls `f` for every live table.
+    pub fn for_each<F: FnMut(&RcTable)>(&self, mut f: F) {
+        for shard in &self.tables {
+            shard.read().values().for_each(&mut f);
+        }
+    }
+
+    /// Transfers a table to another process.
+    pub fn set_owner(&self, table: &RcTable, pid: process::PID) {
+        let meta = table.meta();
+        let mut owned = self.owned.lock();
+        unindex(&mut owned, meta.owner(), meta.tid);
+        meta.set_owner(pid);
+        owned.entry(pid).or_default().insert(meta.tid);
+    }
+
+    /// Takes the tables owned by an exiting process out of the owner index.
+    pub fn take_owned(&self, pid: process::PID) -> Vec<RcTable> {
+        let tids = self.owned.lock().remove(&pid).unwrap_or_default();
+        tids.into_iter().filter_map(|tid| self.get(tid)).collect()
+    }
+
+    /// Fixes a table for a process with `ets:safe_fixtable/2`.
+    pub fn fix(&self, table: &RcTable, pid: process::PID, now: Duration) {
+        let meta = table.meta();
+        let mut fixed = self.fixed.lock();
+        meta.fixations.lock().fix(pid, now);
+        fixed.entry(pid).or_default().insert(meta.tid);
+    }
+
+    /// Drops one fixation of a process.
+    pub fn unfix(&self, table: &RcTable, pid: process::PID) {
+        let meta = table.meta();
+        let mut fixed = self.fixed.lock();
+        let mut fixations = meta.fixations.lock();
+        fixations.unfix(pid);
+        if !fixations.contains(pid) {
+            unindex(&mut fixed, pid, meta.tid);
+        }
+    }
+
+    /// Releases all safe_fixtable fixations held by an exiting process.
+    pub fn release_fixations(&self, pid: process::PID) {
+        let tids = self.fixed.lock().remove(&pid).unwrap_or_default();
+        for tid in tids {
+            if let Some(table) = self.get(tid) {
+                table.meta().fixations.lock().release(pid);
+            }
+        }
+    }
+
     pub fn whereis(&self, name: Atom) -> Option<process::Ref> {
         self.named_tables
+            .read()
             .get(&(name.0 as usize))
             .map(|table| table.meta().tid)
     }
@@ -1,14 +1,14 @@
 use super::*;
-use crate::immix::Heap;
-use crate::value::{CastFrom, Cons, Term, Tuple};
+use crate::value::{BigInt, CastFrom, Term, Tuple};
 use error::*;
-use parking_lot::RwLock;
-use std::collections::HashSet;
+use segment::Segments;
 
+/// Table for bag and duplicate_bag tables. Objects with the same key are kept in insertion order.
 pub(crate) struct Bag {
     meta: Metadata,
-    hashmap: RwLock<HashMap<Term, HashSet<Term>>>,
-    heap: Heap,
+    segments: Segments<Vec<Term>>,
+    /// duplicate_bag: identical objects may be stored more than once.
+    duplicates: bool,
 }
 
 unsafe impl Sync for Bag {}
@@ -16,12 +16,36 @@ unsafe impl Send for Bag {}
 
 impl Bag {
     pub fn new(meta: Metadata, _process: &RcProcess) -> Self {
+        let duplicates = table_kind!(meta.kind) == Status::DB_DUPLICATE_BAG;
         Self {
+            segments: Segments::new(meta.status),
             meta,
-            hashmap: RwLock::new(HashMap::new()),
-            heap: Heap::new(),
+            duplicates,
         }
     }
+
+    /// Removes the objects the predicate returns true for, dropping keys that end up empty.
+    /// Returns the number of removed objects.
+    fn remove_where<F>(&self, mut pred: F) -> usize
+    where
+        F: FnMut(&Term) -> bool,
+    {
+        let mut count = 0;
+        for mut segment in self.segments.write_all() {
+            segment.retain(|_key, objects| {
+                objects.retain(|object| {
+                    let remove = pred(object);
+                    if remove {
+                        self.meta.release(*object);
+                        count += 1;
+                    }
+                    !remove
+                });
+                !objects.is_empty()
+            });
+        }
+        count
+    }
 }
 
 fn get_key(pos: usize, value: Term) -> Term {
@@ -34,41 +58,61 @@ impl Table for Bag {
         &self.meta
     }
 
-    fn first(&self, _process: &RcProcess) -> Result<Term> {
-        unimplemented!()
+    fn first(&self, process: &RcProcess) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.segments.first() {
+            Some(key) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
-    fn next(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn next(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.segments.next(key) {
+            Some(key) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
-    fn last(&self, _process: &RcProcess) -> Result<Term> {
-        unimplemented!()
+    // bag tables have no order, so last/prev are the same as first/next.
+    fn last(&self, process: &RcProcess) -> Result<Term> {
+        self.first(process)
     }
 
-    fn prev(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn prev(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        self.next(process, key)
     }
 
     // put
     fn insert(&self, _process: &RcProcess, value: Term, _key_clash_fail: bool) -> Result<()> {
-        let value = value.deep_clone(&self.heap);
         let key = get_key(self.meta().keypos, value);
-        self.hashmap
-            .write()
-            .entry(key)
-            .or_insert_with(HashSet::new)
-            .insert(value);
+        let mut segment = self.segments.write(&key);
+        let segment = &mut *segment;
+        match segment.map.get_mut(&key) {
+            Some(objects) => {
+                // a bag stores identical objects only once
+                if self.duplicates || !objects.iter().any(|val| self.meta.same(*val, value)) {
+                    let (_, value) = self.meta.store(&segment.heap, value);
+                    objects.push(value);
+                }
+            }
+            None => {
+                let (key, value) = self.meta.store(&segment.heap, value);
+                segment.insert(key, vec![value]);
+            }
+        }
         Ok(())
     }
 
     fn get(&self, process: &RcProcess, key: Term) -> Result<Term> {
         let heap = &process.context_mut().heap;
 
-        match self.hashmap.read().get(&key) {
-            Some(set) => Ok(set
-                .iter()
-                .fold(Term::nil(), |acc, v| cons!(heap, v.deep_clone(heap), acc))),
+        match self.segments.read(&key).map.get(&key) {
+            Some(objects) => Ok(objects.iter().rev().fold(Term::nil(), |acc, v| {
+                cons!(heap, self.meta.copy(heap, *v), acc)
+            })),
             None => Ok(Term::nil()),
         }
     }
@@ -76,11 +120,13 @@ impl Table for Bag {
     fn get_element(&self, process: &RcProcess, key: Term, index: usize) -> Result<Term> {
         let heap = &process.context_mut().heap;
 
-        match self.hashmap.read().get(&key) {
-            Some(set) => Ok(set
+        match self.segments.read(&key).map.get(&key) {
+            Some(objects) => Ok(objects
                 .iter()
+                .rev()
                 .map(|v| {
-                    let tup = Tuple::cast_from(&*v).unwrap();
+                    let v = self.meta.load(heap, *v);
+                    let tup = Tuple::cast_from(&v).unwrap();
                     assert!(tup.len() > index);
                     tup[index]
                 })
@@ -91,23 +137,57 @@ impl Table for Bag {
 
     // contains_key ? why is result a Term, not bool
     fn member(&self, key: Term) -> bool {
-        self.hashmap.read().contains_key(&key)
+        self.segments.read(&key).map.contains_key(&key)
     }
 
-    fn update_element(&self, _process: &RcProcess, _key: Term, _list: Term) -> Result<Term> {
-        unimplemented!();
+    // only supported on set tables
+    fn update_element(
+        &self,
+        _process: &RcProcess,
+        _key: Term,
+        _list: Term,
+        _default: Option<Term>,
+    ) -> Result<Term> {
+        Err(new_error(ErrorKind::BadItem))
+    }
+
+    fn update_counter(
+        &self,
+        _process: &RcProcess,
+        _key: Term,
+        _ops: &[CounterOp],
+        _default: Option<Term>,
+    ) -> Result<Vec<BigInt>> {
+        Err(new_error(ErrorKind::BadItem))
     }
 
     // erase  (remove_entry in rust)
-    fn remove(&self, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn remove(&self, key: Term) -> Result<Term> {
+        if let Some(objects) = self.segments.write(&key).remove(&key) {
+            objects.iter().for_each(|val| self.meta.release(*val));
+        }
+        Ok(atom!(TRUE))
     }
 
-    fn remove_object(&mut self, _object: Term) -> Result<Term> {
-        unimplemented!()
+    fn remove_object(&self, object: Term) -> Result<Term> {
+        let key = get_key(self.meta().keypos, object);
+        let mut segment = self.segments.write(&key);
+        if let Some(objects) = segment.map.get_mut(&key) {
+            objects.retain(|val| {
+                let remove = self.meta.same(*val, object);
+                if remove {
+                    self.meta.release(*val);
+                }
+                !remove
+            });
+            if objects.is_empty() {
+                segment.remove(&key);
+            }
+        }
+        Ok(atom!(TRUE))
     }
 
-    fn slot(&self, _slot: Term) -> Result<Term> {
+    fn slot(&self, _process: &RcProcess, _slot: Term) -> Result<Term> {
         unimplemented!()
     }
 
@@ -119,58 +199,151 @@ impl Table for Bag {
     // int reverse,
     // Eterm* ret);
 
-    // _continue is for when the main function traps, let's just use generators
-    fn select(
+    fn select_chunk(
         &self,
-        _vm: &vm::Machine,
-        _process: &RcProcess,
-        _pattern: &pam::Pattern,
-        _flags: pam::r#match::Flag,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+        from: Option<Term>,
+        limit: usize,
         _reverse: bool,
-    ) -> Result<Term> {
-        unimplemented!()
-    }
+    ) -> Result<(Vec<Term>, Option<Term>)> {
+        let heap = &process.context_mut().heap;
+        let segments = self.segments.read_all();
+        let start = from.map_or(0, |key| self.segments.index(&key));
 
-    // fn select_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
-    //     unimplemented!()
-    // }
+        // bag tables have no order, so reverse is ignored.
+        let objects = segments[start..]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, segment)| segment.iter_from(if i == 0 { from } else { None }))
+            .flat_map(|(key, objects)| objects.iter().map(move |val| (*key, *val)))
+            .map(|(key, val)| (key, self.meta.load(heap, val)));
+        Ok(select_objects(vm, process, pattern, flags, objects, limit))
+    }
 
     fn select_delete(
         &self,
-        _vm: &vm::Machine,
-        _process: &RcProcess,
-        _pattern: &pam::Pattern,
-        _flags: pam::r#match::Flag,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
     ) -> Result<Term> {
-        unimplemented!()
+        let heap = &process.context_mut().heap;
+        let am_true = atom!(TRUE);
+        let count = self.remove_where(|val| {
+            let object = self.meta.load(heap, *val);
+            pam::r#match::run(vm, process, pattern, object, flags) == Some(am_true)
+        });
+        Ok(Term::uint(heap, count as u32))
     }
 
     // fn select_delete_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn select_count(&self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
-    }
-
     // fn select_count_continue(&self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn select_replace(&mut self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
+    fn select_replace(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let keypos = self.meta().keypos;
+        let mut segments = self.segments.write_all();
+
+        // compute all replacements first, so a bad replacement leaves the table untouched
+        let mut replacements = Vec::new();
+        for (s, segment) in segments.iter().enumerate() {
+            for (key, objects) in segment.map.iter() {
+                for (i, object) in objects.iter().enumerate() {
+                    let object = self.meta.load(heap, *object);
+                    if let Some(res) =
+                        replacement(vm, process, pattern, flags, keypos, *key, object)?
+                    {
+                        replacements.push((s, *key, i, res));
+                    }
+                }
+            }
+        }
+
+        let count = replacements.len();
+        for (s, key, i, res) in replacements {
+            let segment = &mut *segments[s];
+            let objects = segment.map.get_mut(&key).unwrap();
+            let prev = std::mem::replace(&mut objects[i], self.meta.store(&segment.heap, res).1);
+            self.meta.release(prev);
+        }
+
+        if !self.duplicates {
+            // replacements might have made objects identical
+            for objects in segments
+                .iter_mut()
+                .flat_map(|segment| segment.map.values_mut())
+            {
+                let mut i = 1;
+                while i < objects.len() {
+                    if objects[..i].contains(&objects[i]) {
+                        self.meta.release(objects.remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+        Ok(Term::uint(heap, count as u32))
     }
 
     // fn select_replace_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn take(&mut self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn take(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        match self.segments.write(&key).remove(&key) {
+            Some(objects) => Ok(objects.iter().rev().fold(Term::nil(), |acc, v| {
+                self.meta.release(*v);
+                cons!(heap, self.meta.copy(heap, *v), acc)
+            })),
+            None => Ok(Term::nil()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let segments = self.segments.read_all();
+        segments
+            .iter()
+            .flat_map(|segment| segment.map.values())
+            .map(Vec::len)
+            .sum()
+    }
+
+    fn memory(&self) -> usize {
+        let heap_bytes = self
+            .segments
+            .read_all()
+            .iter()
+            .map(|segment| segment.heap.size())
+            .sum();
+        self.meta.memory(heap_bytes)
     }
 
     /// takes reds, then returns new reds (equal to delete_all)
-    fn clear(&mut self, _process: &RcProcess, _reds: usize) -> Result<usize> {
-        unimplemented!()
+    fn clear(&self, _process: &RcProcess, reds: usize) -> Result<usize> {
+        for mut segment in self.segments.write_all() {
+            segment
+                .map
+                .values()
+                .flatten()
+                .for_each(|val| self.meta.release(*val));
+            segment.clear();
+        }
+        Ok(reds)
     }
 }
@@ -7,6 +7,7 @@ use crate::value::{CastFrom, Cons, Term, Tuple, Type, Variant};
 use crate::vm;
 
 use super::bag::Bag;
+use super::ca_ordered_set::CaOrderedSet;
 use super::error::{new_error, ErrorKind};
 use super::hash_table::HashTable;
 use super::ordered_set::OrderedSet;
@@ -29,8 +30,7 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
     let mut is_named = false;
     let mut is_fine_locked = false;
     let mut frequent_read = false;
-    let mut heir = atom!(NONE);
-    let mut heir_data = atom!(UNDEFINED);
+    let mut heir = None;
     // is_compressed = erts_ets_always_compress;
     let mut is_compressed = false;
 
@@ -86,21 +86,17 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
                                     None => return Err(badarg!()),
                                 };
                             }
-                            Variant::Atom(atom::HEIR) => {
-                                if tup[1] == atom!(NONE) {
-                                    heir = atom!(NONE);
-                                    heir_data = atom!(UNDEFINED);
-                                } else {
-                                    return Err(badarg!());
-                                }
-                            }
+                            Variant::Atom(atom::HEIR) => match heir_option(tup) {
+                                Some(val) => heir = val,
+                                None => return Err(badarg!()),
+                            },
                             _ => return Err(badarg!()),
                         }
                     } else if tup.len() == 3 {
-                        //&& tup[0] == am_heir && is_internal_pid(tp[2]) {
-                        unimplemented!()
-                    //     heir = tp[2];
-                    //     heir_data = tp[3];
+                        match heir_option(tup) {
+                            Some(val) => heir = val,
+                            None => return Err(badarg!()),
+                        }
                     } else {
                         return Err(badarg!());
                     }
@@ -131,11 +127,17 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
     //     return Err(badarg!());
     // }
 
-    /*if IS_TREE_TABLE(status) && is_fine_locked && !status.contains(Status::DB_PRIVATE) {
+    // ordered sets with write_concurrency use the contention adapting tree
+    if table_kind!(status) == Status::DB_ORDERED_SET
+        && is_fine_locked
+        && !status.contains(Status::DB_PRIVATE)
+    {
         status.insert(Status::DB_CA_ORDERED_SET);
-        status.remove(Status::DB_SET | Status::DB_BAG | Status::DB_DUPLICATE_BAG | Status::DB_ORDERED_SET);
+        status.remove(
+            Status::DB_SET | Status::DB_BAG | Status::DB_DUPLICATE_BAG | Status::DB_ORDERED_SET,
+        );
         status.insert(Status::DB_FINE_LOCKED);
-    } */
+    }
 
     // if is_hash_table
     if let Status::DB_SET | Status::DB_BAG | Status::DB_DUPLICATE_BAG = table_kind!(status) {
@@ -156,15 +158,18 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
         tid,
         name: Some(args[0].to_atom().unwrap()), // unsound conversion
         status,
+        protection: AtomicU32::new(table_protection!(status).bits()),
         kind: status, // Note, 'kind' is *read only* from now on...
         keypos,
-        owner: process.pid,
+        owner: AtomicU32::new(process.pid),
+        heir: Mutex::new(None),
         compress: is_compressed,
-        // init fixing to count 0 and procs NULL
+        compressed_bytes: AtomicUsize::new(0),
+        fixations: Mutex::new(Fixations::default()),
     };
     // erts_refc_init(&tb->common.fix_count, 0);
     // db_init_lock(tb, status & (DB_FINE_LOCKED|DB_FREQ_READ));
-    // set_heir(BIF_P, tb, heir, heir_data);
+    meta.set_heir(heir);
     // erts_atomic_init_nob(&tb->common.nitems, 0);
 
     // #ifdef ETS_DBG_FORCE_TRAP
@@ -176,21 +181,22 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
         Status::DB_SET /*| Status::DB_BAG | Status::DB_DUPLICATE_BAG */=> {
             Arc::new(HashTable::new(meta, process))
         }
-        Status::DB_BAG /*| Status::DB_BAG | Status::DB_DUPLICATE_BAG */=> {
+        Status::DB_BAG | Status::DB_DUPLICATE_BAG => {
             Arc::new(Bag::new(meta, process))
         }
-        Status::DB_DUPLICATE_BAG => unimplemented!(), // TODO, will be part of hashtable
         Status::DB_ORDERED_SET => {
             Arc::new(OrderedSet::new(meta, process))
         },
-        Status::DB_CA_ORDERED_SET => unimplemented!(),
+        Status::DB_CA_ORDERED_SET => {
+            Arc::new(CaOrderedSet::new(meta, process))
+        }
         _ => return Err(badarg!()),
     };
 
     {
         // TODO: need clone since insert_named might run, not ideal
         // println!("inserting table as {}", tid);
-        vm.ets_tables.lock().insert(tid, table.clone());
+        vm.ets_tables.insert(tid, table.clone());
     }
     // process.save_sched_table(tabletb);
     // process.save_owned_table(table);
@@ -198,12 +204,12 @@ pub fn new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Resul
     if is_named {
         if vm
             .ets_tables
-            .lock()
-            .insert_named(args[0].to_atom().unwrap(), table)
+            .insert_named(args[0].to_atom().unwrap(), table.clone())
         {
             return Ok(args[0]);
         }
-        // table drops
+        // the name is taken, table drops
+        vm.ets_tables.remove(&table);
 
         // tid_clear(BIF_P, tb);
         // delete_owned_table(BIF_P, tb);
@@ -237,7 +243,6 @@ pub fn whereis_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::R
 
     let tid = vm
         .ets_tables
-        .lock()
         .whereis(name)
         .map(|tid| Term::reference(&process.context_mut().heap, tid));
 
@@ -247,27 +252,62 @@ pub fn whereis_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::R
     }
 }
 
+/// Parses a `{heir, none}` or `{heir, Pid, HeirData}` option.
+fn heir_option(tup: &Tuple) -> Option<Option<(process::PID, Term)>> {
+    if tup.len() < 2 || tup[0] != atom!(HEIR) {
+        return None;
+    }
+    match tup.len() {
+        2 if tup[1] == atom!(NONE) => Some(None),
+        3 if tup[1].is_pid() => Some(Some((tup[1].to_pid().unwrap(), tup[2]))),
+        _ => None,
+    }
+}
+
+/// Parses a `{protection, private | protected | public}` option.
+fn protection_option(tup: &Tuple) -> Option<Status> {
+    if tup.len() != 2 || tup[0] != atom!(PROTECTION) {
+        return None;
+    }
+    match tup[1].into_variant() {
+        Variant::Atom(atom::PRIVATE) => Some(Status::DB_PRIVATE),
+        Variant::Atom(atom::PROTECTED) => Some(Status::DB_PROTECTED),
+        Variant::Atom(atom::PUBLIC) => Some(Status::DB_PUBLIC),
+        _ => None,
+    }
+}
+
+/// An option accepted by ets:setopts/2.
+enum Setopt {
+    Heir(Option<(process::PID, Term)>),
+    Protection(Status),
+}
+
+/// Looks up a table by tid or name, checking that the process has the required access.
 #[inline]
-fn get_table(vm: &vm::Machine, term: Term) -> std::result::Result<RcTable, Exception> {
+fn get_table(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    term: Term,
+    access: Access,
+) -> std::result::Result<RcTable, Exception> {
     /*let key = match args[0].into_variant() {
         Variant::Atom(name) => name as usize,
         _ => unimplemented!()
     };*/
     // get DB_WRITE, lock kind, ets_insert_2
     match term.get_type() {
-        // TODO: inefficient
         Type::Atom => {
             let key = term.to_atom().unwrap();
-            let lock = vm.ets_tables.lock();
-            lock.get_named(key)
+            vm.ets_tables.get_named(key)
         }
         Type::Ref => {
             let key = term.to_ref().unwrap(); // TODO: HANDLE Atom
-            let lock = vm.ets_tables.lock();
-            lock.get(key)
+            vm.ets_tables.get(key)
         }
         _ => None,
     }
+    .filter(|table| table.meta().allows(process.pid, access))
     .ok_or_else(|| badarg!())
 }
 
@@ -278,7 +318,7 @@ pub fn insert_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Re
     // eprintln!("inserting into {} val {}", args[0], args[1]);
 
     // find table
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Write)?;
 
     if args[1].is_nil() {
         return Ok(atom!(TRUE));
@@ -322,7 +362,7 @@ pub fn insert_new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif
     // println!("ets:insert_new/2, {}, {}", args[0], args[1]);
 
     // find table
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Write)?;
 
     if args[1].is_nil() {
         return Ok(atom!(TRUE));
@@ -368,7 +408,7 @@ pub fn insert_new_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif
 }
 
 pub fn lookup_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Read)?;
 
     // println!("ets:lookup/2: {}", args[1]);
     // for some reason just returning won't work
@@ -378,7 +418,7 @@ pub fn lookup_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Re
 }
 
 pub fn lookup_element_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Read)?;
 
     let index = match args[2].into_number() {
         Ok(value::Num::Integer(i)) if i > 0 => (i - 1) as usize,
@@ -390,40 +430,81 @@ pub fn lookup_element_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
 }
 
 /// Deletes an entire table.
-pub fn delete_1(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+pub fn delete_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
 
     // TODO: set access bits to none to disable access
 
     // TODO: transfer ownership to current process just in case
 
-    {
-        // remove table from index
-        let mut tables = vm.ets_tables.lock();
-        tables.remove(&table);
-    }
+    // remove table from index
+    vm.ets_tables.remove(&table);
 
     // TODO: bump reds
 
     Ok(atom!(TRUE))
 }
 
-pub fn delete_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+pub fn delete_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
 
     Ok(table.remove(args[1])?)
 }
 
+/// Deletes the exact object from the table.
+pub fn delete_object_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+
+    match Tuple::cast_from(&args[1]) {
+        Ok(tup) if tup.len() > table.meta().keypos => (),
+        _ => return Err(badarg!()),
+    }
+
+    Ok(table.remove_object(args[1])?)
+}
+
+pub fn delete_all_objects_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+
+    // TODO: bump reds
+    table.clear(process, 0)?;
+    Ok(atom!(TRUE))
+}
+
+/// ets:delete_all_objects/1 calls this in newer OTP versions.
+pub fn internal_delete_all_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    delete_all_objects_1(vm, process, &args[..1])
+}
+
+pub fn take_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+
+    Ok(table.take(process, args[1])?)
+}
+
 pub fn update_element_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    update_element(vm, process, args, None)
+}
+
+pub fn update_element_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    update_element(vm, process, args, Some(args[3]))
+}
+
+fn update_element(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+    default: Option<Term>,
+) -> bif::Result {
     let heap = &process.context_mut().heap;
     // DB_BIF_GET_TABLE(tb, DB_WRITE, LCK_WRITE_REC, BIF_ets_update_element_3);
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Write)?;
     // println!("pam=update_element {}", args[1]);
 
-    if table
+    if !table
         .meta()
         .kind
-        .contains(Status::DB_SET | Status::DB_ORDERED_SET | Status::DB_CA_ORDERED_SET)
+        .intersects(Status::DB_SET | Status::DB_ORDERED_SET | Status::DB_CA_ORDERED_SET)
     {
         // Err(new_error(ErrorKind::BadItem))
         return Err(badarg!());
@@ -435,13 +516,81 @@ pub fn update_element_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
         args[2]
     };
 
-    Ok(table.update_element(process, args[1], list)?)
+    Ok(table.update_element(process, args[1], list, default)?)
+}
+
+pub fn update_counter_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    update_counter(vm, process, args, None)
+}
+
+pub fn update_counter_4(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    update_counter(vm, process, args, Some(args[3]))
+}
+
+/// Atomically updates counters, the update op is `Incr`, `{Pos, Incr}`,
+/// `{Pos, Incr, Threshold, SetValue}` or a list of the tuple forms.
+fn update_counter(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    args: &[Term],
+    default: Option<Term>,
+) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+
+    if !table
+        .meta()
+        .kind
+        .intersects(Status::DB_SET | Status::DB_ORDERED_SET | Status::DB_CA_ORDERED_SET)
+    {
+        return Err(badarg!());
+    };
+
+    let keypos = table.meta().keypos;
+    let is_list = args[2].is_list();
+    let ops = if args[2].is_nil() {
+        Vec::new()
+    } else if is_list {
+        Cons::cast_from(&args[2])?
+            .iter()
+            // the list form only accepts tuples
+            .map(|op| {
+                if op.is_tuple() {
+                    CounterOp::from_term(*op, keypos)
+                } else {
+                    None
+                }
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| badarg!())?
+    } else {
+        vec![CounterOp::from_term(args[2], keypos).ok_or_else(|| badarg!())?]
+    };
+
+    let values = table.update_counter(process, args[1], &ops, default)?;
+
+    let heap = &process.context_mut().heap;
+    let mut values = values.into_iter().map(|value| {
+        use num_traits::ToPrimitive;
+        match value.to_i32() {
+            Some(i) => Term::int(i),
+            None => Term::bigint(heap, value),
+        }
+    });
+    if is_list {
+        let values: Vec<_> = values.collect();
+        Ok(values
+            .into_iter()
+            .rev()
+            .fold(Term::nil(), |acc, val| cons!(heap, val, acc)))
+    } else {
+        Ok(values.next().unwrap())
+    }
 }
 
 pub fn select_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Read)?;
     // println!("pam=select {}", args[1]);
-    let pattern = analyze_pattern(&table, args[1]).unwrap();
+    let pattern = analyze_pattern(&table, args[1])?;
 
     let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
 
@@ -449,25 +598,129 @@ pub fn select_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Re
     Ok(table.select(vm, process, &pattern, flags, false)?)
 }
 
-pub fn match_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+pub fn select_reverse_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+    let pattern = analyze_pattern(&table, args[1])?;
+
+    let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
+
+    Ok(table.select(vm, process, &pattern, flags, true)?)
+}
+
+pub fn select_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    select_chunk(vm, process, args[0], args[1], args[2], false)
+}
+
+pub fn select_reverse_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    select_chunk(vm, process, args[0], args[1], args[2], true)
+}
+
+/// Continues a select/3 (or match/3, match_object/3, select_reverse/3) from a continuation.
+pub fn select_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    if args[0] == atom!(DOLLAR_END_OF_TABLE) {
+        return Ok(args[0]);
+    }
+
+    // {Tab, LastKey, Limit, MatchSpec, Reverse}
+    let cont = match Tuple::cast_from(&args[0]) {
+        Ok(tup) if tup.len() == 5 => tup,
+        _ => return Err(badarg!()),
+    };
+    let reverse = cont[4].to_bool().ok_or_else(|| badarg!())?;
+    let table = get_table(vm, process, cont[0], Access::Read)?;
+    let limit = match cont[2].to_int() {
+        Some(i) if i > 0 => i as usize,
+        _ => return Err(badarg!()),
+    };
+    let pattern = analyze_pattern(&table, cont[3])?;
+
+    let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
+    let chunk = table.select_chunk(vm, process, &pattern, flags, Some(cont[1]), limit, reverse)?;
+    Ok(chunk_result(process, cont[0], cont[2], cont[3], reverse, chunk))
+}
+
+/// Selects the first `limit` matches and returns them with a continuation.
+fn select_chunk(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    tab: Term,
+    ms: Term,
+    limit: Term,
+    reverse: bool,
+) -> bif::Result {
+    let table = get_table(vm, process, tab, Access::Read)?;
+    let chunk_size = match limit.to_int() {
+        Some(i) if i > 0 => i as usize,
+        _ => return Err(badarg!()),
+    };
+    let pattern = analyze_pattern(&table, ms)?;
+
+    let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
+    let chunk = table.select_chunk(vm, process, &pattern, flags, None, chunk_size, reverse)?;
+    Ok(chunk_result(process, tab, limit, ms, reverse, chunk))
+}
+
+/// Builds `{Matches, Continuation}`, or `'$end_of_table'` if nothing matched. The continuation
+/// carries the match spec, which is recompiled on every call to select/1.
+fn chunk_result(
+    process: &RcProcess,
+    tab: Term,
+    limit: Term,
+    ms: Term,
+    reverse: bool,
+    (matches, last): (Vec<Term>, Option<Term>),
+) -> Term {
+    let heap = &process.context_mut().heap;
+
+    if matches.is_empty() && last.is_none() {
+        return atom!(DOLLAR_END_OF_TABLE);
+    }
+
+    let cont = match last {
+        Some(key) => tup!(heap, tab, key, limit, ms, Term::boolean(reverse)),
+        None => atom!(DOLLAR_END_OF_TABLE),
+    };
+    let matches = matches
+        .into_iter()
+        .rev()
+        .fold(Term::nil(), |acc, val| cons!(heap, val, acc));
+    tup2!(heap, matches, cont)
+}
+
+/// Wraps a match pattern into a match spec with the given result (`'$$'` or `'$_'`).
+fn match_spec(process: &RcProcess, pattern: Term, result: Term) -> Term {
     let heap = &process.context_mut().heap;
-    let ms = cons!(
+    cons!(
         heap,
-        tup3!(
-            heap,
-            args[1],
-            Term::nil(),
-            cons!(heap, atom!(DOLLAR_DOLLAR), Term::nil())
-        ),
+        tup3!(heap, pattern, Term::nil(), cons!(heap, result, Term::nil())),
         Term::nil()
-    );
+    )
+}
+
+pub fn match_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let ms = match_spec(process, args[1], atom!(DOLLAR_DOLLAR));
+    select_2(vm, process, &[args[0], ms])
+}
+
+pub fn match_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let ms = match_spec(process, args[1], atom!(DOLLAR_DOLLAR));
+    select_chunk(vm, process, args[0], ms, args[2], false)
+}
+
+pub fn match_object_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let ms = match_spec(process, args[1], atom!(DOLLAR_UNDERSCORE));
     select_2(vm, process, &[args[0], ms])
 }
 
+pub fn match_object_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let ms = match_spec(process, args[1], atom!(DOLLAR_UNDERSCORE));
+    select_chunk(vm, process, args[0], ms, args[2], false)
+}
+
 pub fn select_delete_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Write)?;
     // println!("pam=select_delete {}", args[1]);
-    let pattern = analyze_pattern(&table, args[1]).unwrap();
+    let pattern = analyze_pattern(&table, args[1])?;
 
     let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
 
@@ -475,8 +728,32 @@ pub fn select_delete_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) ->
     Ok(table.select_delete(vm, process, &pattern, flags)?)
 }
 
-pub fn member_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+pub fn match_delete_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let ms = match_spec(process, args[1], atom!(TRUE));
+    select_delete_2(vm, process, &[args[0], ms])?;
+    Ok(atom!(TRUE))
+}
+
+pub fn select_count_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+    let pattern = analyze_pattern(&table, args[1])?;
+
+    let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
+
+    Ok(table.select_count(vm, process, &pattern, flags)?)
+}
+
+pub fn select_replace_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+    let pattern = analyze_pattern(&table, args[1])?;
+
+    let flags = pam::r#match::Flag::COPY_RESULT | pam::r#match::Flag::CONTIGUOUS_TUPLE;
+
+    Ok(table.select_replace(vm, process, &pattern, flags)?)
+}
+
+pub fn member_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
 
     // eprintln!(
     //     "member_2: {} {} {}",
@@ -488,19 +765,128 @@ pub fn member_2(vm: &vm::Machine, _process: &RcProcess, args: &[Term]) -> bif::R
 }
 
 pub fn first_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Read)?;
 
     // eprintln!("first_1: {} {}", args[0], table.first(process)?);
     Ok(table.first(process)?)
 }
 
 pub fn last_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
+    let table = get_table(vm, process, args[0], Access::Read)?;
 
     // eprintln!("last_1: {} {}", args[0], table.last(process)?);
     Ok(table.last(process)?)
 }
 
+pub fn next_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+
+    Ok(table.next(process, args[1])?)
+}
+
+pub fn prev_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+
+    Ok(table.prev(process, args[1])?)
+}
+
+/// Returns all objects in the table, in traversal order.
+pub fn tab2list_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+    let heap = &process.context_mut().heap;
+    let end = atom!(DOLLAR_END_OF_TABLE);
+
+    let mut objects = Vec::new();
+    let mut key = table.first(process)?;
+    while key != end {
+        let res = table.get(process, key)?;
+        if let Ok(cons) = Cons::cast_from(&res) {
+            objects.extend(cons.iter().copied());
+        }
+        key = table.next(process, key)?;
+    }
+
+    Ok(objects
+        .into_iter()
+        .rev()
+        .fold(Term::nil(), |acc, val| cons!(heap, val, acc)))
+}
+
+/// Fixes a table for safe traversal. Our tables can always be traversed safely, so this only
+/// records the fixation for ets:info/2.
+pub fn safe_fixtable_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Read)?;
+
+    match args[1].to_bool() {
+        Some(true) => vm.ets_tables.fix(&table, process.pid, vm.elapsed_time()),
+        Some(false) => vm.ets_tables.unfix(&table, process.pid),
+        None => return Err(badarg!()),
+    }
+    Ok(atom!(TRUE))
+}
+
+/// Transfers the table to another process, which receives
+/// `{'ETS-TRANSFER', Tab, FromPid, GiftData}`. Only the owner may give a table away.
+pub fn give_away_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+    let pid = args[1].to_pid().ok_or_else(|| badarg!())?;
+
+    if table.meta().owner() != process.pid || pid == process.pid {
+        return Err(badarg!());
+    }
+
+    let receiver = vm.process_table.lock().get(pid).ok_or_else(|| badarg!())?;
+    vm.ets_tables.set_owner(&table, pid);
+    // the gift data lives on our heap, copy it so the signal doesn't point into it
+    let heap = Heap::fragment();
+    let data = args[2].deep_clone(&heap);
+    receiver.send_signal(process::Signal::EtsTransfer {
+        from: process.pid,
+        table,
+        data,
+        heap,
+    });
+    Ok(atom!(TRUE))
+}
+
+/// Sets table options after creation, `heir` and `protection` are supported. Only the owner may
+/// do this.
+pub fn setopts_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
+    let table = get_table(vm, process, args[0], Access::Write)?;
+
+    if table.meta().owner() != process.pid {
+        return Err(badarg!());
+    }
+
+    let opts = if args[1].is_tuple() {
+        vec![args[1]]
+    } else if args[1].is_list() {
+        Cons::cast_from(&args[1])?.iter().copied().collect()
+    } else {
+        return Err(badarg!());
+    };
+
+    // validate everything before applying anything
+    let opts = opts
+        .iter()
+        .map(|opt| {
+            let tup = Tuple::cast_from(opt).ok();
+            tup.and_then(heir_option)
+                .map(Setopt::Heir)
+                .or_else(|| tup.and_then(protection_option).map(Setopt::Protection))
+                .ok_or_else(|| badarg!())
+        })
+        .collect::<std::result::Result<Vec<_>, Exception>>()?;
+
+    for opt in opts {
+        match opt {
+            Setopt::Heir(heir) => table.meta().set_heir(heir),
+            Setopt::Protection(protection) => table.meta().set_protection(protection),
+        }
+    }
+    Ok(atom!(TRUE))
+}
+
 struct MpInfo {
     /// The match_spec is not "impossible"
     something_can_match: bool,
@@ -519,7 +905,7 @@ struct MpInfo {
 /// For the select functions, analyzes the pattern and determines which
 /// slots should be searched. Also compiles the match program
 fn analyze_pattern(
-    _table: &RcTable,
+    table: &RcTable,
     pattern: Term, /* extra_validator: Fn optional callback */
 ) -> Result<pam::Pattern> {
     // Eterm *ptpl;
@@ -640,8 +1026,15 @@ fn analyze_pattern(
     // but then the select calls would not fail like they should on bad
     // match specs that happen to specify non existent keys etc.
 
+    // ordered tables only need to scan the keys the heads and guards allow
+    let key_range = range::KeyRange::analyze(table.meta().keypos, &matches, &guards);
+
     let compiler = pam::Compiler::new(matches, guards, bodies, num_heads, pam::Flag::DCOMP_TABLE);
-    let mp = compiler.match_compile().unwrap();
+    // a malformed match spec
+    let mut mp = compiler
+        .match_compile()
+        .map_err(|_| new_error(ErrorKind::BadParameter))?;
+    mp.key_range = key_range;
     // mpi.mp = compiler.match_compile().unwrap();
     //if mpi.mp == NULL {
     //    //if buff != sbuff { erts_free(ERTS_ALC_T_DB_TMP, buff); }
@@ -653,63 +1046,762 @@ fn analyze_pattern(
     Ok(mp)
 }
 
+/// Items returned by `ets:info/1`, in order.
+const INFO_ITEMS: [Atom; 14] = [
+    atom::ID,
+    atom::READ_CONCURRENCY,
+    atom::WRITE_CONCURRENCY,
+    atom::COMPRESSED,
+    atom::MEMORY,
+    atom::OWNER,
+    atom::HEIR,
+    atom::NAME,
+    atom::SIZE,
+    atom::NODE,
+    atom::NAMED_TABLE,
+    atom::TYPE,
+    atom::KEYPOS,
+    atom::PROTECTION,
+];
+
+/// Looks up a table for `ets:info/1,2`, which return undefined for tables that don't exist.
+fn info_table(
+    vm: &vm::Machine,
+    process: &RcProcess,
+    term: Term,
+) -> std::result::Result<Option<RcTable>, Exception> {
+    match term.get_type() {
+        Type::Atom | Type::Ref => Ok(get_table(vm, process, term, Access::Info).ok()),
+        _ => Err(badarg!()),
+    }
+}
+
 pub fn info_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    unimplemented!("ets:info/1")
+    let table = match info_table(vm, process, args[0])? {
+        Some(table) => table,
+        None => return Ok(atom!(UNDEFINED)),
+    };
+    let heap = &process.context_mut().heap;
+    INFO_ITEMS.iter().rev().try_fold(Term::nil(), |acc, item| {
+        let item = Term::atom(*item);
+        let value = table_info(process, &table, item)?;
+        Ok(cons!(heap, tup2!(heap, item, value), acc))
+    })
 }
 
 pub fn info_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> bif::Result {
-    let table = get_table(vm, args[0])?;
-    match args[1].into_variant() {
-        Variant::Atom(atom::PROTECTION) => match table_protection!(table.meta().status) {
+    match info_table(vm, process, args[0])? {
+        Some(table) => table_info(process, &table, args[1]),
+        None => Ok(atom!(UNDEFINED)),
+    }
+}
+
+fn table_info(process: &RcProcess, table: &RcTable, item: Term) -> bif::Result {
+    let heap = &process.context_mut().heap;
+    let meta = table.meta();
+    match item.into_variant() {
+        Variant::Atom(atom::ID) => Ok(meta.id(heap)),
+        Variant::Atom(atom::NAME) => Ok(meta.name.map_or(atom!(UNDEFINED), Term::atom)),
+        Variant::Atom(atom::NAMED_TABLE) => {
+            Ok(Term::boolean(meta.status.contains(Status::DB_NAMED_TABLE)))
+        }
+        Variant::Atom(atom::NODE) => Ok(atom!(NO_NODE_NO_HOST)),
+        Variant::Atom(atom::TYPE) => match table_kind!(meta.kind) {
+            Status::DB_SET => Ok(atom!(SET)),
+            Status::DB_BAG => Ok(atom!(BAG)),
+            Status::DB_DUPLICATE_BAG => Ok(atom!(DUPLICATE_BAG)),
+            Status::DB_ORDERED_SET | Status::DB_CA_ORDERED_SET => Ok(atom!(ORDERED_SET)),
+            _ => unreachable!(),
+        },
+        Variant::Atom(atom::KEYPOS) => Ok(Term::uint(heap, meta.keypos as u32 + 1)),
+        Variant::Atom(atom::SIZE) => Ok(Term::uint(heap, table.len() as u32)),
+        Variant::Atom(atom::MEMORY) => Ok(Term::uint(heap, table.memory() as u32)),
+        Variant::Atom(atom::COMPRESSED) => Ok(Term::boolean(meta.compress)),
+        Variant::Atom(atom::READ_CONCURRENCY) => {
+            Ok(Term::boolean(meta.status.contains(Status::DB_FREQ_READ)))
+        }
+        Variant::Atom(atom::WRITE_CONCURRENCY) => Ok(Term::boolean(
+            meta.status.contains(Status::DB_FINE_LOCKED)
+                || table_kind!(meta.kind) == Status::DB_CA_ORDERED_SET,
+        )),
+        Variant::Atom(atom::PROTECTION) => match meta.protection() {
             Status::DB_PRIVATE => Ok(atom!(PRIVATE)),
             Status::DB_PROTECTED => Ok(atom!(PROTECTED)),
             Status::DB_PUBLIC => Ok(atom!(PUBLIC)),
             _ => unreachable!(),
         },
-        _ => unimplemented!("ets:info/2 {} {}", args[0], args[1]),
+        Variant::Atom(atom::OWNER) => Ok(Term::pid(meta.owner())),
+        Variant::Atom(atom::HEIR) => match meta.heir() {
+            Some(pid) => Ok(Term::pid(pid)),
+            None => Ok(atom!(NONE)),
+        },
+        Variant::Atom(atom::FIXED) => Ok(Term::boolean(meta.fixations.lock().since().is_some())),
+        Variant::Atom(atom::SAFE_FIXED) | Variant::Atom(atom::SAFE_FIXED_MONOTONIC_TIME) => {
+            let fixations = meta.fixations.lock();
+            let (monotonic, timestamp) = match fixations.since() {
+                Some(since) => since,
+                None => return Ok(atom!(FALSE)),
+            };
+            let time = if item == atom!(SAFE_FIXED) {
+                let time = timestamp
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let secs = time.as_secs();
+                tup3!(
+                    heap,
+                    Term::uint64(heap, secs / 1_000_000),
+                    Term::uint64(heap, secs % 1_000_000),
+                    Term::uint(heap, time.subsec_micros())
+                )
+            } else {
+                Term::uint64(heap, monotonic.as_nanos() as u64)
+            };
+            let procs = fixations.iter().fold(Term::nil(), |acc, (pid, count)| {
+                let count = Term::uint(heap, *count as u32);
+                cons!(heap, tup2!(heap, Term::pid(*pid), count), acc)
+            });
+            Ok(tup2!(heap, time, procs))
+        }
+        _ => Err(badarg!()),
     }
 }
 
-// safe_fixtable_2
-// first_1
-// next_2
-// last_1
-// prev_2
-// take_2
-// update_element_3
-// update_counter_3
-// update_counter_4
 // insert_new_2
 // rename_2
 // lookup_2
 // member_2
 // give_away_3
 // setopts_2
-// internal_delete_all_2
 // delete_2
-// delete_object_2
 // select_delete_2
-// internal_select_delete_2
 // internal_request_all_0
 // slot_2
-// match_1
-// match_2
-// match_3
-// select_3
-// select_1
-// select_2
 // select_count_1
-// select_count_2
 // select_replace_1
-// select_replace_2
-// select_reverse_3
-// select_reverse_1
-// select_reverse_2
-// match_object_1
-// match_object_2
-// match_object_3
 // info_1
 // info_2
 // is_compiled_ms_1
 // match_spec_compile_1
 // match_spec_run_r_3
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::module;
+    use crate::process;
+    use std::sync::atomic::Ordering;
+
+    fn keys(vm: &vm::Machine, process: &RcProcess, tid: Term) -> Vec<Term> {
+        let mut keys = Vec::new();
+        let mut key = first_1(vm, process, &[tid]).unwrap();
+        while key != atom!(DOLLAR_END_OF_TABLE) {
+            keys.push(key);
+            key = next_2(vm, process, &[tid, key]).unwrap();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_ordered_set_traversal() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let opts = cons!(heap, atom!(ORDERED_SET), Term::nil());
+        let tid = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+        for i in &[3, 1, 2] {
+            let obj = tup2!(heap, Term::int(*i), atom!(TRUE));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+        }
+
+        assert_eq!(
+            keys(&vm, &process, tid),
+            vec![Term::int(1), Term::int(2), Term::int(3)]
+        );
+        assert_eq!(prev_2(&vm, &process, &[tid, Term::int(3)]), Ok(Term::int(2)));
+        assert_eq!(
+            prev_2(&vm, &process, &[tid, Term::int(1)]),
+            Ok(atom!(DOLLAR_END_OF_TABLE))
+        );
+        // keys don't have to exist
+        assert_eq!(next_2(&vm, &process, &[tid, Term::int(0)]), Ok(Term::int(1)));
+    }
+
+    #[test]
+    fn test_set_traversal() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let tid = new_2(&vm, &process, &[atom!(SET), Term::nil()]).unwrap();
+        for i in 0..10 {
+            let obj = tup2!(heap, Term::int(i), atom!(TRUE));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+        }
+
+        safe_fixtable_2(&vm, &process, &[tid, atom!(TRUE)]).unwrap();
+        assert_eq!(info_2(&vm, &process, &[tid, atom!(FIXED)]), Ok(atom!(TRUE)));
+
+        let all = keys(&vm, &process, tid);
+        assert_eq!(all.len(), 10);
+
+        // deleting the current key doesn't break the traversal
+        delete_2(&vm, &process, &[tid, all[4]]).unwrap();
+        assert_eq!(next_2(&vm, &process, &[tid, all[4]]), Ok(all[5]));
+
+        let list = tab2list_1(&vm, &process, &[tid]).unwrap();
+        assert_eq!(Cons::cast_from(&list).unwrap().iter().count(), 9);
+
+        safe_fixtable_2(&vm, &process, &[tid, atom!(FALSE)]).unwrap();
+        assert_eq!(info_2(&vm, &process, &[tid, atom!(FIXED)]), Ok(atom!(FALSE)));
+    }
+
+    #[test]
+    fn test_select_continuation() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let opts = cons!(heap, atom!(ORDERED_SET), Term::nil());
+        let tid = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+        for i in 0..5 {
+            let obj = tup2!(heap, Term::int(i), atom!(TRUE));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+        }
+
+        let len = |list: Term| Cons::cast_from(&list).unwrap().iter().count();
+
+        let res = match_object_3(&vm, &process, &[tid, atom!(UNDERSCORE), Term::int(2)]).unwrap();
+        let res = Tuple::cast_from(&res).unwrap();
+        assert_eq!(len(res[0]), 2);
+
+        let res = select_1(&vm, &process, &[res[1]]).unwrap();
+        let res = Tuple::cast_from(&res).unwrap();
+        assert_eq!(len(res[0]), 2);
+
+        let res = select_1(&vm, &process, &[res[1]]).unwrap();
+        let res = Tuple::cast_from(&res).unwrap();
+        assert_eq!(len(res[0]), 1);
+        assert_eq!(res[1], atom!(DOLLAR_END_OF_TABLE));
+
+        assert_eq!(
+            select_1(&vm, &process, &[res[1]]),
+            Ok(atom!(DOLLAR_END_OF_TABLE))
+        );
+
+        // a body that isn't a list doesn't compile
+        let head = tup!(heap, atom!(UNDERSCORE), Term::nil(), atom!(TRUE));
+        let spec = cons!(heap, head, Term::nil());
+        assert!(select_2(&vm, &process, &[tid, spec]).is_err());
+        assert!(select_delete_2(&vm, &process, &[tid, spec]).is_err());
+    }
+
+    #[test]
+    fn test_bag_semantics() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let len = |list: Term| {
+            if list.is_nil() {
+                return 0;
+            }
+            Cons::cast_from(&list).unwrap().iter().count()
+        };
+
+        for (kind, expected) in &[(atom!(BAG), 2), (atom!(DUPLICATE_BAG), 3)] {
+            let opts = cons!(heap, *kind, Term::nil());
+            let tid = new_2(&vm, &process, &[*kind, opts]).unwrap();
+            let key = atom!(TRUE);
+            for i in &[1, 1, 2] {
+                let obj = tup2!(heap, key, Term::int(*i));
+                insert_2(&vm, &process, &[tid, obj]).unwrap();
+            }
+            let res = lookup_2(&vm, &process, &[tid, key]).unwrap();
+            assert_eq!(len(res), *expected);
+
+            // delete_object removes all copies
+            let obj = tup2!(heap, key, Term::int(1));
+            delete_object_2(&vm, &process, &[tid, obj]).unwrap();
+            let res = lookup_2(&vm, &process, &[tid, key]).unwrap();
+            assert_eq!(len(res), 1);
+
+            let res = take_2(&vm, &process, &[tid, key]).unwrap();
+            assert_eq!(len(res), 1);
+            assert_eq!(member_2(&vm, &process, &[tid, key]), Ok(atom!(FALSE)));
+        }
+    }
+
+    #[test]
+    fn test_delete_all_objects() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let tid = new_2(&vm, &process, &[atom!(SET), Term::nil()]).unwrap();
+        for i in 0..3 {
+            let obj = tup2!(heap, Term::int(i), atom!(TRUE));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+        }
+
+        let pattern = tup2!(heap, Term::int(1), atom!(UNDERSCORE));
+        match_delete_2(&vm, &process, &[tid, pattern]).unwrap();
+        assert_eq!(member_2(&vm, &process, &[tid, Term::int(1)]), Ok(atom!(FALSE)));
+
+        delete_all_objects_1(&vm, &process, &[tid]).unwrap();
+        assert_eq!(first_1(&vm, &process, &[tid]), Ok(atom!(DOLLAR_END_OF_TABLE)));
+    }
+
+    #[test]
+    fn test_update_counter() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let tid = new_2(&vm, &process, &[atom!(SET), Term::nil()]).unwrap();
+        let key = atom!(TRUE);
+
+        // missing key without a default
+        assert!(update_counter_3(&vm, &process, &[tid, key, Term::int(1)]).is_err());
+
+        let default = tup3!(heap, atom!(UNDEFINED), Term::int(0), Term::int(10));
+        let res = update_counter_4(&vm, &process, &[tid, key, Term::int(5), default]);
+        assert_eq!(res, Ok(Term::int(5)));
+
+        // {Pos, Incr, Threshold, SetValue} wraps around
+        let op = tup!(heap, Term::int(2), Term::int(3), Term::int(6), Term::int(0));
+        let res = update_counter_3(&vm, &process, &[tid, key, op]);
+        assert_eq!(res, Ok(Term::int(0)));
+
+        let ops = cons!(
+            heap,
+            tup2!(heap, Term::int(2), Term::int(1)),
+            cons!(heap, tup2!(heap, Term::int(3), Term::int(-1)), Term::nil())
+        );
+        let res = update_counter_3(&vm, &process, &[tid, key, ops]).unwrap();
+        let res: Vec<_> = Cons::cast_from(&res).unwrap().iter().copied().collect();
+        assert_eq!(res, vec![Term::int(1), Term::int(9)]);
+
+        // the key can't be updated
+        let op = tup2!(heap, Term::int(1), Term::int(1));
+        assert!(update_counter_3(&vm, &process, &[tid, key, op]).is_err());
+    }
+
+    #[test]
+    fn test_update_element_ordered_set() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let opts = cons!(heap, atom!(ORDERED_SET), Term::nil());
+        let tid = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+        let key = Term::int(1);
+
+        let spec = tup2!(heap, Term::int(2), atom!(TRUE));
+        let res = update_element_3(&vm, &process, &[tid, key, spec]);
+        assert_eq!(res, Ok(atom!(FALSE)));
+
+        let default = tup2!(heap, Term::int(0), atom!(FALSE));
+        let res = update_element_4(&vm, &process, &[tid, key, spec, default]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+
+        let res = lookup_element_3(&vm, &process, &[tid, key, Term::int(2)]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+    }
+
+    #[test]
+    fn test_protection() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let owner = process::allocate(&vm, 0, 0, module).unwrap();
+        let other = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &owner.context_mut().heap;
+
+        // protected by default: others can read but not write
+        let tid = new_2(&vm, &owner, &[atom!(SET), Term::nil()]).unwrap();
+        let obj = tup2!(heap, Term::int(1), atom!(TRUE));
+        insert_2(&vm, &owner, &[tid, obj]).unwrap();
+        assert!(lookup_2(&vm, &other, &[tid, Term::int(1)]).is_ok());
+        assert!(insert_2(&vm, &other, &[tid, obj]).is_err());
+
+        let opts = cons!(heap, atom!(PRIVATE), Term::nil());
+        let tid = new_2(&vm, &owner, &[atom!(PRIVATE), opts]).unwrap();
+        assert!(lookup_2(&vm, &other, &[tid, Term::int(1)]).is_err());
+        assert_eq!(
+            info_2(&vm, &other, &[tid, atom!(OWNER)]),
+            Ok(Term::pid(owner.pid))
+        );
+
+        // the protection can be changed later on, by the owner only
+        let opt = tup2!(heap, atom!(PROTECTION), atom!(PUBLIC));
+        assert!(setopts_2(&vm, &other, &[tid, opt]).is_err());
+        assert_eq!(setopts_2(&vm, &owner, &[tid, opt]), Ok(atom!(TRUE)));
+        assert!(insert_2(&vm, &other, &[tid, obj]).is_ok());
+        assert_eq!(
+            info_2(&vm, &other, &[tid, atom!(PROTECTION)]),
+            Ok(atom!(PUBLIC))
+        );
+        let opt = tup2!(heap, atom!(PROTECTION), atom!(TRUE));
+        assert!(setopts_2(&vm, &owner, &[tid, opt]).is_err());
+    }
+
+    #[test]
+    fn test_give_away() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let owner = process::allocate(&vm, 0, 0, module).unwrap();
+        let other = process::allocate(&vm, 0, 0, module).unwrap();
+
+        let tid = new_2(&vm, &owner, &[atom!(SET), Term::nil()]).unwrap();
+        let heap = &owner.context_mut().heap;
+        let gift = tup2!(heap, atom!(TRUE), cons!(heap, Term::int(1), Term::nil()));
+
+        // only the owner can give the table away, and not to itself
+        assert!(give_away_3(&vm, &other, &[tid, Term::pid(owner.pid), gift]).is_err());
+        assert!(give_away_3(&vm, &owner, &[tid, Term::pid(owner.pid), gift]).is_err());
+
+        let res = give_away_3(&vm, &owner, &[tid, Term::pid(other.pid), gift]);
+        assert_eq!(res, Ok(atom!(TRUE)));
+        assert_eq!(
+            info_2(&vm, &owner, &[tid, atom!(OWNER)]),
+            Ok(Term::pid(other.pid))
+        );
+        assert!(setopts_2(&vm, &owner, &[tid, Term::nil()]).is_err());
+
+        // the gift data was copied, it doesn't point into the old owner's heap
+        other.process_incoming().unwrap();
+        let msg = *other.local_data().mailbox.iter().next().unwrap();
+        let data = Tuple::cast_from(&msg).unwrap()[3];
+        assert_eq!(data, gift);
+        assert_ne!(
+            Tuple::cast_from(&data).unwrap() as *const Tuple,
+            Tuple::cast_from(&gift).unwrap() as *const Tuple
+        );
+    }
+
+    #[test]
+    fn test_owner_exited() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let owner = process::allocate(&vm, 0, 0, module).unwrap();
+        let other = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &owner.context_mut().heap;
+
+        let heir = tup!(heap, atom!(HEIR), Term::pid(other.pid), atom!(TRUE));
+        let opts = cons!(heap, heir, Term::nil());
+        let inherited = new_2(&vm, &owner, &[atom!(SET), opts]).unwrap();
+        let deleted = new_2(&vm, &owner, &[atom!(SET), Term::nil()]).unwrap();
+        safe_fixtable_2(&vm, &owner, &[inherited, atom!(TRUE)]).unwrap();
+
+        crate::ets::owner_exited(&vm, owner.pid);
+
+        // the table with an heir changes hands and loses the owner's fixation
+        assert_eq!(
+            info_2(&vm, &other, &[inherited, atom!(OWNER)]),
+            Ok(Term::pid(other.pid))
+        );
+        assert_eq!(
+            info_2(&vm, &other, &[inherited, atom!(FIXED)]),
+            Ok(atom!(FALSE))
+        );
+        assert_eq!(
+            info_2(&vm, &other, &[deleted, atom!(OWNER)]),
+            Ok(atom!(UNDEFINED))
+        );
+        assert_eq!(vm.ets_tables.len(), 1);
+
+        other.process_incoming().unwrap();
+        let msg = *other.local_data().mailbox.iter().next().unwrap();
+        assert_eq!(Tuple::cast_from(&msg).unwrap()[3], atom!(TRUE));
+
+        // the heir is now the owner, so its exit deletes the table
+        crate::ets::owner_exited(&vm, other.pid);
+        assert_eq!(vm.ets_tables.len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_tables() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let concurrency = cons!(
+            heap,
+            tup2!(heap, atom!(WRITE_CONCURRENCY), atom!(TRUE)),
+            cons!(
+                heap,
+                tup2!(heap, atom!(READ_CONCURRENCY), atom!(TRUE)),
+                Term::nil()
+            )
+        );
+        for kind in &[atom!(SET), atom!(BAG), atom!(ORDERED_SET)] {
+            let opts = cons!(heap, *kind, concurrency);
+            let tid = new_2(&vm, &process, &[*kind, opts]).unwrap();
+            for i in 0..100 {
+                let obj = tup2!(heap, Term::int(i), atom!(TRUE));
+                insert_2(&vm, &process, &[tid, obj]).unwrap();
+            }
+            assert_eq!(keys(&vm, &process, tid).len(), 100);
+            assert_eq!(
+                member_2(&vm, &process, &[tid, Term::int(42)]),
+                Ok(atom!(TRUE))
+            );
+            assert_eq!(
+                delete_2(&vm, &process, &[tid, Term::int(42)]),
+                Ok(atom!(TRUE))
+            );
+            let list = tab2list_1(&vm, &process, &[tid]).unwrap();
+            assert_eq!(Cons::cast_from(&list).unwrap().iter().count(), 99);
+        }
+    }
+
+    #[test]
+    fn test_compressed() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        for kind in &[atom!(SET), atom!(BAG), atom!(ORDERED_SET)] {
+            let opts = cons!(heap, *kind, cons!(heap, atom!(COMPRESSED), Term::nil()));
+            let tid = new_2(&vm, &process, &[*kind, opts]).unwrap();
+            for i in 0..10 {
+                let obj = tup2!(heap, Term::int(i), Term::int(i * 2));
+                insert_2(&vm, &process, &[tid, obj]).unwrap();
+            }
+            let obj = tup2!(heap, Term::int(3), Term::int(6));
+            assert_eq!(
+                lookup_2(&vm, &process, &[tid, Term::int(3)]),
+                Ok(cons!(heap, obj, Term::nil()))
+            );
+            assert_eq!(delete_object_2(&vm, &process, &[tid, obj]), Ok(atom!(TRUE)));
+            assert_eq!(
+                lookup_2(&vm, &process, &[tid, Term::int(3)]),
+                Ok(Term::nil())
+            );
+
+            assert_eq!(
+                info_2(&vm, &process, &[tid, atom!(COMPRESSED)]),
+                Ok(atom!(TRUE))
+            );
+            assert_eq!(info_2(&vm, &process, &[tid, atom!(SIZE)]), Ok(Term::int(9)));
+            assert_eq!(info_2(&vm, &process, &[tid, atom!(TYPE)]), Ok(*kind));
+            assert!(
+                info_2(&vm, &process, &[tid, atom!(MEMORY)])
+                    .unwrap()
+                    .to_int()
+                    .unwrap()
+                    > 0
+            );
+            let info = info_1(&vm, &process, &[tid]).unwrap();
+            assert_eq!(Cons::cast_from(&info).unwrap().iter().count(), 14);
+
+            delete_1(&vm, &process, &[tid]).unwrap();
+            assert_eq!(info_1(&vm, &process, &[tid]), Ok(atom!(UNDEFINED)));
+        }
+
+        let opts = cons!(heap, atom!(COMPRESSED), Term::nil());
+        let tid = new_2(&vm, &process, &[atom!(SET), opts]).unwrap();
+        let obj = tup2!(heap, atom!(OK), Term::int(1));
+        insert_2(&vm, &process, &[tid, obj]).unwrap();
+        let args = [tid, atom!(OK), Term::int(41)];
+        assert_eq!(update_counter_3(&vm, &process, &args), Ok(Term::int(42)));
+        assert_eq!(
+            lookup_2(&vm, &process, &[tid, atom!(OK)]),
+            Ok(cons!(
+                heap,
+                tup2!(heap, atom!(OK), Term::int(42)),
+                Term::nil()
+            ))
+        );
+    }
+
+    /// What ets:tab2file/file2tab do with a table: every object goes through term_to_binary into
+    /// an incremental md5, and is read back with binary_to_term into a new table.
+    #[test]
+    fn test_tab2file_round_trip() {
+        use crate::bif::erlang::{
+            binary_to_term_1, md5_final_1, md5_init_0, md5_update_2, term_to_binary_1,
+        };
+        use crate::bitstring;
+
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let dump = |tid: Term| {
+            let list = tab2list_1(&vm, &process, &[tid]).unwrap();
+            let mut md5 = md5_init_0(&vm, &process, &[]).unwrap();
+            let mut objects = Vec::new();
+            for object in Cons::cast_from(&list).unwrap().iter() {
+                let bin = term_to_binary_1(&vm, &process, &[*object]).unwrap();
+                md5 = md5_update_2(&vm, &process, &[md5, bin]).unwrap();
+                objects.push(bin);
+            }
+            (objects, md5_final_1(&vm, &process, &[md5]).unwrap())
+        };
+
+        let opts = cons!(heap, atom!(ORDERED_SET), Term::nil());
+        let tid = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+        for i in 0..20 {
+            let bin = Term::binary(heap, bitstring::Binary::from(vec![i as u8; i]));
+            let obj = tup3!(heap, Term::int(i as i32), bin, atom!(OK));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+        }
+        let (objects, digest) = dump(tid);
+
+        let opts = cons!(heap, atom!(ORDERED_SET), Term::nil());
+        let copy = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+        for bin in &objects {
+            let obj = binary_to_term_1(&vm, &process, &[*bin]).unwrap();
+            insert_2(&vm, &process, &[copy, obj]).unwrap();
+        }
+        let (_, copy_digest) = dump(copy);
+        assert_eq!(copy_digest, digest);
+        assert_eq!(
+            info_2(&vm, &process, &[copy, atom!(SIZE)]),
+            Ok(Term::int(20))
+        );
+        assert_eq!(
+            lookup_2(&vm, &process, &[copy, Term::int(7)]),
+            lookup_2(&vm, &process, &[tid, Term::int(7)])
+        );
+    }
+
+    #[test]
+    fn test_compressed_bytes() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        for kind in &[atom!(SET), atom!(BAG), atom!(ORDERED_SET)] {
+            let opts = cons!(heap, *kind, cons!(heap, atom!(COMPRESSED), Term::nil()));
+            let tid = new_2(&vm, &process, &[*kind, opts]).unwrap();
+            let table = get_table(&vm, &process, tid, Access::Info).unwrap();
+            let compressed_bytes = || table.meta().compressed_bytes.load(Ordering::Relaxed);
+
+            for i in 0..10 {
+                let obj = tup2!(heap, Term::int(i), Term::int(0));
+                insert_2(&vm, &process, &[tid, obj]).unwrap();
+            }
+            let bytes = compressed_bytes();
+            assert!(bytes > 0);
+
+            // updates and overwrites replace the encoding
+            if *kind != atom!(BAG) {
+                for _ in 0..10 {
+                    let args = [tid, Term::int(1), Term::int(1)];
+                    update_counter_3(&vm, &process, &args).unwrap();
+                }
+                let obj = tup2!(heap, Term::int(2), Term::int(0));
+                insert_2(&vm, &process, &[tid, obj]).unwrap();
+                assert_eq!(compressed_bytes(), bytes);
+            }
+
+            delete_2(&vm, &process, &[tid, Term::int(3)]).unwrap();
+            assert!(compressed_bytes() < bytes);
+            take_2(&vm, &process, &[tid, Term::int(4)]).unwrap();
+            delete_all_objects_1(&vm, &process, &[tid]).unwrap();
+            assert_eq!(compressed_bytes(), 0);
+
+            // objects that can't be encoded are stored as is
+            let obj = tup2!(heap, Term::int(1), Term::reference(heap, 197));
+            insert_2(&vm, &process, &[tid, obj]).unwrap();
+            assert_eq!(
+                lookup_2(&vm, &process, &[tid, Term::int(1)]),
+                Ok(cons!(heap, obj, Term::nil()))
+            );
+            assert_eq!(compressed_bytes(), 0);
+            assert_eq!(delete_object_2(&vm, &process, &[tid, obj]), Ok(atom!(TRUE)));
+            assert_eq!(compressed_bytes(), 0);
+
+            delete_1(&vm, &process, &[tid]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_select_key_prefix() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let user = Term::atom(Atom::from("user"));
+        let ints = |list: Term| -> Vec<i32> {
+            if list.is_nil() {
+                return Vec::new();
+            }
+            Cons::cast_from(&list)
+                .unwrap()
+                .iter()
+                .map(|t| t.to_int().unwrap())
+                .collect()
+        };
+
+        let concurrency = cons!(
+            heap,
+            tup2!(heap, atom!(WRITE_CONCURRENCY), atom!(TRUE)),
+            Term::nil()
+        );
+        for opts in &[Term::nil(), concurrency] {
+            let opts = cons!(heap, atom!(ORDERED_SET), *opts);
+            let tid = new_2(&vm, &process, &[atom!(ORDERED_SET), opts]).unwrap();
+            for tag in &[atom!(TRUE), user, Term::atom(Atom::from("zzz"))] {
+                for i in 0..20 {
+                    let obj = tup2!(heap, tup2!(heap, *tag, Term::int(i)), Term::int(i));
+                    insert_2(&vm, &process, &[tid, obj]).unwrap();
+                }
+            }
+
+            // [{{{user, '_'}, '$1'}, [], ['$1']}]
+            let var = Term::atom(Atom::from("$1"));
+            let head = tup2!(heap, tup2!(heap, user, atom!(UNDERSCORE)), var);
+            let body = cons!(heap, var, Term::nil());
+            let ms = cons!(heap, tup3!(heap, head, Term::nil(), body), Term::nil());
+            let res = select_2(&vm, &process, &[tid, ms]).unwrap();
+            assert_eq!(ints(res), (0..20).collect::<Vec<_>>());
+            let res = select_reverse_2(&vm, &process, &[tid, ms]).unwrap();
+            assert_eq!(ints(res), (0..20).rev().collect::<Vec<_>>());
+
+            // [{{{user, '$1'}, '_'}, [{'>=', '$1', 5}, {'<', '$1', 8}], ['$1']}]
+            let head = tup2!(heap, tup2!(heap, user, var), atom!(UNDERSCORE));
+            let guard = cons!(
+                heap,
+                tup3!(heap, atom!(GE), var, Term::int(5)),
+                cons!(heap, tup3!(heap, atom!(LT), var, Term::int(8)), Term::nil())
+            );
+            let ms = cons!(heap, tup3!(heap, head, guard, body), Term::nil());
+            let res = select_2(&vm, &process, &[tid, ms]).unwrap();
+            assert_eq!(ints(res), vec![5, 6, 7]);
+
+            // a guard on the whole key
+            let key = tup2!(heap, user, Term::int(17));
+            let head = tup2!(heap, var, atom!(UNDERSCORE));
+            let guard = cons!(
+                heap,
+                tup3!(heap, atom!(GT), var, tup2!(heap, atom!(CONST), key)),
+                Term::nil()
+            );
+            let body = cons!(heap, atom!(TRUE), Term::nil());
+            let ms = cons!(heap, tup3!(heap, head, guard, body), Term::nil());
+            let res = select_count_2(&vm, &process, &[tid, ms]).unwrap();
+            assert_eq!(res, Term::int(22));
+
+            let res = select_delete_2(&vm, &process, &[tid, ms]).unwrap();
+            assert_eq!(res, Term::int(22));
+            let list = tab2list_1(&vm, &process, &[tid]).unwrap();
+            assert_eq!(Cons::cast_from(&list).unwrap().iter().count(), 38);
+        }
+    }
+}
@@ -0,0 +1,684 @@
+//! Contention adapting ordered set, used for `ordered_set` tables with `write_concurrency`.
+//!
+//! Keys are split over a sorted list of base nodes, each covering a key range with its own lock
+//! and heap. Base nodes keep statistics on how often their lock was contended: contended nodes
+//! are split in two so that writers spread over more locks, and uncontended nodes are joined
+//! with a neighbour so that operations on the whole table lock fewer nodes.
+//!
+//! Operations on a single key find their node with the routing layer read locked, release it
+//! and then lock the node. A node can be split or joined in between, in which case it's marked
+//! invalid and the lookup is retried. Splits and joins only ever `try_write` the routing layer,
+//! so operations on the whole table can keep it read locked while locking the nodes in order.
+use super::*;
+use crate::immix::Heap;
+use crate::value::{BigInt, CastFrom, Term, Tuple};
+use error::*;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::ops::Bound::{Excluded, Unbounded};
+
+/// Added to the node statistics when the lock was contended.
+const CONTENDED: isize = 250;
+/// Subtracted from the node statistics when the lock was free.
+const UNCONTENDED: isize = 1;
+const SPLIT_THRESHOLD: isize = 1000;
+const JOIN_THRESHOLD: isize = -1000;
+/// Nodes with fewer objects aren't split.
+const MIN_SPLIT_SIZE: usize = 16;
+
+struct Base {
+    map: BTreeMap<Term, Term>,
+    heap: Heap,
+    /// Contention statistics.
+    stat: isize,
+    /// Cleared once the node was replaced by a split or join.
+    valid: bool,
+}
+
+impl Base {
+    fn new(map: BTreeMap<Term, Term>, heap: Heap) -> Self {
+        Self {
+            map,
+            heap,
+            stat: 0,
+            valid: true,
+        }
+    }
+
+    /// Invalidates the node, handing its objects and heap over to the node replacing it.
+    fn retire(&mut self) -> (BTreeMap<Term, Term>, Heap) {
+        self.valid = false;
+        let map = std::mem::replace(&mut self.map, BTreeMap::new());
+        // nothing reads a node's objects once it's invalid, so the heap has a single owner again
+        (map, unsafe { std::ptr::read(&self.heap) })
+    }
+}
+
+impl Drop for Base {
+    fn drop(&mut self) {
+        // retired nodes gave their heap away
+        if self.valid {
+            unsafe { std::ptr::read(&self.heap).free() }
+        }
+    }
+}
+
+type Node = Arc<Mutex<Base>>;
+
+pub(crate) struct CaOrderedSet {
+    meta: Metadata,
+    /// Routing layer: `(lowest key, node)` sorted by key. The lowest key lives on the node's
+    /// heap, and is ignored for the first node.
+    route: RwLock<Vec<(Term, Node)>>,
+}
+
+unsafe impl Sync for CaOrderedSet {}
+unsafe impl Send for CaOrderedSet {}
+
+fn get_key(pos: usize, value: Term) -> Term {
+    let tuple = Tuple::cast_from(&value).unwrap();
+    tuple[pos]
+}
+
+/// Index of the node covering the key.
+fn find(route: &[(Term, Node)], key: &Term) -> usize {
+    match route[1..].binary_search_by(|(bound, _)| bound.cmp(key)) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+impl CaOrderedSet {
+    pub fn new(meta: Metadata, _process: &RcProcess) -> Self {
+        let node = Arc::new(Mutex::new(Base::new(BTreeMap::new(), Heap::new())));
+        Self {
+            meta,
+            route: RwLock::new(vec![(Term::nil(), node)]),
+        }
+    }
+
+    /// Runs `f` on the node covering the key, then adapts the node to the contention.
+    fn access<R>(&self, key: &Term, f: impl FnOnce(&mut Base) -> R) -> R {
+        loop {
+            let node = {
+                let route = self.route.read();
+                route[find(&route, key)].1.clone()
+            };
+            let mut base = match node.try_lock() {
+                Some(mut base) => {
+                    base.stat -= UNCONTENDED;
+                    base
+                }
+                None => {
+                    let mut base = node.lock();
+                    base.stat += CONTENDED;
+                    base
+                }
+            };
+            if !base.valid {
+                continue;
+            }
+            let res = f(&mut *base);
+            self.adapt(&node, &mut *base);
+            return res;
+        }
+    }
+
+    /// Runs `f` with all nodes locked, in key order.
+    fn access_all<R>(&self, f: impl FnOnce(&mut [MutexGuard<Base>]) -> R) -> R {
+        let route = self.route.read();
+        let mut bases: Vec<_> = route.iter().map(|(_, node)| node.lock()).collect();
+        f(&mut bases)
+    }
+
+    fn adapt(&self, node: &Node, base: &mut Base) {
+        if base.stat > SPLIT_THRESHOLD {
+            if base.map.len() >= MIN_SPLIT_SIZE {
+                self.split(node, base);
+            } else {
+                base.stat = 0;
+            }
+        } else if base.stat < JOIN_THRESHOLD {
+            self.join(node, base);
+        }
+    }
+
+    /// Splits a contended node in two. The left half keeps the heap, the right half is copied.
+    fn split(&self, node: &Node, base: &mut Base) {
+        let mut route = match self.route.try_write() {
+            Some(route) => route,
+            None => return,
+        };
+        let pos = route
+            .iter()
+            .position(|(_, n)| Arc::ptr_eq(n, node))
+            .unwrap();
+
+        let mid = *base.map.keys().nth(base.map.len() / 2).unwrap();
+        let heap = Heap::fragment();
+        let right: BTreeMap<_, _> = base
+            .map
+            .split_off(&mid)
+            .iter()
+            .map(|(key, val)| self.copy_entry(&heap, *key, *val))
+            .collect();
+        let bound = *right.keys().next().unwrap();
+
+        let (left, left_heap) = base.retire();
+        let left = Base::new(left, left_heap);
+
+        route[pos].1 = Arc::new(Mutex::new(left));
+        route.insert(
+            pos + 1,
+            (bound, Arc::new(Mutex::new(Base::new(right, heap)))),
+        );
+    }
+
+    /// Joins an uncontended node with a neighbour, copying the neighbour's objects. The
+    /// neighbour's heap is freed.
+    fn join(&self, node: &Node, base: &mut Base) {
+        let mut route = match self.route.try_write() {
+            Some(route) => route,
+            None => return,
+        };
+        if route.len() < 2 {
+            base.stat = 0;
+            return;
+        }
+        let pos = route
+            .iter()
+            .position(|(_, n)| Arc::ptr_eq(n, node))
+            .unwrap();
+        let (lo, hi) = if pos + 1 < route.len() {
+            (pos, pos + 1)
+        } else {
+            (pos - 1, pos)
+        };
+        let neighbour = if lo == pos { hi } else { lo };
+        let neighbour = route[neighbour].1.clone();
+        // never block while holding a node, the neighbour might be waiting on us
+        let mut other = match neighbour.try_lock() {
+            Some(other) => other,
+            None => return,
+        };
+
+        let (mut map, heap) = base.retire();
+        let (other_map, other_heap) = other.retire();
+        for (key, val) in other_map {
+            let (key, val) = self.copy_entry(&heap, key, val);
+            map.insert(key, val);
+        }
+        let bound = if lo == pos {
+            route[lo].0
+        } else {
+            route[lo].0.deep_clone(&heap)
+        };
+        unsafe { other_heap.free() };
+
+        route[lo] = (bound, Arc::new(Mutex::new(Base::new(map, heap))));
+        route.remove(hi);
+    }
+
+    /// Copies a stored entry onto another node's heap.
+    fn copy_entry(&self, heap: &Heap, key: Term, val: Term) -> (Term, Term) {
+        if self.meta.compress {
+            // the key isn't part of the encoded object
+            (key.deep_clone(heap), val.deep_clone(heap))
+        } else {
+            let val = val.deep_clone(heap);
+            (get_key(self.meta.keypos, val), val)
+        }
+    }
+
+    /// Finds the first key after `key` (or the first key), copied onto the process heap.
+    fn next_key(&self, process: &RcProcess, key: Option<Term>) -> Term {
+        let heap = &process.context_mut().heap;
+        let route = self.route.read();
+        let start = key.map_or(0, |key| find(&route, &key));
+
+        route[start..]
+            .iter()
+            .find_map(|(_, node)| {
+                let base = node.lock();
+                let mut keys: Box<dyn Iterator<Item = &Term> + '_> = match key {
+                    Some(key) => {
+                        Box::new(base.map.range((Excluded(key), Unbounded)).map(|(k, _)| k))
+                    }
+                    None => Box::new(base.map.keys()),
+                };
+                keys.next().map(|key| key.deep_clone(heap))
+            })
+            .unwrap_or_else(|| atom!(DOLLAR_END_OF_TABLE))
+    }
+
+    /// Finds the last key before `key` (or the last key), copied onto the process heap.
+    fn prev_key(&self, process: &RcProcess, key: Option<Term>) -> Term {
+        let heap = &process.context_mut().heap;
+        let route = self.route.read();
+        let end = key.map_or(route.len() - 1, |key| find(&route, &key));
+
+        route[..=end]
+            .iter()
+            .rev()
+            .find_map(|(_, node)| {
+                let base = node.lock();
+                let mut keys: Box<dyn DoubleEndedIterator<Item = &Term> + '_> = match key {
+                    Some(key) => Box::new(base.map.range(..key).map(|(k, _)| k)),
+                    None => Box::new(base.map.keys()),
+                };
+                keys.next_back().map(|key| key.deep_clone(heap))
+            })
+            .unwrap_or_else(|| atom!(DOLLAR_END_OF_TABLE))
+    }
+}
+
+impl Table for CaOrderedSet {
+    fn meta(&self) -> &Metadata {
+        &self.meta
+    }
+
+    fn first(&self, process: &RcProcess) -> Result<Term> {
+        Ok(self.next_key(process, None))
+    }
+
+    fn next(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        // the key doesn't have to exist, we return the next key in term order.
+        Ok(self.next_key(process, Some(key)))
+    }
+
+    fn last(&self, process: &RcProcess) -> Result<Term> {
+        Ok(self.prev_key(process, None))
+    }
+
+    fn prev(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        Ok(self.prev_key(process, Some(key)))
+    }
+
+    // put
+    fn insert(&self, _process: &RcProcess, value: Term, _key_clash_fail: bool) -> Result<()> {
+        let keypos = self.meta.keypos;
+        self.access(&get_key(keypos, value), |base| {
+            let (key, value) = self.meta.store(&base.heap, value);
+            if let Some(prev) = base.map.insert(key, value) {
+                self.meta.release(prev);
+            }
+        });
+        Ok(())
+    }
+
+    fn get(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        Ok(self.access(&key, |base| {
+            base.map
+                .get(&key)
+                .map(|v| cons!(heap, self.meta.copy(heap, *v), Term::nil()))
+                .unwrap_or_else(Term::nil)
+        }))
+    }
+
+    fn get_element(&self, process: &RcProcess, key: Term, index: usize) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        Ok(self.access(&key, |base| match base.map.get(&key) {
+            Some(value) => {
+                let value = self.meta.load(heap, *value);
+                let tup = Tuple::cast_from(&value).unwrap();
+                assert!(tup.len() > index);
+                tup[index].deep_clone(heap)
+            }
+            None => Term::nil(),
+        }))
+    }
+
+    fn member(&self, key: Term) -> bool {
+        self.access(&key, |base| base.map.contains_key(&key))
+    }
+
+    fn update_element(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        list: Term,
+        default: Option<Term>,
+    ) -> Result<Term> {
+        let keypos = self.meta.keypos;
+        self.access(&key, |base| {
+            match base.map.get(&key) {
+                Some(item) => {
+                    let (object, _) = self.meta.update(&base.heap, *item, |heap, object| {
+                        update_elements(heap, keypos, object, list)
+                    })?;
+                    base.map.insert(key, object);
+                }
+                None => {
+                    let default = match default {
+                        Some(default) => default,
+                        None => return Ok(atom!(FALSE)),
+                    };
+                    let (key, object, _) = self.meta.build(&base.heap, |heap| {
+                        let object = default_object(heap, keypos, key, default)?;
+                        update_elements(heap, keypos, object, list)?;
+                        Ok((object, ()))
+                    })?;
+                    base.map.insert(key, object);
+                }
+            }
+            Ok(atom!(TRUE))
+        })
+    }
+
+    fn update_counter(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        ops: &[CounterOp],
+        default: Option<Term>,
+    ) -> Result<Vec<BigInt>> {
+        let keypos = self.meta.keypos;
+        self.access(&key, |base| match base.map.get(&key) {
+            Some(item) => {
+                let (object, res) = self.meta.update(&base.heap, *item, |heap, object| {
+                    update_counters(heap, keypos, object, ops)
+                })?;
+                base.map.insert(key, object);
+                Ok(res)
+            }
+            None => {
+                let default = match default {
+                    Some(default) => default,
+                    None => return Err(new_error(ErrorKind::BadKey)),
+                };
+                let (key, object, res) = self.meta.build(&base.heap, |heap| {
+                    let object = default_object(heap, keypos, key, default)?;
+                    let res = update_counters(heap, keypos, object, ops)?;
+                    Ok((object, res))
+                })?;
+                base.map.insert(key, object);
+                Ok(res)
+            }
+        })
+    }
+
+    // erase  (remove_entry in rust)
+    fn remove(&self, key: Term) -> Result<Term> {
+        let removed = self.access(&key, |base| base.map.remove(&key));
+        if let Some(prev) = removed {
+            self.meta.release(prev);
+        }
+        Ok(Term::boolean(removed.is_some()))
+    }
+
+    fn remove_object(&self, object: Term) -> Result<Term> {
+        let key = get_key(self.meta.keypos, object);
+        self.access(&key, |base| {
+            if base
+                .map
+                .get(&key)
+                .map_or(false, |val| self.meta.same(*val, object))
+            {
+                let prev = base.map.remove(&key).unwrap();
+                self.meta.release(prev);
+            }
+        });
+        Ok(atom!(TRUE))
+    }
+
+    fn slot(&self, process: &RcProcess, slot: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let mut slot = slot
+            .to_int()
+            .filter(|slot| *slot >= 0)
+            .ok_or_else(|| new_error(ErrorKind::BadParameter))? as usize;
+
+        // walk the nodes in key order, skipping whole nodes until we reach the slot
+        self.access_all(|bases| {
+            for base in bases.iter() {
+                if slot < base.map.len() {
+                    let value = base.map.values().nth(slot).unwrap();
+                    return Ok(cons!(heap, self.meta.copy(heap, *value), Term::nil()));
+                }
+                slot -= base.map.len();
+            }
+            match slot {
+                0 => Ok(atom!(DOLLAR_END_OF_TABLE)),
+                _ => Err(new_error(ErrorKind::BadParameter)),
+            }
+        })
+    }
+
+    fn select_chunk(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+        from: Option<Term>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<(Vec<Term>, Option<Term>)> {
+        let heap = &process.context_mut().heap;
+        self.access_all(|bases| {
+            let maps = bases.iter().map(|base| &base.map);
+            let scan = |map| range::scan(map, &pattern.key_range, from, reverse);
+            let objects: Box<dyn Iterator<Item = (&Term, &Term)> + '_> = if reverse {
+                Box::new(maps.rev().flat_map(scan))
+            } else {
+                Box::new(maps.flat_map(scan))
+            };
+            let objects = objects.map(|(key, val)| (*key, self.meta.load(heap, *val)));
+            Ok(select_objects(vm, process, pattern, flags, objects, limit))
+        })
+    }
+
+    fn select_delete(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let am_true = atom!(TRUE);
+        let count = self.access_all(|bases| {
+            let mut count = 0;
+            for base in bases.iter_mut() {
+                let keys: Vec<Term> = range::scan(&base.map, &pattern.key_range, None, false)
+                    .filter(|(_, val)| {
+                        let object = self.meta.load(heap, **val);
+                        pam::r#match::run(vm, process, pattern, object, flags) == Some(am_true)
+                    })
+                    .map(|(key, _)| *key)
+                    .collect();
+                keys.iter().for_each(|key| {
+                    let prev = base.map.remove(key).unwrap();
+                    self.meta.release(prev);
+                });
+                count += keys.len();
+            }
+            count
+        });
+        Ok(Term::uint(heap, count as u32))
+    }
+
+    fn select_replace(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let keypos = self.meta.keypos;
+        let count = self.access_all(|bases| {
+            // compute all replacements first, so a bad replacement leaves the table untouched
+            let mut replacements = Vec::new();
+            for (i, base) in bases.iter().enumerate() {
+                for (key, object) in range::scan(&base.map, &pattern.key_range, None, false) {
+                    let object = self.meta.load(heap, *object);
+                    if let Some(res) =
+                        replacement(vm, process, pattern, flags, keypos, *key, object)?
+                    {
+                        replacements.push((i, *key, res));
+                    }
+                }
+            }
+
+            let count = replacements.len();
+            for (i, key, res) in replacements {
+                let base = &mut *bases[i];
+                let (_, res) = self.meta.store(&base.heap, res);
+                if let Some(prev) = base.map.insert(key, res) {
+                    self.meta.release(prev);
+                }
+            }
+            Ok(count)
+        })?;
+        Ok(Term::uint(heap, count as u32))
+    }
+
+    fn take(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        Ok(self.access(&key, |base| match base.map.remove(&key) {
+            Some(object) => {
+                self.meta.release(object);
+                cons!(heap, self.meta.copy(heap, object), Term::nil())
+            }
+            None => Term::nil(),
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.access_all(|bases| bases.iter().map(|base| base.map.len()).sum())
+    }
+
+    fn memory(&self) -> usize {
+        let heap_bytes = self.access_all(|bases| bases.iter().map(|base| base.heap.size()).sum());
+        self.meta.memory(heap_bytes)
+    }
+
+    /// takes reds, then returns new reds (equal to delete_all)
+    fn clear(&self, _process: &RcProcess, reds: usize) -> Result<usize> {
+        self.access_all(|bases| {
+            for base in bases.iter_mut() {
+                base.map.values().for_each(|val| self.meta.release(*val));
+                base.map.clear();
+            }
+        });
+        Ok(reds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use crate::process;
+
+    #[test]
+    fn test_split_and_join() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let meta = Metadata {
+            tid: 0,
+            name: None,
+            status: Status::DB_CA_ORDERED_SET | Status::DB_PUBLIC,
+            protection: AtomicU32::new(Status::DB_PUBLIC.bits()),
+            kind: Status::DB_CA_ORDERED_SET,
+            keypos: 0,
+            owner: AtomicU32::new(process.pid),
+            heir: Mutex::new(None),
+            compress: false,
+            compressed_bytes: AtomicUsize::new(0),
+            fixations: Mutex::new(Fixations::default()),
+        };
+        let table = CaOrderedSet::new(meta, &process);
+        for i in 0..100 {
+            let obj = tup2!(heap, Term::int(i), Term::int(i * 2));
+            table.insert(&process, obj, false).unwrap();
+        }
+
+        // pretend the node was heavily contended
+        let node = table.route.read()[0].1.clone();
+        {
+            let mut base = node.lock();
+            base.stat = SPLIT_THRESHOLD + 1;
+            table.adapt(&node, &mut *base);
+            assert!(!base.valid);
+        }
+        assert_eq!(table.route.read().len(), 2);
+
+        // objects are still found on both sides of the split
+        assert!(table.member(Term::int(0)));
+        assert!(table.member(Term::int(99)));
+        assert_eq!(table.next(&process, Term::int(49)), Ok(Term::int(50)));
+        assert_eq!(table.prev(&process, Term::int(50)), Ok(Term::int(49)));
+
+        let node = table.route.read()[1].1.clone();
+        {
+            let mut base = node.lock();
+            base.stat = JOIN_THRESHOLD - 1;
+            table.adapt(&node, &mut *base);
+        }
+        assert_eq!(table.route.read().len(), 1);
+        assert_eq!(
+            table.get_element(&process, Term::int(99), 1),
+            Ok(Term::int(198))
+        );
+        assert_eq!(table.last(&process), Ok(Term::int(99)));
+    }
+
+    #[test]
+    fn test_slot() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let meta = Metadata {
+            tid: 0,
+            name: None,
+            status: Status::DB_CA_ORDERED_SET | Status::DB_PUBLIC,
+            protection: AtomicU32::new(Status::DB_PUBLIC.bits()),
+            kind: Status::DB_CA_ORDERED_SET,
+            keypos: 0,
+            owner: AtomicU32::new(process.pid),
+            heir: Mutex::new(None),
+            compress: false,
+            compressed_bytes: AtomicUsize::new(0),
+            fixations: Mutex::new(Fixations::default()),
+        };
+        let table = CaOrderedSet::new(meta, &process);
+        for i in (0..40).rev() {
+            let obj = tup2!(heap, Term::int(i), Term::int(i * 2));
+            table.insert(&process, obj, false).unwrap();
+        }
+
+        // split the table, so that the slots span multiple nodes
+        let node = table.route.read()[0].1.clone();
+        {
+            let mut base = node.lock();
+            base.stat = SPLIT_THRESHOLD + 1;
+            table.adapt(&node, &mut *base);
+        }
+        assert_eq!(table.route.read().len(), 2);
+
+        for i in &[0, 19, 20, 39] {
+            let expected = cons!(
+                heap,
+                tup2!(heap, Term::int(*i), Term::int(i * 2)),
+                Term::nil()
+            );
+            assert_eq!(table.slot(&process, Term::int(*i)), Ok(expected));
+        }
+        assert_eq!(
+            table.slot(&process, Term::int(40)),
+            Ok(atom!(DOLLAR_END_OF_TABLE))
+        );
+        assert!(table.slot(&process, Term::int(41)).is_err());
+        assert!(table.slot(&process, Term::int(-1)).is_err());
+    }
+}
@@ -1,14 +1,11 @@
 use super::*;
-use crate::immix::Heap;
-use crate::value::{CastFrom, CastInto, CastIntoMut, Cons, Term, Tuple, Variant};
+use crate::value::{BigInt, CastFrom, Term, Tuple};
 use error::*;
-use hashbrown::HashMap;
-use parking_lot::RwLock;
+use segment::Segments;
 
 pub(crate) struct HashTable {
     meta: Metadata,
-    hashmap: RwLock<HashMap<Term, Term>>,
-    heap: Heap,
+    segments: Segments<Term>,
 }
 
 unsafe impl Sync for HashTable {}
@@ -17,9 +14,8 @@ unsafe impl Send for HashTable {}
 impl HashTable {
     pub fn new(meta: Metadata, _process: &RcProcess) -> Self {
         Self {
+            segments: Segments::new(meta.status),
             meta,
-            hashmap: RwLock::new(HashMap::new()),
-            heap: Heap::new(),
         }
     }
 }
@@ -34,28 +30,41 @@ impl Table for HashTable {
         &self.meta
     }
 
-    fn first(&self, _process: &RcProcess) -> Result<Term> {
-        unimplemented!()
+    fn first(&self, process: &RcProcess) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.segments.first() {
+            Some(key) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
-    fn next(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn next(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.segments.next(key) {
+            Some(key) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
-    fn last(&self, _process: &RcProcess) -> Result<Term> {
-        unimplemented!()
+    // set tables have no order, so last/prev are the same as first/next.
+    fn last(&self, process: &RcProcess) -> Result<Term> {
+        self.first(process)
     }
 
-    fn prev(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn prev(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        self.next(process, key)
     }
 
     // put
     fn insert(&self, _process: &RcProcess, value: Term, _key_clash_fail: bool) -> Result<()> {
-        // TODO deep copy that value
-        let value = value.deep_clone(&self.heap);
         let key = get_key(self.meta().keypos, value);
-        self.hashmap.write().insert(key, value);
+        let mut segment = self.segments.write(&key);
+        let (key, value) = self.meta.store(&segment.heap, value);
+        if let Some(prev) = segment.insert(key, value) {
+            self.meta.release(prev);
+        }
         Ok(())
     }
 
@@ -68,20 +77,22 @@ impl Table for HashTable {
         // });
         // println!("debug: end----");
         Ok(self
-            .hashmap
-            .read()
+            .segments
+            .read(&key)
+            .map
             .get(&key)
             // TODO: bag types
-            .map(|v| cons!(heap, v.deep_clone(heap), Term::nil()))
+            .map(|v| cons!(heap, self.meta.copy(heap, *v), Term::nil()))
             .unwrap_or_else(Term::nil))
     }
 
     fn get_element(&self, process: &RcProcess, key: Term, index: usize) -> Result<Term> {
         let heap = &process.context_mut().heap;
 
-        match self.hashmap.read().get(&key) {
+        match self.segments.read(&key).map.get(&key) {
             Some(value) => {
-                let tup = Tuple::cast_from(&*value).unwrap();
+                let value = self.meta.load(heap, *value);
+                let tup = Tuple::cast_from(&value).unwrap();
                 assert!(tup.len() > index);
                 Ok(tup[index].deep_clone(heap))
             }
@@ -91,62 +102,99 @@ impl Table for HashTable {
 
     // contains_key ? why is result a Term, not bool
     fn member(&self, key: Term) -> bool {
-        self.hashmap.read().contains_key(&key)
-    }
-
-    fn update_element(&self, _process: &RcProcess, key: Term, list: Term) -> Result<Term> {
-        let mut table = self.hashmap.write();
-        let item = match table.get_mut(&key) {
-            Some(item) => item,
-            None => return Ok(atom!(FALSE)), // return BadKey
-        };
-        // println!("item! {}", *item);
-        // println!("values {}", list);
-        // TODO verify that items are always tuples
-        let item: &mut Tuple = match item.cast_into_mut() {
-            Ok(t) => t,
-            Err(_) => unreachable!(),
-        };
-
-        // First verify that list is ok to avoid nasty rollback scenarios
-        let list = Cons::cast_from(&list).unwrap();
-        let res: std::result::Result<Vec<_>, _> = list
-            .iter()
-            .map(|val| {
-                // value contains tuple
-                // tuple is arity 2 {pos, val}
-                // and has pos as integer, >= 1 and isn't == to keypos and is in the db term tuple
-                // arity range
-                if let Ok(tup) = Tuple::cast_from(&val) {
-                    if tup.len() == 2 && tup[0].is_smallint() {
-                        let pos = (tup[0].to_int().unwrap() - 1) as usize; // 1 indexed
-                        if pos != self.meta().keypos && pos < item.len() {
-                            return Ok((pos, tup[1]));
-                        }
-                    }
-                }
-                // println!("update_element_3 failed!");
-                Err(new_error(ErrorKind::BadItem))
-            })
-            .collect();
-
-        // The point of no return, no failures from here on.
-        res?.iter()
-            .for_each(|(pos, val)| item[*pos] = val.deep_clone(&self.heap));
-        // println!("Updated items!");
+        self.segments.read(&key).map.contains_key(&key)
+    }
+
+    fn update_element(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        list: Term,
+        default: Option<Term>,
+    ) -> Result<Term> {
+        let keypos = self.meta().keypos;
+        let mut segment = self.segments.write(&key);
+        match segment.map.get(&key) {
+            Some(item) => {
+                let (object, _) = self.meta.update(&segment.heap, *item, |heap, object| {
+                    update_elements(heap, keypos, object, list)
+                })?;
+                segment.map.insert(key, object);
+            }
+            None => {
+                let default = match default {
+                    Some(default) => default,
+                    None => return Ok(atom!(FALSE)), // return BadKey
+                };
+                let (key, object, _) = self.meta.build(&segment.heap, |heap| {
+                    let object = default_object(heap, keypos, key, default)?;
+                    update_elements(heap, keypos, object, list)?;
+                    Ok((object, ()))
+                })?;
+                segment.insert(key, object);
+            }
+        }
         Ok(atom!(TRUE))
     }
 
+    fn update_counter(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        ops: &[CounterOp],
+        default: Option<Term>,
+    ) -> Result<Vec<BigInt>> {
+        let keypos = self.meta().keypos;
+        // the write lock is held for the whole update, so concurrent updates can't be lost
+        let mut segment = self.segments.write(&key);
+        match segment.map.get(&key) {
+            Some(item) => {
+                let (object, res) = self.meta.update(&segment.heap, *item, |heap, object| {
+                    update_counters(heap, keypos, object, ops)
+                })?;
+                segment.map.insert(key, object);
+                Ok(res)
+            }
+            None => {
+                let default = match default {
+                    Some(default) => default,
+                    None => return Err(new_error(ErrorKind::BadKey)),
+                };
+                let (key, object, res) = self.meta.build(&segment.heap, |heap| {
+                    let object = default_object(heap, keypos, key, default)?;
+                    let res = update_counters(heap, keypos, object, ops)?;
+                    Ok((object, res))
+                })?;
+                segment.insert(key, object);
+                Ok(res)
+            }
+        }
+    }
+
     // erase  (remove_entry in rust)
     fn remove(&self, key: Term) -> Result<Term> {
-        Ok(Term::boolean(self.hashmap.write().remove(&key).is_some()))
+        let removed = self.segments.write(&key).remove(&key);
+        if let Some(prev) = removed {
+            self.meta.release(prev);
+        }
+        Ok(Term::boolean(removed.is_some()))
     }
 
-    fn remove_object(&mut self, _object: Term) -> Result<Term> {
-        unimplemented!()
+    fn remove_object(&self, object: Term) -> Result<Term> {
+        let key = get_key(self.meta().keypos, object);
+        let mut segment = self.segments.write(&key);
+        if segment
+            .map
+            .get(&key)
+            .map_or(false, |val| self.meta.same(*val, object))
+        {
+            let prev = segment.remove(&key).unwrap();
+            self.meta.release(prev);
+        }
+        Ok(atom!(TRUE))
     }
 
-    fn slot(&self, _slot: Term) -> Result<Term> {
+    fn slot(&self, _process: &RcProcess, _slot: Term) -> Result<Term> {
         unimplemented!()
     }
 
@@ -158,36 +206,29 @@ impl Table for HashTable {
     // int reverse,
     // Eterm* ret);
 
-    // _continue is for when the main function traps, let's just use generators
-    fn select(
+    fn select_chunk(
         &self,
         vm: &vm::Machine,
         process: &RcProcess,
         pattern: &pam::Pattern,
         flags: pam::r#match::Flag,
+        from: Option<Term>,
+        limit: usize,
         _reverse: bool,
-    ) -> Result<Term> {
+    ) -> Result<(Vec<Term>, Option<Term>)> {
         let heap = &process.context_mut().heap;
-        let values: Vec<_> = self
-            .hashmap
-            .read()
-            .iter()
-            .filter_map(|(_key, val)| pam::r#match::run(vm, process, pattern, *val, flags))
-            .collect();
+        let segments = self.segments.read_all();
+        let start = from.map_or(0, |key| self.segments.index(&key));
 
-        // TODO: need the intermediate because of reverse, in the future don't reverse
-        let res = values
-            .into_iter()
-            .rev()
-            .fold(Term::nil(), |acc, val| cons!(heap, val, acc));
-        // println!("PAM res: {}", res);
-        Ok(res)
+        // set tables have no order, so reverse is ignored.
+        let objects = segments[start..]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, segment)| segment.iter_from(if i == 0 { from } else { None }))
+            .map(|(key, val)| (*key, self.meta.load(heap, *val)));
+        Ok(select_objects(vm, process, pattern, flags, objects, limit))
     }
 
-    // fn select_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
-    //     unimplemented!()
-    // }
-
     fn select_delete(
         &self,
         vm: &vm::Machine,
@@ -198,17 +239,19 @@ impl Table for HashTable {
         let heap = &process.context_mut().heap;
         let mut count = 0;
         let am_true = atom!(TRUE);
-        self.hashmap.write().retain(|_key, val| {
-            // println!("running retain for {}", val);
-            match pam::r#match::run(vm, process, pattern, *val, flags) {
-                Some(res) if res == am_true => {
-                    // println!("deleting {}", val);
-                    count += 1;
-                    false
-                } // don't keep
-                _ => true,
-            }
-        });
+        for mut segment in self.segments.write_all() {
+            segment.retain(|_key, val| {
+                let object = self.meta.load(heap, *val);
+                match pam::r#match::run(vm, process, pattern, object, flags) {
+                    Some(res) if res == am_true => {
+                        count += 1;
+                        self.meta.release(*val);
+                        false
+                    } // don't keep
+                    _ => true,
+                }
+            });
+        }
         Ok(Term::uint(heap, count as u32))
     }
 
@@ -216,28 +259,82 @@ impl Table for HashTable {
     //     unimplemented!()
     // }
 
-    fn select_count(&self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
-    }
-
     // fn select_count_continue(&self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn select_replace(&mut self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
+    fn select_replace(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let keypos = self.meta().keypos;
+        let mut segments = self.segments.write_all();
+
+        // compute all replacements first, so a bad replacement leaves the table untouched
+        let mut replacements = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            for (key, object) in segment.map.iter() {
+                let object = self.meta.load(heap, *object);
+                if let Some(res) = replacement(vm, process, pattern, flags, keypos, *key, object)? {
+                    replacements.push((i, *key, res));
+                }
+            }
+        }
+
+        let count = replacements.len();
+        for (i, key, res) in replacements {
+            let segment = &mut segments[i];
+            let (_, res) = self.meta.store(&segment.heap, res);
+            if let Some(prev) = segment.map.insert(key, res) {
+                self.meta.release(prev);
+            }
+        }
+        Ok(Term::uint(heap, count as u32))
     }
 
     // fn select_replace_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn take(&mut self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn take(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        match self.segments.write(&key).remove(&key) {
+            Some(object) => {
+                self.meta.release(object);
+                Ok(cons!(heap, self.meta.copy(heap, object), Term::nil()))
+            }
+            None => Ok(Term::nil()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.segments
+            .read_all()
+            .iter()
+            .map(|segment| segment.map.len())
+            .sum()
+    }
+
+    fn memory(&self) -> usize {
+        let heap_bytes = self
+            .segments
+            .read_all()
+            .iter()
+            .map(|segment| segment.heap.size())
+            .sum();
+        self.meta.memory(heap_bytes)
     }
 
     /// takes reds, then returns new reds (equal to delete_all)
-    fn clear(&mut self, _process: &RcProcess, _reds: usize) -> Result<usize> {
-        unimplemented!()
+    fn clear(&self, _process: &RcProcess, reds: usize) -> Result<usize> {
+        for mut segment in self.segments.write_all() {
+            segment.map.values().for_each(|val| self.meta.release(*val));
+            segment.clear();
+        }
+        Ok(reds)
     }
 }
@@ -0,0 +1,217 @@
+//! Table locks.
+//!
+//! Tables created with `{read_concurrency, true}` use a reader-biased lock: every reader only
+//! touches a counter on its own cache line, so concurrent readers on different schedulers
+//! don't contend. The price is paid by writers, which have to wait for every reader slot to
+//! drain. Other tables use a plain `RwLock`.
+use super::Status;
+use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of reader slots of a reader-biased lock.
+const READER_SLOTS: usize = 16;
+
+#[repr(align(64))]
+#[derive(Default)]
+struct Slot(AtomicUsize);
+
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Reader slot of the current thread, assigned round robin.
+    static READER_SLOT: usize = NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % READER_SLOTS;
+}
+
+/// A reader-biased readers-writer lock (a "big reader" lock).
+pub(crate) struct BrLock<T> {
+    readers: [Slot; READER_SLOTS],
+    /// Serializes writers, and parks readers while a write is in progress.
+    writer: Mutex<()>,
+    writing: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for BrLock<T> {}
+unsafe impl<T: Send + Sync> Sync for BrLock<T> {}
+
+impl<T> BrLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            readers: Default::default(),
+            writer: Mutex::new(()),
+            writing: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> BrReadGuard<T> {
+        let slot = &self.readers[READER_SLOT.with(|slot| *slot)].0;
+        loop {
+            slot.fetch_add(1, Ordering::SeqCst);
+            if !self.writing.load(Ordering::SeqCst) {
+                return BrReadGuard { lock: self, slot };
+            }
+            // back off and wait for the writer to finish
+            slot.fetch_sub(1, Ordering::SeqCst);
+            drop(self.writer.lock());
+        }
+    }
+
+    pub fn write(&self) -> BrWriteGuard<T> {
+        let guard = self.writer.lock();
+        self.writing.store(true, Ordering::SeqCst);
+        for slot in &self.readers {
+            while slot.0.load(Ordering::SeqCst) != 0 {
+                std::thread::yield_now();
+            }
+        }
+        BrWriteGuard {
+            lock: self,
+            _guard: guard,
+        }
+    }
+}
+
+pub(crate) struct BrReadGuard<'a, T> {
+    lock: &'a BrLock<T>,
+    slot: &'a AtomicUsize,
+}
+
+impl<'a, T> Deref for BrReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for BrReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.slot.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct BrWriteGuard<'a, T> {
+    lock: &'a BrLock<T>,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl<'a, T> Deref for BrWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for BrWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for BrWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        // the writer mutex is released after this, waking up parked readers
+        self.lock.writing.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Table lock, picked based on the table's `read_concurrency` option.
+pub(crate) enum Lock<T> {
+    Normal(RwLock<T>),
+    FreqRead(BrLock<T>),
+}
+
+impl<T> Lock<T> {
+    pub fn new(data: T, status: Status) -> Self {
+        if status.contains(Status::DB_FREQ_READ) {
+            Lock::FreqRead(BrLock::new(data))
+        } else {
+            Lock::Normal(RwLock::new(data))
+        }
+    }
+
+    pub fn read(&self) -> ReadGuard<T> {
+        match self {
+            Lock::Normal(lock) => ReadGuard::Normal(lock.read()),
+            Lock::FreqRead(lock) => ReadGuard::FreqRead(lock.read()),
+        }
+    }
+
+    pub fn write(&self) -> WriteGuard<T> {
+        match self {
+            Lock::Normal(lock) => WriteGuard::Normal(lock.write()),
+            Lock::FreqRead(lock) => WriteGuard::FreqRead(lock.write()),
+        }
+    }
+}
+
+pub(crate) enum ReadGuard<'a, T> {
+    Normal(RwLockReadGuard<'a, T>),
+    FreqRead(BrReadGuard<'a, T>),
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            ReadGuard::Normal(guard) => guard,
+            ReadGuard::FreqRead(guard) => guard,
+        }
+    }
+}
+
+pub(crate) enum WriteGuard<'a, T> {
+    Normal(RwLockWriteGuard<'a, T>),
+    FreqRead(BrWriteGuard<'a, T>),
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            WriteGuard::Normal(guard) => guard,
+            WriteGuard::FreqRead(guard) => guard,
+        }
+    }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            WriteGuard::Normal(guard) => guard,
+            WriteGuard::FreqRead(guard) => guard,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_br_lock() {
+        let lock = Arc::new(BrLock::new(0));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *lock.write() += 1;
+                        assert!(*lock.read() > 0);
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        assert_eq!(*lock.read(), 4000);
+    }
+}
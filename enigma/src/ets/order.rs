@@ -0,0 +1,95 @@
+//! Traversal order for hash based tables (set, bag).
+//!
+//! Hash tables don't have a natural key order, and the iteration order of the underlying
+//! `HashMap` changes whenever it rehashes. Instead we traverse keys sorted by their hash (ties
+//! broken by the key itself), which only depends on the keys present in the table. A key that
+//! was deleted mid-traversal still has a well defined successor, so `next/2` keeps working
+//! while other processes modify the table.
+use crate::value::Term;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound::{Excluded, Unbounded};
+
+#[inline]
+pub(crate) fn hash(key: &Term) -> u64 {
+    // DefaultHasher::new() uses fixed keys, so the order is stable for the VM lifetime.
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Default)]
+pub(crate) struct HashOrder(BTreeSet<(u64, Term)>);
+
+impl HashOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: Term) {
+        self.0.insert((hash(&key), key));
+    }
+
+    pub fn remove(&mut self, key: &Term) {
+        self.0.remove(&(hash(key), *key));
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn first(&self) -> Option<Term> {
+        self.0.iter().next().map(|(_, key)| *key)
+    }
+
+    /// The key following `key`. `key` doesn't have to be in the table anymore.
+    pub fn next(&self, key: Term) -> Option<Term> {
+        self.0
+            .range((Excluded((hash(&key), key)), Unbounded))
+            .next()
+            .map(|(_, key)| *key)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Term> {
+        self.0.iter().map(|(_, key)| key)
+    }
+
+    /// Iterates over the keys following `key`.
+    pub fn iter_from(&self, key: Term) -> impl Iterator<Item = &Term> {
+        self.0
+            .range((Excluded((hash(&key), key)), Unbounded))
+            .map(|(_, key)| key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal() {
+        let mut order = HashOrder::new();
+        (0..10).for_each(|i| order.insert(Term::int(i)));
+
+        let mut keys = Vec::new();
+        let mut key = order.first();
+        while let Some(k) = key {
+            keys.push(k);
+            key = order.next(k);
+        }
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys, order.iter().copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_next_after_remove() {
+        let mut order = HashOrder::new();
+        (0..10).for_each(|i| order.insert(Term::int(i)));
+        let keys: Vec<_> = order.iter().copied().collect();
+
+        order.remove(&keys[4]);
+        assert_eq!(order.next(keys[4]), Some(keys[5]));
+        assert_eq!(order.next(keys[9]), None);
+    }
+}
@@ -1,12 +1,13 @@
 use super::*;
 use crate::immix::Heap;
-use crate::value::{CastFrom, Cons, Term, Tuple};
+use crate::value::{BigInt, CastFrom, Term, Tuple};
 use error::*;
-use parking_lot::RwLock;
+use lock::Lock;
+use std::ops::Bound::{Excluded, Unbounded};
 
 pub(crate) struct OrderedSet {
     meta: Metadata,
-    hashmap: RwLock<BTreeMap<Term, Term>>,
+    hashmap: Lock<BTreeMap<Term, Term>>,
     heap: Heap,
 }
 
@@ -16,8 +17,8 @@ unsafe impl Send for OrderedSet {}
 impl OrderedSet {
     pub fn new(meta: Metadata, _process: &RcProcess) -> Self {
         Self {
+            hashmap: Lock::new(BTreeMap::new(), meta.status),
             meta,
-            hashmap: RwLock::new(BTreeMap::new()),
             heap: Heap::new(),
         }
     }
@@ -42,8 +43,19 @@ impl Table for OrderedSet {
         }
     }
 
-    fn next(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn next(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        // the key doesn't have to exist, we return the next key in term order.
+        match self
+            .hashmap
+            .read()
+            .range((Excluded(key), Unbounded))
+            .next()
+        {
+            Some((key, _value)) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
     fn last(&self, process: &RcProcess) -> Result<Term> {
@@ -55,15 +67,22 @@ impl Table for OrderedSet {
         }
     }
 
-    fn prev(&self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn prev(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.hashmap.read().range(..key).next_back() {
+            Some((key, _value)) => Ok(key.deep_clone(heap)),
+            None => Ok(atom!(DOLLAR_END_OF_TABLE)),
+        }
     }
 
     // put
     fn insert(&self, _process: &RcProcess, value: Term, _key_clash_fail: bool) -> Result<()> {
-        let value = value.deep_clone(&self.heap);
-        let key = get_key(self.meta().keypos, value);
-        self.hashmap.write().insert(key, value);
+        let mut map = self.hashmap.write();
+        let (key, value) = self.meta.store(&self.heap, value);
+        if let Some(prev) = map.insert(key, value) {
+            self.meta.release(prev);
+        }
         Ok(())
     }
 
@@ -75,7 +94,7 @@ impl Table for OrderedSet {
             .read()
             .get(&key)
             // TODO: bag types
-            .map(|v| cons!(heap, v.deep_clone(heap), Term::nil()))
+            .map(|v| cons!(heap, self.meta.copy(heap, *v), Term::nil()))
             .unwrap_or_else(Term::nil))
     }
 
@@ -84,7 +103,8 @@ impl Table for OrderedSet {
 
         match self.hashmap.read().get(&key) {
             Some(value) => {
-                let tup = Tuple::cast_from(&*value).unwrap();
+                let value = self.meta.load(heap, *value);
+                let tup = Tuple::cast_from(&value).unwrap();
                 assert!(tup.len() > index);
                 Ok(tup[index].deep_clone(heap))
             }
@@ -97,21 +117,107 @@ impl Table for OrderedSet {
         self.hashmap.read().contains_key(&key)
     }
 
-    fn update_element(&self, _process: &RcProcess, _key: Term, _list: Term) -> Result<Term> {
-        unimplemented!();
+    fn update_element(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        list: Term,
+        default: Option<Term>,
+    ) -> Result<Term> {
+        let keypos = self.meta().keypos;
+        let mut map = self.hashmap.write();
+        match map.get(&key) {
+            Some(item) => {
+                let (object, _) = self.meta.update(&self.heap, *item, |heap, object| {
+                    update_elements(heap, keypos, object, list)
+                })?;
+                map.insert(key, object);
+            }
+            None => {
+                let default = match default {
+                    Some(default) => default,
+                    None => return Ok(atom!(FALSE)),
+                };
+                let (key, object, _) = self.meta.build(&self.heap, |heap| {
+                    let object = default_object(heap, keypos, key, default)?;
+                    update_elements(heap, keypos, object, list)?;
+                    Ok((object, ()))
+                })?;
+                map.insert(key, object);
+            }
+        }
+        Ok(atom!(TRUE))
+    }
+
+    fn update_counter(
+        &self,
+        _process: &RcProcess,
+        key: Term,
+        ops: &[CounterOp],
+        default: Option<Term>,
+    ) -> Result<Vec<BigInt>> {
+        let keypos = self.meta().keypos;
+        // the write lock is held for the whole update, so concurrent updates can't be lost
+        let mut map = self.hashmap.write();
+        match map.get(&key) {
+            Some(item) => {
+                let (object, res) = self.meta.update(&self.heap, *item, |heap, object| {
+                    update_counters(heap, keypos, object, ops)
+                })?;
+                map.insert(key, object);
+                Ok(res)
+            }
+            None => {
+                let default = match default {
+                    Some(default) => default,
+                    None => return Err(new_error(ErrorKind::BadKey)),
+                };
+                let (key, object, res) = self.meta.build(&self.heap, |heap| {
+                    let object = default_object(heap, keypos, key, default)?;
+                    let res = update_counters(heap, keypos, object, ops)?;
+                    Ok((object, res))
+                })?;
+                map.insert(key, object);
+                Ok(res)
+            }
+        }
     }
 
     // erase  (remove_entry in rust)
     fn remove(&self, key: Term) -> Result<Term> {
-        Ok(Term::boolean(self.hashmap.write().remove(&key).is_some()))
+        let removed = self.hashmap.write().remove(&key);
+        if let Some(prev) = removed {
+            self.meta.release(prev);
+        }
+        Ok(Term::boolean(removed.is_some()))
     }
 
-    fn remove_object(&mut self, _object: Term) -> Result<Term> {
-        unimplemented!()
+    fn remove_object(&self, object: Term) -> Result<Term> {
+        let key = get_key(self.meta().keypos, object);
+        let mut map = self.hashmap.write();
+        if map
+            .get(&key)
+            .map_or(false, |val| self.meta.same(*val, object))
+        {
+            let prev = map.remove(&key).unwrap();
+            self.meta.release(prev);
+        }
+        Ok(atom!(TRUE))
     }
 
-    fn slot(&self, _slot: Term) -> Result<Term> {
-        unimplemented!()
+    fn slot(&self, process: &RcProcess, slot: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let slot = slot
+            .to_int()
+            .filter(|slot| *slot >= 0)
+            .ok_or_else(|| new_error(ErrorKind::BadParameter))? as usize;
+        let map = self.hashmap.read();
+
+        match map.values().nth(slot) {
+            Some(value) => Ok(cons!(heap, self.meta.copy(heap, *value), Term::nil())),
+            None if slot == map.len() => Ok(atom!(DOLLAR_END_OF_TABLE)),
+            None => Err(new_error(ErrorKind::BadParameter)),
+        }
     }
 
     // int (*db_select_chunk)(process: &RcProcess,
@@ -122,58 +228,117 @@ impl Table for OrderedSet {
     // int reverse,
     // Eterm* ret);
 
-    // _continue is for when the main function traps, let's just use generators
-    fn select(
+    fn select_chunk(
         &self,
-        _vm: &vm::Machine,
-        _process: &RcProcess,
-        _pattern: &pam::Pattern,
-        _flags: pam::r#match::Flag,
-        _reverse: bool,
-    ) -> Result<Term> {
-        unimplemented!()
-    }
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+        from: Option<Term>,
+        limit: usize,
+        reverse: bool,
+    ) -> Result<(Vec<Term>, Option<Term>)> {
+        let heap = &process.context_mut().heap;
+        let map = self.hashmap.read();
 
-    // fn select_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
-    //     unimplemented!()
-    // }
+        let objects = range::scan(&map, &pattern.key_range, from, reverse)
+            .map(|(key, val)| (*key, self.meta.load(heap, *val)));
+        Ok(select_objects(vm, process, pattern, flags, objects, limit))
+    }
 
     fn select_delete(
         &self,
-        _vm: &vm::Machine,
-        _process: &RcProcess,
-        _pattern: &pam::Pattern,
-        _flags: pam::r#match::Flag,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
     ) -> Result<Term> {
-        unimplemented!()
+        let heap = &process.context_mut().heap;
+        let am_true = atom!(TRUE);
+        let mut map = self.hashmap.write();
+
+        let keys: Vec<Term> = range::scan(&map, &pattern.key_range, None, false)
+            .filter(|(_, val)| {
+                let object = self.meta.load(heap, **val);
+                pam::r#match::run(vm, process, pattern, object, flags) == Some(am_true)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        keys.iter().for_each(|key| {
+            let prev = map.remove(key).unwrap();
+            self.meta.release(prev);
+        });
+        Ok(Term::uint(heap, keys.len() as u32))
     }
 
     // fn select_delete_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn select_count(&self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
-    }
-
     // fn select_count_continue(&self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn select_replace(&mut self, _process: &RcProcess, _tid: Term, _pattern: Term) -> Result<Term> {
-        unimplemented!()
+    fn select_replace(
+        &self,
+        vm: &vm::Machine,
+        process: &RcProcess,
+        pattern: &pam::Pattern,
+        flags: pam::r#match::Flag,
+    ) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+        let keypos = self.meta().keypos;
+        let mut map = self.hashmap.write();
+
+        // compute all replacements first, so a bad replacement leaves the table untouched
+        let mut replacements = Vec::new();
+        for (key, object) in range::scan(&map, &pattern.key_range, None, false) {
+            let object = self.meta.load(heap, *object);
+            if let Some(res) = replacement(vm, process, pattern, flags, keypos, *key, object)? {
+                replacements.push((*key, res));
+            }
+        }
+
+        let count = replacements.len();
+        for (key, res) in replacements {
+            if let Some(prev) = map.insert(key, self.meta.store(&self.heap, res).1) {
+                self.meta.release(prev);
+            }
+        }
+        Ok(Term::uint(heap, count as u32))
     }
 
     // fn select_replace_continue(&mut self, process: &RcProcess, continuation: Term) -> Result<Term> {
     //     unimplemented!()
     // }
 
-    fn take(&mut self, _process: &RcProcess, _key: Term) -> Result<Term> {
-        unimplemented!()
+    fn take(&self, process: &RcProcess, key: Term) -> Result<Term> {
+        let heap = &process.context_mut().heap;
+
+        match self.hashmap.write().remove(&key) {
+            Some(object) => {
+                self.meta.release(object);
+                Ok(cons!(heap, self.meta.copy(heap, object), Term::nil()))
+            }
+            None => Ok(Term::nil()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.hashmap.read().len()
+    }
+
+    fn memory(&self) -> usize {
+        // hold the lock, the heap is only allocated on while writing
+        let _map = self.hashmap.read();
+        self.meta.memory(self.heap.size())
     }
 
     /// takes reds, then returns new reds (equal to delete_all)
-    fn clear(&mut self, _process: &RcProcess, _reds: usize) -> Result<usize> {
-        unimplemented!()
+    fn clear(&self, _process: &RcProcess, reds: usize) -> Result<usize> {
+        let mut map = self.hashmap.write();
+        map.values().for_each(|val| self.meta.release(*val));
+        map.clear();
+        Ok(reds)
     }
 }
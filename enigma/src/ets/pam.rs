@@ -16,6 +16,8 @@ pub struct Pattern {
     pub(crate) program: Vec<Opcode>,
     pub(crate) stack_need: usize,
     pub(crate) num_bindings: usize,
+    /// Keys the pattern can match in an ordered table, see `analyze_pattern`.
+    pub(crate) key_range: range::KeyRange,
 }
 
 bitflags! {
@@ -211,36 +213,45 @@ static GUARD_BIFS: Lazy<HashMap<(Atom, usize), (bif::Fn, Flag)>> = Lazy::new(||
     table.insert((atom::IS_MAP, 1), (bif::bif_erlang_is_map_1, Flag::DBIF_ALL));
     table.insert((atom::IS_BINARY, 1), (bif::bif_erlang_is_binary_1, Flag::DBIF_ALL));
     table.insert((atom::IS_FUNCTION, 1), (bif::bif_erlang_is_function_1, Flag::DBIF_ALL));
-    // table.insert((atom::IS_RECORD, 3), (bif::bif_erlang_is_record_3, Flag::DBIF_ALL));
+    table.insert((atom::IS_FUNCTION, 2), (bif::bif_erlang_is_function_2, Flag::DBIF_ALL));
+    table.insert((atom::IS_BOOLEAN, 1), (bif::bif_erlang_is_boolean_1, Flag::DBIF_ALL));
+    table.insert((atom::IS_BITSTRING, 1), (bif::bif_erlang_is_bitstring_1, Flag::DBIF_ALL));
+    table.insert((atom::IS_RECORD, 3), (bif::bif_erlang_is_record_3, Flag::DBIF_ALL));
     table.insert((atom::ABS, 1), (bif::arith::abs_1, Flag::DBIF_ALL));
     table.insert((atom::ELEMENT, 2), (bif::erlang::element_2, Flag::DBIF_ALL));
     table.insert((atom::HD, 1), (bif::bif_erlang_hd_1, Flag::DBIF_ALL));
     table.insert((atom::LENGTH, 1), (bif::bif_erlang_length_1, Flag::DBIF_ALL));
     table.insert((atom::NODE, 1), (bif::erlang::node_1, Flag::DBIF_ALL));
     table.insert((atom::NODE, 0), (bif::erlang::node_0, Flag::DBIF_ALL));
-    // table.insert((atom::ROUND, 1), (&round_1, Flag::DBIF_ALL));
-    // table.insert((atom::SIZE, 1), (&size_1, Flag::DBIF_ALL));
+    table.insert((atom::ROUND, 1), (bif::arith::round_1, Flag::DBIF_ALL));
+    table.insert((atom::CEIL, 1), (bif::arith::ceil_1, Flag::DBIF_ALL));
+    table.insert((atom::FLOOR, 1), (bif::arith::floor_1, Flag::DBIF_ALL));
+    table.insert((atom::SIZE, 1), (bif::bif_erlang_size_1, Flag::DBIF_ALL));
+    table.insert((atom::TUPLE_SIZE, 1), (bif::bif_erlang_tuple_size_1, Flag::DBIF_ALL));
+    table.insert((atom::BYTE_SIZE, 1), (bif::bif_erlang_byte_size_1, Flag::DBIF_ALL));
+    table.insert((atom::BIT_SIZE, 1), (bif::bif_erlang_bit_size_1, Flag::DBIF_ALL));
+    table.insert((atom::BINARY_PART, 2), (bif::binary::part_2, Flag::DBIF_ALL));
+    table.insert((atom::BINARY_PART, 3), (bif::binary::part_3, Flag::DBIF_ALL));
     table.insert((atom::MAP_SIZE, 1), (bif::bif_erlang_map_size_1, Flag::DBIF_ALL));
-    // table.insert((atom::MAP_GET, 2), (&map_get_2, Flag::DBIF_ALL));
-    // table.insert((atom::IS_MAP_KEY, 2), (&is_map_key_2, Flag::DBIF_ALL));
-    // table.insert((atom::BIT_SIZE, 1), (&bit_size_1, Flag::DBIF_ALL));
+    table.insert((atom::MAP_GET, 2), (bif::bif_erlang_map_get_2, Flag::DBIF_ALL));
+    table.insert((atom::IS_MAP_KEY, 2), (bif::bif_erlang_is_map_key_2, Flag::DBIF_ALL));
     table.insert((atom::TL, 1), (bif::bif_erlang_tl_1, Flag::DBIF_ALL));
     table.insert((atom::TRUNC, 1), (bif::bif_erlang_trunc_1, Flag::DBIF_ALL));
-    // table.insert((atom::FLOAT, 1), (&float_1, Flag::DBIF_ALL));
-    // table.insert((atom::PLUS, 1), (&splus_1, Flag::DBIF_ALL));
-    // table.insert((atom::MINUS, 1), (&sminus_1, Flag::DBIF_ALL));
+    table.insert((atom::FLOAT, 1), (bif::arith::float_1, Flag::DBIF_ALL));
+    table.insert((atom::PLUS, 1), (bif::erlang::splus_1, Flag::DBIF_ALL));
+    table.insert((atom::MINUS, 1), (bif::erlang::sminus_1, Flag::DBIF_ALL));
     table.insert((atom::PLUS, 2), (bif::arith::add_2, Flag::DBIF_ALL));
     table.insert((atom::MINUS, 2), (bif::arith::sub_2, Flag::DBIF_ALL));
     table.insert((atom::TIMES, 2), (bif::arith::mult_2, Flag::DBIF_ALL));
-    // table.insert((atom::DIV, 2), (&div_2, Flag::DBIF_ALL)); // different sym
-    table.insert((atom::DIV, 2), (bif::arith::intdiv_2, Flag::DBIF_ALL));
+    table.insert((atom::DIV, 2), (bif::arith::fdiv_2, Flag::DBIF_ALL)); // '/'
+    table.insert((atom::INTDIV, 2), (bif::arith::intdiv_2, Flag::DBIF_ALL));
     table.insert((atom::REM, 2), (bif::arith::mod_2, Flag::DBIF_ALL));
-    // table.insert((atom::BAND, 2), (bif::erlang::band_2, Flag::DBIF_ALL));
-    // table.insert((atom::BOR, 2), (bif::erlang::bor_2, Flag::DBIF_ALL));
-    // table.insert((atom::BXOR, 2), (bif::erlang::bxor_2, Flag::DBIF_ALL));
-    // table.insert((atom::BNOT, 1), (bif::erlang::bnot_1, Flag::DBIF_ALL));
-    // table.insert((atom::BSL, 2), (bif::erlang::bsl_2, Flag::DBIF_ALL));
-    // table.insert((atom::BSR, 2), (bif::erlang::bsr_2, Flag::DBIF_ALL));
+    table.insert((atom::BAND, 2), (bif::erlang::band_2, Flag::DBIF_ALL));
+    table.insert((atom::BOR, 2), (bif::erlang::bor_2, Flag::DBIF_ALL));
+    table.insert((atom::BXOR, 2), (bif::erlang::bxor_2, Flag::DBIF_ALL));
+    table.insert((atom::BNOT, 1), (bif::erlang::bnot_1, Flag::DBIF_ALL));
+    table.insert((atom::BSL, 2), (bif::erlang::bsl_2, Flag::DBIF_ALL));
+    table.insert((atom::BSR, 2), (bif::erlang::bsr_2, Flag::DBIF_ALL));
     table.insert((atom::GT, 2), (bif::erlang::sgt_2, Flag::DBIF_ALL));
     table.insert((atom::GE, 2), (bif::erlang::sge_2, Flag::DBIF_ALL));
     table.insert((atom::LT, 2), (bif::erlang::slt_2, Flag::DBIF_ALL));
@@ -295,7 +306,7 @@ pub fn has_variable(node: Term) -> bool {
                     s.push(*head);
                     list = *tail;
                 }
-                s.push(node) // Non wellformed list or []
+                s.push(list) // Non wellformed list or []
             }
             value::TERM_POINTER => {
                 if node.is_tuple() {
@@ -584,7 +595,9 @@ impl Compiler {
             program: self.text,
             heap: self.constant_heap,
             stack_need: self.stack_need,
-            num_bindings: self.vars.len(),
+            // variables are indexed by their number
+            num_bindings: self.vars.keys().max().map_or(0, |n| n + 1),
+            key_range: range::KeyRange::default(),
             // TODO: num_bindings: heap.len(), single_variable: special
         })
     }
@@ -697,7 +710,7 @@ impl Compiler {
     */
 
     fn do_emit_constant(&mut self, t: Term) {
-        let tmp = t.deep_clone(&self.constant_heap);
+        let tmp = self.copy_constant(t);
         self.text.push(Opcode::PushC(tmp));
         self.stack_used += 1;
         if self.stack_used > self.stack_need {
@@ -705,6 +718,45 @@ impl Compiler {
         }
     }
 
+    /// Copy a constant expression onto the constant heap, turning it into the value it
+    /// evaluates to: `{const, X}` becomes X and `{{A, ...}}` becomes `{A, ...}`.
+    fn copy_constant(&self, t: Term) -> Term {
+        let heap = &self.constant_heap;
+        match t.into_variant() {
+            Variant::Cons(..) => {
+                let cons = Cons::cast_from(&t).unwrap();
+                let head = self.copy_constant(cons.head);
+                let tail = self.copy_constant(cons.tail);
+                cons!(heap, head, tail)
+            }
+            Variant::Pointer(..) if t.is_tuple() => {
+                let p = Tuple::cast_from(&t).unwrap();
+                if p.len() == 1 && p[0].is_tuple() {
+                    let inner = Tuple::cast_from(&p[0]).unwrap();
+                    let tuple = value::tuple(heap, inner.len() as u32);
+                    for (i, val) in inner.iter().enumerate() {
+                        unsafe {
+                            std::ptr::write(&mut tuple[i], self.copy_constant(*val));
+                        }
+                    }
+                    Term::from(tuple)
+                } else if p.len() == 2 && p[0] == atom!(CONST) {
+                    p[1].deep_clone(heap)
+                } else {
+                    unreachable!("trying to constant-copy non constant expression {}", t)
+                }
+            }
+            Variant::Pointer(..) if t.is_map() => {
+                let map = Map::cast_from(&t).unwrap();
+                let map = map.0.iter().map(|(key, val)| {
+                    (key.deep_clone(heap), self.copy_constant(*val))
+                }).collect();
+                Term::map(heap, map)
+            }
+            _ => t.deep_clone(heap),
+        }
+    }
+
     fn list(&mut self, t: Term) -> DMCRet {
         let cons = Cons::cast_from(&t).unwrap();
         let c1 = self.expr(cons.head)?;
@@ -728,49 +780,32 @@ impl Compiler {
         Ok(false)
     }
 
-    //fn rearrange_constants(&mut self, textpos: usize, p: &[Term], nelems: usize) {
-    //    //STACK_TYPE(UWord) instr_save;
-    //    // Uint i;
-
-    //    INIT_STACK(instr_save);
-    //    while self.text.len() > textpos {
-    //        PUSH(instr_save, POP(*text));
-    //    }
-    //    for (i = nelems; i--;) {
-    //        self.do_emit_constant(p[i]);
-    //    }
-    //    while(!EMPTY(instr_save)) {
-    //        PUSH(*text, POP(instr_save));
-    //    }
-    //    FREE(instr_save);
-    //}
+    /// Emit the constants in `p` so that they end up before the code laid out since `textpos`.
+    fn rearrange_constants(&mut self, textpos: usize, p: &[Term]) {
+        let instr_save = self.text.split_off(textpos);
+        for val in p {
+            self.do_emit_constant(*val);
+        }
+        self.text.extend(instr_save);
+    }
 
     fn array(&mut self, terms: &[Term]) -> DMCRet {
-        let all_constant = true;
-        let _textpos = self.text.len();
-        // Uint i;
+        let mut all_constant = true;
+        let textpos = self.text.len();
 
         // We remember where we started to layout code,
         // assume all is constant and back up and restart if not so.
-        // The array should be laid out with the last element first,
-        // so we can memcpy it to the eheap.
-
-
-        // TODO: checking if we can avoid rearranging
-        for val in terms.iter() {
-            self.do_emit_constant(*val);
+        // Unlike BEAM, the array is laid out with the first element first, so
+        // MkTuple can take the elements off the stack in order.
+        for (i, val) in terms.iter().enumerate() {
+            let res = self.expr(*val)?;
+            if !res && all_constant {
+                all_constant = false;
+                self.rearrange_constants(textpos, &terms[..i]);
+            } else if res && !all_constant {
+                self.do_emit_constant(*val);
+            }
         }
-        // for (val, i) in terms.iter().enumerate() { // i is current index
-        //     let res = self.expr(*val)?;
-        //     if !res && all_constant {
-        //         all_constant = false;
-        //         if i < nelems - 1 {
-        //             self.rearrange_constants(textpos, &mut terms[i + 1..terms.len() - i - 1]);
-        //         }
-        //     } else if res && !all_constant {
-        //         self.do_emit_constant(p[i]);
-        //     }
-        // }
         Ok(all_constant)
     }
 
@@ -794,6 +829,10 @@ impl Compiler {
         let mut constant_values = true;
         let nelems = map.0.len();
 
+        let textpos = self.text.len();
+        let stackpos = self.stack_used;
+        let vars = self.vars.clone();
+
         for (_, val) in map.0.iter() {
             let c = self.expr(*val)?;
             if !c {
@@ -805,7 +844,10 @@ impl Compiler {
             return Ok(true);
         }
 
-        // not constant
+        // not constant, throw away the probe and lay out keys and values in pairs
+        self.text.truncate(textpos);
+        self.stack_used = stackpos;
+        self.vars = vars;
 
         for (key, value) in map.0.iter() {
             // push key
@@ -820,7 +862,7 @@ impl Compiler {
             }
         }
         self.text.push(Opcode::MkHashMap(nelems));
-        self.stack_used -= nelems;
+        self.stack_used -= 2 * nelems - 1;
         Ok(false)
     }
 
@@ -875,7 +917,8 @@ impl Compiler {
 
     fn all_bindings(&mut self) -> DMCRet {
         self.text.push(Opcode::PushC(Term::nil()));
-        let keys: Vec<_> = self.vars.keys().cloned().collect();
+        let mut keys: Vec<_> = self.vars.keys().cloned().collect();
+        keys.sort_unstable();
         keys.into_iter().rev().for_each(|n| {
             self.add_pushv_variant(n);
             self.text.push(Opcode::ConsB());
@@ -962,7 +1005,7 @@ impl Compiler {
         if c {
             self.do_emit_constant(*last);
         }
-        self.text.push(Opcode::Jump(self.text.len() + 1)); // skips that PushC(true)
+        self.text.push(Opcode::Jump(1)); // skips that PushC(true)
         // lbl = self.text.len()-1; we do this manually above
         self.stack_used -= 1;
         // -- end
@@ -1010,8 +1053,9 @@ impl Compiler {
         if c {
             self.do_emit_constant(*last);
         }
-        self.text.push(Opcode::Jump(self.text.len() + 1)); // skips that PushC(true)
+        self.text.push(Opcode::Jump(1)); // skips that PushC(false)
         // lbl = self.text.len()-1; we do this manually above
+        self.stack_used -= 1;
         // -- end
 
         self.text.push(Opcode::PushC(atom!(FALSE)));
@@ -1425,7 +1469,7 @@ impl Compiler {
 
         // not constant
 
-        // why are constants emitted backwards
+        // arguments are laid out in order, the CallN instructions pop them in reverse
         for val in &p[1..] { // skip the function name
             let c = self.expr(*val)?;
             if c {
@@ -1440,7 +1484,7 @@ impl Compiler {
             3 => self.text.push(Opcode::Call3(*bif)),
             _ => panic!("ets:match() internal error, guard with more than 3 arguments."),
         }
-        self.stack_used -= a - 2;
+        self.stack_used = self.stack_used + 1 - arity; // arity arguments become one result
         if self.stack_used > self.stack_need {
             self.stack_need = self.stack_used;
         }
@@ -1491,3 +1535,280 @@ impl Compiler {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstring;
+    use crate::module;
+    use crate::process::{self, RcProcess};
+    use crate::vm;
+
+    /// Reads just enough Erlang term syntax to write down `ets:fun2ms/1` output.
+    struct Reader<'a> {
+        input: &'a [u8],
+        pos: usize,
+        heap: &'a Heap,
+    }
+
+    impl<'a> Reader<'a> {
+        fn read(heap: &'a Heap, input: &'a str) -> Term {
+            let mut reader = Reader {
+                input: input.as_bytes(),
+                pos: 0,
+                heap,
+            };
+            let term = reader.term();
+            assert_eq!(
+                reader.pos,
+                reader.input.len(),
+                "trailing input in {}",
+                input
+            );
+            term
+        }
+
+        fn peek(&mut self) -> u8 {
+            while self.input[self.pos] == b' ' {
+                self.pos += 1;
+            }
+            self.input[self.pos]
+        }
+
+        fn expect(&mut self, token: &str) {
+            self.peek();
+            assert!(self.input[self.pos..].starts_with(token.as_bytes()));
+            self.pos += token.len();
+        }
+
+        fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &'a str {
+            let start = self.pos;
+            while self.pos < self.input.len() && f(self.input[self.pos]) {
+                self.pos += 1;
+            }
+            std::str::from_utf8(&self.input[start..self.pos]).unwrap()
+        }
+
+        /// Comma separated terms up to `close` or a list tail.
+        fn elements(&mut self, close: u8) -> Vec<Term> {
+            let mut elements = Vec::new();
+            while self.peek() != close && self.peek() != b'|' {
+                elements.push(self.term());
+                if self.peek() == b',' {
+                    self.pos += 1;
+                }
+            }
+            elements
+        }
+
+        fn term(&mut self) -> Term {
+            match self.peek() {
+                b'{' => {
+                    self.pos += 1;
+                    let elements = self.elements(b'}');
+                    self.expect("}");
+                    let tuple = value::tuple(self.heap, elements.len() as u32);
+                    for (i, val) in elements.into_iter().enumerate() {
+                        unsafe {
+                            std::ptr::write(&mut tuple[i], val);
+                        }
+                    }
+                    Term::from(tuple)
+                }
+                b'[' => {
+                    self.pos += 1;
+                    let elements = self.elements(b']');
+                    let tail = if self.peek() == b'|' {
+                        self.pos += 1;
+                        self.term()
+                    } else {
+                        Term::nil()
+                    };
+                    self.expect("]");
+                    elements
+                        .into_iter()
+                        .rev()
+                        .fold(tail, |acc, val| cons!(self.heap, val, acc))
+                }
+                b'#' => {
+                    self.expect("#{");
+                    let mut map = value::HAMT::new();
+                    while self.peek() != b'}' {
+                        let key = self.term();
+                        self.expect("=>");
+                        map.insert(key, self.term());
+                        if self.peek() == b',' {
+                            self.pos += 1;
+                        }
+                    }
+                    self.expect("}");
+                    Term::map(self.heap, map)
+                }
+                b'<' => {
+                    self.expect("<<\"");
+                    let bytes = self.take_while(|c| c != b'"').as_bytes().to_vec();
+                    self.expect("\">>");
+                    Term::binary(self.heap, bitstring::Binary::from(bytes))
+                }
+                b'\'' => {
+                    self.pos += 1;
+                    let name = self.take_while(|c| c != b'\'');
+                    self.pos += 1;
+                    Term::atom(Atom::from(name))
+                }
+                b'-' | b'0'..=b'9' => {
+                    let num = self.take_while(|c| c == b'-' || c == b'.' || c.is_ascii_digit());
+                    if num.contains('.') {
+                        Term::from(num.parse::<f64>().unwrap())
+                    } else {
+                        Term::int(num.parse().unwrap())
+                    }
+                }
+                _ => Term::atom(Atom::from(
+                    self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_'),
+                )),
+            }
+        }
+    }
+
+    fn run_spec(vm: &vm::Machine, process: &RcProcess, spec: Term, obj: Term) -> Option<Term> {
+        let (mut heads, mut guards, mut bodies) = (Vec::new(), Vec::new(), Vec::new());
+        for clause in Cons::cast_from(&spec).unwrap().iter() {
+            let clause = Tuple::cast_from(clause).unwrap();
+            heads.push(clause[0]);
+            guards.push(clause[1]);
+            bodies.push(clause[2]);
+        }
+        let num_match = heads.len();
+        let pattern = Compiler::new(heads, guards, bodies, num_match, Flag::DCOMP_TABLE)
+            .match_compile()
+            .unwrap();
+        r#match::run(vm, process, &pattern, obj, r#match::Flag::COPY_RESULT)
+    }
+
+    /// `ets:fun2ms/1` output, an object, and what running the spec on it returns.
+    const CONFORMANCE: &[(&str, &str, Option<&str>)] = &[
+        // fun({K, V}) when element(1, V) =:= a -> K end
+        ("[{{'$1','$2'},[{'=:=',{element,1,'$2'},a}],['$1']}]", "{k,{a,b}}", Some("k")),
+        ("[{{'$1','$2'},[{'=:=',{element,1,'$2'},a}],['$1']}]", "{k,{b,a}}", None),
+        ("[{{'$1','$2'},[{'=:=',{element,1,'$2'},a}],['$1']}]", "{k,{}}", None),
+        // fun({_, M}) when is_map_key(x, M) -> map_get(x, M) end
+        ("[{{'_','$1'},[{is_map_key,x,'$1'}],[{map_get,x,'$1'}]}]", "{k,#{x => 1}}", Some("1")),
+        ("[{{'_','$1'},[{is_map_key,x,'$1'}],[{map_get,x,'$1'}]}]", "{k,#{y => 1}}", None),
+        // fun({_, B}) when binary_part(B, 0, 2) =:= <<"ab">> -> binary_part(B, {2, 1}) end
+        (
+            "[{{'_','$1'},[{'=:=',{binary_part,'$1',0,2},<<\"ab\">>}],[{binary_part,'$1',{{2,1}}}]}]",
+            "{k,<<\"abc\">>}",
+            Some("<<\"c\">>"),
+        ),
+        (
+            "[{{'_','$1'},[{'=:=',{binary_part,'$1',0,2},<<\"ab\">>}],[{binary_part,'$1',{{2,1}}}]}]",
+            "{k,<<\"a\">>}",
+            None,
+        ),
+        // fun({K, V}) when V * 2 + 1 > 5 -> {K, V div 2, V rem 2, V / 2} end
+        (
+            "[{{'$1','$2'},[{'>',{'+',{'*','$2',2},1},5}],[{{'$1',{'div','$2',2},{'rem','$2',2},{'/','$2',2}}}]}]",
+            "{k,5}",
+            Some("{k,2,1,2.5}"),
+        ),
+        (
+            "[{{'$1','$2'},[{'>',{'+',{'*','$2',2},1},5}],[{{'$1',{'div','$2',2},{'rem','$2',2},{'/','$2',2}}}]}]",
+            "{k,2}",
+            None,
+        ),
+        // fun({_, V}) when abs(V) > 3 -> -V end
+        ("[{{'_','$1'},[{'>',{abs,'$1'},3}],[{'-','$1'}]}]", "{k,-4}", Some("4")),
+        ("[{{'_','$1'},[{'>',{abs,'$1'},3}],[{'-','$1'}]}]", "{k,2}", None),
+        // fun({_, L}) when hd(L) == 1 -> tl(L) end
+        ("[{{'_','$1'},[{'==',{hd,'$1'},1}],[{tl,'$1'}]}]", "{k,[1,2,3]}", Some("[2,3]")),
+        ("[{{'_','$1'},[{'==',{hd,'$1'},1}],[{tl,'$1'}]}]", "{k,[]}", None),
+        // X = {1, 2}, fun({K, _}) -> {K, X} end
+        ("[{{'$1','_'},[],[{{'$1',{const,{1,2}}}}]}]", "{k,v}", Some("{k,{1,2}}")),
+        // fun({K, V}) -> {a, b, K, c, V} end
+        ("[{{'$1','$2'},[],[{{a,b,'$1',c,'$2'}}]}]", "{k,v}", Some("{a,b,k,c,v}")),
+        // fun({K, V}) -> {{a, K}, [V | tail]} end
+        (
+            "[{{'$1','$2'},[],[{{{{a,'$1'}},['$2'|tail]}}]}]",
+            "{k,v}",
+            Some("{{a,k},[v|tail]}"),
+        ),
+        // fun({K, V}) -> #{key => K, val => V + 1} end
+        (
+            "[{{'$1','$2'},[],[#{key => '$1',val => {'+','$2',1}}]}]",
+            "{k,1}",
+            Some("#{key => k,val => 2}"),
+        ),
+        // fun({K, V}) when is_integer(V) andalso V > 1 orelse V == a -> K end
+        (
+            "[{{'$1','$2'},[{'orelse',{'andalso',{is_integer,'$2'},{'>','$2',1}},{'==','$2',a}}],['$1']}]",
+            "{k,2}",
+            Some("k"),
+        ),
+        (
+            "[{{'$1','$2'},[{'orelse',{'andalso',{is_integer,'$2'},{'>','$2',1}},{'==','$2',a}}],['$1']}]",
+            "{k,a}",
+            Some("k"),
+        ),
+        (
+            "[{{'$1','$2'},[{'orelse',{'andalso',{is_integer,'$2'},{'>','$2',1}},{'==','$2',a}}],['$1']}]",
+            "{k,0}",
+            None,
+        ),
+        // fun({_, V}) when is_record(V, r, 2), byte_size(K) > 1 -> {tuple_size(V), element(2, V) bsl 2, float(element(2, V))} end
+        (
+            "[{{'$1','$2'},[{is_record,'$2',r,2},{'>',{byte_size,'$1'},1}],[{{{tuple_size,'$2'},{'bsl',{element,2,'$2'},2},{float,{element,2,'$2'}}}}]}]",
+            "{<<\"ab\">>,{r,1}}",
+            Some("{2,4,1.0}"),
+        ),
+        // fun({K, a}) -> one; ({K, b}) -> two end
+        ("[{{'$1',a},[],[one]},{{'$1',b},[],[two]}]", "{k,b}", Some("two")),
+        ("[{{'$1',a},[],[one]},{{'$1',b},[],[two]}]", "{k,c}", None),
+        // fun(X = {K, _}) when K > 1 -> X end
+        ("[{{'$1','_'},[{'>','$1',1}],['$_']}]", "{2,v}", Some("{2,v}")),
+        // ets:match(T, {'$1', '$2'})
+        ("[{{'$1','$2'},[],['$$']}]", "{k,v}", Some("[k,v]")),
+        // fun({_, #{x := X}}) -> X end
+        ("[{{'_',#{x => '$1'}},[],['$1']}]", "{k,#{x => 1,y => 2}}", Some("1")),
+        ("[{{'_',#{x => '$1'}},[],['$1']}]", "{k,#{y => 2}}", None),
+        // fun({_, [H | _]}) -> H end
+        ("[{{'_',['$1'|'_']},[],['$1']}]", "{k,[1,2]}", Some("1")),
+        // fun({K, {K, V}}) -> V end
+        ("[{{'$1',{'$1','$2'}},[],['$2']}]", "{k,{k,v}}", Some("v")),
+        ("[{{'$1',{'$1','$2'}},[],['$2']}]", "{k,{j,v}}", None),
+        // errors in the body don't fail the match
+        ("[{{'_','$1'},[],[{element,5,'$1'}]}]", "{k,{a}}", Some("'EXIT'")),
+    ];
+
+    #[test]
+    fn test_fun2ms_conformance() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        for (spec, obj, expected) in CONFORMANCE {
+            let res = run_spec(
+                &vm,
+                &process,
+                Reader::read(heap, spec),
+                Reader::read(heap, obj),
+            );
+            let expected = expected.map(|expected| Reader::read(heap, expected));
+            assert_eq!(res, expected, "{} on {}", spec, obj);
+        }
+    }
+
+    #[test]
+    fn test_self() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        // fun({K, _}) -> {K, self()} end
+        let spec = Reader::read(heap, "[{{'$1','_'},[],[{{'$1',{self}}}]}]");
+        let res = run_spec(&vm, &process, spec, Reader::read(heap, "{k,v}"));
+        let key = Reader::read(heap, "k");
+        assert_eq!(res, Some(tup2!(heap, key, Term::pid(process.pid))));
+    }
+}
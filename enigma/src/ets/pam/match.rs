@@ -151,7 +151,7 @@ pub fn run(
     // #else
     // #define fail!() goto fail
     // #endif
-    let _FAIL_TERM = atom!(EXIT); // The term to set as return when bif fails and do_catch != 0
+    let fail_term = atom!(EXIT); // The term to set as return when bif fails and do_catch != 0
     let mut pc = 0;
 
     //*return_flags = 0U;
@@ -242,94 +242,73 @@ pub fn run(
                         fail!()
                     }
                 }
-                //                Opcode::List() => {
-                //                    if !is_list(*ep) {
-                //                        fail!();
-                //                    }
-                //                    ep = list_val(*ep);
-                //                }
-                //                Opcode::PushL() => {
-                //                    if !is_list(*ep)
-                //                        fail!();
-                //                    *sp++ = list_val(*ep);
-                //                    ++ep;
-                //                }
-                //                Opcode::Map(n) => {
-                //                    if !is_map(*ep) {
-                //                        fail!();
-                //                    }
-                //                    if hashmap_size(*ep) < n {
-                //                        fail!();
-                //                    }
-                //                    ep = flatmap_val(*ep);
-                //                }
-                //                Opcode::PushM(n) => {
-                //                    if !is_map(*ep) {
-                //                        fail!();
-                //                    }
-                //                    if hashmap_size(*ep) < n {
-                //                        fail!();
-                //                    }
-                //                    *sp++ = flatmap_val(*ep++);
-                //                }
-                //                Opcode::Key(t) => {
-                //                    tp = erts_maps_get(t, make_boxed(ep));
-                //                    if !tp {
-                //                        fail!();
-                //                    }
-                //                    *sp++ = ep;
-                //                    ep = tp;
-                //                }
+                Opcode::List() => {
+                    e = ep.next().unwrap();
+                    if let Ok(cons) = Cons::cast_from(e) {
+                        ep = Box::new(
+                            std::iter::once(&cons.head).chain(std::iter::once(&cons.tail)),
+                        );
+                    } else {
+                        fail!();
+                    }
+                }
+                Opcode::PushL(_) => {
+                    e = ep.next().unwrap();
+                    if let Ok(cons) = Cons::cast_from(e) {
+                        sp.push(Box::new(
+                            std::iter::once(&cons.head).chain(std::iter::once(&cons.tail)),
+                        ));
+                    } else {
+                        fail!();
+                    }
+                }
+                Opcode::Map(n) => {
+                    // every Key lookup starts from the map itself
+                    e = ep.next().unwrap();
+                    match Map::cast_from(e) {
+                        Ok(map) if map.0.len() >= n => ep = Box::new(std::iter::repeat(e)),
+                        _ => fail!(),
+                    }
+                }
+                Opcode::PushM(n) => {
+                    e = ep.next().unwrap();
+                    match Map::cast_from(e) {
+                        Ok(map) if map.0.len() >= n => sp.push(Box::new(std::iter::repeat(e))),
+                        _ => fail!(),
+                    }
+                }
+                Opcode::Key(t) => {
+                    e = ep.next().unwrap();
+                    let map = Map::cast_from(e).unwrap();
+                    if let Some(val) = map.0.get(&t) {
+                        let map_ep = std::mem::replace(&mut ep, Box::new(std::iter::once(val)));
+                        sp.push(map_ep);
+                    } else {
+                        fail!();
+                    }
+                }
                 Opcode::Pop() => {
                     ep = sp.pop().unwrap();
                 }
-                //                Opcode::Swap() => {
-                //                    tp = sp[-1];
-                //                    sp[-1] = sp[-2];
-                //                    sp[-2] = tp;
-                //                }
+                Opcode::Swap() => {
+                    let len = sp.len();
+                    sp.swap(len - 1, len - 2);
+                }
                 Opcode::Bind(n) => {
                     variables[n] = *ep.next().unwrap();
                 }
-                //                Opcode::Cmp(n) => {
-                //                    if !EQ(variables[n].term, *ep) {
-                //                        fail!();
-                //                    }
-                //                    ++ep;
-                //                }
-                //                Opcode::EqBin(t) => {
-                //                    if !EQ(t,*ep) {
-                //                        fail!();
-                //                    }
-                //                    ++ep;
-                //                }
-                //                Opcode::EqFloat(f) => {
-                //                    if !is_float(*ep) {
-                //                        fail!();
-                //                    }
-                //                    // if sys_memcmp(float_val(*ep) + 1, pc, sizeof(double)) {
-                //                    //     fail!();
-                //                    // }
-                //                    ++ep;
-                //                }
-                //                Opcode::EqRef(reference) => {
-                //                    if !is_ref(*ep) {
-                //                        fail!();
-                //                    }
-                //                    if !EQ(reference, *ep) {
-                //                        fail!();
-                //                    }
-                //                    ++ep;
-                //                }
-                //                Opcode::EqBig(bignum) => {
-                //                    if !is_big(*ep) {
-                //                        fail!();
-                //                    }
-                //                    if !EQ(bignum, *ep) {
-                //                        fail!();
-                //                    }
-                //                    ++ep;
-                //                }
+                Opcode::Cmp(n) => {
+                    e = ep.next().unwrap();
+                    if variables[n] != *e {
+                        fail!();
+                    }
+                }
+                Opcode::EqBin(t) | Opcode::EqFloat(t) | Opcode::EqRef(t) | Opcode::EqBig(t) => {
+                    e = ep.next().unwrap();
+                    if t != *e {
+                        fail!();
+                    }
+                }
                 Opcode::Eq(t) => {
                     // assert!(is_immed(t));
                     e = ep.next().unwrap();
@@ -361,15 +340,18 @@ pub fn run(
                     let tail = esp.pop().unwrap();
                     esp.push(cons!(&process.context_mut().heap, head, tail))
                 }
-                //                Opcode::MkTuple(n) => {
-                //                    ehp = HAllocX(build_proc, n+1, HEAP_XTRA);
-                //                    t = make_tuple(ehp);
-                //                    *ehp++ = make_arityval(n);
-                //                    while n-- {
-                //                        *ehp++ = *--esp;
-                //                    }
-                //                    *esp++ = t;
-                //                }
+                Opcode::MkTuple(n) => {
+                    let heap = &process.context_mut().heap;
+                    let tuple = value::tuple(heap, n as u32);
+                    // elements were pushed first to last
+                    let start = esp.len() - n;
+                    for (i, val) in esp.drain(start..).enumerate() {
+                        unsafe {
+                            std::ptr::write(&mut tuple[i], val);
+                        }
+                    }
+                    esp.push(Term::from(tuple));
+                }
                 // Opcode::MkFlatMap(n) => {
                 //     ehp = HAllocX(build_proc, MAP_HEADER_FLATMAP_SZ + n, HEAP_XTRA);
                 //     t = *--esp;
@@ -386,31 +368,26 @@ pub fn run(
                 //     }
                 //     *esp++ = t;
                 // }
-                //                Opcode::MkHashMap(n) => {
-                //                    esp -= 2*n;
-                //                    ehp = HAllocX(build_proc, 2*n, HEAP_XTRA);
-                //                    {
-                //                        ErtsHeapFactory factory;
-                //                        Uint ix;
-                //                        for (ix = 0; ix < 2*n; ix++){
-                //                            ehp[ix] = esp[ix];
-                //                        }
-                //                        erts_factory_proc_init(&factory, build_proc);
-                //                        t = erts_hashmap_from_array(&factory, ehp, n, 0);
-                //                        erts_factory_close(&factory);
-                //                    }
-                //                    *esp++ = t;
-                //                }
-                //                Opcode::Call0(bif) => {
-                //                    t = (*bif)(build_proc, bif_args);
-                //                    if is_non_value(t) {
-                //                        if (do_catch)
-                //                            t = FAIL_TERM;
-                //                        else
-                //                            fail!();
-                //                    }
-                //                    *esp++ = t;
-                //                }
+                Opcode::MkHashMap(n) => {
+                    // keys and values were pushed in pairs
+                    let start = esp.len() - 2 * n;
+                    let map: value::HAMT = esp[start..]
+                        .chunks(2)
+                        .map(|pair| (pair[0], pair[1]))
+                        .collect();
+                    esp.truncate(start);
+                    esp.push(Term::map(&process.context_mut().heap, map));
+                }
+                Opcode::Call0(bif) => match bif(vm, process, &[]) {
+                    Ok(t) => esp.push(t),
+                    Err(_) => {
+                        if do_catch {
+                            esp.push(fail_term);
+                        } else {
+                            fail!();
+                        }
+                    }
+                },
                 Opcode::Call1(bif) => {
                     let arg0 = esp.pop().unwrap();
                     let args = &[arg0];
@@ -420,7 +397,7 @@ pub fn run(
                         }
                         Err(_) => {
                             if do_catch {
-                                // t = FAIL_TERM;
+                                esp.push(fail_term);
                             } else {
                                 fail!();
                             }
@@ -437,7 +414,7 @@ pub fn run(
                         }
                         Err(_) => {
                             if do_catch {
-                                // t = FAIL_TERM;
+                                esp.push(fail_term);
                             } else {
                                 fail!();
                             }
@@ -455,7 +432,7 @@ pub fn run(
                         }
                         Err(_) => {
                             if do_catch {
-                                // t = FAIL_TERM;
+                                esp.push(fail_term);
                             } else {
                                 fail!();
                             }
@@ -515,72 +492,74 @@ pub fn run(
                         fail!();
                     }
                 }
-                //                Opcode::Or(n) => {
-                //                    t = atom!(FALSE);
-                //                    while n-- {
-                //                        if *--esp == atom!(TRUE) {
-                //                            t = atom!(TRUE);
-                //                        } else if *esp != atom!(FALSE) {
-                //                            esp -= n;
-                //                            if do_catch {
-                //                                t = FAIL_TERM;
-                //                                break;
-                //                            } else {
-                //                                fail!();
-                //                            }
-                //                        }
-                //                    }
-                //                    *esp++ = t;
-                //                }
-                //                Opcode::And(n) => {
-                //                    t = atom!(TRUE);
-                //                    while n-- {
-                //                        if *--esp == atom!(FALSE) {
-                //                            t = atom!(FALSE);
-                //                        } else if *esp != atom!(TRUE) {
-                //                            esp -= n;
-                //                            if do_catch {
-                //                                t = FAIL_TERM;
-                //                                break;
-                //                            } else {
-                //                                fail!();
-                //                            }
-                //                        }
-                //                    }
-                //                    *esp++ = t;
-                //                }
-                //                Opcode::OrElse(n) => {
-                //                    if *--esp == atom!(TRUE) { // check top item
-                //                        ++esp; // undo --
-                //                        pc += n;
-                //                    } else if *esp != atom!(FALSE) {
-                //                        if do_catch {
-                //                            *esp++ = FAIL_TERM;
-                //                            pc += n;
-                //                        } else {
-                //                            fail!();
-                //                        }
-                //                    }
-                //                }
-                //                Opcode::AndAlso(n) => {
-                //                    if *--esp == atom!(FALSE) { // check top item
-                //                        esp++; // undo --
-                //                        pc += n;
-                //                    } else if *esp != atom!(TRUE) {
-                //                        if do_catch {
-                //                            *esp++ = FAIL_TERM;
-                //                            pc += n;
-                //                        } else {
-                //                            fail!();
-                //                        }
-                //                    }
-                //                }
-                //                Opcode::Jump(n) => {
-                //                    pc += n;
-                //                }
-                //                Opcode::Selff() => {
-                //                    *esp++ = self->common.id;
-                //                }
+                Opcode::Or(n) => {
+                    let start = esp.len() - n;
+                    let mut t = atom!(FALSE);
+                    for val in esp.drain(start..) {
+                        if val == atom!(TRUE) {
+                            t = atom!(TRUE);
+                        } else if val != atom!(FALSE) {
+                            t = fail_term;
+                            break;
+                        }
+                    }
+                    if t == fail_term && !do_catch {
+                        fail!();
+                    }
+                    esp.push(t);
+                }
+                Opcode::And(n) => {
+                    let start = esp.len() - n;
+                    let mut t = atom!(TRUE);
+                    for val in esp.drain(start..) {
+                        if val == atom!(FALSE) {
+                            t = atom!(FALSE);
+                        } else if val != atom!(TRUE) {
+                            t = fail_term;
+                            break;
+                        }
+                    }
+                    if t == fail_term && !do_catch {
+                        fail!();
+                    }
+                    esp.push(t);
+                }
+                Opcode::OrElse(n) => {
+                    // check top item, a true short-circuits and stays on the stack
+                    let t = esp.pop().unwrap();
+                    if t == atom!(TRUE) {
+                        esp.push(t);
+                        pc += n;
+                    } else if t != atom!(FALSE) {
+                        if do_catch {
+                            esp.push(fail_term);
+                            pc += n;
+                        } else {
+                            fail!();
+                        }
+                    }
+                }
+                Opcode::AndAlso(n) => {
+                    // check top item, a false short-circuits and stays on the stack
+                    let t = esp.pop().unwrap();
+                    if t == atom!(FALSE) {
+                        esp.push(t);
+                        pc += n;
+                    } else if t != atom!(TRUE) {
+                        if do_catch {
+                            esp.push(fail_term);
+                            pc += n;
+                        } else {
+                            fail!();
+                        }
+                    }
+                }
+                Opcode::Jump(n) => {
+                    pc += n;
+                }
+                Opcode::Selff() => {
+                    esp.push(Term::pid(process.pid));
+                }
                 Opcode::Waste() => {
                     esp.pop();
                 }
@@ -851,7 +830,7 @@ pub fn run(
         // *return_flags = 0U;
         if let Some(fail) = fail_label {
             // We failed during a "TryMeElse", lets restart, with the next match program
-            pc += fail;
+            pc = fail;
         // cleanup_match_pseudo_process(mpsp, 1);
         // break 'restart;
         } else {
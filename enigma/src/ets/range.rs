@@ -0,0 +1,426 @@
+//! Key ranges for ordered tables (ordered_set).
+//!
+//! When the key in a match spec head is partially bound, like `{{user, '_'}, '_'}`, or the
+//! guard bounds the key variable, like `[{'>=', '$1', 10}]`, only a contiguous range of keys
+//! can match. Each end of that range is a `Probe`: a pattern that compares against keys in
+//! term order, but never as equal. Seeking a `BTreeMap` to a probe lands right on the first
+//! (or last) key of the range, and the scan stops as soon as a key passes the other end.
+use crate::atom;
+use crate::value::{CastFrom, Cons, Term, Tuple};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+use super::pam;
+
+/// Something a table can be searched by: either a key, or a probe between keys.
+pub trait Seek {
+    /// Compares with a key in the table.
+    fn cmp_key(&self, key: &Term) -> Ordering;
+
+    /// The key itself, if this is one.
+    fn as_key(&self) -> Option<&Term>;
+}
+
+impl Seek for Term {
+    fn cmp_key(&self, key: &Term) -> Ordering {
+        self.cmp(key)
+    }
+
+    fn as_key(&self) -> Option<&Term> {
+        Some(self)
+    }
+}
+
+impl Borrow<dyn Seek> for Term {
+    fn borrow(&self) -> &(dyn Seek + 'static) {
+        self
+    }
+}
+
+impl PartialEq for dyn Seek {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for dyn Seek {}
+
+impl PartialOrd for dyn Seek {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for dyn Seek {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.as_key(), other.as_key()) {
+            (_, Some(key)) => self.cmp_key(key),
+            (Some(key), None) => other.cmp_key(key).reverse(),
+            (None, None) => unreachable!("probes are only compared with keys"),
+        }
+    }
+}
+
+/// One end of a key range.
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    pattern: Term,
+    /// Result of the comparison when the key matches the bound parts of the pattern.
+    edge: Ordering,
+}
+
+impl Probe {
+    pub fn cmp_key(self, key: &Term) -> Ordering {
+        match cmp_bound(self.pattern, *key) {
+            Some(Ordering::Equal) | None => self.edge,
+            Some(ordering) => ordering,
+        }
+    }
+}
+
+impl Seek for Probe {
+    fn cmp_key(&self, key: &Term) -> Ordering {
+        Probe::cmp_key(*self, key)
+    }
+
+    fn as_key(&self) -> Option<&Term> {
+        None
+    }
+}
+
+/// Compares the bound prefix of a pattern with a key, in term order.
+///
+/// Returns `None` once a variable is reached with everything before it equal, since the
+/// variable could be anything from there on.
+fn cmp_bound(pattern: Term, key: Term) -> Option<Ordering> {
+    if !pam::has_variable(pattern) {
+        return Some(pattern.cmp(&key));
+    }
+    if pattern == atom!(UNDERSCORE) || pam::is_variable(pattern).is_some() {
+        return None;
+    }
+    let (t1, t2) = (pattern.get_type(), key.get_type());
+    if t1 != t2 {
+        return Some(t1.cmp(&t2));
+    }
+
+    if let Ok(pattern) = Tuple::cast_from(&pattern) {
+        let key = Tuple::cast_from(&key).unwrap();
+        if pattern.len() != key.len() {
+            return Some(pattern.len().cmp(&key.len()));
+        }
+        for (p, k) in pattern.iter().zip(key.iter()) {
+            match cmp_bound(*p, *k) {
+                Some(Ordering::Equal) => (),
+                res => return res,
+            }
+        }
+        return Some(Ordering::Equal);
+    }
+
+    if pattern.is_list() {
+        // lists compare element by element, a shorter list is smaller
+        let (mut pattern, mut key) = (pattern, key);
+        loop {
+            match (Cons::cast_from(&pattern), Cons::cast_from(&key)) {
+                (Ok(p), Ok(k)) => {
+                    match cmp_bound(p.head, k.head) {
+                        Some(Ordering::Equal) => (),
+                        res => return res,
+                    }
+                    pattern = p.tail;
+                    key = k.tail;
+                }
+                (Ok(_), Err(_)) => return Some(Ordering::Greater),
+                (Err(_), k) => {
+                    if pattern == atom!(UNDERSCORE) || pam::is_variable(pattern).is_some() {
+                        return None;
+                    }
+                    return Some(if k.is_ok() {
+                        Ordering::Less
+                    } else {
+                        Ordering::Equal
+                    });
+                }
+            }
+        }
+    }
+
+    // maps with variables in them: can't tell where in the map order a match would be
+    None
+}
+
+/// The range of keys a match spec can match.
+#[derive(Debug, Default)]
+pub struct KeyRange {
+    pub lower: Option<Probe>,
+    pub upper: Option<Probe>,
+}
+
+impl KeyRange {
+    /// Derives the key range from a compiled match spec's heads and guards. Only single clause
+    /// specs are narrowed, anything else scans the whole table.
+    pub fn analyze(keypos: usize, heads: &[Term], guards: &[Term]) -> Self {
+        match (heads, guards) {
+            ([head], [guard]) => Self::from_clause(keypos, *head, *guard),
+            _ => Self::default(),
+        }
+    }
+
+    fn from_clause(keypos: usize, head: Term, guard: Term) -> Self {
+        let key = match Tuple::cast_from(&head) {
+            Ok(tuple) if tuple.len() > keypos => tuple[keypos],
+            _ => return Self::default(),
+        };
+
+        if let Some(var) = pam::is_variable(key) {
+            return Self::from_guard(var, guard);
+        }
+        if key == atom!(UNDERSCORE) {
+            return Self::default();
+        }
+        Self {
+            lower: Some(Probe {
+                pattern: key,
+                edge: Ordering::Less,
+            }),
+            upper: Some(Probe {
+                pattern: key,
+                edge: Ordering::Greater,
+            }),
+        }
+    }
+
+    /// Narrows the range by the top level comparisons of the key variable with constants.
+    fn from_guard(var: usize, guard: Term) -> Self {
+        let mut range = Self::default();
+        let guard = match Cons::cast_from(&guard) {
+            Ok(guard) => guard,
+            Err(_) => return range,
+        };
+
+        for test in guard.iter() {
+            let test = match Tuple::cast_from(test) {
+                Ok(test) if test.len() == 3 => test,
+                _ => continue,
+            };
+            let is_var = |t: Term| pam::is_variable(t) == Some(var);
+            // rewrite to '$N' op C
+            let (op, c) = match (is_var(test[1]), is_var(test[2])) {
+                (true, false) => (test[0], test[2]),
+                (false, true) => (mirror(test[0]), test[1]),
+                _ => continue,
+            };
+            let c = match guard_constant(c) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let (lower, upper) = match op {
+                op if op == atom!(GT) => (Some(Ordering::Greater), None),
+                op if op == atom!(GE) => (Some(Ordering::Less), None),
+                op if op == atom!(LT) => (None, Some(Ordering::Less)),
+                op if op == atom!(LE) => (None, Some(Ordering::Greater)),
+                op if op == atom!(EQ) => (Some(Ordering::Less), Some(Ordering::Greater)),
+                _ => continue,
+            };
+            if let Some(edge) = lower {
+                range.lower = Some(tighter(
+                    range.lower,
+                    Probe { pattern: c, edge },
+                    Ordering::Greater,
+                ));
+            }
+            if let Some(edge) = upper {
+                range.upper = Some(tighter(
+                    range.upper,
+                    Probe { pattern: c, edge },
+                    Ordering::Less,
+                ));
+            }
+        }
+        range
+    }
+}
+
+/// Swaps the operands of a comparison.
+fn mirror(op: Term) -> Term {
+    match op {
+        op if op == atom!(GT) => atom!(LT),
+        op if op == atom!(GE) => atom!(LE),
+        op if op == atom!(LT) => atom!(GT),
+        op if op == atom!(LE) => atom!(GE),
+        op => op,
+    }
+}
+
+/// Returns the value of a guard operand if it is a constant.
+fn guard_constant(t: Term) -> Option<Term> {
+    if let Ok(tuple) = Tuple::cast_from(&t) {
+        // {const, X} is a literal, other tuples are expressions
+        return match tuple.len() {
+            2 if tuple[0] == atom!(CONST) => Some(tuple[1]),
+            _ => None,
+        };
+    }
+    if t.is_list() || t.is_map() {
+        return None;
+    }
+    if t == atom!(DOLLAR_UNDERSCORE) || t == atom!(DOLLAR_DOLLAR) || pam::is_variable(t).is_some() {
+        return None;
+    }
+    Some(t)
+}
+
+/// Picks the stricter of two ground bounds; `dir` is the direction the range shrinks in.
+fn tighter(current: Option<Probe>, probe: Probe, dir: Ordering) -> Probe {
+    match current {
+        None => probe,
+        Some(current) => match probe.pattern.cmp(&current.pattern) {
+            Ordering::Equal if probe.edge == dir => probe,
+            Ordering::Equal => current,
+            ordering if ordering == dir => probe,
+            _ => current,
+        },
+    }
+}
+
+/// Iterates over the entries of `map` within `range`, continuing after the key `from`.
+pub fn scan<'a>(
+    map: &'a BTreeMap<Term, Term>,
+    range: &KeyRange,
+    from: Option<Term>,
+    reverse: bool,
+) -> Box<dyn Iterator<Item = (&'a Term, &'a Term)> + 'a> {
+    // One side of the range is always unbounded: BTreeMap::range panics if the start is past
+    // the end, and the probes can't be compared with each other.
+    fn seek<'b>(
+        from: &'b Option<Term>,
+        probe: &'b Option<Probe>,
+    ) -> Bound<&'b (dyn Seek + 'static)> {
+        match (from, probe) {
+            (Some(key), _) => Excluded(key),
+            (None, Some(probe)) => Included(probe),
+            (None, None) => Unbounded,
+        }
+    }
+    let (lower, upper) = (range.lower, range.upper);
+    if reverse {
+        let objects = map
+            .range::<dyn Seek, _>((Unbounded, seek(&from, &upper)))
+            .rev();
+        Box::new(objects.take_while(move |(key, _)| {
+            lower.map_or(true, |probe| probe.cmp_key(key) == Ordering::Less)
+        }))
+    } else {
+        let objects = map.range::<dyn Seek, _>((seek(&from, &lower), Unbounded));
+        Box::new(objects.take_while(move |(key, _)| {
+            upper.map_or(true, |probe| probe.cmp_key(key) == Ordering::Greater)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atom::Atom;
+    use crate::immix::Heap;
+
+    fn keys(
+        map: &BTreeMap<Term, Term>,
+        range: &KeyRange,
+        from: Option<Term>,
+        reverse: bool,
+    ) -> Vec<Term> {
+        scan(map, range, from, reverse)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    #[test]
+    fn test_partially_bound_key() {
+        let heap = &Heap::new();
+        let user = Term::atom(Atom::from("user"));
+        let mut map = BTreeMap::new();
+        let mut users = Vec::new();
+        for tag in &[atom!(TRUE), user, Term::atom(Atom::from("zzz"))] {
+            for i in 0..3 {
+                let key = tup2!(heap, *tag, Term::int(i));
+                map.insert(key, Term::nil());
+                if *tag == user {
+                    users.push(key);
+                }
+            }
+        }
+        // keys of other types and sizes around the range
+        map.insert(Term::int(1), Term::nil());
+        map.insert(tup3!(heap, user, Term::int(0), Term::int(0)), Term::nil());
+        map.insert(Term::nil(), Term::nil());
+
+        // {{user, '_'}, '_'}
+        let head = tup2!(
+            heap,
+            tup2!(heap, user, atom!(UNDERSCORE)),
+            atom!(UNDERSCORE)
+        );
+        let range = KeyRange::analyze(0, &[head], &[Term::nil()]);
+
+        assert_eq!(keys(&map, &range, None, false), users);
+        assert_eq!(keys(&map, &range, Some(users[0]), false), &users[1..]);
+        users.reverse();
+        assert_eq!(keys(&map, &range, None, true), users);
+        assert_eq!(keys(&map, &range, Some(users[0]), true), &users[1..]);
+
+        // unbound key and multiple clauses scan everything
+        let range = KeyRange::analyze(0, &[atom!(UNDERSCORE)], &[Term::nil()]);
+        assert_eq!(keys(&map, &range, None, false).len(), map.len());
+        let range = KeyRange::analyze(0, &[head, head], &[Term::nil(), Term::nil()]);
+        assert_eq!(keys(&map, &range, None, false).len(), map.len());
+    }
+
+    #[test]
+    fn test_guard_bounds() {
+        let heap = &Heap::new();
+        let mut map = BTreeMap::new();
+        for i in 0..10 {
+            map.insert(Term::int(i), Term::nil());
+        }
+        map.insert(atom!(TRUE), Term::nil());
+
+        let var = Term::atom(Atom::from("$1"));
+        let head = tup2!(heap, var, atom!(UNDERSCORE));
+        let ints = |from: i32, to: i32| (from..to).map(Term::int).collect::<Vec<_>>();
+
+        // [{'>', '$1', 2}, {'=<', '$1', {const, 6}}, {'<', 8, '$1'}]
+        let guard = cons!(heap, tup3!(heap, atom!(GT), var, Term::int(2)), Term::nil());
+        let le = tup3!(
+            heap,
+            atom!(LE),
+            var,
+            tup2!(heap, atom!(CONST), Term::int(6))
+        );
+        let guard = cons!(heap, le, guard);
+        let range = KeyRange::analyze(0, &[head], &[guard]);
+        assert_eq!(keys(&map, &range, None, false), ints(3, 7));
+        let mut expected = ints(3, 7);
+        expected.reverse();
+        assert_eq!(keys(&map, &range, None, true), expected);
+
+        // mirrored, and the tighter of two bounds wins
+        let guard = cons!(heap, tup3!(heap, atom!(LT), Term::int(7), var), guard);
+        let range = KeyRange::analyze(0, &[head], &[guard]);
+        assert!(keys(&map, &range, None, false).is_empty());
+
+        let guard = cons!(heap, tup3!(heap, atom!(EQ), var, Term::int(4)), Term::nil());
+        let range = KeyRange::analyze(0, &[head], &[guard]);
+        assert_eq!(keys(&map, &range, None, false), ints(4, 5));
+
+        // expressions aren't constants
+        let sum = tup3!(heap, atom!(PLUS), var, Term::int(1));
+        let guard = cons!(heap, tup3!(heap, atom!(GT), sum, Term::int(4)), Term::nil());
+        let range = KeyRange::analyze(0, &[head], &[guard]);
+        assert_eq!(keys(&map, &range, None, false).len(), map.len());
+    }
+}
@@ -0,0 +1,178 @@
+//! Lock striping for hash based tables (set, bag).
+//!
+//! Tables created with `{write_concurrency, true}` split their keys over several segments, each
+//! with its own lock and heap, so writers on different keys rarely contend. Other tables use a
+//! single segment.
+//!
+//! Keys are assigned to segments by the high bits of their hash. Since traversal is in hash
+//! order (see `order`), each segment covers a contiguous part of the traversal and a full
+//! traversal simply visits the segments in order.
+use super::lock::{Lock, ReadGuard, WriteGuard};
+use super::order::{self, HashOrder};
+use super::Status;
+use crate::immix::Heap;
+use crate::value::Term;
+use hashbrown::HashMap;
+
+/// Number of segments of a `write_concurrency` table.
+const FINE_LOCKED_SEGMENTS: usize = 64;
+
+pub(crate) struct Segment<V> {
+    pub map: HashMap<Term, V>,
+    /// Traversal order of the keys in `map`.
+    pub order: HashOrder,
+    /// Objects in this segment are allocated here.
+    pub heap: Heap,
+}
+
+impl<V> Segment<V> {
+    fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+            order: HashOrder::new(),
+            heap: Heap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: Term, value: V) -> Option<V> {
+        let prev = self.map.insert(key, value);
+        if prev.is_none() {
+            self.order.insert(key);
+        }
+        prev
+    }
+
+    pub fn remove(&mut self, key: &Term) -> Option<V> {
+        let prev = self.map.remove(key);
+        if prev.is_some() {
+            self.order.remove(key);
+        }
+        prev
+    }
+
+    /// Keeps only the entries the predicate returns true for.
+    pub fn retain<F>(&mut self, mut pred: F)
+    where
+        F: FnMut(&Term, &mut V) -> bool,
+    {
+        let order = &mut self.order;
+        self.map.retain(|key, value| {
+            let keep = pred(key, value);
+            if !keep {
+                order.remove(key);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    /// Iterates over entries in traversal order, starting after `from` if given.
+    pub fn iter_from(&self, from: Option<Term>) -> impl Iterator<Item = (&Term, &V)> {
+        let keys: Box<dyn Iterator<Item = &Term> + '_> = match from {
+            Some(key) => Box::new(self.order.iter_from(key)),
+            None => Box::new(self.order.iter()),
+        };
+        keys.map(move |key| (key, &self.map[key]))
+    }
+}
+
+pub(crate) struct Segments<V> {
+    segments: Vec<Lock<Segment<V>>>,
+}
+
+impl<V> Segments<V> {
+    pub fn new(status: Status) -> Self {
+        let count = if status.contains(Status::DB_FINE_LOCKED) {
+            FINE_LOCKED_SEGMENTS
+        } else {
+            1
+        };
+        Self {
+            segments: (0..count)
+                .map(|_| Lock::new(Segment::new(), status))
+                .collect(),
+        }
+    }
+
+    /// Index of the segment holding the key. Monotonic in the key hash.
+    pub fn index(&self, key: &Term) -> usize {
+        ((order::hash(key) >> 48) as usize * self.segments.len()) >> 16
+    }
+
+    pub fn read(&self, key: &Term) -> ReadGuard<Segment<V>> {
+        self.segments[self.index(key)].read()
+    }
+
+    pub fn write(&self, key: &Term) -> WriteGuard<Segment<V>> {
+        self.segments[self.index(key)].write()
+    }
+
+    /// Read locks all segments, in order.
+    pub fn read_all(&self) -> Vec<ReadGuard<Segment<V>>> {
+        self.segments.iter().map(|segment| segment.read()).collect()
+    }
+
+    /// Write locks all segments, in order.
+    pub fn write_all(&self) -> Vec<WriteGuard<Segment<V>>> {
+        self.segments
+            .iter()
+            .map(|segment| segment.write())
+            .collect()
+    }
+
+    pub fn first(&self) -> Option<Term> {
+        self.first_from(0)
+    }
+
+    /// The key following `key` in traversal order. `key` doesn't have to be in the table.
+    pub fn next(&self, key: Term) -> Option<Term> {
+        let index = self.index(&key);
+        match self.segments[index].read().order.next(key) {
+            Some(key) => Some(key),
+            None => self.first_from(index + 1),
+        }
+    }
+
+    fn first_from(&self, index: usize) -> Option<Term> {
+        self.segments[index..]
+            .iter()
+            .find_map(|segment| segment.read().order.first())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal() {
+        let segments = Segments::new(Status::DB_SET | Status::DB_FINE_LOCKED);
+        for i in 0..100 {
+            let key = Term::int(i);
+            segments.write(&key).insert(key, ());
+        }
+
+        let mut count = 0;
+        let mut key = segments.first();
+        while let Some(k) = key {
+            count += 1;
+            key = segments.next(k);
+        }
+        assert_eq!(count, 100);
+
+        // traversal order matches the order of the locked segments
+        let all: Vec<_> = {
+            let guards = segments.read_all();
+            guards
+                .iter()
+                .flat_map(|segment| segment.iter_from(None).map(|(key, _)| *key))
+                .collect()
+        };
+        assert_eq!(Some(all[0]), segments.first());
+        assert_eq!(segments.next(all[41]), Some(all[42]));
+    }
+}
@@ -3,8 +3,10 @@ use crate::immix::Heap;
 use crate::instruction;
 use crate::loader::FuncInfo;
 use crate::module::MFA;
-use crate::process::RcProcess;
+use crate::process::{Process, RcProcess};
 use crate::value::{self, CastFrom, CastInto, Term, Variant};
+use crate::vm::Machine;
+use std::sync::atomic::Ordering;
 
 /// http://erlang.org/doc/reference_manual/errors.html#exceptions
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -43,8 +45,8 @@ impl From<value::WrongBoxError> for Exception {
 impl From<crate::ets::error::Error> for Exception {
     fn from(value: crate::ets::error::Error) -> Self {
         match value.kind() {
-            crate::ets::error::ErrorKind::BadItem => badarg!(),
-            _ => unimplemented!(),
+            crate::ets::error::ErrorKind::SystemLimit => Exception::new(Reason::EXC_SYSTEM_LIMIT),
+            _ => badarg!(),
         }
     }
 }
@@ -219,7 +221,7 @@ macro_rules! exception_code {
     };
 }
 
-const MAX_BACKTRACE_SIZE: u32 = 64;
+pub const MAX_BACKTRACE_SIZE: u32 = 64;
 pub const DEFAULT_BACKTRACE_SIZE: u32 = 8;
 
 const EXIT_TAGS: [Atom; 3] = [atom::ERROR, atom::EXIT, atom::THROW];
@@ -553,8 +555,8 @@ fn save_stacktrace(
 ) {
     let context = process.context_mut();
     // let pc = context.ip;
-    let mut depth = DEFAULT_BACKTRACE_SIZE;
     // int depth = erts_backtrace_depth;    /* max depth (never negative) */
+    let mut depth = Machine::with_current(|vm| vm.backtrace_depth.load(Ordering::Relaxed)) as u32;
     if depth > 0 {
         // There will always be a current function
         depth -= 1;
@@ -651,7 +653,7 @@ fn save_stacktrace(
 }
 
 pub fn erts_save_stacktrace(
-    process: &RcProcess,
+    process: &Process,
     trace: &mut Vec<instruction::Ptr>,
     mut depth: u32,
 ) {
@@ -12,6 +12,9 @@ pub const DEFAULT_BLOCK_SIZE: usize = 32 * 1024 + mem::size_of::<Block>();
 
 pub const DEFAULT_BLOCK_ALIGN: usize = mem::align_of::<Block>();
 
+/// The number of bytes in the first block of a heap fragment (+ the header).
+pub const FRAGMENT_BLOCK_SIZE: usize = 256 + mem::size_of::<Block>();
+
 pub struct Block {
     /// Points to the start of the block (including this header)
     data: NonNull<u8>,
@@ -53,38 +56,38 @@ pub(crate) fn round_up_to(n: usize, divisor: usize) -> usize {
 }
 
 impl Block {
-    fn default_block_layout() -> Layout {
-        if cfg!(debug_assertions) {
-            Layout::from_size_align(DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_ALIGN).unwrap()
-        } else {
-            unsafe { Layout::from_size_align_unchecked(DEFAULT_BLOCK_SIZE, DEFAULT_BLOCK_ALIGN) }
-        }
-    }
-
-    /// Allocate a new block and return its initialized header.
+    /// Allocate a new block of `block_size` bytes (including the header) and return its
+    /// initialized header.
     ///
     /// If given, `alloc_layout` is the layout of the allocation request that
     /// triggered us to fall back to allocating a new block of memory.
-    #[allow(clippy::cast_ptr_alignment)]
-    fn new(alloc_layout: Option<Layout>) -> NonNull<Block> {
-        let layout = alloc_layout.map_or_else(Block::default_block_layout, |l| {
-            let align = cmp::max(l.align(), mem::align_of::<Block>());
-            if l.size() < DEFAULT_BLOCK_SIZE {
-                // If it is a small allocation, just use our default block size,
-                // but make sure it is aligned for the requested allocation.
-                Layout::from_size_align(DEFAULT_BLOCK_SIZE, align).unwrap()
-            } else {
-                // If the requested allocation is bigger than we can fit in one
-                // of our default blocks, make a special block just for this
-                // allocation.
-                //
-                // Round the size up to a multiple of our header's alignment so
-                // that we can be sure that our header is properly aligned.
-                let size = round_up_to(l.size(), mem::align_of::<Block>());
-                Layout::from_size_align(size + mem::size_of::<Block>(), align).unwrap()
+    fn new(block_size: usize, alloc_layout: Option<Layout>) -> NonNull<Block> {
+        let layout = match alloc_layout {
+            None => Layout::from_size_align(block_size, DEFAULT_BLOCK_ALIGN).unwrap(),
+            Some(l) => {
+                let align = cmp::max(l.align(), mem::align_of::<Block>());
+                if l.size() <= block_size - mem::size_of::<Block>() {
+                    // If it is a small allocation, just use our block size,
+                    // but make sure it is aligned for the requested allocation.
+                    Layout::from_size_align(block_size, align).unwrap()
+                } else {
+                    // If the requested allocation is bigger than we can fit in one
+                    // of our blocks, make a special block just for this
+                    // allocation.
+                    //
+                    // Round the size up to a multiple of our header's alignment so
+                    // that we can be sure that our header is properly aligned.
+                    let size = round_up_to(l.size(), mem::align_of::<Block>());
+                    Layout::from_size_align(size + mem::size_of::<Block>(), align).unwrap()
+                }
             }
-        });
+        };
+        Block::with_layout(layout)
+    }
 
+    /// Allocate a block of exactly `layout` (including the header).
+    #[allow(clippy::cast_ptr_alignment)]
+    fn with_layout(layout: Layout) -> NonNull<Block> {
         let size = layout.size();
 
         unsafe {
@@ -111,13 +114,36 @@ impl Block {
 
 impl Heap {
     pub fn new() -> Self {
-        let block = Block::new(None);
+        let block = Block::new(DEFAULT_BLOCK_SIZE, None);
+        Heap {
+            current_block: Cell::new(block),
+            all_blocks: Cell::new(block),
+        }
+    }
+
+    /// A heap for a few terms that are built in one place and then handed over to another heap
+    /// with `absorb` (messages, replies). It starts out with a small block, and each new block
+    /// doubles in size up to the default.
+    pub fn fragment() -> Self {
+        let block = Block::new(FRAGMENT_BLOCK_SIZE, None);
         Heap {
             current_block: Cell::new(block),
             all_blocks: Cell::new(block),
         }
     }
 
+    /// Takes over the blocks of `other`, so that terms allocated there live as long as this heap.
+    pub fn absorb(&self, other: Heap) {
+        unsafe {
+            let first = other.all_blocks.get();
+            let last = other.blocks().last().unwrap() as *const Block;
+            let current = self.current_block.get();
+            (*last).next.set(current.as_ref().next.get());
+            current.as_ref().next.set(Some(first));
+        }
+        mem::forget(other);
+    }
+
     /// Allocate an object.
     ///
     /// ## Example
@@ -166,6 +192,60 @@ impl Heap {
         self.alloc_layout_slow(layout)
     }
 
+    /// Number of bytes currently bump allocated across all blocks.
+    pub fn size(&self) -> usize {
+        self.blocks()
+            .map(|footer| footer.ptr.get().as_ptr() as usize - footer.data.as_ptr() as usize)
+            .sum()
+    }
+
+    /// Number of bytes reserved by all blocks (excluding the block headers).
+    pub fn capacity(&self) -> usize {
+        self.blocks()
+            .map(|footer| footer.layout.size() - mem::size_of::<Block>())
+            .sum()
+    }
+
+    /// Frees all allocations, keeping the first block around for reuse.
+    ///
+    /// ## Safety
+    ///
+    /// Nothing may point into the heap anymore. Destructors of the allocated objects aren't run.
+    pub unsafe fn clear(&mut self) {
+        let first = self.all_blocks.get();
+        let mut next = first.as_ref().next.take();
+        while let Some(block) = next {
+            let block = block.as_ref();
+            next = block.next.get();
+            // the block header lives inside the allocation, so read it before freeing
+            let (data, layout) = (block.data, block.layout.clone());
+            Global.dealloc(data, layout);
+        }
+        first.as_ref().ptr.set(first.as_ref().data);
+        self.current_block.set(first);
+    }
+
+    /// Frees all allocations and the blocks themselves.
+    ///
+    /// ## Safety
+    ///
+    /// Same as `clear`.
+    pub unsafe fn free(mut self) {
+        self.clear();
+        let first = self.all_blocks.get();
+        let (data, layout) = (first.as_ref().data, first.as_ref().layout.clone());
+        Global.dealloc(data, layout);
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = &Block> {
+        let mut next = Some(self.all_blocks.get());
+        std::iter::from_fn(move || {
+            let footer = unsafe { &*next?.as_ptr() };
+            next = footer.next.get();
+            Some(footer)
+        })
+    }
+
     #[inline(never)]
     #[cold]
     fn overflow(&self) -> ! {
@@ -177,12 +257,17 @@ impl Heap {
     #[inline(never)]
     fn alloc_layout_slow(&self, layout: Layout) -> NonNull<u8> {
         unsafe {
-            // Get a new block from the global allocator.
+            // Get a new block from the global allocator, twice the size of the current one (so
+            // fragments grow up to the default block size).
+            let current = self.current_block.get();
+            let block_size = cmp::min(current.as_ref().layout.size() * 2, DEFAULT_BLOCK_SIZE);
             let size = layout.size();
-            let footer = Block::new(Some(layout));
+            let footer = Block::new(block_size, Some(layout));
 
-            // Set our current block's next link to this new block.
-            self.current_block.get().as_ref().next.set(Some(footer));
+            // Link the new block in after our current block, keeping any absorbed blocks that
+            // follow it.
+            footer.as_ref().next.set(current.as_ref().next.get());
+            current.as_ref().next.set(Some(footer));
 
             // Set the new block as our new current block.
             self.current_block.set(footer);
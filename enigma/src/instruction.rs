@@ -459,18 +459,46 @@ macro_rules! op_call_ext {
     }};
 }
 
+/// Calls a BIF and stores the result in x0. If the BIF offloaded its work (see `bif::blocking`),
+/// the process goes back to the scheduler with `$ret` recorded as where the result goes.
 macro_rules! op_call_bif {
     ($vm:expr, $context:expr, $process:expr, $bif:expr, $arity:expr) => {{
+        op_call_bif!($vm, $context, $process, $bif, $arity, bif::blocking::Return::X0)
+    }};
+    ($vm:expr, $context:expr, $process:expr, $bif:expr, $arity:expr, $ret:expr) => {{
+        let process: &RcProcess = $process;
         // make a slice out of arity x registers
         let args = &$context.x[0..$arity];
-        match $bif($vm, $process, args) {
-            Ok(val) => {
-                $context.x[0] = val;
+        match $bif($vm, process, args) {
+            Ok(val) => $context.x[0] = val,
+            Err(exc) => {
+                if exc.reason == Reason::TRAP {
+                    process.local_data_mut().blocking_return = $ret;
+                }
+                return Err(exc);
+            }
+        }
+    }};
+}
+
+/// Calls a guard BIF and stores the result in `$reg`, jumping to `$fail` on errors if it's set.
+macro_rules! op_bif {
+    ($vm:expr, $context:expr, $process:expr, $bif:expr, $args:expr, $fail:expr, $reg:expr) => {{
+        let process: &RcProcess = $process;
+        match $bif($vm, process, $args) {
+            Ok(val) => $context.set_register($reg, val),
+            Err(exc) => {
+                if exc.reason == Reason::TRAP {
+                    process.local_data_mut().blocking_return =
+                        bif::blocking::Return::Register($reg, $fail);
+                    return Err(exc);
+                }
+                cond_fail!($context, $fail, exc)
             }
-            Err(exc) => return Err(exc),
         }
     }};
 }
+
 macro_rules! op_call_fun {
     ($vm:expr, $context:expr, $process:expr, $value:expr, $arity:expr) => {{
         if let Ok(closure) = value::Closure::cast_from(&$value) {
@@ -858,6 +886,8 @@ instruction!(
 
         // println!("pid={} resumption ", process.pid);
         process.process_incoming()?;
+        // erlang:suspend_process/1 might have been called while we were waiting
+        process.wait_while_suspended().await?;
     },
     fn wait_timeout(label: l, time: s) {
         // Sets up a timeout of Time milliseconds and saves the address of the
@@ -880,6 +910,7 @@ instruction!(
 
                 cancel.await; // suspend process
                 // println!("select! resumption pid={}", process.pid);
+                process.wait_while_suspended().await?;
             },
             Variant::Integer(ms) => {
                 let when = time::Duration::from_millis(ms as u64);
@@ -903,6 +934,7 @@ instruction!(
                     }
 
                 }
+                process.wait_while_suspended().await?;
                 // select! { // suspend process
                 //     _t = future => {
                 //         // jump to success (start of recv loop)
@@ -986,37 +1018,21 @@ instruction!(
     },
     fn call_bif(arity: t, bif: b) {
         // call a bif, store result in x0
-
-        let args = &context.x[0..arity as usize];
-        match bif(vm, &process, args) {
-            Ok(val) => context.x[0] = val,
-            Err(exc) => return Err(exc),
-        }
+        op_call_bif!(vm, context, &process, bif, arity as usize);
     },
     fn call_bif_last(arity: t, bif: b, words: r) {
         // call a bif, store result in x0, return
         op_deallocate(context, words);
 
-        let args = &context.x[0..arity as usize];
-        match bif(vm, &process, args) {
-            Ok(val) => {
-                context.x[0]= val;
-                op_return!(process, context);
-            },
-            Err(exc) => return Err(exc),
-        }
+        let ret = bif::blocking::Return::X0AndReturn;
+        op_call_bif!(vm, context, &process, bif, arity as usize, ret);
+        op_return!(process, context);
     },
     fn call_bif_only(arity: t, bif: b) {
         // call a bif, store result in x0, return
-
-        let args = &context.x[0..arity as usize];
-        match bif(vm, &process, args) {
-            Ok(val) => {
-                context.x[0]= val;
-                op_return!(process, context);
-            },
-            Err(exc) => return Err(exc),
-        }
+        let ret = bif::blocking::Return::X0AndReturn;
+        op_call_bif!(vm, context, &process, bif, arity as usize, ret);
+        op_return!(process, context);
     },
     fn apply_fun() {
         // save pointer onto CP
@@ -1052,17 +1068,11 @@ instruction!(
     },
     fn bif1(fail: l, bif: b, arg1: s, reg: d) {
         let args = &[#arg1];
-        match bif(vm, &process, args) {
-            Ok(val) => context.set_register(reg, val),
-            Err(exc) => cond_fail!(context, fail, exc),
-        }
+        op_bif!(vm, context, &process, bif, args, fail, reg);
     },
     fn bif2(fail: l, bif: b, arg1: s, arg2: s, reg: d) {
         let args = &[#arg1, #arg2];
-        match bif(vm, &process, args) {
-            Ok(val) => context.set_register(reg, val),
-            Err(exc) => cond_fail!(context, fail, exc),
-        }
+        op_bif!(vm, context, &process, bif, args, fail, reg);
     },
     fn allocate(stackneed: r, live: r) {
         context
@@ -1486,26 +1496,17 @@ instruction!(
     fn gc_bif1(fail: l, _live: r, bif: b, arg1: s, reg: d) {
         // TODO: GcBif needs to handle GC as necessary
         let args = &[#arg1];
-        match bif(vm, &process, args) {
-            Ok(val) => context.set_register(reg, val),
-            Err(exc) => cond_fail!(context, fail, exc),
-        }
+        op_bif!(vm, context, &process, bif, args, fail, reg);
     },
     fn gc_bif2(fail: l, _live: r, bif: b, arg1: s, arg2: s, reg: d) {
         // TODO: GcBif needs to handle GC as necessary
         let args = &[#arg1, #arg2];
-        match bif(vm, &process, args) {
-            Ok(val) => context.set_register(reg, val),
-            Err(exc) => cond_fail!(context, fail, exc),
-        }
+        op_bif!(vm, context, &process, bif, args, fail, reg);
     },
     fn gc_bif3(fail: l, _live: r, bif: b, arg1: s, arg2: s, arg3: s, reg: d) {
         // TODO: GcBif needs to handle GC as necessary
         let args = &[#arg1, #arg2, #arg3];
-        match bif(vm, &process, args) {
-            Ok(val) => context.set_register(reg, val),
-            Err(exc) => cond_fail!(context, fail, exc),
-        }
+        op_bif!(vm, context, &process, bif, args, fail, reg);
     },
     // TODO unit needs to be u16
     fn bs_add(fail: l, size1: s, size2: s, unit: r, destination: d) {
@@ -36,7 +36,14 @@ impl InstrPtr {
     /// in all available information (including location in the
     /// source code).
     pub fn lookup_func_info(&self) -> Option<(MFA, Option<FuncInfo>)> {
+        // processes that haven't started running code yet
+        if self.module.is_null() {
+            return None;
+        }
         let module = unsafe { &(*self.module) };
+        if module.funs.is_empty() {
+            return None;
+        }
 
         let mut vec: Vec<(&(Atom, u32), &u32)> = module.funs.iter().collect();
         vec.sort_by(|(_, v1), (_, v2)| v1.cmp(v2));
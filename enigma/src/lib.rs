@@ -24,6 +24,7 @@ pub mod numeric;
 pub mod opcodes;
 pub mod persistent_term;
 pub mod port;
+pub mod posix;
 pub mod process;
 pub mod regex;
 pub mod servo_arc;
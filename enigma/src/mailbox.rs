@@ -64,4 +64,12 @@ impl Mailbox {
     pub fn len(&self) -> usize {
         self.queue.len()
     }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Term> {
+        self.queue.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut Term> {
+        self.queue.iter_mut()
+    }
 }
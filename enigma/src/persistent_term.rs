@@ -31,7 +31,8 @@ impl Table {
     }
 
     pub fn put(&self, key: Term, value: Term) {
-        self.map.write().insert(key, value.deep_clone(&self.heap)); // TODO: copy key too?
+        let mut map = self.map.write();
+        map.insert(key.deep_clone(&self.heap), value.deep_clone(&self.heap));
     }
 
     // pub fn info() -> Term {
@@ -87,6 +87,11 @@ impl Table {
         self.ports.get(&pid).map(|port| port.lock())
     }
 
+    /// Number of open ports.
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
     fn next_pid(&mut self) -> ID {
         let pid = self.next_pid;
 
@@ -101,43 +106,55 @@ impl Table {
     }
 }
 
+/// Opens a port. Malformed arguments raise badarg, drivers that can't be opened return the
+/// posix reason.
 pub fn spawn(
     vm: &Machine,
     owner: PID,
     args: Term,
     _opts: Term
-) -> Result<ID, Exception> {
+) -> Result<std::result::Result<ID, atom::Atom>, Exception> {
     let tup = Tuple::cast_from(&args)?;
+    let enotsup = || Ok(Err(atom::ENOTSUP));
 
-    // TODO: opts
-    let (port, input) = mpsc::unbounded::<Signal>();
-    // put the port (sender) in a ports table
-    let pid = vm.port_table.write().insert(owner, port);
+    enum Kind { Tty, Stderr }
 
-    match tup[0].into_variant() {
+    // pick the driver before registering the port, so a failed open doesn't leave one behind
+    let kind = match tup[0].into_variant() {
         Variant::Atom(atom::SPAWN) => {
             match tup[1].into_variant() {
-                Variant::Atom(atom::TTY_SL) => vm.runtime.executor().spawn(tty(pid, owner, input)),
+                Variant::Atom(atom::TTY_SL) => Kind::Tty,
                 Variant::Cons(..) => {
                     let cons = value::Cons::cast_from(&tup[1]).unwrap();
                     match value::cons::unicode_list_to_buf(cons, 2048).unwrap().as_ref() {
-                        "tty_sl -c -e" => vm.runtime.executor().spawn(tty(pid, owner, input)),
-                        _ => unimplemented!("port::spawn for {}", args),
-                    };
+                        "tty_sl -c -e" => Kind::Tty,
+                        // like a command that isn't on the path
+                        _ => return Ok(Err(atom::ENOENT)),
+                    }
                 }
-                _ => unimplemented!("port::spawn for {}", args),
+                _ => return enotsup(),
             }
         }
         Variant::Atom(atom::FD) => {
             match (tup[1].into_variant(), tup[2].into_variant()) {
-                (Variant::Integer(2), Variant::Integer(2)) => vm.runtime.executor().spawn(stderr(pid, owner, input)),
-                _ => unimplemented!("port::spawn for {}", args),
+                (Variant::Integer(2), Variant::Integer(2)) => Kind::Stderr,
+                _ => return enotsup(),
             }
         }
-        _ => unimplemented!("port::spawn for {}", args),
+        _ => return enotsup(),
     };
 
-    Ok(pid)
+    // TODO: opts
+    let (port, input) = mpsc::unbounded::<Signal>();
+    // put the port (sender) in a ports table
+    let pid = vm.port_table.write().insert(owner, port);
+
+    match kind {
+        Kind::Tty => vm.runtime.executor().spawn(tty(pid, owner, input)),
+        Kind::Stderr => vm.runtime.executor().spawn(stderr(pid, owner, input)),
+    };
+
+    Ok(Ok(pid))
 }
 
 pub fn send_message(
@@ -308,9 +325,6 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
 
     // need to disable echo and canon
 
-    // TODO FIXME: this heap will get trashed if tty shuts down
-    let heap = crate::immix::Heap::new();
-
     const TTYSL_DRV_CONTROL_MAGIC_NUMBER: usize = 0x018b_0900;
 
     let mut renderer = Renderer::new(out);
@@ -352,9 +366,12 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                                 // info!("putc_sync: bytes={:?}", &bytes[1..]);
                                 renderer.put_chars(&bytes[1..]);
 
+                                // built on its own heap, which the receiver takes over
+                                let heap = crate::immix::Heap::fragment();
                                 crate::process::send_signal(&Machine::current(), owner, crate::process::Signal::Message {
                                     from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::port(id), atom!(OK))
+                                    value: tup2!(&heap, Term::port(id), atom!(OK)),
+                                    heap
                                 });
                             }
                             n => unimplemented!("command {} for tty", n),
@@ -378,40 +395,53 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                         match opcode - TTYSL_DRV_CONTROL_MAGIC_NUMBER {
                             // WINSIZE
                             100 => {
-                                let (w, h) = terminal_size().unwrap();
-                                let w = u32::from(w).to_ne_bytes();
-                                let h = u32::from(h).to_ne_bytes();
-                                let bytes = &[w, h].concat();
-
-                                // basically bitstring!
-                                let mut list = Term::nil();
-                                for char in bytes.iter().copied().rev() {
-                                    list = cons!(&heap, Term::int(i32::from(char)), list);
-                                }
+                                let heap = crate::immix::Heap::fragment();
+                                let list = match terminal_size() {
+                                    Ok((w, h)) => {
+                                        let w = u32::from(w).to_ne_bytes();
+                                        let h = u32::from(h).to_ne_bytes();
+                                        let bytes = &[w, h].concat();
+
+                                        // basically bitstring!
+                                        let mut list = Term::nil();
+                                        for char in bytes.iter().copied().rev() {
+                                            list = cons!(&heap, Term::int(i32::from(char)), list);
+                                        }
+                                        list
+                                    }
+                                    Err(err) => crate::posix::error_tuple(&heap, err),
+                                };
 
                                 crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
                                     from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), list)
+                                    value: tup2!(&heap, Term::reference(&heap, reference), list),
+                                    heap
                                 });
                             },
                             // GET_UNICODE_STATE
                             101 => {
+                                let heap = crate::immix::Heap::fragment();
                                 crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
                                     from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
+                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil())),
+                                    heap
                                 });
                             },
                             // SET_UNICODE_STATE
                             102 => {
+                                let heap = crate::immix::Heap::fragment();
                                 crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
                                     from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil()))
+                                    value: tup2!(&heap, Term::reference(&heap, reference), cons!(&heap, Term::int(1), Term::nil())),
+                                    heap
                                 });
                             },
                             _ => {
+                                let heap = crate::immix::Heap::fragment();
                                 crate::process::send_signal(&Machine::current(), from, crate::process::Signal::Message {
                                     from: id, // TODO: this was supposed to be port id, not pid
-                                    value: tup2!(&heap, Term::reference(&heap, reference), atom!(BADARG))
+                                    value: tup2!(&heap, Term::reference(&heap, reference), atom!(BADARG)),
+                                    heap
                                 });
                                 break;
                             }
@@ -435,7 +465,17 @@ async fn tty(id: ID, owner: PID, input: mpsc::UnboundedReceiver<Signal>) {
                             value: bin
                         });
                     },
-                    Err(err) => panic!(err)
+                    Err(err) => {
+                        // tell the owner and stop the port instead of taking the whole VM down
+                        let heap = crate::immix::Heap::fragment();
+                        let error = crate::posix::error_tuple(&heap, err);
+                        crate::process::send_signal(&Machine::current(), owner, crate::process::Signal::Message {
+                            from: id, // TODO: this was supposed to be port id, not pid
+                            value: tup2!(&heap, Term::port(id), error),
+                            heap
+                        });
+                        break;
+                    }
                 }
                 // send {port, {:data, <bytes>}} back
             },
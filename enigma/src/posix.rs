@@ -0,0 +1,212 @@
+//! POSIX error codes, shared by everything that reports OS errors to Erlang code (prim_file,
+//! ports). Like `erl_errno_id`, errors are reported as the lowercase errno name: `enoent`,
+//! `eacces`, ... and `unknown` for anything without a name.
+use crate::atom::{self, Atom};
+use crate::exception::{Exception, Reason};
+use crate::immix::Heap;
+use crate::value::Term;
+use std::io;
+
+/// Returns the name of an errno value.
+pub fn errno_id(errno: i32) -> &'static str {
+    match errno {
+        libc::E2BIG => "e2big",
+        libc::EACCES => "eacces",
+        libc::EADDRINUSE => "eaddrinuse",
+        libc::EADDRNOTAVAIL => "eaddrnotavail",
+        libc::EAFNOSUPPORT => "eafnosupport",
+        libc::EAGAIN => "eagain",
+        libc::EALREADY => "ealready",
+        libc::EBADF => "ebadf",
+        libc::EBADMSG => "ebadmsg",
+        libc::EBUSY => "ebusy",
+        libc::ECANCELED => "ecanceled",
+        libc::ECHILD => "echild",
+        libc::ECONNABORTED => "econnaborted",
+        libc::ECONNREFUSED => "econnrefused",
+        libc::ECONNRESET => "econnreset",
+        libc::EDEADLK => "edeadlk",
+        libc::EDESTADDRREQ => "edestaddrreq",
+        libc::EDOM => "edom",
+        libc::EDQUOT => "edquot",
+        libc::EEXIST => "eexist",
+        libc::EFAULT => "efault",
+        libc::EFBIG => "efbig",
+        libc::EHOSTDOWN => "ehostdown",
+        libc::EHOSTUNREACH => "ehostunreach",
+        libc::EIDRM => "eidrm",
+        libc::EILSEQ => "eilseq",
+        libc::EINPROGRESS => "einprogress",
+        libc::EINTR => "eintr",
+        libc::EINVAL => "einval",
+        libc::EIO => "eio",
+        libc::EISCONN => "eisconn",
+        libc::EISDIR => "eisdir",
+        libc::ELOOP => "eloop",
+        libc::EMFILE => "emfile",
+        libc::EMLINK => "emlink",
+        libc::EMSGSIZE => "emsgsize",
+        libc::EMULTIHOP => "emultihop",
+        libc::ENAMETOOLONG => "enametoolong",
+        libc::ENETDOWN => "enetdown",
+        libc::ENETRESET => "enetreset",
+        libc::ENETUNREACH => "enetunreach",
+        libc::ENFILE => "enfile",
+        libc::ENOBUFS => "enobufs",
+        libc::ENODATA => "enodata",
+        libc::ENODEV => "enodev",
+        libc::ENOENT => "enoent",
+        libc::ENOEXEC => "enoexec",
+        libc::ENOLCK => "enolck",
+        libc::ENOLINK => "enolink",
+        libc::ENOMEM => "enomem",
+        libc::ENOMSG => "enomsg",
+        libc::ENOPROTOOPT => "enoprotoopt",
+        libc::ENOSPC => "enospc",
+        libc::ENOSR => "enosr",
+        libc::ENOSTR => "enostr",
+        libc::ENOSYS => "enosys",
+        libc::ENOTBLK => "enotblk",
+        libc::ENOTCONN => "enotconn",
+        libc::ENOTDIR => "enotdir",
+        libc::ENOTEMPTY => "enotempty",
+        libc::ENOTRECOVERABLE => "enotrecoverable",
+        libc::ENOTSOCK => "enotsock",
+        libc::ENOTTY => "enotty",
+        libc::ENXIO => "enxio",
+        libc::EOPNOTSUPP => "eopnotsupp",
+        libc::EOVERFLOW => "eoverflow",
+        libc::EOWNERDEAD => "eownerdead",
+        libc::EPERM => "eperm",
+        libc::EPFNOSUPPORT => "epfnosupport",
+        libc::EPIPE => "epipe",
+        libc::EPROTO => "eproto",
+        libc::EPROTONOSUPPORT => "eprotonosupport",
+        libc::EPROTOTYPE => "eprototype",
+        libc::ERANGE => "erange",
+        libc::EREMOTE => "eremote",
+        libc::EROFS => "erofs",
+        libc::ESHUTDOWN => "eshutdown",
+        libc::ESOCKTNOSUPPORT => "esocktnosupport",
+        libc::ESPIPE => "espipe",
+        libc::ESRCH => "esrch",
+        libc::ESTALE => "estale",
+        libc::ETIME => "etime",
+        libc::ETIMEDOUT => "etimedout",
+        libc::ETOOMANYREFS => "etoomanyrefs",
+        libc::ETXTBSY => "etxtbsy",
+        libc::EUSERS => "eusers",
+        libc::EXDEV => "exdev",
+        // aliases on linux, distinct on some other platforms
+        errno if errno == libc::EWOULDBLOCK => "ewouldblock",
+        errno if errno == libc::ENOTSUP => "enotsup",
+        _ => platform_errno_id(errno),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn platform_errno_id(errno: i32) -> &'static str {
+    match errno {
+        libc::EADV => "eadv",
+        libc::EBADE => "ebade",
+        libc::EBADFD => "ebadfd",
+        libc::EBADR => "ebadr",
+        libc::EBADRQC => "ebadrqc",
+        libc::EBADSLT => "ebadslt",
+        libc::EBFONT => "ebfont",
+        libc::ECHRNG => "echrng",
+        libc::ECOMM => "ecomm",
+        libc::EDOTDOT => "edotdot",
+        libc::EISNAM => "eisnam",
+        libc::EL2HLT => "el2hlt",
+        libc::EL2NSYNC => "el2nsync",
+        libc::EL3HLT => "el3hlt",
+        libc::EL3RST => "el3rst",
+        libc::ELIBACC => "elibacc",
+        libc::ELIBBAD => "elibbad",
+        libc::ELIBEXEC => "elibexec",
+        libc::ELIBMAX => "elibmax",
+        libc::ELIBSCN => "elibscn",
+        libc::ELNRNG => "elnrng",
+        libc::ENAVAIL => "enavail",
+        libc::ENOANO => "enoano",
+        libc::ENOCSI => "enocsi",
+        libc::ENONET => "enonet",
+        libc::ENOPKG => "enopkg",
+        libc::ENOTNAM => "enotnam",
+        libc::ENOTUNIQ => "enotuniq",
+        libc::EREMCHG => "eremchg",
+        libc::EREMOTEIO => "eremoteio",
+        libc::ERESTART => "erestart",
+        libc::ESRMNT => "esrmnt",
+        libc::ESTRPIPE => "estrpipe",
+        libc::EUCLEAN => "euclean",
+        libc::EUNATCH => "eunatch",
+        libc::EXFULL => "exfull",
+        _ => "unknown",
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn platform_errno_id(_errno: i32) -> &'static str {
+    "unknown"
+}
+
+/// Returns the posix atom for an I/O error. Errors that didn't come from the OS are mapped by
+/// their kind.
+pub fn error_atom(error: &io::Error) -> Atom {
+    use io::ErrorKind;
+    if let Some(errno) = error.raw_os_error() {
+        return Atom::from(errno_id(errno));
+    }
+    let errno = match error.kind() {
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::PermissionDenied => libc::EACCES,
+        ErrorKind::ConnectionRefused => libc::ECONNREFUSED,
+        ErrorKind::ConnectionReset => libc::ECONNRESET,
+        ErrorKind::ConnectionAborted => libc::ECONNABORTED,
+        ErrorKind::NotConnected => libc::ENOTCONN,
+        ErrorKind::AddrInUse => libc::EADDRINUSE,
+        ErrorKind::AddrNotAvailable => libc::EADDRNOTAVAIL,
+        ErrorKind::BrokenPipe => libc::EPIPE,
+        ErrorKind::AlreadyExists => libc::EEXIST,
+        ErrorKind::WouldBlock => libc::EAGAIN,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => libc::EINVAL,
+        ErrorKind::TimedOut => libc::ETIMEDOUT,
+        ErrorKind::WriteZero => libc::ENOSPC,
+        ErrorKind::Interrupted => libc::EINTR,
+        ErrorKind::UnexpectedEof => return atom::EOF,
+        _ => return Atom::from("unknown"),
+    };
+    Atom::from(errno_id(errno))
+}
+
+/// Returns `{error, Posix}`.
+pub fn error_tuple(heap: &Heap, error: io::Error) -> Term {
+    tup2!(heap, atom!(ERROR), Term::atom(error_atom(&error)))
+}
+
+/// Raises `error:Posix`, like a failing `open_port/2`.
+impl From<io::Error> for Exception {
+    fn from(error: io::Error) -> Self {
+        Exception::with_value(Reason::EXC_ERROR, Term::atom(error_atom(&error)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno_id() {
+        assert_eq!(errno_id(libc::ENOENT), "enoent");
+        assert_eq!(errno_id(libc::EACCES), "eacces");
+        assert_eq!(errno_id(libc::EAGAIN), "eagain");
+        assert_eq!(errno_id(-1), "unknown");
+
+        let error = io::Error::from_raw_os_error(libc::EISDIR);
+        assert_eq!(error_atom(&error), Atom::from("eisdir"));
+        let error = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(error_atom(&error), Atom::from("eacces"));
+    }
+}
@@ -1,6 +1,7 @@
 pub use self::table::PID;
 use crate::atom::{self, Atom};
 use crate::bitstring;
+use crate::ets;
 use crate::exception::{Exception, Reason};
 use crate::immix::Heap;
 use crate::instruction::Ptr;
@@ -20,6 +21,7 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use parking_lot::Mutex;
 use tokio::prelude::*;
 
 use futures::prelude::*;
@@ -36,6 +38,9 @@ pub type Ref = usize;
 
 /// Maximum amount of X registers.
 pub const MAX_REG: usize = 1024;
+
+/// Reductions a process can use before it has to yield.
+pub const CONTEXT_REDS: usize = 2000;
 // pub const MAX_REG: usize = 255;
 
 bitflags! {
@@ -225,6 +230,32 @@ pub struct LocalData {
 
     /// A [process dictionary](https://www.erlang.org/course/advanced#dict)
     pub dictionary: HashMap<Term, Term>,
+
+    /// Processes suspended by this process, with the number of times each was suspended.
+    pub suspending: HashMap<PID, usize>,
+
+    /// Set by `erlang:hibernate/3`, the process waits for a message before resuming.
+    pub hibernating: bool,
+
+    /// Offloaded work the process is suspended on, see `bif::blocking`.
+    pub blocking: Option<crate::bif::blocking::Job>,
+
+    /// Where the result of the offloaded work goes, set by the instruction that trapped.
+    pub blocking_return: crate::bif::blocking::Return,
+
+    /// The rest of a BIF that trapped, with the state it saved, see `bif::blocking::trap`.
+    pub trap: Option<crate::bif::blocking::Resume>,
+}
+
+/// Suspension state of a process, shared with other processes calling
+/// `erlang:suspend_process/2` and `erlang:resume_process/1`.
+#[derive(Default)]
+pub struct Suspension {
+    /// How many times the process has been suspended. Runs only when zero.
+    count: usize,
+
+    /// Wakes the process once the count drops back to zero.
+    resume: Option<futures::channel::oneshot::Sender<()>>,
 }
 
 pub struct Process {
@@ -237,6 +268,9 @@ pub struct Process {
 
     /// If the process is waiting for a message.
     pub waiting_for_message: AtomicBool,
+
+    /// Suspend counter, see `erlang:suspend_process/2`.
+    pub suspension: Mutex<Suspension>,
 }
 
 unsafe impl Sync for LocalData {}
@@ -272,12 +306,18 @@ impl Process {
             mailbox: Mailbox::new(),
             thread_id: None,
             dictionary: HashMap::new(),
+            suspending: HashMap::new(),
+            hibernating: false,
+            blocking: None,
+            blocking_return: crate::bif::blocking::Return::X0,
+            trap: None,
         };
 
         Arc::pin(Process {
             pid,
             local_data: UnsafeCell::new(local_data),
             waiting_for_message: AtomicBool::new(false),
+            suspension: Mutex::new(Suspension::default()),
         })
     }
 
@@ -334,10 +374,7 @@ impl Process {
         } else {
             self.local_data_mut()
                 .signal_queue
-                .send_external(Signal::Message {
-                    value: message,
-                    from,
-                });
+                .send_external(Signal::message(from, message));
         }
         self.wake_up()
     }
@@ -362,6 +399,116 @@ impl Process {
         // TODO: pass through the chan.send result ret
     }
 
+    pub fn is_suspended(&self) -> bool {
+        self.suspension.lock().count > 0
+    }
+
+    /// Increments the suspend count. The process stops executing at its next
+    /// scheduling point.
+    pub fn suspend(&self) {
+        self.suspension.lock().count += 1;
+    }
+
+    /// Decrements the suspend count, resuming the process when it reaches zero.
+    pub fn resume(&self) {
+        let mut suspension = self.suspension.lock();
+        suspension.count = suspension.count.saturating_sub(1);
+        if suspension.count == 0 {
+            if let Some(chan) = suspension.resume.take() {
+                let _ = chan.send(());
+            }
+        }
+    }
+
+    /// Waits until all suspensions have been lifted. Signals are still handled
+    /// while suspended, so an Err means the process was killed in the meantime.
+    pub async fn wait_while_suspended(&self) -> Result<(), Exception> {
+        loop {
+            self.process_incoming()?;
+
+            let resumed = {
+                let mut suspension = self.suspension.lock();
+                if suspension.count == 0 {
+                    return Ok(());
+                }
+                let (trigger, receiver) = futures::channel::oneshot::channel::<()>();
+                suspension.resume = Some(trigger);
+                receiver
+            };
+
+            match self.context_mut().recv_channel.take() {
+                Some(signal) => {
+                    let _ = futures::future::select(resumed, signal).await;
+                }
+                None => {
+                    let _ = resumed.await;
+                }
+            }
+            // the receive channel was consumed, make process_incoming set up a new one
+            self.context_mut().timeout.take();
+        }
+    }
+
+    /// Discards the call stack and compacts the live data (`x[..live]`, the mailbox and the
+    /// dictionary) into a new, minimal heap ahead of `erlang:hibernate/3`. The process resumes
+    /// once a message arrives.
+    pub fn hibernate(&self) {
+        let context = self.context_mut();
+        let local_data = self.local_data_mut();
+        context.stack = Vec::new();
+        context.callstack = Vec::new();
+        context.catches = 0;
+        context.cp = None;
+        context.exc = None;
+
+        let heap = Heap::new();
+        let live = context.live;
+        for reg in context.x[..live].iter_mut() {
+            *reg = reg.deep_clone(&heap);
+        }
+        for reg in context.x[live..].iter_mut() {
+            *reg = Term::nil();
+        }
+        for message in local_data.mailbox.iter_mut() {
+            *message = message.deep_clone(&heap);
+        }
+        local_data.dictionary = local_data
+            .dictionary
+            .drain()
+            .map(|(key, value)| (key.deep_clone(&heap), value.deep_clone(&heap)))
+            .collect();
+        // Everything that leaves the process (messages, exit reasons, spawn arguments, table
+        // data) is copied, so nothing else points into the old heap.
+        let old = std::mem::replace(&mut context.heap, heap);
+        unsafe { old.free() };
+
+        local_data.hibernating = true;
+    }
+
+    /// Waits for a new message (or signal) to arrive, used to wake up from hibernation.
+    pub async fn wait_for_message(&self) -> Result<(), Exception> {
+        self.local_data_mut().hibernating = false;
+        loop {
+            self.process_incoming()?;
+            if self.local_data().mailbox.has_messages() {
+                break;
+            }
+            // other signals wake us up too, keep waiting until a message arrives
+            match self.context_mut().recv_channel.take() {
+                Some(cancel) => {
+                    self.set_waiting_for_message(true);
+                    let _ = cancel.await;
+                }
+                None => {
+                    // the channel was used up, make process_incoming set up a new one
+                    self.context_mut().timeout.take();
+                }
+            }
+        }
+        // a message wakes the process up, but it can't run until it's resumed
+        self.wait_while_suspended().await
+    }
+
     pub fn set_waiting_for_message(&self, value: bool) {
         self.waiting_for_message.store(value, Ordering::Relaxed)
     }
@@ -381,7 +528,8 @@ impl Process {
         // get internal, if we ran out, start processing external
         while let Some(signal) = self.local_data_mut().signal_queue.receive() {
             match signal {
-                Signal::Message { value, .. } => {
+                Signal::Message { value, heap, .. } => {
+                    self.context_mut().heap.absorb(heap);
                     self.local_data_mut().mailbox.send(value);
                 }
                 Signal::PortMessage { from, value, .. } => {
@@ -401,6 +549,24 @@ impl Process {
                 Signal::Exit { .. } => {
                     self.handle_exit_signal(signal)?;
                 }
+                Signal::EtsTransfer {
+                    from,
+                    table,
+                    data,
+                    heap,
+                } => {
+                    // {'ETS-TRANSFER', Tab, FromPid, HeirData}
+                    self.context_mut().heap.absorb(heap);
+                    let heap = &self.context_mut().heap;
+                    let msg = tup!(
+                        heap,
+                        atom!(ETS_TRANSFER),
+                        table.meta().id(heap),
+                        Term::pid(from),
+                        data
+                    );
+                    self.local_data_mut().mailbox.send(msg);
+                }
                 Signal::Link { from } => {
                     self.local_data_mut().links.insert(from);
                 }
@@ -424,6 +590,9 @@ impl Process {
                         self.local_data_mut().lt_monitors.remove(pos);
                     }
                 }
+                Signal::ProcessInfo { request, .. } => {
+                    crate::bif::info::reply(self, request);
+                }
             }
         }
         Ok(())
@@ -456,7 +625,14 @@ impl Process {
         // this is extremely awkward, wish we could enforce a signal variant on the function signature
         // we're also technically matching twice since process_incoming also pattern matches.
         // TODO: inline?
-        if let Signal::Exit { kind, from, reason } = signal {
+        if let Signal::Exit {
+            kind,
+            from,
+            reason,
+            heap,
+        } = signal
+        {
+            self.context_mut().heap.absorb(heap);
             let mut reason = reason.value;
             let local_data = self.local_data_mut();
 
@@ -468,7 +644,11 @@ impl Process {
                 }
             }
 
-            if reason != atom!(KILL) && local_data.flags.contains(Flag::TRAP_EXIT) {
+            // exit(Pid, kill) can't be trapped. A `kill` reason received through a link
+            // is a regular exit signal though.
+            let untrappable = kind == ExitKind::Exit && reason == atom!(KILL);
+
+            if !untrappable && local_data.flags.contains(Flag::TRAP_EXIT) {
                 // if reason is immed, create an EXIT message tuple instead and replace
                 // (push to internal msg queue as message)
                 let msg = tup3!(
@@ -481,26 +661,22 @@ impl Process {
                 // erts_proc_notify_new_message(c_p, ERTS_PROC_LOCK_MAIN);
                 local_data.mailbox.send(msg);
                 Ok(())
-            } else if reason == atom!(NORMAL)
-            /*&& xsigd.u.normal_kills */
-            {
-                /* TODO: for exit/2, exit_signal/2 implement normal kills
+            } else if reason == atom!(NORMAL) && !(kind == ExitKind::Exit && from == self.pid) {
+                /*
                  * Preserve the very old and *very strange* behaviour
                  * of erlang:exit/2...
                  *
                  * - terminate ourselves even though exit reason
                  *   is normal (unless we trap exit)
                  * - terminate ourselves before exit/2 return
+                 *
+                 * Any other process ignores a normal exit signal.
                  */
-
-                // ignore
                 Ok(())
             } else {
                 // terminate
                 // save = true;
-                if
-                /*op == ERTS_SIG_Q_OP_EXIT && */
-                reason == atom!(KILL) {
+                if untrappable {
                     reason = atom!(KILLED);
                 }
 
@@ -541,15 +717,20 @@ impl Process {
             vm.process_registry.lock().unregister(name);
         }
 
+        // delete owned ETS tables, or hand them over to their heir
+        ets::owner_exited(vm, self.pid);
+
+        // resume any processes we've suspended
+        for (pid, count) in local_data.suspending.drain() {
+            if let Some(process) = vm.process_table.lock().get(pid) {
+                (0..count).for_each(|_| process.resume());
+            }
+        }
+
         // delete links
         for pid in local_data.links.drain() {
-            // TODO: reason has to be deep cloned, make a constructor
             // println!("pid={} sending exit signal to from={}", self.pid, pid);
-            let msg = Signal::Exit {
-                reason: reason.clone(),
-                from: self.pid,
-                kind: ExitKind::ExitLinked,
-            };
+            let msg = Signal::exit(self.pid, &reason, ExitKind::ExitLinked);
             self::send_signal(vm, pid, msg);
             // erts_proc_sig_send_link_exit(c_p, c_p->common.id, lnk, reason, SEQ_TRACE_TOKEN(c_p));
         }
@@ -628,8 +809,8 @@ pub fn spawn(
     let context = new_proc.context_mut();
     let mut ret = Term::pid(new_proc.pid);
 
-    // Set the arglist into process registers.
-    // TODO: it also needs to deep clone all the vals (for example lists etc)
+    // Set the arglist into process registers, copied onto the new process' heap.
+    let args = args.deep_clone(&context.heap);
     let mut i = 0;
     let mut cons = &args;
     while let Ok(value::Cons { head, tail }) = cons.cast_into() {
@@ -724,3 +905,118 @@ pub fn send_signal(vm: &Machine, pid: PID, signal: Signal) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::Poll;
+
+    #[test]
+    fn test_hibernate() {
+        let vm = Machine::new();
+        let process = allocate(&vm, 0, 0, std::ptr::null()).unwrap();
+        let context = process.context_mut();
+        let heap = &context.heap;
+        for i in 0..10_000 {
+            tup2!(heap, Term::int(i), Term::nil());
+        }
+        context.x[0] = tup2!(heap, atom!(OK), cons!(heap, Term::int(1), Term::nil()));
+        context.x[1] = tup2!(heap, atom!(ERROR), Term::nil());
+        context.live = 1;
+        context.stack.push(Term::int(1));
+        process.send_message(process.pid, tup2!(heap, atom!(DATA), Term::int(2)));
+        process
+            .local_data_mut()
+            .dictionary
+            .insert(atom!(TRUE), tup2!(heap, atom!(VALUE), Term::int(3)));
+        let size = heap.size();
+
+        process.hibernate();
+
+        let context = process.context_mut();
+        let heap = &context.heap;
+        assert!(heap.size() < size / 100);
+        assert!(context.stack.is_empty());
+        assert_eq!(
+            context.x[0],
+            tup2!(heap, atom!(OK), cons!(heap, Term::int(1), Term::nil()))
+        );
+        assert!(context.x[1].is_nil());
+        let local_data = process.local_data();
+        assert!(local_data.hibernating);
+        assert_eq!(
+            local_data.mailbox.iter().next(),
+            Some(&tup2!(heap, atom!(DATA), Term::int(2)))
+        );
+        assert_eq!(
+            local_data.dictionary.get(&atom!(TRUE)),
+            Some(&tup2!(heap, atom!(VALUE), Term::int(3)))
+        );
+    }
+
+    #[test]
+    fn test_hibernate_wake_up() {
+        let vm = Machine::new();
+        let process = allocate(&vm, 0, 0, std::ptr::null()).unwrap();
+        process.hibernate();
+
+        futures::executor::block_on(async {
+            let mut wait = Box::pin(process.wait_for_message());
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+
+            // other signals don't wake the process up
+            process.send_signal(Signal::Link {
+                from: process.pid + 1,
+            });
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+
+            process.send_message(process.pid + 1, Term::int(1));
+            assert_eq!(futures::poll!(wait.as_mut()), Poll::Ready(Ok(())));
+        });
+        assert!(!process.local_data().hibernating);
+        assert_eq!(process.local_data().mailbox.len(), 1);
+    }
+
+    #[test]
+    fn test_suspend_while_waiting_for_message() {
+        let vm = Machine::new();
+        let process = allocate(&vm, 0, 0, std::ptr::null()).unwrap();
+
+        futures::executor::block_on(async {
+            let mut wait = Box::pin(process.wait_for_message());
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+
+            process.suspend();
+            process.send_message(process.pid + 1, Term::int(1));
+            // the message arrived, but the process stays suspended
+            assert!(futures::poll!(wait.as_mut()).is_pending());
+            assert_eq!(process.local_data().mailbox.len(), 1);
+
+            process.resume();
+            assert_eq!(futures::poll!(wait.as_mut()), Poll::Ready(Ok(())));
+        });
+    }
+
+    #[test]
+    fn test_send_message_copies() {
+        let vm = Machine::new();
+        let sender = allocate(&vm, 0, 0, std::ptr::null()).unwrap();
+        let process = allocate(&vm, 0, 0, std::ptr::null()).unwrap();
+        let heap = &sender.context_mut().heap;
+        let message = tup2!(heap, atom!(OK), cons!(heap, Term::int(1), Term::nil()));
+        let capacity = process.context().heap.capacity();
+
+        process.send_message(sender.pid, message);
+        process.process_incoming().unwrap();
+
+        let received = process.local_data_mut().mailbox.receive().unwrap();
+        assert_eq!(received, message);
+        // the copy lives on the receiver's heap, so the sender's heap can go away
+        let address = |term: Term| match term.into_variant() {
+            value::Variant::Pointer(ptr) => ptr as usize,
+            _ => unreachable!(),
+        };
+        assert_ne!(address(received), address(message));
+        assert!(process.context().heap.capacity() > capacity);
+    }
+}
@@ -20,16 +20,19 @@
 //!
 //! ## Recycling
 //!
-//! PIDs are recycled once a certain number of PIDs has been used. The exact
-//! amount of PIDs that can be used before recycling depends on the system
-//! architecture. In most cases PID recycling will only occur very rarely, if
-//! ever at all.
+//! Like on BEAM, a PID is a monotonically increasing counter. The low bits of
+//! the counter select a slot in the table, the remaining bits act as a serial
+//! number. Slots are reused once their process exits, but the serial number
+//! will differ, so a stale PID never addresses a newer process that happens to
+//! occupy the same slot.
+//!
+//! The number and serial are exposed as `<0.Number.Serial>` when a PID is
+//! printed or sent over the external term format.
 //!
 //! ## PID Availability
 //!
-//! It's possible (though very unlikely) for a Table to run out of
-//! available PIDs. This can happen when many processes are added and kept
-//! around. Callers should ensure they can handle such a scenario.
+//! A Table holds at most `limit` processes (`+P`). Once the limit is reached,
+//! `reserve` returns None and callers are expected to raise `system_limit`.
 use hashbrown::HashMap;
 use std::u32;
 
@@ -39,90 +42,176 @@ pub type PID = u32;
 /// The maximum PID value.
 pub const MAX_PID: PID = u32::MAX;
 
-#[derive(Debug, Default)]
+/// Default maximum amount of processes (same as BEAM).
+pub const DEFAULT_PROCESS_LIMIT: usize = 262_144;
+
+/// Smallest accepted process limit.
+pub const MIN_PROCESS_LIMIT: usize = 1024;
+
+/// Largest accepted process limit.
+pub const MAX_PROCESS_LIMIT: usize = 134_217_727;
+
+/// Number of PID bits shown as the process number, the rest is the serial.
+const NUMBER_BITS: u32 = 15;
+
+/// The `N` in `<0.N.S>`.
+#[inline]
+pub fn pid_number(pid: PID) -> u32 {
+    pid & ((1 << NUMBER_BITS) - 1)
+}
+
+/// The `S` in `<0.N.S>`.
+#[inline]
+pub fn pid_serial(pid: PID) -> u32 {
+    pid >> NUMBER_BITS
+}
+
+/// Reassemble a PID from its `<0.N.S>` parts. Returns None if they're out of range.
+pub fn make_pid(number: u32, serial: u32) -> Option<PID> {
+    if number >= (1 << NUMBER_BITS) || serial >= (1 << (32 - NUMBER_BITS)) {
+        return None;
+    }
+    Some((serial << NUMBER_BITS) | number)
+}
+
+/// Like `make_pid`, but truncates out of range parts instead of failing.
+/// Used for PIDs decoded from the external term format.
+pub fn make_pid_truncated(number: u32, serial: u32) -> PID {
+    (serial << NUMBER_BITS) | pid_number(number)
+}
+
+#[derive(Debug)]
 pub struct Table<T: Clone> {
-    /// The PID to use for the next process.
+    /// The counter used to derive the next PID.
     next_pid: PID,
 
-    /// When set to true, previously used PIDs may be recycled.
-    recycle: bool,
+    /// Maximum amount of processes.
+    limit: usize,
+
+    /// Number of slots, always a power of two larger or equal to `limit`.
+    size: usize,
 
-    /// PIDs of existing processes, and their corresponding processes.
+    /// Existing processes, indexed by slot. Each slot stores the full PID so
+    /// that stale PIDs can be detected.
     ///
     /// An entry's value may be set to None, indicating that the PID has been
     /// reserved but a process has yet to be inserted.
-    processes: HashMap<PID, Option<T>>,
+    processes: HashMap<usize, (PID, Option<T>)>,
+}
+
+impl<T: Clone> Default for Table<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T: Clone> Table<T> {
     pub fn new() -> Self {
-        Table {
+        Self::with_limit(DEFAULT_PROCESS_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        let mut table = Table {
             next_pid: 0,
-            recycle: false,
+            limit: 0,
+            size: 0,
             processes: HashMap::new(),
-        }
+        };
+        table.set_limit(limit);
+        table
+    }
+
+    /// Changes the maximum amount of processes (`+P`). The value is clamped to
+    /// the range BEAM accepts, and can't be lowered below the current size.
+    pub fn set_limit(&mut self, limit: usize) {
+        let limit = limit.max(MIN_PROCESS_LIMIT).min(MAX_PROCESS_LIMIT);
+        // the slot size can only grow, otherwise live PIDs would map to other slots.
+        self.limit = limit.max(self.processes.len());
+        self.size = self.size.max(self.limit.next_power_of_two());
     }
 
     /// Reserves a new PID.
     ///
-    /// If no PID could be reserved a None value is returned.
+    /// If the process limit was reached, a None value is returned.
     pub fn reserve(&mut self) -> Option<PID> {
-        while (self.processes.len() as u32) < MAX_PID {
+        if self.processes.len() >= self.limit {
+            return None;
+        }
+
+        // there's at least one free slot, since len < limit <= size.
+        loop {
             let pid = self.next_pid();
+            let slot = self.slot(pid);
 
-            if self.recycle && self.processes.contains_key(&pid) {
+            if self.processes.contains_key(&slot) {
                 continue;
             }
 
-            self.processes.insert(pid, None);
+            self.processes.insert(slot, (pid, None));
 
             return Some(pid);
         }
-
-        None
     }
 
     /// Maps a process to the given PID.
     pub fn map(&mut self, pid: PID, process: T) {
-        self.processes.insert(pid, Some(process));
+        let slot = self.slot(pid);
+        self.processes.insert(slot, (pid, Some(process)));
     }
 
     /// Releases a PID.
     pub fn release(&mut self, pid: PID) {
-        self.processes.remove(&pid);
+        let slot = self.slot(pid);
+        if self.processes.get(&slot).map(|(p, _)| *p) == Some(pid) {
+            self.processes.remove(&slot);
+        }
     }
 
     /// Returns the process for a given PID.
     pub fn get(&self, pid: PID) -> Option<T> {
-        if let Some(slot) = self.processes.get(&pid) {
-            match *slot {
-                Some(ref process) => Some(process.clone()),
-                None => None,
-            }
-        } else {
-            None
+        match self.processes.get(&self.slot(pid)) {
+            Some((p, Some(process))) if *p == pid => Some(process.clone()),
+            _ => None,
         }
     }
 
+    /// Iterates over all mapped processes.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.processes
+            .values()
+            .filter_map(|(_, slot)| slot.clone())
+    }
+
+    /// Returns the maximum number of processes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
     pub fn all(&self) -> Vec<PID> {
-        self.processes.keys().copied().collect()
+        self.processes.values().map(|(pid, _)| *pid).collect()
+    }
+
+    /// Returns the number of reserved PIDs.
+    pub fn len(&self) -> usize {
+        self.processes.len()
     }
 
     /// Returns true if the process exists.
     pub fn contains_key(&self, pid: PID) -> bool {
-        self.processes.contains_key(&pid)
+        match self.processes.get(&self.slot(pid)) {
+            Some((p, _)) => *p == pid,
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn slot(&self, pid: PID) -> usize {
+        pid as usize & (self.size - 1)
     }
 
     fn next_pid(&mut self) -> PID {
         let pid = self.next_pid;
-
-        if pid == MAX_PID {
-            self.next_pid = 0;
-            self.recycle = true;
-        } else {
-            self.next_pid += 1;
-        }
-
+        self.next_pid = self.next_pid.wrapping_add(1);
         pid
     }
 }
@@ -136,7 +225,7 @@ mod tests {
         let table = Table::<()>::new();
 
         assert_eq!(table.next_pid, 0);
-        assert_eq!(table.recycle, false);
+        assert_eq!(table.limit, DEFAULT_PROCESS_LIMIT);
         assert_eq!(table.processes.len(), 0);
     }
 
@@ -156,15 +245,63 @@ mod tests {
 
     #[test]
     fn test_reserve_with_recycle() {
-        let mut table = Table::<()>::new();
+        let mut table = Table::<()>::with_limit(MIN_PROCESS_LIMIT);
+        let size = table.size as u32;
 
-        table.reserve();
-        table.next_pid = 0;
-        table.recycle = true;
+        let pid = table.reserve().unwrap();
+        table.next_pid = size;
 
-        let pid2 = table.reserve();
+        // slot 0 is still taken, so the next free slot is used
+        let pid2 = table.reserve().unwrap();
+        assert_eq!(pid2, size + 1);
 
-        assert_eq!(pid2.unwrap(), 1);
+        // once released, the slot is reused with a new serial
+        table.release(pid);
+        table.next_pid = size * 2;
+        let pid3 = table.reserve().unwrap();
+        assert_eq!(pid3, size * 2);
+        assert!(!table.contains_key(pid));
+    }
+
+    #[test]
+    fn test_reserve_limit() {
+        let mut table = Table::<()>::with_limit(MIN_PROCESS_LIMIT);
+
+        for _ in 0..MIN_PROCESS_LIMIT {
+            assert!(table.reserve().is_some());
+        }
+        assert!(table.reserve().is_none());
+
+        table.release(0);
+        assert!(table.reserve().is_some());
+    }
+
+    #[test]
+    fn test_stale_pid() {
+        let mut table = Table::with_limit(MIN_PROCESS_LIMIT);
+        let pid = table.reserve().unwrap();
+        table.map(pid, 10);
+        table.release(pid);
+
+        table.next_pid = table.size as u32;
+        let pid2 = table.reserve().unwrap();
+        table.map(pid2, 20);
+
+        assert_eq!(table.slot(pid), table.slot(pid2));
+        assert!(table.get(pid).is_none());
+        assert_eq!(table.get(pid2).unwrap(), 20);
+
+        // releasing a stale pid doesn't touch the new process
+        table.release(pid);
+        assert_eq!(table.get(pid2).unwrap(), 20);
+    }
+
+    #[test]
+    fn test_pid_parts() {
+        let pid = make_pid(42, 3).unwrap();
+        assert_eq!(pid_number(pid), 42);
+        assert_eq!(pid_serial(pid), 3);
+        assert!(make_pid(1 << NUMBER_BITS, 0).is_none());
     }
 
     #[test]
@@ -1,20 +1,728 @@
-//use crate::servo_arc::Arc;
-use crate::value::{Cons, Variant};
-use crate::{atom, bitstring};
+//! Perl compatible regular expressions for the `re` module.
+//!
+//! Patterns are parsed by `syntax` and compiled for the backtracking matcher in `backtrack`. When
+//! a pattern has an equivalent in `regex` crate syntax it is translated as well, and matching
+//! uses the `regex` crate whenever the options allow it.
+//!
+//! Matching is charged to the calling process' reductions. When they run out the search is saved
+//! in the process, which traps, and continues in its next slice, from the middle of a match
+//! attempt if need be.
+use crate::atom;
+use crate::bif::{blocking, erlang::list_to_iodata};
+use crate::bitstring;
+use crate::exception::Exception;
+use crate::immix::Heap;
+use crate::process::RcProcess;
+use crate::value::{self, CastFrom, Cons, Term, Tuple, Variant};
+use backtrack::{Attempt, Budget, Stop};
 use regex::bytes::{Regex, RegexBuilder};
+use std::sync::Arc;
 
-// pub mod error;
-// use std::error::Error;
-// pub use error::Result;
+mod backtrack;
+mod syntax;
+
+use syntax::Newline;
+
+/// Matching steps per reduction, PCRE's loops for the backtracker and bytes scanned for the
+/// `regex` crate.
+const LOOP_FACTOR: usize = 10;
+
+/// Capture slots of a match, a start and end per group, with the whole match first.
+type Slots = Vec<Option<usize>>;
+
+/// A compiled pattern, the last element of the `re_pattern` tuple.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    program: Arc<backtrack::Program>,
+    regex: Option<Regex>,
+    /// The `regex` translation is only equivalent on subjects not ending with a newline.
+    final_newline: bool,
+    captures: usize,
+    /// Group names and indices, sorted by name.
+    names: Arc<Vec<(Vec<u8>, usize)>>,
+    options: syntax::Options,
+}
+
+impl CastFrom<Term> for Pattern {
+    type Error = value::WrongBoxError;
+
+    #[inline]
+    fn cast_from(value: &Term) -> Result<&Self, value::WrongBoxError> {
+        if let Variant::Pointer(ptr) = value.into_variant() {
+            unsafe {
+                if *ptr == value::BOXED_RE_PATTERN {
+                    return Ok(&(*(ptr as *const value::Boxed<Self>)).value);
+                }
+            }
+        }
+        Err(value::WrongBoxError)
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &[u8], options: syntax::Options) -> Result<Self, syntax::Error> {
+        let ast = syntax::parse(pattern, &options)?;
+        let program = backtrack::Program::new(&ast, &options)?;
+
+        let mut translated = String::new();
+        let regex = if options.newline == Newline::Lf
+            && !options.firstline
+            && ast.node.write_regex(&mut translated, &options).is_some()
+        {
+            // fails on size limits, the backtracker can still try
+            RegexBuilder::new(&translated)
+                .unicode(options.unicode)
+                .build()
+                .ok()
+        } else {
+            None
+        };
+
+        Ok(Pattern {
+            program: Arc::new(program),
+            regex,
+            final_newline: ast.node.depends_on_final_newline(),
+            captures: ast.captures,
+            names: Arc::new(ast.names),
+            options,
+        })
+    }
+
+    /// Returns the pattern of a `re_pattern` tuple.
+    fn from_term(term: &Term) -> Option<&Self> {
+        let tuple = Tuple::cast_from(term).ok()?;
+        if tuple.len() != 5 || tuple[0] != atom!(RE_PATTERN) {
+            return None;
+        }
+        Pattern::cast_from(&tuple[4]).ok()
+    }
+
+    fn to_term(&self, heap: &Heap) -> Term {
+        let use_crlf = match self.options.newline {
+            Newline::CrLf | Newline::AnyCrLf | Newline::Any => 1,
+            Newline::Cr | Newline::Lf => 0,
+        };
+        tup!(
+            heap,
+            atom!(RE_PATTERN),
+            Term::uint64(heap, self.captures as u64),
+            Term::int(self.options.unicode as i32),
+            Term::int(use_crlf),
+            Term::re_pattern(heap, self.clone())
+        )
+    }
+
+    /// Finds the first match at or after `start`, continuing an interrupted search from `from`
+    /// and `attempt`. The `regex` crate can't be interrupted, so it's only used when the rest of
+    /// the subject fits in the steps left; longer subjects go through the backtracker, which
+    /// yields.
+    fn find(
+        &self,
+        subject: &[u8],
+        start: usize,
+        from: usize,
+        flags: backtrack::Flags,
+        budget: &mut Budget,
+        attempt: &mut Option<Attempt>,
+    ) -> Result<Option<Slots>, Stop> {
+        let plain = !(flags.notbol || flags.noteol || flags.notempty || flags.notempty_atstart);
+        match &self.regex {
+            Some(regex)
+                if plain
+                    && flags.newline == Newline::Lf
+                    && !budget.is_limited()
+                    && from == start
+                    && attempt.is_none()
+                    && subject.len() - start <= budget.steps
+                    && !(self.final_newline && subject.last() == Some(&b'\n')) =>
+            {
+                let mut locations = regex.capture_locations();
+                let found = regex.captures_read_at(&mut locations, subject, start);
+                let scanned = found.map_or(subject.len(), |found| found.end()) - start;
+                budget.steps = budget.steps.saturating_sub(scanned);
+                let found = match found {
+                    Some(found) => found,
+                    None => return Ok(None),
+                };
+                // the leftmost match starts at `start` if there is an anchored one
+                if (flags.anchored || self.program.anchored()) && found.start() != start {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    (0..=self.captures)
+                        .flat_map(|i| {
+                            let location = locations.get(i);
+                            vec![location.map(|l| l.0), location.map(|l| l.1)]
+                        })
+                        .collect(),
+                ))
+            }
+            _ => self
+                .program
+                .exec(subject, start, from, flags, budget, attempt),
+        }
+    }
+
+    /// The range of the first set group named `name`.
+    fn named(&self, name: &[u8], slots: &[Option<usize>]) -> Option<(usize, usize)> {
+        self.names
+            .iter()
+            .filter(|(n, _)| *n == name)
+            .find_map(|(_, index)| group(slots, *index))
+    }
+}
+
+fn group(slots: &[Option<usize>], index: usize) -> Option<(usize, usize)> {
+    match (slots.get(2 * index)?, slots.get(2 * index + 1)?) {
+        (Some(start), Some(end)) => Some((*start, *end)),
+        _ => None,
+    }
+}
+
+/// A `run`, `replace` or `split` in progress, kept in the process in between slices.
+struct Search {
+    pattern: Pattern,
+    subject: Vec<u8>,
+    options: Options,
+    matches: Vec<Slots>,
+    /// Where the next match is searched from.
+    pos: usize,
+    /// Where an interrupted search continues.
+    from: usize,
+    /// The match attempt at `from` the search was interrupted in, if any.
+    attempt: Option<Attempt>,
+    /// Whether the last match was empty. The next one is first tried as a non-empty match at
+    /// the same position, like PCRE's `pcredemo`.
+    retry: bool,
+    budget: Budget,
+}
+
+impl Search {
+    fn new(pattern: Pattern, subject: Vec<u8>, mut options: Options) -> Self {
+        options.flags.newline = options.newline.unwrap_or(pattern.options.newline);
+        let budget = Budget {
+            match_limit: options.match_limit,
+            match_limit_recursion: options.match_limit_recursion,
+            ..Budget::default()
+        };
+        Search {
+            pattern,
+            subject,
+            pos: options.offset,
+            from: options.offset,
+            attempt: None,
+            options,
+            matches: Vec::new(),
+            retry: false,
+            budget,
+        }
+    }
+
+    /// Finds the first match, or all of them with `global`, until the steps run out. Returns
+    /// whether the search is done.
+    fn resume(&mut self) -> Result<bool, Stop> {
+        while self.pos <= self.subject.len() {
+            if self.budget.steps == 0 {
+                return Ok(false);
+            }
+            let mut flags = self.options.flags;
+            if self.retry {
+                flags.notempty_atstart = true;
+                flags.anchored = true;
+            }
+            let found = self.pattern.find(
+                &self.subject,
+                self.pos,
+                self.from,
+                flags,
+                &mut self.budget,
+                &mut self.attempt,
+            );
+            match found {
+                Err(Stop::Yield(from)) => {
+                    self.from = from;
+                    return Ok(false);
+                }
+                Err(stop) => return Err(stop),
+                Ok(Some(slots)) => {
+                    let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+                    self.matches.push(slots);
+                    if !self.options.global {
+                        return Ok(true);
+                    }
+                    self.retry = start == end;
+                    self.pos = end;
+                }
+                Ok(None) if self.retry => {
+                    self.retry = false;
+                    let newline = self.options.flags.newline;
+                    self.pos = self
+                        .pattern
+                        .program
+                        .advance(&self.subject, self.pos, newline);
+                }
+                Ok(None) => return Ok(true),
+            }
+            self.from = self.pos;
+            self.budget.calls = 0;
+        }
+        Ok(true)
+    }
+
+    /// Runs the search in slices of the process' reductions, trapping in between, then calls
+    /// `then` to build the result. If a limit is hit the search ends without matches, unless
+    /// errors are reported.
+    fn run<F>(mut self, process: &RcProcess, then: F) -> crate::bif::Result
+    where
+        F: FnOnce(&RcProcess, Search) -> crate::bif::Result + Send + 'static,
+    {
+        let context = process.context_mut();
+        let steps = context.reds * LOOP_FACTOR;
+        self.budget.steps = steps;
+        let result = self.resume();
+        let used = steps - self.budget.steps;
+        context.reds = context
+            .reds
+            .saturating_sub((used + LOOP_FACTOR - 1) / LOOP_FACTOR);
+
+        match result {
+            Ok(true) => then(process, self),
+            Ok(false) => blocking::trap(process, move |process| self.run(process, then)),
+            Err(stop) if self.options.report_errors => {
+                let reason = match stop {
+                    Stop::MatchLimitRecursion => atom!(MATCH_LIMIT_RECURSION),
+                    _ => atom!(MATCH_LIMIT),
+                };
+                Ok(tup2!(&context.heap, atom!(ERROR), reason))
+            }
+            Err(_) => {
+                self.matches.clear();
+                then(process, self)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Caller {
+    Compile,
+    Run,
+    Replace,
+    Split,
+}
+
+/// Which groups `{capture, ValueSpec}` returns.
+#[derive(Debug)]
+enum Values {
+    All,
+    AllButFirst,
+    AllNames,
+    First,
+    None,
+    List(Vec<Capture>),
+}
+
+/// A group in a `{capture, ValueList}`.
+#[derive(Debug)]
+enum Capture {
+    Index(usize),
+    Name(Vec<u8>),
+}
+
+impl Capture {
+    fn from_term(term: Term) -> Result<Self, Exception> {
+        match term.into_variant() {
+            Variant::Integer(i) if i >= 0 => Ok(Capture::Index(i as usize)),
+            Variant::Atom(name) => {
+                let name = name.to_str().ok_or_else(|| badarg!())?;
+                Ok(Capture::Name(name.as_bytes().to_vec()))
+            }
+            _ => Ok(Capture::Name(list_to_iodata(term)?)),
+        }
+    }
+}
+
+/// How captured groups and results are returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Index,
+    Iodata,
+    List,
+    Binary,
+}
+
+#[derive(Debug)]
+struct Options {
+    compile: syntax::Options,
+    /// Whether there are options that can't be used with an already compiled pattern.
+    compile_only: bool,
+    flags: backtrack::Flags,
+    newline: Option<Newline>,
+    global: bool,
+    offset: usize,
+    report_errors: bool,
+    match_limit: usize,
+    match_limit_recursion: usize,
+    values: Values,
+    /// Type of captured values for `run`, of the result for `replace` and `split`.
+    kind: Kind,
+    /// Maximum number of parts for `split`, 0 trims empty trailing parts.
+    parts: Option<usize>,
+    group: bool,
+}
+
+impl Options {
+    fn parse(list: Term, caller: Caller) -> Result<Self, Exception> {
+        let mut options = Options {
+            compile: syntax::Options::default(),
+            compile_only: false,
+            flags: backtrack::Flags::default(),
+            newline: None,
+            global: false,
+            offset: 0,
+            report_errors: false,
+            match_limit: backtrack::MATCH_LIMIT,
+            match_limit_recursion: backtrack::MATCH_LIMIT_RECURSION,
+            values: Values::All,
+            kind: match caller {
+                Caller::Run | Caller::Compile => Kind::Index,
+                Caller::Replace | Caller::Split => Kind::Iodata,
+            },
+            parts: None,
+            group: false,
+        };
+        if list.is_nil() {
+            return Ok(options);
+        }
+
+        let matching = caller != Caller::Compile;
+        for option in Cons::cast_from(&list)?.iter() {
+            if let Variant::Atom(name) = option.into_variant() {
+                let compile = &mut options.compile;
+                let flag = match name {
+                    // valid with compiled patterns as well
+                    atom::UNICODE => &mut compile.unicode,
+                    atom::ANCHORED => {
+                        options.flags.anchored = true;
+                        &mut compile.anchored
+                    }
+                    atom::GLOBAL if caller == Caller::Run || caller == Caller::Replace => {
+                        &mut options.global
+                    }
+                    atom::NOTBOL if matching => &mut options.flags.notbol,
+                    atom::NOTEOL if matching => &mut options.flags.noteol,
+                    atom::NOTEMPTY if matching => &mut options.flags.notempty,
+                    atom::NOTEMPTY_ATSTART if matching => &mut options.flags.notempty_atstart,
+                    atom::REPORT_ERRORS if caller == Caller::Run => &mut options.report_errors,
+                    atom::GROUP if caller == Caller::Split => &mut options.group,
+                    atom::TRIM if caller == Caller::Split => {
+                        options.parts = Some(0);
+                        continue;
+                    }
+                    _ => {
+                        options.compile_only = true;
+                        match name {
+                            atom::CASELESS => &mut compile.caseless,
+                            atom::MULTILINE => &mut compile.multiline,
+                            atom::DOTALL => &mut compile.dotall,
+                            atom::EXTENDED => &mut compile.extended,
+                            atom::UNGREEDY => &mut compile.ungreedy,
+                            atom::DOLLAR_ENDONLY => &mut compile.dollar_endonly,
+                            atom::NO_AUTO_CAPTURE => &mut compile.no_auto_capture,
+                            atom::DUPNAMES => &mut compile.dupnames,
+                            atom::FIRSTLINE => &mut compile.firstline,
+                            atom::UCP => &mut compile.ucp,
+                            atom::BSR_ANYCRLF => &mut compile.bsr_anycrlf,
+                            atom::BSR_UNICODE => {
+                                compile.bsr_anycrlf = false;
+                                continue;
+                            }
+                            // optimization hints
+                            atom::NO_START_OPTIMIZE | atom::NEVER_UTF => continue,
+                            _ => return Err(badarg!()),
+                        }
+                    }
+                };
+                *flag = true;
+                continue;
+            }
+
+            let tuple = Tuple::cast_from(&option)?;
+            match (tuple[0].into_variant(), tuple.len()) {
+                (Variant::Atom(atom::NEWLINE), 2) => {
+                    let newline = match tuple[1].into_variant() {
+                        Variant::Atom(atom::CR) => Newline::Cr,
+                        Variant::Atom(atom::LF) => Newline::Lf,
+                        Variant::Atom(atom::CRLF) => Newline::CrLf,
+                        Variant::Atom(atom::ANYCRLF) => Newline::AnyCrLf,
+                        Variant::Atom(atom::ANY) => Newline::Any,
+                        _ => return Err(badarg!()),
+                    };
+                    options.compile.newline = newline;
+                    options.newline = Some(newline);
+                }
+                (Variant::Atom(atom::OFFSET), 2) if matching => {
+                    options.offset = tuple[1].to_uint().ok_or_else(|| badarg!())? as usize;
+                }
+                (Variant::Atom(atom::MATCH_LIMIT), 2) if caller == Caller::Run => {
+                    options.match_limit = tuple[1].to_uint().ok_or_else(|| badarg!())? as usize;
+                }
+                (Variant::Atom(atom::MATCH_LIMIT_RECURSION), 2) if caller == Caller::Run => {
+                    let limit = tuple[1].to_uint().ok_or_else(|| badarg!())?;
+                    options.match_limit_recursion = limit as usize;
+                }
+                (Variant::Atom(atom::CAPTURE), 2) | (Variant::Atom(atom::CAPTURE), 3)
+                    if caller == Caller::Run =>
+                {
+                    options.values = match tuple[1].into_variant() {
+                        Variant::Atom(atom::ALL) => Values::All,
+                        Variant::Atom(atom::ALL_BUT_FIRST) => Values::AllButFirst,
+                        Variant::Atom(atom::ALL_NAMES) => Values::AllNames,
+                        Variant::Atom(atom::FIRST) => Values::First,
+                        Variant::Atom(atom::NONE) => Values::None,
+                        Variant::Nil(..) => Values::List(Vec::new()),
+                        Variant::Cons(..) => Values::List(
+                            Cons::cast_from(&tuple[1])?
+                                .iter()
+                                .map(|item| Capture::from_term(*item))
+                                .collect::<Result<_, Exception>>()?,
+                        ),
+                        _ => return Err(badarg!()),
+                    };
+                    if tuple.len() == 3 {
+                        options.kind = match tuple[2].into_variant() {
+                            Variant::Atom(atom::INDEX) => Kind::Index,
+                            Variant::Atom(atom::LIST) => Kind::List,
+                            Variant::Atom(atom::BINARY) => Kind::Binary,
+                            _ => return Err(badarg!()),
+                        };
+                    }
+                }
+                (Variant::Atom(atom::RETURN), 2)
+                    if caller == Caller::Replace || caller == Caller::Split =>
+                {
+                    options.kind = match tuple[1].into_variant() {
+                        Variant::Atom(atom::IODATA) => Kind::Iodata,
+                        Variant::Atom(atom::LIST) => Kind::List,
+                        Variant::Atom(atom::BINARY) => Kind::Binary,
+                        _ => return Err(badarg!()),
+                    };
+                }
+                (Variant::Atom(atom::PARTS), 2) if caller == Caller::Split => {
+                    options.parts = match tuple[1].into_variant() {
+                        Variant::Atom(atom::INFINITY) => None,
+                        _ => Some(tuple[1].to_uint().ok_or_else(|| badarg!())? as usize),
+                    };
+                }
+                _ => return Err(badarg!()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Converts a subject or pattern to bytes. In unicode mode lists can contain any code point and
+/// are encoded as UTF-8, binaries have to be UTF-8 already.
+fn to_bytes(term: Term, unicode: bool) -> Result<Vec<u8>, Exception> {
+    if !unicode {
+        return list_to_iodata(term);
+    }
+
+    let mut bytes = Vec::new();
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+        match term.into_variant() {
+            Variant::Nil(..) => (),
+            Variant::Integer(i) => {
+                let c = std::char::from_u32(i as u32).ok_or_else(|| badarg!())?;
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            Variant::Cons(ptr) => {
+                let cons = unsafe { &*ptr };
+                stack.push(cons.tail);
+                stack.push(cons.head);
+            }
+            Variant::Pointer(..) => match term.to_bytes() {
+                Some(data) => bytes.extend_from_slice(data),
+                None => return Err(badarg!()),
+            },
+            _ => return Err(badarg!()),
+        }
+    }
+    if std::str::from_utf8(&bytes).is_err() {
+        return Err(badarg!());
+    }
+    Ok(bytes)
+}
+
+/// Returns the pattern to match with, compiling it if needed. If it doesn't compile and errors
+/// are reported, returns the error term instead.
+fn get_pattern(
+    heap: &Heap,
+    term: Term,
+    options: &Options,
+) -> Result<Result<Pattern, Term>, Exception> {
+    if let Some(pattern) = Pattern::from_term(&term) {
+        if options.compile_only {
+            return Err(badarg!());
+        }
+        return Ok(Ok(pattern.clone()));
+    }
+
+    let bytes = to_bytes(term, options.compile.unicode)?;
+    match Pattern::new(&bytes, options.compile) {
+        Ok(pattern) => Ok(Ok(pattern)),
+        Err(error) if options.report_errors => Ok(Err(tup2!(
+            heap,
+            atom!(ERROR),
+            tup2!(heap, atom!(COMPILE), compile_error(heap, &error))
+        ))),
+        Err(_) => Err(badarg!()),
+    }
+}
+
+/// The `{ErrString, Position}` of a compile error.
+fn compile_error(heap: &Heap, error: &syntax::Error) -> Term {
+    tup2!(
+        heap,
+        bitstring!(heap, error.message),
+        Term::uint64(heap, error.offset as u64)
+    )
+}
+
+/// Converts the subject, checking that matching starts inside it.
+fn get_subject(term: Term, pattern: &Pattern, options: &Options) -> Result<Vec<u8>, Exception> {
+    let subject = to_bytes(term, pattern.options.unicode)?;
+    if options.offset > subject.len()
+        || (pattern.options.unicode && !is_char_boundary(&subject, options.offset))
+    {
+        return Err(badarg!());
+    }
+    Ok(subject)
+}
+
+fn is_char_boundary(bytes: &[u8], pos: usize) -> bool {
+    pos == bytes.len() || bytes[pos] & 0xC0 != 0x80
+}
+
+/// Returns part of the subject as a list or binary.
+fn to_term(heap: &Heap, bytes: &[u8], kind: Kind, unicode: bool) -> Term {
+    match kind {
+        Kind::List if unicode => {
+            // the subject is valid UTF-8, but `\C` can split a character: those bytes are
+            // returned as is.
+            let mut chars = Vec::with_capacity(bytes.len());
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                let (valid, invalid) = match std::str::from_utf8(rest) {
+                    Ok(valid) => (valid, 0),
+                    Err(err) => {
+                        // the bytes up to valid_up_to() are checked already
+                        let valid =
+                            unsafe { std::str::from_utf8_unchecked(&rest[..err.valid_up_to()]) };
+                        (valid, err.error_len().unwrap_or(rest.len() - valid.len()))
+                    }
+                };
+                chars.extend(valid.chars().map(|c| c as u32));
+                let end = valid.len() + invalid;
+                chars.extend(rest[valid.len()..end].iter().map(|b| u32::from(*b)));
+                rest = &rest[end..];
+            }
+            iter_to_list!(heap, chars.into_iter().rev().map(|c| Term::int(c as i32)))
+        }
+        Kind::List => bitstring::bytes_to_list(heap, Term::nil(), bytes, bytes.len(), 0),
+        _ => Term::binary(heap, bitstring::Binary::from(bytes.to_vec())),
+    }
+}
+
+/// Expands a replacement: `&` and `\0` insert the whole match, `\N`, `\gN` and `\g{N}` a group.
+/// Any other character following a backslash is inserted as is.
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Literal(Vec<u8>),
+    Group(usize),
+}
+
+fn parse_replacement(bytes: &[u8]) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut literal = Vec::new();
+    let mut i = 0;
+
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let number = |from: usize, len: usize| {
+        std::str::from_utf8(&bytes[from..from + len])
+            .unwrap()
+            .parse()
+            .unwrap_or(usize::max_value())
+    };
+
+    while i < bytes.len() {
+        let group = match bytes[i] {
+            b'&' => {
+                i += 1;
+                Some(0)
+            }
+            b'\\' if i + 1 < bytes.len() => {
+                let next = bytes[i + 1];
+                if next.is_ascii_digit() {
+                    let len = digits(i + 1);
+                    let group = number(i + 1, len);
+                    i += 1 + len;
+                    Some(group)
+                } else if next == b'g' && bytes.get(i + 2) == Some(&b'{') {
+                    let len = digits(i + 3);
+                    if len > 0 && bytes.get(i + 3 + len) == Some(&b'}') {
+                        let group = number(i + 3, len);
+                        i += 4 + len;
+                        Some(group)
+                    } else {
+                        literal.push(next);
+                        i += 2;
+                        None
+                    }
+                } else if next == b'g' && digits(i + 2) > 0 {
+                    let len = digits(i + 2);
+                    let group = number(i + 2, len);
+                    i += 2 + len;
+                    Some(group)
+                } else {
+                    literal.push(next);
+                    i += 2;
+                    None
+                }
+            }
+            byte => {
+                literal.push(byte);
+                i += 1;
+                None
+            }
+        };
+        if let Some(group) = group {
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::replace(&mut literal, Vec::new())));
+            }
+            pieces.push(Piece::Group(group));
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    pieces
+}
 
 pub mod bif {
     use super::*;
     use crate::bif::Result;
     use crate::process::RcProcess;
-    use crate::value::{self, CastFrom, Term};
     use crate::vm;
 
-    pub fn version_0(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+    pub fn version_0(_vm: &vm::Machine, process: &RcProcess, _args: &[Term]) -> Result {
         // TODO: static regex version for now
         let version = "1.1.7";
         Ok(Term::binary(
@@ -28,95 +736,622 @@ pub mod bif {
     }
 
     pub fn run_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-        use std::borrow::Cow;
         let heap = &process.context_mut().heap;
-        // println!("run/3: {} {}", args[0], args[1]);
-        let string = crate::bif::erlang::list_to_iodata(args[0]).unwrap(); // TODO: error handling
-
-        let regex = match args[1].get_boxed_header() {
-            Ok(value::BOXED_REGEX) => {
-                let regex = regex::bytes::Regex::cast_from(&args[1]).unwrap();
-                Cow::Borrowed(regex)
-            }
-            _ => {
-                let pattern = crate::bif::erlang::list_to_iodata(args[1]).unwrap(); // TODO: error handling
-
-                // TODO verify args
-                let regex = Regex::new(std::str::from_utf8(&pattern).unwrap()).unwrap();
-                Cow::Owned(regex)
-            }
+        let options = Options::parse(args[2], Caller::Run)?;
+        let pattern = match get_pattern(heap, args[1], &options)? {
+            Ok(pattern) => pattern,
+            Err(error) => return Ok(error),
         };
+        let subject = get_subject(args[0], &pattern, &options)?;
+        Search::new(pattern, subject, options).run(process, run_result)
+    }
 
-        //println!("inspect {:?} -- {:?}", string, pattern);
-        // str = [115, 117, 112, 101, 114, 118, 105, 115, 111, 114, 58, 32, 123, 108, 111, 99, 97, 108, 44, 107, 101, 114, 110, 101, 108, 95, 115, 117, 112, 125, 10, 32, 32, 32, 32, 115, 116, 97, 114, 116, 101, 100, 58, 32, 91, 123, 112, 105, 100, 44, 60, 48, 46, 48, 46, 52, 52, 62, 125, 44, 123, 105, 100, 44, 99, 111, 100, 101, 95, 115, 101, 114, 118, 101, 114, 125, 44, 123, 109, 102, 97, 114, 103, 115, 44, 123, 99, 111, 100, 101, 44, 115, 116, 97, 114, 116, 95, 108, 105, 110, 107, 44, 91, 93, 125, 125, 44, 123, 114, 101, 115, 116, 97, 114, 116, 95, 116, 121, 112, 101, 44, 112, 101, 114, 109, 97, 110, 101, 110, 116, 125, 44, 123, 115, 104, 117, 116, 100, 111, 119, 110, 44, 50, 48, 48, 48, 125, 44, 123, 99, 104, 105, 108, 100, 95, 116, 121, 112, 101, 44, 119, 111, 114, 107, 101, 114, 125, 93]
-        // pat = [44, 63, 13, 63, 10, 32, 42]
-        // iex(5)> :re.run(str, pat, [:unicode, :global])
-        // {:match, [[{30, 5}]]}
+    fn run_result(process: &RcProcess, search: Search) -> Result {
+        let heap = &process.context_mut().heap;
+        let Search {
+            pattern,
+            subject,
+            options,
+            matches,
+            ..
+        } = search;
+        let unicode = pattern.options.unicode;
 
-        let res = regex.find_iter(&string).fold(Term::nil(), |acc, m| {
-            cons!(
-                heap,
-                tup2!(
-                    heap,
-                    Term::uint64(heap, m.start() as u64),
-                    Term::uint64(heap, (m.end() - m.start()) as u64)
-                ),
-                acc
-            )
-        });
+        if matches.is_empty() {
+            return Ok(atom!(NOMATCH));
+        }
+        if let Values::None = options.values {
+            return Ok(atom!(MATCH));
+        }
+
+        let captured = |slots: &Slots| {
+            // PCRE only reports groups up to the last one that is set
+            let set = (0..=pattern.captures)
+                .rev()
+                .find(|i| group(slots, *i).is_some())
+                .map_or(0, |i| i + 1);
+            let values: Vec<_> = match &options.values {
+                Values::All => (0..set).map(|i| group(slots, i)).collect(),
+                Values::AllButFirst => (1..set).map(|i| group(slots, i)).collect(),
+                Values::First => vec![group(slots, 0)],
+                Values::None => Vec::new(),
+                Values::AllNames => {
+                    let mut names: Vec<_> = pattern.names.iter().map(|(name, _)| name).collect();
+                    names.dedup();
+                    names
+                        .into_iter()
+                        .map(|name| pattern.named(name, slots))
+                        .collect()
+                }
+                Values::List(items) => items
+                    .iter()
+                    .map(|item| match item {
+                        Capture::Index(i) if *i < set => group(slots, *i),
+                        Capture::Index(_) => None,
+                        Capture::Name(name) => pattern.named(name, slots),
+                    })
+                    .collect(),
+            };
 
-        // println!("re_run_3 inputs {:?} and {:?}", string, pattern);
-        // println!("re_run_3 res {}", res);
+            let values: Vec<_> = values
+                .into_iter()
+                .map(|range| match (range, options.kind) {
+                    (Some((start, end)), Kind::Index) => tup2!(
+                        heap,
+                        Term::uint64(heap, start as u64),
+                        Term::uint64(heap, (end - start) as u64)
+                    ),
+                    (None, Kind::Index) => tup2!(heap, Term::int(-1), Term::int(0)),
+                    (Some((start, end)), kind) => {
+                        to_term(heap, &subject[start..end], kind, unicode)
+                    }
+                    (None, kind) => to_term(heap, &[], kind, unicode),
+                })
+                .collect();
+            iter_to_list!(heap, values.into_iter().rev())
+        };
 
-        if !res.is_nil() {
-            Ok(tup2!(heap, atom!(MATCH), cons!(heap, res, Term::nil())))
+        let result = if options.global {
+            let lists: Vec<_> = matches.iter().map(captured).collect();
+            iter_to_list!(heap, lists.into_iter().rev())
         } else {
-            Ok(atom!(NOMATCH))
-        }
+            captured(&matches[0])
+        };
+        Ok(tup2!(heap, atom!(MATCH), result))
     }
 
     pub fn compile_1(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
-        compile_2(vm, process, &[args[0], args[1], Term::nil()])
+        compile_2(vm, process, &[args[0], Term::nil()])
     }
 
     pub fn compile_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
         let heap = &process.context_mut().heap;
+        let options = Options::parse(args[1], Caller::Compile)?;
+        let bytes = to_bytes(args[0], options.compile.unicode)?;
+
+        match Pattern::new(&bytes, options.compile) {
+            Ok(pattern) => Ok(tup2!(heap, atom!(OK), pattern.to_term(heap))),
+            Err(error) => Ok(tup2!(heap, atom!(ERROR), compile_error(heap, &error))),
+        }
+    }
+
+    pub fn inspect_2(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+        let pattern = Pattern::from_term(&args[0]).ok_or_else(|| badarg!())?;
+        if args[1] != atom!(NAMELIST) {
+            return Err(badarg!());
+        }
 
-        let pattern = crate::bif::erlang::list_to_iodata(args[0]).unwrap(); // TODO: error handling
+        let mut names: Vec<_> = pattern.names.iter().map(|(name, _)| name).collect();
+        names.dedup();
+        let names = iter_to_list!(
+            heap,
+            names
+                .into_iter()
+                .rev()
+                .map(|name| Term::binary(heap, bitstring::Binary::from(name.clone())))
+        );
+        Ok(tup2!(heap, atom!(NAMELIST), names))
+    }
+
+    pub fn replace_3(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        replace_4(vm, process, &[args[0], args[1], args[2], Term::nil()])
+    }
 
-        // this is terrible, but the syntax is incompatible
-        let pattern = String::from_utf8(pattern).unwrap().replace("(?<", "(?P<");
+    pub fn replace_4(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+        let options = Options::parse(args[3], Caller::Replace)?;
+        let pattern = match get_pattern(heap, args[1], &options)? {
+            Ok(pattern) => pattern,
+            Err(error) => return Ok(error),
+        };
+        let subject = get_subject(args[0], &pattern, &options)?;
+        let replacement = parse_replacement(&to_bytes(args[2], pattern.options.unicode)?);
+        let search = Search::new(pattern, subject, options);
+        search.run(process, move |process, search| {
+            replace_result(process, search, &replacement)
+        })
+    }
 
-        let mut builder = RegexBuilder::new(&pattern);
-        let mut unicode = false;
+    fn replace_result(process: &RcProcess, search: Search, replacement: &[Piece]) -> Result {
+        let heap = &process.context_mut().heap;
+        let subject = &search.subject;
 
-        if !args[1].is_list() {
-            for value in Cons::cast_from(&args[1])?.iter() {
-                match value.into_variant() {
-                    Variant::Atom(atom::CASELESS) => builder.case_insensitive(true),
-                    Variant::Atom(atom::UNICODE) => {
-                        unicode = true;
-                        builder.unicode(true)
+        let mut result = Vec::with_capacity(subject.len());
+        let mut last = 0;
+        for slots in &search.matches {
+            let (start, end) = group(slots, 0).unwrap();
+            result.extend_from_slice(&subject[last..start]);
+            for piece in replacement {
+                match piece {
+                    Piece::Literal(bytes) => result.extend_from_slice(bytes),
+                    Piece::Group(index) => {
+                        if let Some((start, end)) = group(slots, *index) {
+                            result.extend_from_slice(&subject[start..end]);
+                        }
                     }
-                    Variant::Atom(atom::UNGREEDY) => builder.swap_greed(true),
-                    Variant::Atom(atom::MULTILINE) => builder.multi_line(true),
-                    Variant::Atom(atom::DOTALL) => builder.dot_matches_new_line(true),
-                    _ => unimplemented!("{}", args[1]),
-                };
+                }
+            }
+            last = end;
+        }
+        result.extend_from_slice(&subject[last..]);
+
+        let unicode = search.pattern.options.unicode;
+        Ok(to_term(heap, &result, search.options.kind, unicode))
+    }
+
+    pub fn split_2(vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        split_3(vm, process, &[args[0], args[1], Term::nil()])
+    }
+
+    pub fn split_3(_vm: &vm::Machine, process: &RcProcess, args: &[Term]) -> Result {
+        let heap = &process.context_mut().heap;
+        let mut options = Options::parse(args[2], Caller::Split)?;
+        options.global = true;
+        let pattern = match get_pattern(heap, args[1], &options)? {
+            Ok(pattern) => pattern,
+            Err(error) => return Ok(error),
+        };
+        let subject = get_subject(args[0], &pattern, &options)?;
+        Search::new(pattern, subject, options).run(process, split_result)
+    }
+
+    fn split_result(process: &RcProcess, search: Search) -> Result {
+        let heap = &process.context_mut().heap;
+        let Search {
+            pattern,
+            subject,
+            options,
+            matches,
+            ..
+        } = search;
+        let unicode = pattern.options.unicode;
+
+        // each part, followed by the groups captured by the match that ended it
+        let mut parts: Vec<Vec<(usize, usize)>> = Vec::new();
+        let mut last = 0;
+        for slots in matches {
+            if let Some(limit) = options.parts {
+                if limit > 0 && parts.len() + 1 >= limit {
+                    break;
+                }
+            }
+            let (start, end) = group(&slots, 0).unwrap();
+            // an empty match doesn't split off an empty part, or one at the end of the subject
+            if start == end && (start == last || start == subject.len()) {
+                continue;
+            }
+            let mut part = vec![(last, start)];
+            part.extend((1..=pattern.captures).map(|i| group(&slots, i).unwrap_or((0, 0))));
+            parts.push(part);
+            last = end;
+        }
+        parts.push(vec![(last, subject.len())]);
+
+        if options.parts == Some(0) {
+            let is_empty = |range: &(usize, usize)| range.0 == range.1;
+            if options.group {
+                while parts.last().map_or(false, |part| part.iter().all(is_empty)) {
+                    parts.pop();
+                }
+            } else {
+                let mut ranges: Vec<_> = parts.into_iter().flatten().collect();
+                while ranges.last().map_or(false, is_empty) {
+                    ranges.pop();
+                }
+                parts = ranges.into_iter().map(|range| vec![range]).collect();
             }
         }
 
-        // TODO verify args
-        let regex = builder.build().unwrap();
+        let kind = options.kind;
+        let to_list = |ranges: Vec<(usize, usize)>| {
+            iter_to_list!(
+                heap,
+                ranges.into_iter().rev().map(|(start, end)| to_term(
+                    heap,
+                    &subject[start..end],
+                    kind,
+                    unicode
+                ))
+            )
+        };
+        let result = if options.group {
+            let groups: Vec<_> = parts.into_iter().map(to_list).collect();
+            iter_to_list!(heap, groups.into_iter().rev())
+        } else {
+            to_list(parts.into_iter().flatten().collect())
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bif::*;
+    use super::*;
+    use crate::bif::blocking::call;
+    use crate::exception::Reason;
+    use crate::module;
+    use crate::process;
+    use crate::vm;
+
+    fn bin(heap: &Heap, s: &str) -> Term {
+        Term::binary(heap, bitstring::Binary::from(s.as_bytes().to_vec()))
+    }
+
+    fn list(heap: &Heap, items: Vec<Term>) -> Term {
+        iter_to_list!(heap, items.into_iter().rev())
+    }
+
+    fn index(heap: &Heap, start: i32, len: i32) -> Term {
+        tup2!(heap, Term::int(start), Term::int(len))
+    }
+
+    fn matched(heap: &Heap, captured: Term) -> Term {
+        tup2!(heap, atom!(MATCH), captured)
+    }
+
+    #[test]
+    fn test_run() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
 
-        Ok(tup!(
+        // groups are reported up to the last one that is set
+        let res = call(
+            &vm,
+            &process,
+            run_2,
+            &[bin(heap, "xabcd"), bin(heap, "(a)(b)|(q)")],
+        );
+        let expected = list(
             heap,
-            atom!(RE_PATTERN),
-            Term::uint64(heap, regex.captures_len() as u64),
-            Term::int(if unicode { 1 } else { 0 }),
-            Term::int(0), // TODO: use_crlf
-            Term::regex(heap, regex)
-        ))
+            vec![index(heap, 1, 2), index(heap, 1, 1), index(heap, 2, 1)],
+        );
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        let options = list(
+            heap,
+            vec![
+                atom!(GLOBAL),
+                tup3!(heap, atom!(CAPTURE), atom!(ALL_BUT_FIRST), atom!(BINARY)),
+            ],
+        );
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[bin(heap, "a=1,b=2"), bin(heap, "(\\w)=(\\d)"), options],
+        );
+        let expected = list(
+            heap,
+            vec![
+                list(heap, vec![bin(heap, "a"), bin(heap, "1")]),
+                list(heap, vec![bin(heap, "b"), bin(heap, "2")]),
+            ],
+        );
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        // empty matches advance one character at a time
+        let options = list(heap, vec![atom!(GLOBAL)]);
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[bin(heap, "ab"), bin(heap, "x*"), options],
+        );
+        let expected = list(
+            heap,
+            vec![
+                list(heap, vec![index(heap, 0, 0)]),
+                list(heap, vec![index(heap, 1, 0)]),
+                list(heap, vec![index(heap, 2, 0)]),
+            ],
+        );
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        let options = list(
+            heap,
+            vec![
+                tup2!(heap, atom!(OFFSET), Term::int(2)),
+                tup3!(
+                    heap,
+                    atom!(CAPTURE),
+                    list(heap, vec![bitstring!(heap, "y"), Term::int(2)]),
+                    atom!(LIST)
+                ),
+            ],
+        );
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[bin(heap, "1 2 3"), bin(heap, "(?<y>\\d)"), options],
+        );
+        let expected = list(heap, vec![bitstring!(heap, "2"), Term::nil()]);
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        let options = list(
+            heap,
+            vec![atom!(NOTEMPTY), tup2!(heap, atom!(CAPTURE), atom!(NONE))],
+        );
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[bin(heap, ""), bin(heap, "a*"), options],
+        );
+        assert_eq!(res, Ok(atom!(NOMATCH)));
+
+        // back references use the backtracking engine
+        let res = call(
+            &vm,
+            &process,
+            run_2,
+            &[bin(heap, "abcbc"), bin(heap, "(bc)\\1")],
+        );
+        let expected = list(heap, vec![index(heap, 1, 4), index(heap, 1, 2)]);
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        // bad patterns are badarg, unless errors are reported
+        let res = call(&vm, &process, run_2, &[bin(heap, "a"), bin(heap, "(")]);
+        assert!(res.is_err());
+        let options = list(heap, vec![atom!(REPORT_ERRORS)]);
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[bin(heap, "a"), bin(heap, "("), options],
+        );
+        let error = tup2!(heap, bitstring!(heap, "missing )"), Term::int(1));
+        let expected = tup2!(heap, atom!(ERROR), tup2!(heap, atom!(COMPILE), error));
+        assert_eq!(res, Ok(expected));
+    }
+
+    #[test]
+    fn test_compile() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let options = list(heap, vec![atom!(CASELESS), atom!(UNICODE)]);
+        let res = compile_2(&vm, &process, &[bin(heap, "(?<b>b)(?<a>é)"), options]).unwrap();
+        let tuple = Tuple::cast_from(&res).unwrap();
+        assert_eq!(tuple[0], atom!(OK));
+        let mp = tuple[1];
+
+        let res = call(&vm, &process, run_2, &[bin(heap, "aBÉ"), mp]);
+        let expected = list(
+            heap,
+            vec![index(heap, 1, 3), index(heap, 1, 1), index(heap, 2, 2)],
+        );
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        let res = inspect_2(&vm, &process, &[mp, atom!(NAMELIST)]);
+        let expected = tup2!(
+            heap,
+            atom!(NAMELIST),
+            list(heap, vec![bin(heap, "a"), bin(heap, "b")])
+        );
+        assert_eq!(res, Ok(expected));
+
+        // compile options can't be used with a compiled pattern
+        let options = list(heap, vec![atom!(MULTILINE)]);
+        assert!(call(&vm, &process, run_3, &[bin(heap, "b"), mp, options]).is_err());
+
+        let res = compile_1(&vm, &process, &[bin(heap, "a{2,1}")]);
+        let error = tup2!(
+            heap,
+            bitstring!(heap, "numbers out of order in {} quantifier"),
+            Term::int(5)
+        );
+        assert_eq!(res, Ok(tup2!(heap, atom!(ERROR), error)));
+    }
+
+    #[test]
+    fn test_replace_and_split() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let options = list(
+            heap,
+            vec![atom!(GLOBAL), tup2!(heap, atom!(RETURN), atom!(BINARY))],
+        );
+        let args = [
+            bin(heap, "a-b c-d"),
+            bin(heap, "(\\w)-(\\w)"),
+            bin(heap, "\\2+\\g{1}[&]\\&"),
+            options,
+        ];
+        let res = call(&vm, &process, replace_4, &args);
+        assert_eq!(res, Ok(bin(heap, "b+a[a-b]& d+c[c-d]&")));
+
+        let options = list(heap, vec![tup2!(heap, atom!(RETURN), atom!(LIST))]);
+        let res = call(
+            &vm,
+            &process,
+            split_3,
+            &[bin(heap, "Erlang"), bin(heap, "[lg]"), options],
+        );
+        let expected = list(
+            heap,
+            vec![bitstring!(heap, "Er"), bitstring!(heap, "an"), Term::nil()],
+        );
+        assert_eq!(res, Ok(expected));
+
+        let options = list(
+            heap,
+            vec![
+                atom!(TRIM),
+                atom!(GROUP),
+                tup2!(heap, atom!(RETURN), atom!(LIST)),
+            ],
+        );
+        let res = call(
+            &vm,
+            &process,
+            split_3,
+            &[bin(heap, "Erlang"), bin(heap, "([ln])"), options],
+        );
+        let expected = list(
+            heap,
+            vec![
+                list(heap, vec![bitstring!(heap, "Er"), bitstring!(heap, "l")]),
+                list(heap, vec![bitstring!(heap, "a"), bitstring!(heap, "n")]),
+                list(heap, vec![bitstring!(heap, "g")]),
+            ],
+        );
+        assert_eq!(res, Ok(expected));
+
+        let options = list(heap, vec![tup2!(heap, atom!(PARTS), Term::int(2))]);
+        let res = call(
+            &vm,
+            &process,
+            split_3,
+            &[bin(heap, "a,b,c"), bin(heap, ","), options],
+        );
+        assert_eq!(res, Ok(list(heap, vec![bin(heap, "a"), bin(heap, "b,c")])));
     }
 
+    #[test]
+    fn test_to_term_split_char() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        // what \C leaves of "aé!" when it splits the é
+        let chars = |bytes: &[u8]| to_term(heap, bytes, Kind::List, true);
+        assert_eq!(
+            chars(&[b'a', 0xC3]),
+            list(heap, vec![Term::int(0x61), Term::int(0xC3)])
+        );
+        assert_eq!(
+            chars(&[0xA9, b'!']),
+            list(heap, vec![Term::int(0xA9), Term::int(0x21)])
+        );
+        assert_eq!(
+            chars("aé".as_bytes()),
+            list(heap, vec![Term::int(0x61), Term::int(0xE9)])
+        );
+    }
+
+    #[test]
+    fn test_parse_replacement() {
+        assert_eq!(
+            parse_replacement(b"x&\\1\\g{12}\\g3\\\\\\&y"),
+            vec![
+                Piece::Literal(b"x".to_vec()),
+                Piece::Group(0),
+                Piece::Group(1),
+                Piece::Group(12),
+                Piece::Group(3),
+                Piece::Literal(b"\\&y".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limits_and_yielding() {
+        let vm = vm::Machine::new();
+        let module: *const module::Module = std::ptr::null();
+        let process = process::allocate(&vm, 0, 0, module).unwrap();
+        let heap = &process.context_mut().heap;
+
+        let subject = bin(heap, &"a".repeat(30));
+        let match_limit = tup2!(heap, atom!(MATCH_LIMIT), Term::int(1000));
+        let options = list(heap, vec![match_limit]);
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[subject, bin(heap, "(a+)+b"), options],
+        );
+        assert_eq!(res, Ok(atom!(NOMATCH)));
+        let options = list(heap, vec![match_limit, atom!(REPORT_ERRORS)]);
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[subject, bin(heap, "(a+)+b"), options],
+        );
+        assert_eq!(res, Ok(tup2!(heap, atom!(ERROR), atom!(MATCH_LIMIT))));
+
+        let recursion_limit = tup2!(heap, atom!(MATCH_LIMIT_RECURSION), Term::int(10));
+        let options = list(heap, vec![recursion_limit, atom!(REPORT_ERRORS)]);
+        let res = call(
+            &vm,
+            &process,
+            run_3,
+            &[subject, bin(heap, "(a|b)*c"), options],
+        );
+        let expected = tup2!(heap, atom!(ERROR), atom!(MATCH_LIMIT_RECURSION));
+        assert_eq!(res, Ok(expected));
+
+        // long subjects use up the reductions and are matched over several slices
+        let subject = bin(heap, &format!("{}x", "ab".repeat(100_000)));
+        let args = [subject, bin(heap, "(?<=b)x"), Term::nil()];
+        process.context_mut().reds = process::CONTEXT_REDS;
+        let res = run_3(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        assert_eq!(process.context_mut().reds, 0);
+        process.local_data_mut().trap = None;
+
+        let res = call(&vm, &process, run_3, &args);
+        let expected = list(heap, vec![index(heap, 200_000, 1)]);
+        assert_eq!(res, Ok(matched(heap, expected)));
+
+        // so do patterns the regex crate could run, even when nothing matches
+        let subject = bin(heap, &"ab".repeat(100_000));
+        let args = [subject, bin(heap, "ba?c"), Term::nil()];
+        process.context_mut().reds = process::CONTEXT_REDS;
+        let res = run_3(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        assert_eq!(process.context_mut().reds, 0);
+        process.local_data_mut().trap = None;
+
+        let res = call(&vm, &process, run_3, &args);
+        assert_eq!(res, Ok(atom!(NOMATCH)));
+
+        // a single attempt is interrupted as well, and the process can be killed in between
+        let subject = bin(heap, &"a".repeat(30));
+        let args = [subject, bin(heap, "^(?=a)(a+)+b"), Term::nil()];
+        process.context_mut().reds = process::CONTEXT_REDS;
+        let res = run_3(&vm, &process, &args);
+        assert_eq!(res.unwrap_err().reason, Reason::TRAP);
+        let then = process.local_data_mut().trap.take().unwrap();
+
+        let reason = Exception::with_value(Reason::EXC_EXIT, atom!(KILL));
+        process.send_signal(process::Signal::exit(
+            process.pid + 1,
+            &reason,
+            process::ExitKind::Exit,
+        ));
+        let res = futures::executor::block_on(blocking::resume_trap(&process, then));
+        assert_eq!(res.unwrap_err().value, atom!(KILLED));
+    }
 }
@@ -0,0 +1,1157 @@
+//! Backtracking matcher for patterns the `regex` crate can't run, like back references and
+//! lookaround.
+//!
+//! The syntax tree is compiled to a small program. Matching walks it depth first, pushing the
+//! alternatives it didn't take (and the capture values it overwrote) on a stack to return to.
+use super::syntax::{self, case_variants, Assertion, Class, Newline, Node, Perl};
+
+/// Counted repetitions are expanded, this keeps `(a{1000}){1000}` from eating all memory.
+const MAX_PROGRAM_SIZE: usize = 1 << 20;
+
+/// PCRE's default `match_limit`.
+pub const MATCH_LIMIT: usize = 10_000_000;
+
+/// PCRE's default `match_limit_recursion`.
+pub const MATCH_LIMIT_RECURSION: usize = 10_000_000;
+
+#[derive(Debug, Clone)]
+enum Inst {
+    /// The whole pattern matched.
+    Match,
+    /// The body of a lookaround or atomic group matched.
+    Succeed,
+    Char(u32),
+    CharCaseless(u32),
+    Any,
+    AnyNotNewline,
+    /// A single byte, for `\C`.
+    Byte,
+    Class(Box<Class>),
+    Assert(Assertion),
+    /// Stores the position in a capture slot.
+    Save(usize),
+    /// Continues at the first target, then the second one on backtracking.
+    Split(usize, usize),
+    Jmp(usize),
+    /// Records where an iteration of an unbounded repetition started.
+    Mark(usize),
+    /// Leaves the repetition if the iteration since `mark` didn't consume anything, instead of
+    /// looping forever.
+    Progress {
+        mark: usize,
+        exit: usize,
+    },
+    Backref {
+        groups: Vec<usize>,
+        caseless: bool,
+    },
+    /// Runs the body following the instruction, then continues at `next`. Lookbehinds start
+    /// their fixed number of characters back.
+    Look {
+        behind: Option<usize>,
+        negated: bool,
+        next: usize,
+    },
+    /// Runs the body following the instruction, without backtracking into it.
+    Atomic {
+        next: usize,
+    },
+}
+
+/// Options that apply to a single match.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Flags {
+    pub newline: Newline,
+    pub anchored: bool,
+    pub notbol: bool,
+    pub noteol: bool,
+    pub notempty: bool,
+    pub notempty_atstart: bool,
+}
+
+/// Bounds the work done by a search.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    /// Instructions left before the search is interrupted. The search can continue later from
+    /// where it stopped, even in the middle of a match attempt.
+    pub steps: usize,
+    /// Maximum number of backtracking points in a search.
+    pub match_limit: usize,
+    /// Maximum depth of the backtracking stack.
+    pub match_limit_recursion: usize,
+    /// Backtracking points passed so far in the current search.
+    pub calls: usize,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            steps: usize::max_value(),
+            match_limit: MATCH_LIMIT,
+            match_limit_recursion: MATCH_LIMIT_RECURSION,
+            calls: 0,
+        }
+    }
+}
+
+impl Budget {
+    /// Whether the limits are lower than PCRE's defaults.
+    pub fn is_limited(&self) -> bool {
+        self.match_limit < MATCH_LIMIT || self.match_limit_recursion < MATCH_LIMIT_RECURSION
+    }
+}
+
+/// Why a search stopped before finding out whether there is a match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    MatchLimit,
+    MatchLimitRecursion,
+    /// Ran out of steps, the search continues from the position. If an `Attempt` was saved,
+    /// it is finished first.
+    Yield(usize),
+}
+
+/// A match attempt interrupted when the steps ran out: where the program was, the captures so
+/// far and the alternatives left to try.
+#[derive(Debug)]
+pub struct Attempt {
+    pc: usize,
+    pos: usize,
+    slots: Vec<Option<usize>>,
+    stack: Vec<Frame>,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    insts: Vec<Inst>,
+    /// Two capture slots per group, including the whole match.
+    slots: usize,
+    /// Capture slots followed by the repetition marks.
+    registers: usize,
+    unicode: bool,
+    ucp: bool,
+    anchored: bool,
+    firstline: bool,
+    /// Byte every match starts with, used to skip ahead.
+    first_byte: Option<u8>,
+}
+
+impl Program {
+    pub fn new(ast: &syntax::Ast, options: &syntax::Options) -> Result<Self, syntax::Error> {
+        let slots = 2 * (ast.captures + 1);
+        let mut compiler = Compiler {
+            insts: Vec::new(),
+            registers: slots,
+            unicode: options.unicode,
+        };
+        compiler.push(Inst::Save(0))?;
+        compiler.compile(&ast.node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+
+        Ok(Program {
+            insts: compiler.insts,
+            slots,
+            registers: compiler.registers,
+            unicode: options.unicode,
+            ucp: options.ucp,
+            anchored: options.anchored || ast.node.is_anchored(),
+            firstline: options.firstline,
+            first_byte: first_byte(&ast.node, options.unicode),
+        })
+    }
+
+    /// Whether matches can only start at the offset matching starts from.
+    pub fn anchored(&self) -> bool {
+        self.anchored
+    }
+
+    /// Finds the first match at or after `start`, returning its capture slots. An interrupted
+    /// search is continued by calling it again with the position it stopped at as `from`, and
+    /// the attempt it saved in `attempt` if it stopped in the middle of one.
+    pub fn exec(
+        &self,
+        input: &[u8],
+        start: usize,
+        from: usize,
+        flags: Flags,
+        budget: &mut Budget,
+        attempt: &mut Option<Attempt>,
+    ) -> Result<Option<Vec<Option<usize>>>, Stop> {
+        let (slots, stack, resume) = match attempt.take() {
+            Some(Attempt {
+                pc,
+                pos,
+                slots,
+                stack,
+            }) => (slots, stack, Some((pc, pos))),
+            None => (vec![None; self.registers], Vec::new(), None),
+        };
+        let mut exec = Exec {
+            program: self,
+            input,
+            newline: flags.newline,
+            start,
+            flags,
+            budget: *budget,
+            match_start: from,
+            slots,
+            stack,
+            resume,
+        };
+        let result = exec.search(from);
+        *budget = exec.budget;
+        if let (Err(Stop::Yield(_)), Some((pc, pos))) = (&result, exec.resume) {
+            *attempt = Some(Attempt {
+                pc,
+                pos,
+                slots: exec.slots,
+                stack: exec.stack,
+            });
+        }
+        result
+    }
+
+    /// Where to continue looking after an empty match at `pos` that couldn't be extended: the
+    /// next character, or past a CRLF if it is a newline.
+    pub fn advance(&self, input: &[u8], pos: usize, newline: Newline) -> usize {
+        match newline {
+            Newline::CrLf | Newline::AnyCrLf | Newline::Any
+                if input[pos..].starts_with(b"\r\n") =>
+            {
+                pos + 2
+            }
+            _ if pos < input.len() && self.unicode => pos + utf8_len(input[pos]),
+            _ => pos + 1,
+        }
+    }
+}
+
+fn utf8_len(byte: u8) -> usize {
+    match byte {
+        0xF0..=0xFF => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
+
+/// The byte every match has to start with, if there is one.
+fn first_byte(node: &Node, unicode: bool) -> Option<u8> {
+    match node {
+        Node::Char { c, caseless } => {
+            if (*c < 0x80 || (!unicode && *c <= 0xFF))
+                && (!caseless || case_variants(*c, unicode) == (*c, *c))
+            {
+                Some(*c as u8)
+            } else {
+                None
+            }
+        }
+        Node::Group(node, _) | Node::Atomic(node) => first_byte(node, unicode),
+        Node::Repeat { node, min, .. } if *min > 0 => first_byte(node, unicode),
+        Node::Concat(nodes) => nodes.first().and_then(|node| first_byte(node, unicode)),
+        _ => None,
+    }
+}
+
+struct Compiler {
+    insts: Vec<Inst>,
+    registers: usize,
+    unicode: bool,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, syntax::Error> {
+        if self.insts.len() >= MAX_PROGRAM_SIZE {
+            return Err(syntax::Error {
+                message: "regular expression is too large",
+                offset: 0,
+            });
+        }
+        self.insts.push(inst);
+        Ok(self.insts.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), syntax::Error> {
+        match node {
+            Node::Empty => (),
+            Node::Char { c, caseless } => {
+                if *caseless && case_variants(*c, self.unicode) != (*c, *c) {
+                    self.push(Inst::CharCaseless(*c))?;
+                } else {
+                    self.push(Inst::Char(*c))?;
+                }
+            }
+            Node::Any { dotall: true } => {
+                self.push(Inst::Any)?;
+            }
+            Node::Any { dotall: false } => {
+                self.push(Inst::AnyNotNewline)?;
+            }
+            Node::Byte => {
+                self.push(Inst::Byte)?;
+            }
+            Node::Class(class) => {
+                self.push(Inst::Class(Box::new(class.clone())))?;
+            }
+            // moving the start of the match is undone when backtracking, like any capture
+            Node::Assert(Assertion::ResetStart) => {
+                self.push(Inst::Save(0))?;
+            }
+            Node::Assert(assertion) => {
+                self.push(Inst::Assert(*assertion))?;
+            }
+            Node::Group(node, Some(index)) => {
+                self.push(Inst::Save(2 * index))?;
+                self.compile(node)?;
+                self.push(Inst::Save(2 * index + 1))?;
+            }
+            Node::Group(node, None) => self.compile(node)?,
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alt(nodes) => {
+                let (last, rest) = nodes.split_last().unwrap();
+                let mut jumps = Vec::with_capacity(rest.len());
+                for node in rest {
+                    let split = self.push(Inst::Split(0, 0))?;
+                    self.compile(node)?;
+                    jumps.push(self.push(Inst::Jmp(0))?);
+                    self.insts[split] = Inst::Split(split + 1, self.insts.len());
+                }
+                self.compile(last)?;
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.insts[jump] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+                possessive,
+            } => {
+                if *possessive {
+                    let atomic = self.push(Inst::Atomic { next: 0 })?;
+                    self.compile_repeat(node, *min, *max, true)?;
+                    self.push(Inst::Succeed)?;
+                    self.insts[atomic] = Inst::Atomic {
+                        next: self.insts.len(),
+                    };
+                } else {
+                    self.compile_repeat(node, *min, *max, *greedy)?;
+                }
+            }
+            Node::Backref { groups, caseless } => {
+                self.push(Inst::Backref {
+                    groups: groups.clone(),
+                    caseless: *caseless,
+                })?;
+            }
+            Node::Look {
+                behind: false,
+                negated,
+                node,
+            } => self.compile_look(None, *negated, node)?,
+            Node::Look {
+                behind: true,
+                negated,
+                node,
+            } => match (node.width(), &**node) {
+                (Some(width), _) => self.compile_look(Some(width), *negated, node)?,
+                // top level alternatives may differ in length, (?<=a|bc) is (?<=a)|(?<=bc)
+                (None, Node::Alt(nodes)) if nodes.iter().all(|node| node.width().is_some()) => {
+                    let looks = nodes
+                        .iter()
+                        .map(|node| Node::Look {
+                            behind: true,
+                            negated: *negated,
+                            node: Box::new(node.clone()),
+                        })
+                        .collect();
+                    if *negated {
+                        self.compile(&Node::Concat(looks))?;
+                    } else {
+                        self.compile(&Node::Alt(looks))?;
+                    }
+                }
+                _ => {
+                    return Err(syntax::Error {
+                        message: "lookbehind assertion is not fixed length",
+                        offset: 0,
+                    })
+                }
+            },
+            Node::Atomic(node) => {
+                let atomic = self.push(Inst::Atomic { next: 0 })?;
+                self.compile(node)?;
+                self.push(Inst::Succeed)?;
+                self.insts[atomic] = Inst::Atomic {
+                    next: self.insts.len(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_look(
+        &mut self,
+        behind: Option<usize>,
+        negated: bool,
+        node: &Node,
+    ) -> Result<(), syntax::Error> {
+        let look = self.push(Inst::Look {
+            behind,
+            negated,
+            next: 0,
+        })?;
+        self.compile(node)?;
+        self.push(Inst::Succeed)?;
+        self.insts[look] = Inst::Look {
+            behind,
+            negated,
+            next: self.insts.len(),
+        };
+        Ok(())
+    }
+
+    fn compile_repeat(
+        &mut self,
+        node: &Node,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    ) -> Result<(), syntax::Error> {
+        let split = |from: usize, to: usize| {
+            if greedy {
+                Inst::Split(from + 1, to)
+            } else {
+                Inst::Split(to, from + 1)
+            }
+        };
+
+        for _ in 0..min {
+            self.compile(node)?;
+        }
+        match max {
+            Some(max) => {
+                let mut splits = Vec::with_capacity((max - min) as usize);
+                for _ in min..max {
+                    splits.push(self.push(Inst::Split(0, 0))?);
+                    self.compile(node)?;
+                }
+                let end = self.insts.len();
+                for from in splits {
+                    self.insts[from] = split(from, end);
+                }
+            }
+            None => {
+                let mark = if node.can_be_empty() {
+                    self.registers += 1;
+                    Some(self.registers - 1)
+                } else {
+                    None
+                };
+                let from = self.push(Inst::Split(0, 0))?;
+                if let Some(mark) = mark {
+                    self.push(Inst::Mark(mark))?;
+                }
+                self.compile(node)?;
+                let progress = match mark {
+                    Some(mark) => Some((self.push(Inst::Progress { mark, exit: 0 })?, mark)),
+                    None => None,
+                };
+                self.push(Inst::Jmp(from))?;
+                let end = self.insts.len();
+                self.insts[from] = split(from, end);
+                if let Some((progress, mark)) = progress {
+                    self.insts[progress] = Inst::Progress { mark, exit: end };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Frame {
+    /// An alternative to resume at: program counter and position.
+    Alt(usize, usize),
+    /// The previous value of a register, restored when backtracking past it.
+    Restore(usize, Option<usize>),
+    /// The body of the lookaround or atomic group at the program counter is running, entered
+    /// at the position. Failing back to it means the body didn't match.
+    Sub(usize, usize),
+}
+
+struct Exec<'a> {
+    program: &'a Program,
+    input: &'a [u8],
+    newline: Newline,
+    /// The offset matching started at, for `\G` and `notempty_atstart`.
+    start: usize,
+    flags: Flags,
+    budget: Budget,
+    match_start: usize,
+    slots: Vec<Option<usize>>,
+    stack: Vec<Frame>,
+    /// Where the interrupted attempt continues.
+    resume: Option<(usize, usize)>,
+}
+
+impl<'a> Exec<'a> {
+    /// Tries to match at each position from `from` on, until the steps run out.
+    fn search(&mut self, from: usize) -> Result<Option<Vec<Option<usize>>>, Stop> {
+        let program = self.program;
+        let input = self.input;
+        let anchored = self.flags.anchored || program.anchored;
+        // with firstline, a match has to start before the first newline
+        let last_start = if program.firstline {
+            (self.start..input.len())
+                .find(|&pos| self.newline_at(pos).is_some())
+                .unwrap_or_else(|| input.len())
+        } else {
+            input.len()
+        };
+
+        let mut pos = from;
+        loop {
+            let found = match self.resume.take() {
+                // finish the interrupted attempt first
+                Some((pc, at)) => self.run(pc, at)?,
+                None => {
+                    // always make progress, even without steps left
+                    if self.budget.steps == 0 && pos != from {
+                        return Err(Stop::Yield(pos));
+                    }
+                    if let (Some(byte), false) = (program.first_byte, anchored) {
+                        // skipping ahead costs a step per byte as well
+                        let end = input
+                            .len()
+                            .min(pos.saturating_add(self.budget.steps.max(1)));
+                        match input[pos..end].iter().position(|b| *b == byte) {
+                            Some(skip) => {
+                                pos += skip;
+                                self.budget.steps = self.budget.steps.saturating_sub(skip);
+                            }
+                            None if end == input.len() => return Ok(None),
+                            None => {
+                                pos = end;
+                                self.budget.steps = 0;
+                                continue;
+                            }
+                        }
+                    }
+                    if pos > last_start {
+                        return Ok(None);
+                    }
+                    self.match_start = pos;
+                    self.run(0, pos)?
+                }
+            };
+            if found.is_some() {
+                return Ok(Some(self.slots[..program.slots].to_vec()));
+            }
+            if anchored || pos >= input.len() {
+                return Ok(None);
+            }
+            pos += self.char_len(pos);
+        }
+    }
+
+    /// Counts a backtracking point against `match_limit`.
+    fn call(&mut self) -> Result<(), Stop> {
+        if self.budget.calls >= self.budget.match_limit {
+            return Err(Stop::MatchLimit);
+        }
+        self.budget.calls += 1;
+        Ok(())
+    }
+
+    /// Runs the program from `pc` until a `Match`, returning the end position. Lookaround and
+    /// atomic bodies run on the same stack, so when the steps run out the attempt stops where
+    /// it is and `resume` records where it continues.
+    fn run(&mut self, mut pc: usize, mut pos: usize) -> Result<Option<usize>, Stop> {
+        let program = self.program;
+
+        'run: loop {
+            if self.budget.steps == 0 {
+                self.resume = Some((pc, pos));
+                return Err(Stop::Yield(self.match_start));
+            }
+            self.budget.steps -= 1;
+            match &program.insts[pc] {
+                Inst::Match => {
+                    if self.accept(pos) {
+                        return Ok(Some(pos));
+                    }
+                }
+                Inst::Succeed => {
+                    let (depth, at, entered) = self.succeed();
+                    match &program.insts[at] {
+                        Inst::Look { negated: true, .. } => {
+                            // undo the captures of the body
+                            self.unwind(depth);
+                        }
+                        Inst::Look { next, .. } => {
+                            pc = *next;
+                            pos = entered;
+                            continue;
+                        }
+                        Inst::Atomic { next } => {
+                            pc = *next;
+                            continue;
+                        }
+                        _ => unreachable!("no lookaround or atomic group at {}", at),
+                    }
+                }
+                Inst::Char(c) => {
+                    if let Some((x, len)) = self.decode(pos) {
+                        if x == *c {
+                            pc += 1;
+                            pos += len;
+                            continue;
+                        }
+                    }
+                }
+                Inst::CharCaseless(c) => {
+                    if let Some((x, len)) = self.decode(pos) {
+                        if x == *c || self.fold_eq(x, *c) {
+                            pc += 1;
+                            pos += len;
+                            continue;
+                        }
+                    }
+                }
+                Inst::Any => {
+                    if let Some((_, len)) = self.decode(pos) {
+                        pc += 1;
+                        pos += len;
+                        continue;
+                    }
+                }
+                Inst::AnyNotNewline => {
+                    if let (Some((_, len)), None) = (self.decode(pos), self.newline_at(pos)) {
+                        pc += 1;
+                        pos += len;
+                        continue;
+                    }
+                }
+                Inst::Byte => {
+                    if pos < self.input.len() {
+                        pc += 1;
+                        pos += 1;
+                        continue;
+                    }
+                }
+                Inst::Class(class) => {
+                    if let Some((x, len)) = self.decode(pos) {
+                        if class.matches(x, program.unicode) {
+                            pc += 1;
+                            pos += len;
+                            continue;
+                        }
+                    }
+                }
+                Inst::Assert(assertion) => {
+                    if self.assert(*assertion, pos) {
+                        pc += 1;
+                        continue;
+                    }
+                }
+                Inst::Save(register) | Inst::Mark(register) => {
+                    let previous = self.slots[*register];
+                    self.stack.push(Frame::Restore(*register, previous));
+                    self.slots[*register] = Some(pos);
+                    pc += 1;
+                    continue;
+                }
+                Inst::Split(first, second) => {
+                    self.call()?;
+                    if self.stack.len() >= self.budget.match_limit_recursion {
+                        return Err(Stop::MatchLimitRecursion);
+                    }
+                    self.stack.push(Frame::Alt(*second, pos));
+                    pc = *first;
+                    continue;
+                }
+                Inst::Jmp(target) => {
+                    pc = *target;
+                    continue;
+                }
+                Inst::Progress { mark, exit } => {
+                    pc = if self.slots[*mark] == Some(pos) {
+                        *exit
+                    } else {
+                        pc + 1
+                    };
+                    continue;
+                }
+                Inst::Backref { groups, caseless } => {
+                    if let Some(len) = self.backref(groups, *caseless, pos) {
+                        pc += 1;
+                        pos += len;
+                        continue;
+                    }
+                }
+                Inst::Look {
+                    behind,
+                    negated,
+                    next,
+                } => {
+                    let from = match behind {
+                        Some(width) => self.back(pos, *width),
+                        None => Some(pos),
+                    };
+                    self.call()?;
+                    match from {
+                        Some(from) => {
+                            self.stack.push(Frame::Sub(pc, pos));
+                            pc += 1;
+                            pos = from;
+                            continue;
+                        }
+                        None if *negated => {
+                            pc = *next;
+                            continue;
+                        }
+                        None => (),
+                    }
+                }
+                Inst::Atomic { .. } => {
+                    self.call()?;
+                    self.stack.push(Frame::Sub(pc, pos));
+                    pc += 1;
+                    continue;
+                }
+            }
+
+            // backtrack
+            loop {
+                match self.stack.pop() {
+                    Some(Frame::Alt(to, from)) => {
+                        pc = to;
+                        pos = from;
+                        continue 'run;
+                    }
+                    Some(Frame::Restore(register, value)) => self.slots[register] = value,
+                    Some(Frame::Sub(at, entered)) => {
+                        // the body didn't match, which is what a negative lookaround wants
+                        if let Inst::Look {
+                            negated: true,
+                            next,
+                            ..
+                        } = &program.insts[at]
+                        {
+                            pc = *next;
+                            pos = entered;
+                            continue 'run;
+                        }
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Ends the innermost lookaround or atomic body after it matched. The alternatives it left
+    /// are dropped, so it can't be backtracked into, but its captures can still be undone.
+    /// Returns the stack depth it started at, its instruction and the position it was entered
+    /// at.
+    fn succeed(&mut self) -> (usize, usize, usize) {
+        let depth = self
+            .stack
+            .iter()
+            .rposition(|frame| match frame {
+                Frame::Sub(..) => true,
+                _ => false,
+            })
+            .expect("a body ended outside of a lookaround or atomic group");
+        let (at, entered) = match self.stack[depth] {
+            Frame::Sub(at, entered) => (at, entered),
+            _ => unreachable!(),
+        };
+        let frames = self.stack.split_off(depth + 1);
+        self.stack.truncate(depth);
+        self.stack
+            .extend(frames.into_iter().filter(|frame| match frame {
+                Frame::Restore(..) => true,
+                _ => false,
+            }));
+        (depth, at, entered)
+    }
+
+    /// Pops the stack back to `depth`, restoring registers on the way.
+    fn unwind(&mut self, depth: usize) {
+        while self.stack.len() > depth {
+            if let Some(Frame::Restore(register, value)) = self.stack.pop() {
+                self.slots[register] = value;
+            }
+        }
+    }
+
+    /// Whether the match ending at `pos` can be reported. Its start may have been moved by `\K`.
+    fn accept(&self, pos: usize) -> bool {
+        let start = self.slots[0].unwrap_or(self.match_start);
+        pos != start
+            || !(self.flags.notempty || (self.flags.notempty_atstart && start == self.start))
+    }
+
+    fn decode(&self, pos: usize) -> Option<(u32, usize)> {
+        let byte = *self.input.get(pos)?;
+        if !self.program.unicode || byte < 0x80 {
+            return Some((u32::from(byte), 1));
+        }
+        // the subject was validated as UTF-8
+        let len = utf8_len(byte);
+        let mut c = u32::from(byte) & (0x7F >> len);
+        for byte in &self.input[pos + 1..pos + len] {
+            c = (c << 6) | u32::from(byte & 0x3F);
+        }
+        Some((c, len))
+    }
+
+    fn char_len(&self, pos: usize) -> usize {
+        self.decode(pos).map_or(1, |(_, len)| len)
+    }
+
+    /// The character ending at `pos`.
+    fn decode_back(&self, pos: usize) -> Option<u32> {
+        let back = self.back(pos, 1)?;
+        self.decode(back).map(|(c, _)| c)
+    }
+
+    /// Steps back `chars` characters from `pos`.
+    fn back(&self, mut pos: usize, chars: usize) -> Option<usize> {
+        if !self.program.unicode {
+            return pos.checked_sub(chars);
+        }
+        for _ in 0..chars {
+            pos = pos.checked_sub(1)?;
+            while pos > 0 && self.input[pos] & 0xC0 == 0x80 {
+                pos -= 1;
+            }
+        }
+        Some(pos)
+    }
+
+    fn fold_eq(&self, a: u32, b: u32) -> bool {
+        let (lower_a, upper_a) = case_variants(a, self.program.unicode);
+        let (lower_b, upper_b) = case_variants(b, self.program.unicode);
+        lower_a == lower_b || upper_a == upper_b
+    }
+
+    fn is_word(&self, c: Option<u32>) -> bool {
+        match c {
+            Some(c) if self.program.ucp => Perl::Word.matches(c),
+            Some(c) => c < 0x80 && ((c as u8).is_ascii_alphanumeric() || c == u32::from(b'_')),
+            None => false,
+        }
+    }
+
+    /// Length of the newline starting at `pos`, if there is one.
+    fn newline_at(&self, pos: usize) -> Option<usize> {
+        let input = &self.input[pos..];
+        let first = *input.first()?;
+        let len = match self.newline {
+            Newline::Lf if first == b'\n' => 1,
+            Newline::Cr if first == b'\r' => 1,
+            Newline::CrLf | Newline::AnyCrLf | Newline::Any if input.starts_with(b"\r\n") => 2,
+            Newline::AnyCrLf if first == b'\r' || first == b'\n' => 1,
+            Newline::Any => match first {
+                b'\n' | 0x0B | 0x0C | b'\r' => 1,
+                0x85 if !self.program.unicode => 1,
+                0xC2 if input.starts_with(&[0xC2, 0x85]) => 2,
+                0xE2 if input.starts_with(&[0xE2, 0x80, 0xA8])
+                    || input.starts_with(&[0xE2, 0x80, 0xA9]) =>
+                {
+                    3
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(len)
+    }
+
+    /// Whether a newline ends at `pos`.
+    fn newline_before(&self, pos: usize) -> bool {
+        let input = &self.input[..pos];
+        match self.newline {
+            Newline::Lf => input.ends_with(b"\n"),
+            Newline::Cr => input.ends_with(b"\r"),
+            Newline::CrLf => input.ends_with(b"\r\n"),
+            Newline::AnyCrLf => input.ends_with(b"\n") || input.ends_with(b"\r"),
+            Newline::Any => match self.decode_back(pos) {
+                Some(0x0A..=0x0D) | Some(0x85) | Some(0x2028) | Some(0x2029) => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether `pos` is at a newline ending the subject.
+    fn final_newline(&self, pos: usize) -> bool {
+        self.newline_at(pos)
+            .map_or(false, |len| pos + len == self.input.len())
+    }
+
+    fn assert(&self, assertion: Assertion, pos: usize) -> bool {
+        let len = self.input.len();
+        match assertion {
+            Assertion::Bol { multiline: false } => pos == 0 && !self.flags.notbol,
+            Assertion::Bol { multiline: true } => {
+                if pos == 0 {
+                    !self.flags.notbol
+                } else {
+                    pos < len && self.newline_before(pos)
+                }
+            }
+            Assertion::Eol {
+                multiline: true, ..
+            } => {
+                if pos < len {
+                    self.newline_at(pos).is_some()
+                } else {
+                    !self.flags.noteol
+                }
+            }
+            Assertion::Eol {
+                multiline: false,
+                endonly,
+            } => !self.flags.noteol && (pos == len || (!endonly && self.final_newline(pos))),
+            Assertion::StartText => pos == 0,
+            Assertion::EndText => pos == len,
+            Assertion::EndTextNewline => pos == len || self.final_newline(pos),
+            Assertion::WordBoundary { negated } => {
+                let before = self.is_word(self.decode_back(pos));
+                let after = self.is_word(self.decode(pos).map(|(c, _)| c));
+                (before != after) != negated
+            }
+            Assertion::StartMatch => pos == self.start,
+            // compiled to a `Save`
+            Assertion::ResetStart => unreachable!(),
+        }
+    }
+
+    /// Matches the text of a back reference at `pos`, returning its length in the subject.
+    fn backref(&self, groups: &[usize], caseless: bool, pos: usize) -> Option<usize> {
+        let (start, end) = groups.iter().find_map(|group| {
+            match (self.slots[2 * group], self.slots[2 * group + 1]) {
+                (Some(start), Some(end)) if start <= end => Some((start, end)),
+                _ => None,
+            }
+        })?;
+        if !caseless {
+            return if self.input[pos..].starts_with(&self.input[start..end]) {
+                Some(end - start)
+            } else {
+                None
+            };
+        }
+        let (mut i, mut j) = (start, pos);
+        while i < end {
+            let (a, a_len) = self.decode(i)?;
+            let (b, b_len) = self.decode(j)?;
+            if a != b && !self.fold_eq(a, b) {
+                return None;
+            }
+            i += a_len;
+            j += b_len;
+        }
+        Some(j - pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, input: &str, flags: Flags) -> Option<Vec<Option<(usize, usize)>>> {
+        let options = syntax::Options {
+            unicode: true,
+            ..syntax::Options::default()
+        };
+        let ast = syntax::parse(pattern.as_bytes(), &options).unwrap();
+        let program = Program::new(&ast, &options).unwrap();
+        let slots = program
+            .exec(
+                input.as_bytes(),
+                0,
+                0,
+                flags,
+                &mut Budget::default(),
+                &mut None,
+            )
+            .unwrap()?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|pair| match (pair[0], pair[1]) {
+                    (Some(start), Some(end)) => Some((start, end)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    fn whole(pattern: &str, input: &str) -> Option<(usize, usize)> {
+        find(pattern, input, Flags::default()).map(|groups| groups[0].unwrap())
+    }
+
+    #[test]
+    fn test_exec() {
+        assert_eq!(whole("b+", "abbbc"), Some((1, 4)));
+        assert_eq!(whole("b+?", "abbbc"), Some((1, 2)));
+        assert_eq!(whole("a|ab|c", "xabc"), Some((1, 2)));
+        assert_eq!(whole("^b", "ab"), None);
+        assert_eq!(whole("(?m)^b$", "a\nb\n"), Some((2, 3)));
+        assert_eq!(whole("a$", "a\n"), Some((0, 1)));
+        assert_eq!(whole("(?i)É+", "xéÉ"), Some((1, 5)));
+        assert_eq!(whole(".\\b.", "ab c"), Some((1, 3)));
+        assert_eq!(whole("x{2,3}", "xxxx"), Some((0, 3)));
+
+        assert_eq!(
+            find("(a)|(b)", "b", Flags::default()),
+            Some(vec![Some((0, 1)), None, Some((0, 1))])
+        );
+        // an empty iteration ends the loop instead of failing it
+        assert_eq!(
+            find("(a?)*", "b", Flags::default()),
+            Some(vec![Some((0, 0)), Some((0, 0))])
+        );
+    }
+
+    #[test]
+    fn test_backtracking_features() {
+        assert_eq!(whole("(\\w+) \\1", "say hello hello"), Some((4, 15)));
+        assert_eq!(whole("(?i)(a)\\1", "aA"), Some((0, 2)));
+        assert_eq!(whole("(?<q>['\"]).*?\\k<q>", "x 'a\"b' y"), Some((2, 7)));
+        assert_eq!(whole("foo(?=bar)", "foobaz foobar"), Some((7, 10)));
+        assert_eq!(whole("foo(?!bar)", "foobar foobaz"), Some((7, 10)));
+        assert_eq!(whole("(?<=\\$)\\d+", "a1 $42"), Some((4, 6)));
+        assert_eq!(whole("(?<!\\$)\\b\\d+", "$42 17"), Some((4, 6)));
+        assert_eq!(whole("(?<=ab|c)x", "abx"), Some((2, 3)));
+        assert_eq!(whole("(?>a+)b", "aaab"), Some((0, 4)));
+        assert_eq!(whole("(?>a+)ab", "aaab"), None);
+        assert_eq!(whole("a++a", "aaaa"), None);
+
+        // captures in a failed negative lookahead are undone
+        assert_eq!(
+            find("(?!(a)b)(a)", "ac", Flags::default()),
+            Some(vec![Some((0, 1)), None, Some((0, 1))])
+        );
+
+        assert_eq!(whole("foo\\Kbar", "foobar"), Some((3, 6)));
+        // undone when backtracking out of the alternative that set it
+        assert_eq!(whole("a\\Kx|ab", "ab"), Some((0, 2)));
+        assert_eq!(whole("(?=ab\\K)", "ab"), Some((2, 0)));
+        assert_eq!(whole("(?!a\\K)b", "b"), Some((0, 1)));
+        // a single byte, even in the middle of a character
+        assert_eq!(whole("\\C", "é"), Some((0, 1)));
+        assert_eq!(whole("\\C\\C!", "é!"), Some((0, 3)));
+        assert_eq!(whole("a\\C", "a"), None);
+    }
+
+    #[test]
+    fn test_flags() {
+        let notempty = Flags {
+            notempty: true,
+            ..Flags::default()
+        };
+        assert_eq!(find("a*", "baa", notempty).unwrap()[0], Some((1, 3)));
+        let notbol = Flags {
+            notbol: true,
+            ..Flags::default()
+        };
+        assert_eq!(find("^a", "a", notbol), None);
+        let anchored = Flags {
+            anchored: true,
+            ..Flags::default()
+        };
+        assert_eq!(find("b", "ab", anchored), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        let options = syntax::Options::default();
+        let program = |pattern: &str| {
+            let ast = syntax::parse(pattern.as_bytes(), &options).unwrap();
+            Program::new(&ast, &options).unwrap()
+        };
+        let flags = Flags::default();
+
+        // out of steps, the search continues where it stopped
+        let input = format!("{}b", "a".repeat(1000));
+        let mut budget = Budget {
+            steps: 100,
+            ..Budget::default()
+        };
+        let mut attempt = None;
+        let ab = program("ab");
+        let from = match ab.exec(input.as_bytes(), 0, 0, flags, &mut budget, &mut attempt) {
+            Err(Stop::Yield(from)) => from,
+            other => panic!("expected to yield, got {:?}", other),
+        };
+        assert!(from > 0 && from < 999);
+        budget.steps = usize::max_value();
+        let found = ab.exec(input.as_bytes(), 0, from, flags, &mut budget, &mut attempt);
+        assert_eq!(found.unwrap().unwrap(), vec![Some(999), Some(1001)]);
+
+        // skipping to the first byte runs out of steps too
+        let mut budget = Budget {
+            steps: 10,
+            ..Budget::default()
+        };
+        let stopped = program("b").exec(input.as_bytes(), 0, 0, flags, &mut budget, &mut None);
+        assert_eq!(stopped, Err(Stop::Yield(10)));
+
+        // so do single attempts, which continue with their captures and alternatives
+        let input = "aaaaaaaaaaab";
+        for pattern in &["(a+)+b", "^(?=(a*))\\1b", "(?>a+)b", "(?!(a)b)(?<!b)(a)+b"] {
+            let program = program(pattern);
+            let expected = program.exec(
+                input.as_bytes(),
+                0,
+                0,
+                flags,
+                &mut Budget::default(),
+                &mut None,
+            );
+            let (mut from, mut attempt, mut slices) = (0, None, 0);
+            let found = loop {
+                let mut budget = Budget {
+                    steps: 3,
+                    ..Budget::default()
+                };
+                match program.exec(input.as_bytes(), 0, from, flags, &mut budget, &mut attempt) {
+                    Err(Stop::Yield(pos)) => from = pos,
+                    found => break found,
+                }
+                assert_eq!(from, 0);
+                slices += 1;
+            };
+            assert!(slices > 1);
+            assert!(expected.as_ref().unwrap().is_some());
+            assert_eq!(found, expected);
+        }
+
+        let input = "a".repeat(30);
+        let mut budget = Budget {
+            match_limit: 1000,
+            ..Budget::default()
+        };
+        let stopped = program("(a+)+b").exec(input.as_bytes(), 0, 0, flags, &mut budget, &mut None);
+        assert_eq!(stopped, Err(Stop::MatchLimit));
+        let mut budget = Budget {
+            match_limit_recursion: 10,
+            ..Budget::default()
+        };
+        let stopped =
+            program("(a|b)*c").exec(input.as_bytes(), 0, 0, flags, &mut budget, &mut None);
+        assert_eq!(stopped, Err(Stop::MatchLimitRecursion));
+    }
+}
@@ -0,0 +1,1429 @@
+//! Parser for the PCRE pattern syntax.
+//!
+//! Option flags are resolved while parsing, so every node carries the behaviour it was written
+//! with (`(?i)` in the middle of a pattern only affects what follows it).
+use std::fmt::Write;
+
+/// Highest code point in unicode mode. Outside of it, every byte is a character.
+const MAX_UNICODE: u32 = 0x10_FFFF;
+const MAX_BYTE: u32 = 0xFF;
+/// Longest group name PCRE accepts.
+const MAX_NAME_LEN: usize = 32;
+const UNSUPPORTED_ESCAPE: &str = "PCRE does not support \\L, \\l, \\N{name}, \\U, or \\u";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    Cr,
+    Lf,
+    CrLf,
+    AnyCrLf,
+    Any,
+}
+
+impl Default for Newline {
+    fn default() -> Self {
+        Newline::Lf
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    pub caseless: bool,
+    pub multiline: bool,
+    pub dotall: bool,
+    pub extended: bool,
+    pub ungreedy: bool,
+    pub dollar_endonly: bool,
+    pub no_auto_capture: bool,
+    pub dupnames: bool,
+    pub unicode: bool,
+    pub ucp: bool,
+    pub bsr_anycrlf: bool,
+    pub anchored: bool,
+    pub firstline: bool,
+    pub newline: Newline,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub message: &'static str,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assertion {
+    /// `^`
+    Bol { multiline: bool },
+    /// `$`
+    Eol { multiline: bool, endonly: bool },
+    /// `\A`
+    StartText,
+    /// `\z`
+    EndText,
+    /// `\Z`, end of subject or before a final newline.
+    EndTextNewline,
+    /// `\b` or `\B` if negated.
+    WordBoundary { negated: bool },
+    /// `\G`, the offset matching started at.
+    StartMatch,
+    /// `\K`, not an assertion but it doesn't consume anything either: the reported match starts
+    /// here instead.
+    ResetStart,
+}
+
+/// A `\d`, `\w` or `\s` in `ucp` mode, where they use unicode properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    pub fn matches(self, c: u32) -> bool {
+        let c = match std::char::from_u32(c) {
+            Some(c) => c,
+            None => return false,
+        };
+        match self {
+            Perl::Digit => c.is_numeric(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Class {
+    pub negated: bool,
+    pub caseless: bool,
+    pub ranges: Vec<(u32, u32)>,
+    pub perl: Vec<(Perl, bool)>,
+}
+
+impl Class {
+    fn new(caseless: bool) -> Self {
+        Class {
+            negated: false,
+            caseless,
+            ranges: Vec::new(),
+            perl: Vec::new(),
+        }
+    }
+
+    fn contains(&self, c: u32) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+            || self
+                .perl
+                .iter()
+                .any(|&(perl, negated)| perl.matches(c) != negated)
+    }
+
+    pub fn matches(&self, c: u32, unicode: bool) -> bool {
+        let found = self.contains(c)
+            || (self.caseless && {
+                let (lower, upper) = case_variants(c, unicode);
+                self.contains(lower) || self.contains(upper)
+            });
+        found != self.negated
+    }
+
+    /// Adds the complement of `ranges` (sorted and disjoint).
+    fn push_negated(&mut self, ranges: &[(u32, u32)], max: u32) {
+        let mut next = 0;
+        for &(lo, hi) in ranges {
+            if lo > next {
+                self.ranges.push((next, lo - 1));
+            }
+            next = hi + 1;
+        }
+        if next <= max {
+            self.ranges.push((next, max));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Empty,
+    Char {
+        c: u32,
+        caseless: bool,
+    },
+    /// `.`
+    Any {
+        dotall: bool,
+    },
+    /// `\C`, a single byte, even in the middle of a character in unicode mode.
+    Byte,
+    Class(Class),
+    Assert(Assertion),
+    /// A group, capturing if it has an index.
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+        possessive: bool,
+    },
+    /// A back reference. A name can refer to several groups with `dupnames`, the first one that
+    /// is set is used.
+    Backref {
+        groups: Vec<usize>,
+        caseless: bool,
+    },
+    Look {
+        behind: bool,
+        negated: bool,
+        node: Box<Node>,
+    },
+    Atomic(Box<Node>),
+}
+
+impl Node {
+    /// Number of characters the node always matches, if fixed.
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            Node::Empty | Node::Assert(..) | Node::Look { .. } => Some(0),
+            Node::Char { .. } | Node::Any { .. } | Node::Byte | Node::Class(..) => Some(1),
+            Node::Group(node, _) | Node::Atomic(node) => node.width(),
+            Node::Concat(nodes) => nodes.iter().map(Node::width).sum(),
+            Node::Alt(nodes) => {
+                let width = nodes[0].width()?;
+                if nodes[1..].iter().all(|node| node.width() == Some(width)) {
+                    Some(width)
+                } else {
+                    None
+                }
+            }
+            Node::Repeat { node, min, max, .. } if *max == Some(*min) => {
+                node.width().map(|width| width * *min as usize)
+            }
+            Node::Repeat { .. } | Node::Backref { .. } => None,
+        }
+    }
+
+    /// Whether the node can match without consuming anything.
+    pub fn can_be_empty(&self) -> bool {
+        match self {
+            Node::Empty | Node::Assert(..) | Node::Look { .. } | Node::Backref { .. } => true,
+            Node::Char { .. } | Node::Any { .. } | Node::Byte | Node::Class(..) => false,
+            Node::Group(node, _) | Node::Atomic(node) => node.can_be_empty(),
+            Node::Concat(nodes) => nodes.iter().all(Node::can_be_empty),
+            Node::Alt(nodes) => nodes.iter().any(Node::can_be_empty),
+            Node::Repeat { node, min, .. } => *min == 0 || node.can_be_empty(),
+        }
+    }
+
+    /// Whether a match can only start at the beginning of the subject.
+    pub fn is_anchored(&self) -> bool {
+        match self {
+            Node::Assert(Assertion::StartText)
+            | Node::Assert(Assertion::Bol { multiline: false }) => true,
+            Node::Group(node, _) | Node::Atomic(node) => node.is_anchored(),
+            Node::Concat(nodes) => nodes.first().map_or(false, Node::is_anchored),
+            Node::Alt(nodes) => nodes.iter().all(Node::is_anchored),
+            _ => false,
+        }
+    }
+
+    /// Whether the node contains assertions that behave differently at the end of a subject
+    /// ending with a newline: `$` and `\Z` also match before it, a multiline `^` doesn't match
+    /// after it.
+    pub fn depends_on_final_newline(&self) -> bool {
+        match self {
+            Node::Assert(Assertion::Eol {
+                multiline: false,
+                endonly: false,
+            })
+            | Node::Assert(Assertion::Bol { multiline: true })
+            | Node::Assert(Assertion::EndTextNewline) => true,
+            Node::Group(node, _)
+            | Node::Atomic(node)
+            | Node::Repeat { node, .. }
+            | Node::Look { node, .. } => node.depends_on_final_newline(),
+            Node::Concat(nodes) | Node::Alt(nodes) => {
+                nodes.iter().any(Node::depends_on_final_newline)
+            }
+            _ => false,
+        }
+    }
+
+    /// Translates the node to `regex` crate syntax, if it has an equivalent there.
+    ///
+    /// `^` in multiline mode and `$` translate to assertions that only agree with PCRE if the
+    /// subject doesn't end with a newline, see `depends_on_final_newline`.
+    pub fn write_regex(&self, out: &mut String, options: &Options) -> Option<()> {
+        match self {
+            Node::Empty => out.push_str("(?:)"),
+            Node::Char { c, caseless } => {
+                let (lower, upper) = case_variants(*c, options.unicode);
+                if *caseless && (lower != *c || upper != *c) {
+                    out.push_str("(?i:");
+                    write_char(out, *c, options.unicode);
+                    out.push(')');
+                } else {
+                    write_char(out, *c, options.unicode);
+                }
+            }
+            Node::Any { dotall: true } => out.push_str("(?s:.)"),
+            Node::Any { dotall: false } => out.push_str("[^\\n]"),
+            Node::Class(class) => {
+                if !class.perl.is_empty() || (class.ranges.is_empty() && !class.negated) {
+                    return None;
+                }
+                out.push_str(if class.caseless { "(?i:[" } else { "(?:[" });
+                if class.negated {
+                    out.push('^');
+                }
+                for &(lo, hi) in &class.ranges {
+                    write_char(out, lo, options.unicode);
+                    if hi != lo {
+                        out.push('-');
+                        write_char(out, hi, options.unicode);
+                    }
+                }
+                out.push_str("])");
+            }
+            Node::Assert(assertion) => out.push_str(match assertion {
+                Assertion::Bol { multiline: false } | Assertion::StartText => "\\A",
+                Assertion::Bol { multiline: true } => "(?m:^)",
+                Assertion::Eol {
+                    multiline: true, ..
+                } => "(?m:$)",
+                Assertion::Eol {
+                    multiline: false, ..
+                }
+                | Assertion::EndText
+                | Assertion::EndTextNewline => "\\z",
+                Assertion::WordBoundary { .. } if options.ucp => return None,
+                Assertion::WordBoundary { negated: false } => "(?-u:\\b)",
+                Assertion::WordBoundary { negated: true } => "(?-u:\\B)",
+                Assertion::StartMatch | Assertion::ResetStart => return None,
+            }),
+            Node::Group(node, index) => {
+                out.push_str(if index.is_some() { "(" } else { "(?:" });
+                node.write_regex(out, options)?;
+                out.push(')');
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    node.write_regex(out, options)?;
+                }
+            }
+            Node::Alt(nodes) => {
+                out.push_str("(?:");
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        out.push('|');
+                    }
+                    node.write_regex(out, options)?;
+                }
+                out.push(')');
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+                possessive: false,
+            } => {
+                out.push_str("(?:");
+                node.write_regex(out, options)?;
+                out.push(')');
+                match max {
+                    Some(max) => write!(out, "{{{},{}}}", min, max).unwrap(),
+                    None => write!(out, "{{{},}}", min).unwrap(),
+                }
+                if !greedy {
+                    out.push('?');
+                }
+            }
+            Node::Byte
+            | Node::Repeat { .. }
+            | Node::Backref { .. }
+            | Node::Look { .. }
+            | Node::Atomic(..) => return None,
+        }
+        Some(())
+    }
+}
+
+fn write_char(out: &mut String, c: u32, unicode: bool) {
+    if unicode {
+        write!(out, "\\x{{{:X}}}", c).unwrap();
+    } else {
+        write!(out, "\\x{:02X}", c).unwrap();
+    }
+}
+
+/// The lower and upper case variants of a character. Outside of unicode mode only ASCII letters
+/// have cases, like PCRE's default character tables.
+pub fn case_variants(c: u32, unicode: bool) -> (u32, u32) {
+    if c < 0x80 || !unicode {
+        return match c {
+            0x41..=0x5A => (c + 32, c),
+            0x61..=0x7A => (c, c - 32),
+            _ => (c, c),
+        };
+    }
+    let single = |chars: &mut dyn Iterator<Item = char>| match (chars.next(), chars.next()) {
+        (Some(v), None) => v as u32,
+        _ => c,
+    };
+    match std::char::from_u32(c) {
+        Some(ch) => (
+            single(&mut ch.to_lowercase()),
+            single(&mut ch.to_uppercase()),
+        ),
+        None => (c, c),
+    }
+}
+
+#[derive(Debug)]
+pub struct Ast {
+    pub node: Node,
+    /// Number of capture groups, not counting the whole match.
+    pub captures: usize,
+    /// Group names with the index they refer to, sorted by name.
+    pub names: Vec<(Vec<u8>, usize)>,
+}
+
+pub fn parse(pattern: &[u8], options: &Options) -> Result<Ast, Error> {
+    if options.unicode {
+        if let Err(err) = std::str::from_utf8(pattern) {
+            return Err(Error {
+                message: "invalid UTF-8 string",
+                offset: err.valid_up_to(),
+            });
+        }
+    }
+    let mut parser = Parser {
+        pattern,
+        pos: 0,
+        options,
+        captures: 0,
+        names: Vec::new(),
+        backrefs: Vec::new(),
+    };
+    let mut flags = Flags {
+        caseless: options.caseless,
+        multiline: options.multiline,
+        dotall: options.dotall,
+        extended: options.extended,
+        ungreedy: options.ungreedy,
+        dupnames: options.dupnames,
+    };
+    let mut node = parser.parse_alternation(&mut flags)?;
+    if parser.pos < pattern.len() {
+        // only an unbalanced ) stops the top level
+        return Err(parser.error("unmatched parentheses"));
+    }
+    parser.resolve(&mut node)?;
+
+    let mut names = parser.names;
+    names.sort();
+    Ok(Ast {
+        node,
+        captures: parser.captures,
+        names,
+    })
+}
+
+/// The flags that can be changed inside of a pattern.
+#[derive(Clone, Copy)]
+struct Flags {
+    caseless: bool,
+    multiline: bool,
+    dotall: bool,
+    extended: bool,
+    ungreedy: bool,
+    dupnames: bool,
+}
+
+/// Back references by name or relative number, resolved once all the groups are known.
+enum Reference {
+    Name(Vec<u8>),
+    Number(usize),
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+    options: &'a Options,
+    captures: usize,
+    names: Vec<(Vec<u8>, usize)>,
+    backrefs: Vec<(Reference, usize)>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> Error {
+        Error {
+            message,
+            offset: self.pos,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<u8> {
+        self.pattern.get(self.pos + n).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_str(&mut self, s: &[u8]) -> bool {
+        if self.pattern[self.pos..].starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn max_char(&self) -> u32 {
+        if self.options.unicode {
+            MAX_UNICODE
+        } else {
+            MAX_BYTE
+        }
+    }
+
+    /// Reads the next literal character, which is a whole UTF-8 sequence in unicode mode.
+    fn next_char(&mut self) -> Option<u32> {
+        let byte = self.peek()?;
+        if !self.options.unicode || byte < 0x80 {
+            self.pos += 1;
+            return Some(u32::from(byte));
+        }
+        // the pattern was validated as UTF-8
+        let len = match byte {
+            0xF0..=0xFF => 4,
+            0xE0..=0xEF => 3,
+            _ => 2,
+        };
+        let s = std::str::from_utf8(&self.pattern[self.pos..self.pos + len]).ok()?;
+        self.pos += len;
+        s.chars().next().map(|c| c as u32)
+    }
+
+    fn skip_extended(&mut self, flags: &Flags) {
+        if !flags.extended {
+            return;
+        }
+        while let Some(byte) = self.peek() {
+            match byte {
+                b' ' | b'\t' | b'\n' | b'\r' | 0x0B | 0x0C => self.pos += 1,
+                b'#' => {
+                    while let Some(byte) = self.peek() {
+                        self.pos += 1;
+                        if byte == b'\n' {
+                            break;
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_alternation(&mut self, flags: &mut Flags) -> Result<Node, Error> {
+        let mut branches = vec![self.parse_concat(flags)?];
+        while self.eat(b'|') {
+            branches.push(self.parse_concat(flags)?);
+        }
+        if branches.len() == 1 {
+            Ok(branches.pop().unwrap())
+        } else {
+            Ok(Node::Alt(branches))
+        }
+    }
+
+    fn parse_concat(&mut self, flags: &mut Flags) -> Result<Node, Error> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_extended(flags);
+            match self.peek() {
+                None | Some(b'|') | Some(b')') => break,
+                _ => (),
+            }
+            if let Some(node) = self.parse_atom(flags)? {
+                self.skip_extended(flags);
+                let node = self.parse_quantifier(node, flags)?;
+                nodes.push(node);
+            }
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    /// Parses a `{n}`, `{n,}` or `{n,m}` quantifier at the current position. Returns `None`
+    /// without consuming anything if the brace isn't one, it's then a literal.
+    fn parse_braces(&mut self) -> Result<Option<(u32, Option<u32>)>, Error> {
+        let start = self.pos;
+        let number = |parser: &mut Self| -> Result<Option<u32>, Error> {
+            let begin = parser.pos;
+            let mut value: u32 = 0;
+            while let Some(digit @ b'0'..=b'9') = parser.peek() {
+                value = value * 10 + u32::from(digit - b'0');
+                if value > 65535 {
+                    return Err(parser.error("number too big in {} quantifier"));
+                }
+                parser.pos += 1;
+            }
+            Ok(if parser.pos > begin {
+                Some(value)
+            } else {
+                None
+            })
+        };
+
+        if !self.eat(b'{') {
+            return Ok(None);
+        }
+        let min = match number(self)? {
+            Some(min) => min,
+            None => {
+                self.pos = start;
+                return Ok(None);
+            }
+        };
+        let max = if self.eat(b',') {
+            number(self)?
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some(b'}') {
+            self.pos = start;
+            return Ok(None);
+        }
+        if let Some(max) = max {
+            if max < min {
+                return Err(self.error("numbers out of order in {} quantifier"));
+            }
+        }
+        self.pos += 1;
+        Ok(Some((min, max)))
+    }
+
+    fn parse_quantifier(&mut self, node: Node, flags: &Flags) -> Result<Node, Error> {
+        let (min, max) = match self.peek() {
+            Some(b'{') => match self.parse_braces()? {
+                Some(bounds) => bounds,
+                None => return Ok(node),
+            },
+            Some(byte) => {
+                let bounds = match byte {
+                    b'*' => (0, None),
+                    b'+' => (1, None),
+                    b'?' => (0, Some(1)),
+                    _ => return Ok(node),
+                };
+                self.pos += 1;
+                bounds
+            }
+            None => return Ok(node),
+        };
+
+        let mut greedy = !flags.ungreedy;
+        let mut possessive = false;
+        if self.eat(b'+') {
+            possessive = true;
+            greedy = true;
+        } else if self.eat(b'?') {
+            greedy = !greedy;
+        }
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+            possessive,
+        })
+    }
+
+    fn parse_atom(&mut self, flags: &mut Flags) -> Result<Option<Node>, Error> {
+        let byte = self.peek().unwrap();
+        let node = match byte {
+            b'(' => {
+                self.pos += 1;
+                return self.parse_group(flags);
+            }
+            b'[' => {
+                self.pos += 1;
+                Node::Class(self.parse_class(flags)?)
+            }
+            b'.' => {
+                self.pos += 1;
+                Node::Any {
+                    dotall: flags.dotall,
+                }
+            }
+            b'^' => {
+                self.pos += 1;
+                Node::Assert(Assertion::Bol {
+                    multiline: flags.multiline,
+                })
+            }
+            b'$' => {
+                self.pos += 1;
+                Node::Assert(Assertion::Eol {
+                    multiline: flags.multiline,
+                    endonly: self.options.dollar_endonly,
+                })
+            }
+            b'\\' => {
+                self.pos += 1;
+                return self.parse_escape(flags);
+            }
+            b'*' | b'+' | b'?' => return Err(self.error("nothing to repeat")),
+            b'{' => {
+                let start = self.pos;
+                if self.parse_braces()?.is_some() {
+                    self.pos = start;
+                    return Err(self.error("nothing to repeat"));
+                }
+                self.pos += 1;
+                self.literal(u32::from(b'{'), flags)
+            }
+            _ => {
+                let c = self.next_char().unwrap();
+                self.literal(c, flags)
+            }
+        };
+        Ok(Some(node))
+    }
+
+    fn literal(&self, c: u32, flags: &Flags) -> Node {
+        Node::Char {
+            c,
+            caseless: flags.caseless,
+        }
+    }
+
+    fn parse_name(&mut self, terminator: u8) -> Result<Vec<u8>, Error> {
+        let start = self.pos;
+        while let Some(byte) = self.peek() {
+            match byte {
+                b'0'..=b'9' if self.pos == start => {
+                    return Err(self.error("group name must start with a non-digit"))
+                }
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' => self.pos += 1,
+                _ => break,
+            }
+        }
+        let name = self.pattern[start..self.pos].to_vec();
+        if name.len() > MAX_NAME_LEN {
+            return Err(self.error("subpattern name is too long (maximum 32 characters)"));
+        }
+        if name.is_empty() || !self.eat(terminator) {
+            return Err(self.error("syntax error in subpattern name (missing terminator)"));
+        }
+        Ok(name)
+    }
+
+    fn parse_group(&mut self, flags: &mut Flags) -> Result<Option<Node>, Error> {
+        let mut inner = *flags;
+
+        let node = if self.eat(b'?') {
+            let start = self.pos;
+            match self.peek() {
+                Some(b'#') => {
+                    while let Some(byte) = self.peek() {
+                        self.pos += 1;
+                        if byte == b')' {
+                            return Ok(None);
+                        }
+                    }
+                    return Err(self.error("missing ) after comment"));
+                }
+                Some(b':') => {
+                    self.pos += 1;
+                    Node::Group(Box::new(self.parse_alternation(&mut inner)?), None)
+                }
+                Some(b'>') => {
+                    self.pos += 1;
+                    Node::Atomic(Box::new(self.parse_alternation(&mut inner)?))
+                }
+                Some(b'=') | Some(b'!') => {
+                    self.pos += 1;
+                    Node::Look {
+                        behind: false,
+                        negated: self.pattern[start] == b'!',
+                        node: Box::new(self.parse_alternation(&mut inner)?),
+                    }
+                }
+                Some(b'<') if self.peek_at(1) == Some(b'=') || self.peek_at(1) == Some(b'!') => {
+                    self.pos += 2;
+                    Node::Look {
+                        behind: true,
+                        negated: self.pattern[start + 1] == b'!',
+                        node: Box::new(self.parse_alternation(&mut inner)?),
+                    }
+                }
+                Some(b'<') | Some(b'\'') | Some(b'P') => {
+                    if self.eat_str(b"P=") {
+                        let name = self.parse_name(b')')?;
+                        self.backrefs.push((Reference::Name(name), start));
+                        return Ok(Some(Node::Backref {
+                            groups: Vec::new(),
+                            caseless: flags.caseless,
+                        }));
+                    }
+                    let terminator = match self.pattern[self.pos] {
+                        b'\'' => b'\'',
+                        b'P' if self.peek_at(1) == Some(b'<') => {
+                            self.pos += 1;
+                            b'>'
+                        }
+                        b'P' => return Err(self.error("recursion is not supported")),
+                        _ => b'>',
+                    };
+                    self.pos += 1;
+                    let name = self.parse_name(terminator)?;
+                    self.captures += 1;
+                    let index = self.captures;
+                    if !flags.dupnames && self.names.iter().any(|(n, _)| *n == name) {
+                        self.pos = start;
+                        return Err(self.error("two named subpatterns have the same name"));
+                    }
+                    self.names.push((name, index));
+                    Node::Group(Box::new(self.parse_alternation(&mut inner)?), Some(index))
+                }
+                Some(b'|') => return Err(self.error("branch reset groups are not supported")),
+                Some(b'(') => return Err(self.error("conditional groups are not supported")),
+                Some(b'R') | Some(b'&') | Some(b'+') | Some(b'0'..=b'9') => {
+                    return Err(self.error("recursion is not supported"))
+                }
+                _ => {
+                    // option setting, either for the rest of the group or a non-capturing one
+                    let mut enable = true;
+                    loop {
+                        let flag = match self.peek() {
+                            Some(b'-') if enable => {
+                                enable = false;
+                                self.pos += 1;
+                                continue;
+                            }
+                            Some(b'i') => &mut inner.caseless,
+                            Some(b'm') => &mut inner.multiline,
+                            Some(b's') => &mut inner.dotall,
+                            Some(b'x') => &mut inner.extended,
+                            Some(b'U') => &mut inner.ungreedy,
+                            Some(b'J') => &mut inner.dupnames,
+                            // extra, PCRE's default
+                            Some(b'X') => {
+                                self.pos += 1;
+                                continue;
+                            }
+                            Some(b')') => {
+                                self.pos += 1;
+                                *flags = inner;
+                                return Ok(None);
+                            }
+                            Some(b':') => break,
+                            _ => return Err(self.error("unrecognized character after (? or (?-")),
+                        };
+                        *flag = enable;
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    Node::Group(Box::new(self.parse_alternation(&mut inner)?), None)
+                }
+            }
+        } else if self.peek() == Some(b'*') {
+            return Err(self.error("(*VERB) not recognized or malformed"));
+        } else if self.options.no_auto_capture {
+            Node::Group(Box::new(self.parse_alternation(&mut inner)?), None)
+        } else {
+            self.captures += 1;
+            let index = self.captures;
+            Node::Group(Box::new(self.parse_alternation(&mut inner)?), Some(index))
+        };
+
+        if !self.eat(b')') {
+            return Err(self.error("missing )"));
+        }
+        Ok(Some(node))
+    }
+
+    fn parse_hex(&mut self, max_digits: usize) -> u32 {
+        let mut value = 0;
+        for _ in 0..max_digits {
+            match self.peek().and_then(|byte| (byte as char).to_digit(16)) {
+                Some(digit) => {
+                    value = value * 16 + digit;
+                    self.pos += 1;
+                }
+                None => break,
+            }
+        }
+        value
+    }
+
+    fn parse_octal(&mut self, max_digits: usize) -> u32 {
+        let mut value = 0;
+        for _ in 0..max_digits {
+            match self.peek() {
+                Some(digit @ b'0'..=b'7') => {
+                    value = value * 8 + u32::from(digit - b'0');
+                    self.pos += 1;
+                }
+                _ => break,
+            }
+        }
+        value
+    }
+
+    fn check_char(&self, c: u32) -> Result<u32, Error> {
+        if c > self.max_char() {
+            Err(self.error("character value in \\x{} or \\o{} is too large"))
+        } else {
+            Ok(c)
+        }
+    }
+
+    /// Parses escapes that stand for a single character, valid both inside and outside of
+    /// classes. The backslash and `byte` were already consumed.
+    fn parse_char_escape(&mut self, byte: u8) -> Result<Option<u32>, Error> {
+        let c = match byte {
+            b'a' => 0x07,
+            b'e' => 0x1B,
+            b'f' => 0x0C,
+            b'n' => 0x0A,
+            b'r' => 0x0D,
+            b't' => 0x09,
+            b'0' => self.parse_octal(2),
+            b'o' => {
+                if !self.eat(b'{') {
+                    return Err(self.error("missing opening brace after \\o"));
+                }
+                let start = self.pos;
+                let c = self.parse_octal(11);
+                if self.pos == start || !self.eat(b'}') {
+                    return Err(self.error("non-octal character in \\o{} (closing brace missing?)"));
+                }
+                self.check_char(c)?
+            }
+            b'x' => {
+                if self.eat(b'{') {
+                    let start = self.pos;
+                    let c = self.parse_hex(8);
+                    if self.pos == start || !self.eat(b'}') {
+                        return Err(
+                            self.error("non-hex character in \\x{} (closing brace missing?)")
+                        );
+                    }
+                    self.check_char(c)?
+                } else {
+                    self.parse_hex(2)
+                }
+            }
+            b'c' => match self.peek() {
+                Some(c @ 0x20..=0x7E) => {
+                    self.pos += 1;
+                    u32::from(c.to_ascii_uppercase() ^ 0x40)
+                }
+                _ => return Err(self.error("\\c must be followed by an ASCII character")),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(c))
+    }
+
+    /// Adds the ranges of a `\d`, `\w`, `\s`, `\h` or `\v` class to `class`.
+    fn push_perl_class(&self, class: &mut Class, byte: u8) {
+        let negated = byte.is_ascii_uppercase();
+        let (ranges, perl): (&[(u32, u32)], _) = match byte.to_ascii_lowercase() {
+            b'd' => (&[(0x30, 0x39)], Perl::Digit),
+            b'w' => (
+                &[(0x30, 0x39), (0x41, 0x5A), (0x5F, 0x5F), (0x61, 0x7A)],
+                Perl::Word,
+            ),
+            b's' => (&[(0x09, 0x0D), (0x20, 0x20)], Perl::Space),
+            b'h' => {
+                let ranges: &[(u32, u32)] = if self.options.unicode {
+                    &[
+                        (0x09, 0x09),
+                        (0x20, 0x20),
+                        (0xA0, 0xA0),
+                        (0x1680, 0x1680),
+                        (0x180E, 0x180E),
+                        (0x2000, 0x200A),
+                        (0x202F, 0x202F),
+                        (0x205F, 0x205F),
+                        (0x3000, 0x3000),
+                    ]
+                } else {
+                    &[(0x09, 0x09), (0x20, 0x20), (0xA0, 0xA0)]
+                };
+                return self.push_ranges(class, ranges, negated);
+            }
+            b'v' => {
+                let ranges: &[(u32, u32)] = if self.options.unicode {
+                    &[(0x0A, 0x0D), (0x85, 0x85), (0x2028, 0x2029)]
+                } else {
+                    &[(0x0A, 0x0D), (0x85, 0x85)]
+                };
+                return self.push_ranges(class, ranges, negated);
+            }
+            _ => unreachable!(),
+        };
+        if self.options.ucp {
+            class.perl.push((perl, negated));
+        } else {
+            self.push_ranges(class, ranges, negated);
+        }
+    }
+
+    fn push_ranges(&self, class: &mut Class, ranges: &[(u32, u32)], negated: bool) {
+        if negated {
+            class.push_negated(ranges, self.max_char());
+        } else {
+            class.ranges.extend_from_slice(ranges);
+        }
+    }
+
+    fn parse_escape(&mut self, flags: &Flags) -> Result<Option<Node>, Error> {
+        let byte = match self.peek() {
+            Some(byte) => byte,
+            None => return Err(self.error("\\ at end of pattern")),
+        };
+        self.pos += 1;
+
+        if let Some(c) = self.parse_char_escape(byte)? {
+            return Ok(Some(self.literal(c, flags)));
+        }
+
+        let assertion = |assertion| Ok(Some(Node::Assert(assertion)));
+        match byte {
+            b'd' | b'D' | b'w' | b'W' | b's' | b'S' | b'h' | b'H' | b'v' | b'V' => {
+                let mut class = Class::new(false);
+                self.push_perl_class(&mut class, byte);
+                Ok(Some(Node::Class(class)))
+            }
+            b'N' if self.peek() == Some(b'{') => Err(self.error(UNSUPPORTED_ESCAPE)),
+            b'N' => Ok(Some(Node::Any { dotall: false })),
+            b'R' => {
+                // (?>\r\n|\n|\x0b|\f|\r|\x85)
+                let mut class = Class::new(false);
+                if self.options.bsr_anycrlf {
+                    class
+                        .ranges
+                        .extend_from_slice(&[(0x0A, 0x0A), (0x0D, 0x0D)]);
+                } else {
+                    self.push_perl_class(&mut class, b'v');
+                }
+                let crlf = Node::Concat(vec![
+                    Node::Char {
+                        c: 0x0D,
+                        caseless: false,
+                    },
+                    Node::Char {
+                        c: 0x0A,
+                        caseless: false,
+                    },
+                ]);
+                Ok(Some(Node::Atomic(Box::new(Node::Alt(vec![
+                    crlf,
+                    Node::Class(class),
+                ])))))
+            }
+            b'b' => assertion(Assertion::WordBoundary { negated: false }),
+            b'B' => assertion(Assertion::WordBoundary { negated: true }),
+            b'A' => assertion(Assertion::StartText),
+            b'z' => assertion(Assertion::EndText),
+            b'Z' => assertion(Assertion::EndTextNewline),
+            b'G' => assertion(Assertion::StartMatch),
+            b'Q' => {
+                let mut nodes = Vec::new();
+                while self.pos < self.pattern.len() && !self.eat_str(b"\\E") {
+                    let c = self.next_char().unwrap();
+                    nodes.push(self.literal(c, flags));
+                }
+                Ok(match nodes.len() {
+                    0 => None,
+                    1 => nodes.pop(),
+                    _ => Some(Node::Concat(nodes)),
+                })
+            }
+            b'E' => Ok(None),
+            b'g' | b'k' => {
+                let start = self.pos - 2;
+                let reference = self.parse_reference(byte)?;
+                self.backrefs.push((reference, start));
+                Ok(Some(Node::Backref {
+                    groups: Vec::new(),
+                    caseless: flags.caseless,
+                }))
+            }
+            b'1'..=b'9' => {
+                let start = self.pos - 1;
+                let digits = self.pattern[start..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                let number = std::str::from_utf8(&self.pattern[start..start + digits])
+                    .unwrap()
+                    .parse()
+                    .unwrap_or(usize::max_value());
+                self.pos = start + digits;
+                if number < 10 || number <= self.captures {
+                    self.backrefs.push((Reference::Number(number), start - 1));
+                    return Ok(Some(Node::Backref {
+                        groups: Vec::new(),
+                        caseless: flags.caseless,
+                    }));
+                }
+                // an octal escape, or a literal 8 or 9
+                self.pos = start;
+                if byte >= b'8' {
+                    self.pos += 1;
+                    return Ok(Some(self.literal(u32::from(byte), flags)));
+                }
+                let c = self.parse_octal(3);
+                Ok(Some(self.literal(self.check_char(c)?, flags)))
+            }
+            b'p' | b'P' | b'X' => Err(self.error("unicode properties are not supported")),
+            b'C' => Ok(Some(Node::Byte)),
+            b'K' => assertion(Assertion::ResetStart),
+            b'L' | b'l' | b'U' | b'u' => Err(self.error(UNSUPPORTED_ESCAPE)),
+            _ if byte.is_ascii_alphanumeric() => {
+                Err(self.error("unrecognized character follows \\"))
+            }
+            _ => {
+                self.pos -= 1;
+                let c = self.next_char().unwrap();
+                Ok(Some(self.literal(c, flags)))
+            }
+        }
+    }
+
+    /// Parses the reference of a `\g` or `\k` escape.
+    fn parse_reference(&mut self, kind: u8) -> Result<Reference, Error> {
+        let terminator = match self.peek() {
+            Some(b'{') => Some(b'}'),
+            Some(b'<') if kind == b'k' => Some(b'>'),
+            Some(b'\'') if kind == b'k' => Some(b'\''),
+            _ if kind == b'k' => {
+                return Err(
+                    self.error("\\k is not followed by a braced, angle-bracketed, or quoted name")
+                )
+            }
+            _ => None,
+        };
+        if terminator.is_some() {
+            self.pos += 1;
+        }
+
+        let relative = kind == b'g' && self.eat(b'-');
+        let digits = self.pattern[self.pos..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if kind == b'k' || (digits == 0 && !relative && terminator.is_some()) {
+            let name = self.parse_name(terminator.unwrap())?;
+            return Ok(Reference::Name(name));
+        }
+        if digits == 0 {
+            return Err(self.error("\\g is not followed by a braced, angle-bracketed, or quoted name/number or by a plain number"));
+        }
+        let number: usize = std::str::from_utf8(&self.pattern[self.pos..self.pos + digits])
+            .unwrap()
+            .parse()
+            .map_err(|_| self.error("number is too big"))?;
+        self.pos += digits;
+        if let Some(terminator) = terminator {
+            if !self.eat(terminator) {
+                return Err(self.error("\\g is not followed by a braced, angle-bracketed, or quoted name/number or by a plain number"));
+            }
+        }
+        if number == 0 {
+            return Err(self.error("a numbered reference must not be zero"));
+        }
+        if relative {
+            if number > self.captures {
+                return Err(self.error("reference to non-existent subpattern"));
+            }
+            Ok(Reference::Number(self.captures + 1 - number))
+        } else {
+            Ok(Reference::Number(number))
+        }
+    }
+
+    /// Fills in the groups of back references, in the order they were parsed.
+    fn resolve(&mut self, node: &mut Node) -> Result<(), Error> {
+        let mut backrefs = std::mem::replace(&mut self.backrefs, Vec::new()).into_iter();
+        self.resolve_node(node, &mut backrefs)
+    }
+
+    fn resolve_node(
+        &self,
+        node: &mut Node,
+        backrefs: &mut impl Iterator<Item = (Reference, usize)>,
+    ) -> Result<(), Error> {
+        match node {
+            Node::Backref { groups, .. } => {
+                let (reference, offset) = backrefs.next().unwrap();
+                let error = Error {
+                    message: "reference to non-existent subpattern",
+                    offset,
+                };
+                match reference {
+                    Reference::Number(number) if number <= self.captures => groups.push(number),
+                    Reference::Number(_) => return Err(error),
+                    Reference::Name(name) => {
+                        groups.extend(
+                            self.names
+                                .iter()
+                                .filter(|(n, _)| *n == name)
+                                .map(|(_, index)| *index),
+                        );
+                        if groups.is_empty() {
+                            return Err(error);
+                        }
+                    }
+                }
+            }
+            Node::Group(node, _)
+            | Node::Atomic(node)
+            | Node::Repeat { node, .. }
+            | Node::Look { node, .. } => self.resolve_node(node, backrefs)?,
+            Node::Concat(nodes) | Node::Alt(nodes) => {
+                for node in nodes {
+                    self.resolve_node(node, backrefs)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn parse_class(&mut self, flags: &Flags) -> Result<Class, Error> {
+        let start = self.pos - 1;
+        let mut class = Class::new(flags.caseless);
+        class.negated = self.eat(b'^');
+        let mut first = true;
+        let mut quoted = false;
+
+        loop {
+            if self.pos >= self.pattern.len() {
+                self.pos = start;
+                return Err(self.error("missing terminating ] for character class"));
+            }
+            if quoted {
+                if self.eat_str(b"\\E") {
+                    quoted = false;
+                    continue;
+                }
+            } else if self.eat(b']') {
+                if !first {
+                    break;
+                }
+                class.ranges.push((0x5D, 0x5D));
+                first = false;
+                continue;
+            }
+            first = false;
+
+            if !quoted && self.pattern[self.pos..].starts_with(b"[:") {
+                let name = self.pattern[self.pos + 2..]
+                    .iter()
+                    .enumerate()
+                    .take_while(|&(i, b)| b.is_ascii_lowercase() || (i == 0 && *b == b'^'))
+                    .count();
+                if self.pattern[self.pos + 2 + name..].starts_with(b":]") {
+                    let name = &self.pattern[self.pos + 2..self.pos + 2 + name];
+                    self.push_posix_class(&mut class, name)?;
+                    self.pos += name.len() + 4;
+                    continue;
+                }
+            }
+
+            let lo = match self.parse_class_char(&mut class, &mut quoted)? {
+                Some(c) => c,
+                None => continue,
+            };
+
+            // a range, unless the - ends the class
+            if self.peek() == Some(b'-')
+                && self.peek_at(1).is_some()
+                && (quoted || self.peek_at(1) != Some(b']'))
+            {
+                let dash = self.pos;
+                self.pos += 1;
+                let mut end_class = Class::new(false);
+                let mut end_quoted = quoted;
+                match self.parse_class_char(&mut end_class, &mut end_quoted)? {
+                    Some(hi) if hi < lo => {
+                        return Err(self.error("range out of order in character class"))
+                    }
+                    Some(hi) => {
+                        class.ranges.push((lo, hi));
+                        quoted = end_quoted;
+                        continue;
+                    }
+                    // [a-\d] is a, - and \d
+                    None => self.pos = dash,
+                }
+            }
+            class.ranges.push((lo, lo));
+        }
+
+        class.ranges.sort();
+        Ok(class)
+    }
+
+    /// Parses a single character in a class. Escaped classes like `\d` are added to `class`
+    /// directly and return `None`.
+    fn parse_class_char(
+        &mut self,
+        class: &mut Class,
+        quoted: &mut bool,
+    ) -> Result<Option<u32>, Error> {
+        if *quoted || self.peek() != Some(b'\\') {
+            return Ok(self.next_char());
+        }
+        self.pos += 1;
+        let byte = match self.peek() {
+            Some(byte) => byte,
+            None => return Err(self.error("\\ at end of pattern")),
+        };
+        self.pos += 1;
+        if let Some(c) = self.parse_char_escape(byte)? {
+            return Ok(Some(c));
+        }
+        match byte {
+            b'd' | b'D' | b'w' | b'W' | b's' | b'S' | b'h' | b'H' | b'v' | b'V' => {
+                self.push_perl_class(class, byte);
+                Ok(None)
+            }
+            b'b' => Ok(Some(0x08)),
+            b'1'..=b'7' => {
+                self.pos -= 1;
+                let c = self.parse_octal(3);
+                Ok(Some(self.check_char(c)?))
+            }
+            b'Q' => {
+                *quoted = true;
+                Ok(None)
+            }
+            b'E' => Ok(None),
+            b'p' | b'P' | b'X' => Err(self.error("unicode properties are not supported")),
+            _ if byte.is_ascii_alphanumeric() => {
+                Err(self.error("unrecognized character follows \\"))
+            }
+            _ => {
+                self.pos -= 1;
+                Ok(self.next_char())
+            }
+        }
+    }
+
+    fn push_posix_class(&self, class: &mut Class, name: &[u8]) -> Result<(), Error> {
+        let (negated, name) = match name.split_first() {
+            Some((b'^', rest)) => (true, rest),
+            _ => (false, name),
+        };
+        let ranges: &[(u32, u32)] = match name {
+            b"alnum" => &[(0x30, 0x39), (0x41, 0x5A), (0x61, 0x7A)],
+            b"alpha" => &[(0x41, 0x5A), (0x61, 0x7A)],
+            b"ascii" => &[(0x00, 0x7F)],
+            b"blank" => &[(0x09, 0x09), (0x20, 0x20)],
+            b"cntrl" => &[(0x00, 0x1F), (0x7F, 0x7F)],
+            b"digit" => &[(0x30, 0x39)],
+            b"graph" => &[(0x21, 0x7E)],
+            b"lower" => &[(0x61, 0x7A)],
+            b"print" => &[(0x20, 0x7E)],
+            b"punct" => &[(0x21, 0x2F), (0x3A, 0x40), (0x5B, 0x60), (0x7B, 0x7E)],
+            b"space" => &[(0x09, 0x0D), (0x20, 0x20)],
+            b"upper" => &[(0x41, 0x5A)],
+            b"word" => &[(0x30, 0x39), (0x41, 0x5A), (0x5F, 0x5F), (0x61, 0x7A)],
+            b"xdigit" => &[(0x30, 0x39), (0x41, 0x46), (0x61, 0x66)],
+            _ => return Err(self.error("unknown POSIX class name")),
+        };
+        self.push_ranges(class, ranges, negated);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(pattern: &str) -> Result<Ast, Error> {
+        parse(pattern.as_bytes(), &Options::default())
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let ast = parse_str("(?<year>\\d+)-(?:x)(\\k<year>)").unwrap();
+        assert_eq!(ast.captures, 2);
+        assert_eq!(ast.names, vec![(b"year".to_vec(), 1)]);
+
+        let ast = parse_str("(a)(?'b'c)(?P<a>d)\\g{-1}\\2").unwrap();
+        assert_eq!(ast.captures, 3);
+        assert_eq!(ast.names, vec![(b"a".to_vec(), 3), (b"b".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |pattern| parse_str(pattern).unwrap_err();
+        assert_eq!(error("a**").message, "nothing to repeat");
+        assert_eq!(error("(ab").message, "missing )");
+        assert_eq!(error("ab)").message, "unmatched parentheses");
+        assert_eq!(error("[a").offset, 0);
+        assert_eq!(
+            error("[z-a]").message,
+            "range out of order in character class"
+        );
+        assert_eq!(
+            error("(a)\\2").message,
+            "reference to non-existent subpattern"
+        );
+        assert_eq!(error("(?<n>a)(?<n>b)").offset, 9);
+        assert_eq!(error("a\\U").message, UNSUPPORTED_ESCAPE);
+        assert_eq!(error("\\N{DIGIT ONE}").message, UNSUPPORTED_ESCAPE);
+        assert!(parse_str("\\C\\K\\N").is_ok());
+    }
+
+    #[test]
+    fn test_write_regex() {
+        let translate = |pattern: &str, options: Options| {
+            let ast = parse(pattern.as_bytes(), &options).unwrap();
+            let mut out = String::new();
+            ast.node.write_regex(&mut out, &options).map(|_| out)
+        };
+        let options = Options::default();
+        assert_eq!(translate("a|b.", options).unwrap(), "(?:\\x61|\\x62[^\\n])");
+        assert_eq!(
+            translate("\\d{2,}?", options).unwrap(),
+            "(?:(?:[\\x30-\\x39])){2,}?"
+        );
+        assert!(translate("(a)\\1", options).is_none());
+        assert!(translate("a(?=b)", options).is_none());
+        assert!(translate("a*+", options).is_none());
+
+        let options = Options {
+            caseless: true,
+            unicode: true,
+            ..Options::default()
+        };
+        assert_eq!(translate("é1", options).unwrap(), "(?i:\\x{E9})\\x{31}");
+    }
+}
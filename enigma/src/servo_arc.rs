@@ -362,6 +362,12 @@ impl<T: ?Sized> Arc<T> {
         // [1] https://github.com/servo/servo/issues/21186
         self.inner().count.load(Acquire) == 1
     }
+
+    /// Gets the number of strong references to this allocation.
+    #[inline]
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().count.load(Acquire)
+    }
 }
 
 impl<T: ?Sized> Drop for Arc<T> {
@@ -1,8 +1,11 @@
 use parking_lot::Mutex;
 use std::collections::VecDeque;
 
+use crate::bif::info;
 use crate::bitstring;
+use crate::ets;
 use crate::exception::Exception;
+use crate::immix::Heap;
 use crate::port;
 use crate::process::{Ref, PID};
 use crate::value::Term;
@@ -20,15 +23,27 @@ pub enum Signal {
         from: PID,
         reason: Exception,
         kind: ExitKind,
+        /// Holds the reason until the receiver takes it over.
+        heap: Heap,
     },
     Message {
         from: PID,
         value: Term,
+        /// Holds the value until the receiver takes it over.
+        heap: Heap,
     },
     PortMessage {
         from: port::ID,
         value: bitstring::RcBinary,
     },
+    /// Table ownership was transferred through an heir or ets:give_away/3.
+    EtsTransfer {
+        from: PID,
+        table: ets::RcTable,
+        /// Heir or gift data, lives on `heap` until the receiver takes it over.
+        data: Term,
+        heap: Heap,
+    },
     Link {
         from: PID,
     },
@@ -48,6 +63,32 @@ pub enum Signal {
         from: PID,
         reference: Ref,
     },
+    /// `process_info/1,2` from another process.
+    ProcessInfo {
+        from: PID,
+        request: info::Request,
+    },
+}
+
+impl Signal {
+    /// A message, copied onto a heap fragment so it doesn't point into the sender's heap.
+    pub fn message(from: PID, value: Term) -> Signal {
+        let heap = Heap::fragment();
+        let value = value.deep_clone(&heap);
+        Signal::Message { from, value, heap }
+    }
+
+    /// An exit signal, with the reason copied like a message.
+    pub fn exit(from: PID, reason: &Exception, kind: ExitKind) -> Signal {
+        let heap = Heap::fragment();
+        let reason = Exception::with_value(reason.reason, reason.value.deep_clone(&heap));
+        Signal::Exit {
+            from,
+            reason,
+            kind,
+            heap,
+        }
+    }
 }
 
 #[derive(Default, Debug)]
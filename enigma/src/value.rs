@@ -340,6 +340,8 @@ pub const BOXED_EXPORT: u8 = 21;
 pub const BOXED_FILE: u8 = 22;
 pub const BOXED_BUFFER: u8 = 23;
 pub const BOXED_REGEX: u8 = 24;
+pub const BOXED_RE_PATTERN: u8 = 25;
+pub const BOXED_ZLIB: u8 = 26;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -546,7 +548,7 @@ impl Term {
         }))
     }
 
-    pub fn file(heap: &Heap, value: std::fs::File) -> Self {
+    pub fn file(heap: &Heap, value: crate::bif::prim_file::RawFile) -> Self {
         Term::from(heap.alloc(Boxed {
             header: BOXED_FILE,
             value,
@@ -567,6 +569,20 @@ impl Term {
         }))
     }
 
+    pub fn re_pattern(heap: &Heap, value: crate::regex::Pattern) -> Self {
+        Term::from(heap.alloc(Boxed {
+            header: BOXED_RE_PATTERN,
+            value,
+        }))
+    }
+
+    pub fn zlib(heap: &Heap, value: crate::bif::zlib::Zstream) -> Self {
+        Term::from(heap.alloc(Boxed {
+            header: BOXED_ZLIB,
+            value,
+        }))
+    }
+
     pub fn boxed<T>(heap: &Heap, header: u8, value: T) -> Self {
         Term::from(heap.alloc(Boxed { header, value }))
     }
@@ -652,6 +668,8 @@ impl Term {
                 BOXED_FILE => Type::Ref,   // files are stored as magic ref pointers in beam
                 BOXED_BUFFER => Type::Ref, // files are stored as magic ref pointers in beam
                 BOXED_REGEX => Type::Ref,
+                BOXED_RE_PATTERN => Type::Ref,
+                BOXED_ZLIB => Type::Ref,
                 i => unimplemented!("get_type for {}", i),
             },
             _ => unreachable!(),
@@ -905,6 +923,20 @@ impl Term {
                         }
                         Term::map(heap, new_map)
                     }
+                    BOXED_CLOSURE => {
+                        let closure = &(*(ptr as *const Boxed<Closure>)).value;
+                        let binding = closure.binding.as_ref().map(|binding| {
+                            binding.iter().map(|val| val.deep_clone(heap)).collect()
+                        });
+                        Term::closure(
+                            heap,
+                            Closure {
+                                ptr: closure.ptr,
+                                mfa: closure.mfa,
+                                binding,
+                            },
+                        )
+                    }
                     BOXED_EXPORT => {
                         let export = &(*(ptr as *const Boxed<module::MFA>)).value;
                         Term::export(heap, *export)
@@ -928,6 +960,24 @@ impl Term {
                             value: bin.clone(),
                         }))
                     }
+                    BOXED_REGEX => {
+                        let regex = &(*(ptr as *const Boxed<regex::bytes::Regex>)).value;
+                        Term::regex(heap, regex.clone())
+                    }
+                    BOXED_RE_PATTERN => {
+                        let pattern = &(*(ptr as *const Boxed<crate::regex::Pattern>)).value;
+                        Term::re_pattern(heap, pattern.clone())
+                    }
+                    BOXED_FILE => {
+                        // the handle itself is shared
+                        let file = &(*(ptr as *const Boxed<crate::bif::prim_file::RawFile>)).value;
+                        Term::file(heap, file.clone())
+                    }
+                    BOXED_ZLIB => {
+                        // the stream itself is shared
+                        let stream = &(*(ptr as *const Boxed<crate::bif::zlib::Zstream>)).value;
+                        Term::zlib(heap, stream.clone())
+                    }
                     _ => unimplemented!("deep_clone for {}", self), // TODO: deep clone for Ref<>
                 }
             },
@@ -1182,8 +1232,24 @@ impl Ord for Variant {
                     }
                 } else {
                     match (h1, h2) {
-                        (BOXED_BINARY, BOXED_SUBBINARY) => unimplemented!(),
-                        (BOXED_SUBBINARY, BOXED_BINARY) => unimplemented!(),
+                        (BOXED_BINARY, BOXED_SUBBINARY) => {
+                            let b1 = &(*(*p1 as *const Boxed<bitstring::RcBinary>)).value;
+                            let b2 = &(*(*p2 as *const Boxed<bitstring::SubBinary>)).value;
+                            let (len1, len2) = (b1.data.len() * 8, b2.size * 8 + b2.bitsize);
+
+                            // compare the common prefix, then the shorter one is smaller
+                            match bitstring::cmp_bits(
+                                b1.data.as_ptr(),
+                                0,
+                                b2.original.data.as_ptr(),
+                                (b2.offset * 8) + b2.bit_offset as usize,
+                                std::cmp::min(len1, len2),
+                            ) {
+                                std::cmp::Ordering::Equal => len1.cmp(&len2),
+                                cmp => cmp,
+                            }
+                        }
+                        (BOXED_SUBBINARY, BOXED_BINARY) => other.cmp(self).reverse(),
                         _ => unimplemented!(),
                     }
                 }
@@ -1222,7 +1288,12 @@ impl std::fmt::Display for Variant {
             Variant::Float(self::Float(i)) => write!(f, "{}", i),
             Variant::Atom(i) => write!(f, ":{}", i.to_str().unwrap()),
             Variant::Port(i) => write!(f, "#Port<{}>", i),
-            Variant::Pid(i) => write!(f, "#Pid<{}>", i),
+            Variant::Pid(i) => write!(
+                f,
+                "#PID<0.{}.{}>",
+                process::table::pid_number(i),
+                process::table::pid_serial(i)
+            ),
             Variant::Cons(c) => unsafe {
                 let cons = &**c;
                 let is_printable = cons.iter().all(|v| match v.into_variant() {
@@ -1326,6 +1397,8 @@ impl std::fmt::Display for Variant {
                     BOXED_FILE => write!(f, "#File<REF>"),
                     BOXED_BUFFER => write!(f, "#Buffer<REF>"),
                     BOXED_REGEX => write!(f, "#Ref<Regex>"),
+                    BOXED_RE_PATTERN => write!(f, "#Ref<Pattern>"),
+                    BOXED_ZLIB => write!(f, "#Ref<Zstream>"),
                     _ => unimplemented!(),
                 }
             },
@@ -1,5 +1,6 @@
 use crate::atom::Atom;
 use crate::{bitstring, module, instruction};
+use crate::bif::blocking;
 use crate::exception::{self, Exception, Reason};
 use crate::process::{self, RcProcess};
 // needs arbitrary_self_types
@@ -65,6 +66,12 @@ pub struct Machine {
     pub ets_tables: RcTableRegistry,
 
     pub persistent_terms: PersistentTermTable,
+
+    /// Number of schedulers (threads in the process pool).
+    pub schedulers: usize,
+
+    /// Maximum depth of the stack trace saved when an exception is raised.
+    pub backtrace_depth: AtomicUsize,
 }
 
 thread_local!(
@@ -148,8 +155,19 @@ pub const PRE_LOADED: &[&[u8]] = &[
     include_bytes!("../../otp/erts/preloaded/ebin/persistent_term.beam"),
 ];
 
+/// Number of logical processors that are currently online.
+pub fn logical_processors() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if count < 1 {
+        1
+    } else {
+        count as usize
+    }
+}
+
 impl Machine {
     pub fn new() -> Arc<Machine> {
+        let schedulers = logical_processors();
         let vm = Arc::new(Machine {
             process_table: Mutex::new(ProcessTable::new()),
             process_registry: Mutex::new(ProcessRegistry::new()),
@@ -164,6 +182,8 @@ impl Machine {
             modules: ModuleRegistry::with_rc(),
             ets_tables: TableRegistry::with_rc(),
             persistent_terms: PersistentTermTable::new(),
+            schedulers,
+            backtrace_depth: AtomicUsize::new(exception::DEFAULT_BACKTRACE_SIZE as usize),
         });
 
         // initialize tokio here
@@ -291,7 +311,16 @@ pub async fn run_with_error_handling(mut process: RcProcess) {
     //let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
     let vm = Machine::current();
     loop {
-        match instruction::run(&*vm, &mut process).await {
+        // a BIF that trapped on offloaded work is resumed once the work is done
+        let result = match process.local_data_mut().blocking.take() {
+            Some(job) => blocking::resume(&process, job).await,
+            // and one that trapped to do its work in slices continues with the next one
+            None => match process.local_data_mut().trap.take() {
+                Some(then) => blocking::resume_trap(&process, then).await,
+                None => instruction::run(&*vm, &mut process).await,
+            },
+        };
+        match result {
             Err(message) => {
                 if message.reason != Reason::TRAP {
                     // just a regular error
@@ -305,13 +334,34 @@ pub async fn run_with_error_handling(mut process: RcProcess) {
                         // println!("pid={} action=exited", process.pid);
                         break // crashed
                     }
+                } else if process.local_data().hibernating {
+                    // erlang:hibernate/3, sleep until there's a message
+                    if let Err(reason) = process.wait_for_message().await {
+                        process.exit(&vm, reason);
+                        break
+                    }
+                } else if process.local_data().blocking.is_some() {
+                    // parked until the offloaded work is done, see above
+                } else if process.local_data().trap.is_some() {
+                    // the BIF continues in its next slice, see above
                 } else {
                     // we're trapping, ip was already set, now reschedule the process
                     eprintln!("TRAP!");
                     // yield
                 }
             }
-            Ok(process::State::Yield) => (), // yield
+            Ok(process::State::Yield) => {
+                // handle pending signals and suspension at the scheduling point
+                let result = match process.process_incoming() {
+                    Ok(()) => process.wait_while_suspended().await,
+                    err => err,
+                };
+                if let Err(reason) = result {
+                    // exit signals can't be caught
+                    process.exit(&vm, reason);
+                    break
+                }
+            } // yield
             Ok(process::State::Done) => {
                 process.exit(&vm, Exception::with_value(Reason::EXC_EXIT, atom!(NORMAL)));
 
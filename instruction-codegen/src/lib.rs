@@ -342,7 +342,7 @@ pub fn instruction(tokens: TokenStream) -> TokenStream {
         ) -> impl std::future::Future<Output = Result<process::State, Exception>> + Captures<'a> + Captures<'b> + 'c {
             async move {  // workaround for https://github.com/rust-lang/rust/issues/56238
             let context = process.context_mut();
-            context.reds = 2000; // self.config.reductions;
+            context.reds = crate::process::CONTEXT_REDS; // self.config.reductions;
 
             // process the incoming signal queue
             process.process_incoming()?;